@@ -0,0 +1,355 @@
+use super::emitter;
+use super::lexer::Token;
+use super::modules;
+use super::parser::{ASTNode, ExternFunction};
+
+use std::collections::HashMap;
+
+// How a call to a global that's never reassigned can skip its closure
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direct<F> {
+    // A Silver function by its index, which still takes its closure
+    Function(F, usize),
+    // A native or external function by its index, which takes only its arguments
+    Extern(F, usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GlobalName<F> {
+    pub index: F,
+    pub direct: Option<Direct<F>>,
+}
+
+// The index of a backend's local slot or captured cell
+pub trait Slot: Copy {
+    fn from_index(index: usize) -> Self;
+}
+
+impl Slot for u16 {
+    fn from_index(index: usize) -> u16 {
+        index as u16
+    }
+}
+
+impl Slot for u32 {
+    fn from_index(index: usize) -> u32 {
+        index as u32
+    }
+}
+
+impl Slot for usize {
+    fn from_index(index: usize) -> usize {
+        index
+    }
+}
+
+// Where a name's value is kept
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Var<S, G> {
+    Local(S),
+    // A slot holding a cell, since a nested function captures the name
+    Cell(S),
+    // A cell of the function's closure, by its index
+    Captured(S),
+    Global(G),
+    // The function being compiled, within its own body
+    Itself,
+}
+
+// The top-level names of one of the program's modules
+pub struct Source<G> {
+    // What the names of the module's globals and functions start with
+    pub prefix: String,
+    pub globals: HashMap<String, G>,
+    pub imports: HashMap<String, usize>,
+    pub exports: Vec<String>,
+}
+
+impl<G> Source<G> {
+    // A module's source, before its globals are added. Imports refer to modules by their
+    // index among the sources, where the prelude comes first.
+    pub fn new(module: &modules::Module, prefix: String) -> Source<G> {
+        Source {
+            prefix,
+            globals: HashMap::new(),
+            imports: module
+                .imports
+                .iter()
+                .map(|&(ref alias, import)| (alias.clone(), import + 1))
+                .collect(),
+            exports: module.exports.clone(),
+        }
+    }
+}
+
+// The names a module binds at the top level, which are each kept in a global
+pub fn global_names(module: &modules::Module) -> Vec<String> {
+    let mut names = Vec::new();
+    emitter::collect_definitions(&module.ast, &mut names);
+    if let ASTNode::Sequence(ref exprs) = module.ast {
+        for expr in exprs.iter() {
+            for name in expr.declared_names() {
+                if !names.iter().any(|defined| defined == name) {
+                    names.push(String::from(name));
+                }
+            }
+        }
+    }
+    names
+}
+
+// The names of a function being compiled. The module's top-level code is compiled as a
+// function too.
+pub struct Scope<S, G> {
+    // The names bound in each block, innermost last
+    pub scopes: Vec<Vec<(String, Var<S, G>)>>,
+    // The names that functions nested in this one refer to, which are kept in cells
+    pub captured: Vec<String>,
+    // The names this function captures from enclosing ones, by index
+    pub captures: Vec<String>,
+}
+
+impl<S: Slot, G: Copy> Scope<S, G> {
+    pub fn new() -> Scope<S, G> {
+        Scope {
+            scopes: vec![Vec::new()],
+            captured: Vec::new(),
+            captures: Vec::new(),
+        }
+    }
+
+    pub fn find(&self, name: &str) -> Option<Var<S, G>> {
+        for scope in self.scopes.iter().rev() {
+            for &(ref bound, var) in scope.iter().rev() {
+                if bound == name {
+                    return Some(var);
+                }
+            }
+        }
+        self.captures
+            .iter()
+            .position(|captured| captured == name)
+            .map(|index| Var::Captured(S::from_index(index)))
+    }
+
+    pub fn enter(&mut self) {
+        self.scopes.push(Vec::new());
+    }
+
+    pub fn leave(&mut self) {
+        self.scopes.pop();
+    }
+
+    // Binds a name in the innermost block
+    pub fn bind(&mut self, name: &str, var: Var<S, G>) {
+        let scope = self.scopes.last_mut().expect("A frame always has a scope");
+        scope.push((String::from(name), var));
+    }
+
+    pub fn is_captured(&self, name: &str) -> bool {
+        self.captured.iter().any(|captured| captured == name)
+    }
+}
+
+impl<S: Slot, G: Copy> Default for Scope<S, G> {
+    fn default() -> Scope<S, G> {
+        Scope::new()
+    }
+}
+
+// A backend's frame, which compiles one of the functions on the stack of those being
+// compiled, the innermost last
+pub trait Frame<S, G> {
+    fn scope(&self) -> &Scope<S, G>;
+    fn scope_mut(&mut self) -> &mut Scope<S, G>;
+}
+
+// Looks a name up in the functions being compiled and then in the current module's
+// globals
+pub fn lookup<S: Slot, G: Copy, F: Frame<S, G>>(
+    frames: &mut [F],
+    sources: &[Source<G>],
+    current: usize,
+    name: &str,
+) -> Option<Var<S, G>> {
+    lookup_frame(frames, name).or_else(|| lookup_global(sources, current, name))
+}
+
+// Looking up a name that an enclosing function binds captures it, in each function
+// between that one and the innermost
+fn lookup_frame<S: Slot, G: Copy, F: Frame<S, G>>(
+    frames: &mut [F],
+    name: &str,
+) -> Option<Var<S, G>> {
+    let (frame, enclosing) = frames.split_last_mut()?;
+    if let Some(var) = frame.scope().find(name) {
+        return Some(var);
+    }
+    // The module's top-level code is at the bottom, and captures nothing
+    if enclosing.is_empty() {
+        return None;
+    }
+
+    lookup_frame(enclosing, name)?;
+    let captures = &mut frame.scope_mut().captures;
+    captures.push(String::from(name));
+    Some(Var::Captured(S::from_index(captures.len() - 1)))
+}
+
+// A module's own names shadow the prelude's
+fn lookup_global<S, G: Copy>(
+    sources: &[Source<G>],
+    current: usize,
+    name: &str,
+) -> Option<Var<S, G>> {
+    if let Some(&global) = sources[current].globals.get(name) {
+        return Some(Var::Global(global));
+    }
+    let prelude = &sources[0];
+    if current != 0 && prelude.exports.iter().any(|export| export == name) {
+        return prelude.globals.get(name).map(|&global| Var::Global(global));
+    }
+    None
+}
+
+pub fn lookup_qualified<S, G: Copy>(
+    sources: &[Source<G>],
+    current: usize,
+    module: &str,
+    name: &str,
+) -> Option<Var<S, G>> {
+    let source = &sources[*sources[current].imports.get(module)?];
+    if !source.exports.iter().any(|export| export == name) {
+        return None;
+    }
+    source.globals.get(name).map(|&global| Var::Global(global))
+}
+
+// The names that a function about to be compiled keeps in its own slots, apart from its
+// parameters and its own name. A let always binds a new name, while an assignment only
+// does if the name isn't bound outside the function.
+pub fn function_locals<S: Slot, G: Copy, F: Frame<S, G>>(
+    frames: &[F],
+    source: &Source<G>,
+    name: Option<&str>,
+    params: &[String],
+    body: &ASTNode,
+) -> Vec<String> {
+    let visible = |local: &str| {
+        frames
+            .iter()
+            .any(|frame| frame.scope().find(local).is_some())
+            || source.globals.contains_key(local)
+    };
+    let mut locals = Vec::new();
+    emitter::collect_locals(body, &visible, &mut locals);
+    locals.retain(|local| !params.contains(local) && Some(local.as_str()) != name);
+    locals
+}
+
+// The import module and field an extern is bound to. A global without a JS name is the JS
+// global of the same name.
+pub fn extern_target(module: &Option<String>, function: &ExternFunction) -> (String, String) {
+    let field = function
+        .js_name
+        .clone()
+        .unwrap_or_else(|| function.name.clone());
+    match *module {
+        Some(ref module) => (module.clone(), field),
+        None => (String::from("host"), field),
+    }
+}
+
+// How many times each name is bound anywhere in a module: declared, assigned or let
+pub fn bindings(ast: &ASTNode) -> HashMap<String, usize> {
+    let mut counts = HashMap::new();
+    let mut count = |name: &str| *counts.entry(String::from(name)).or_insert(0) += 1;
+    ast.walk(&mut |node| match *node {
+        ASTNode::Sequence(ref exprs) => {
+            for (name, _) in exprs.iter().filter_map(ASTNode::function_name) {
+                count(name);
+            }
+        }
+        ASTNode::Let { ref name, .. } => {
+            if let Some((name, _)) = name.binding() {
+                count(name);
+            }
+        }
+        ASTNode::Binary {
+            op: Token::Operator(ref op),
+            ref lhs,
+            ..
+        } if op == "=" => {
+            if let ASTNode::Name(ref name, _) = **lhs {
+                count(name);
+            }
+        }
+        ASTNode::TypeDeclaration { ref variants, .. } => {
+            for variant in variants.iter() {
+                count(&variant.name);
+            }
+        }
+        ASTNode::Extern { ref functions, .. } => {
+            for function in functions.iter() {
+                count(&function.name);
+            }
+        }
+        _ => {}
+    });
+    counts
+}
+
+// Whether a function calls itself in tail position with as many arguments as it takes
+pub fn calls_itself(name: &str, arity: usize, node: &ASTNode) -> bool {
+    match *node {
+        ASTNode::Invocation {
+            ref func, ref args, ..
+        } => args.len() == arity && matches!(**func, ASTNode::Name(ref func, _) if func == name),
+        ASTNode::Conditional {
+            ref if_body,
+            ref else_body,
+            ..
+        } => {
+            calls_itself(name, arity, if_body)
+                || (**else_body)
+                    .as_ref()
+                    .is_some_and(|else_body| calls_itself(name, arity, else_body))
+        }
+        ASTNode::Sequence(ref exprs) => exprs
+            .last()
+            .is_some_and(|last| calls_itself(name, arity, last)),
+        ASTNode::Annotated { ref expr, .. } => calls_itself(name, arity, expr),
+        ASTNode::Match { ref arms, .. } => {
+            arms.iter().any(|arm| calls_itself(name, arity, &arm.body))
+        }
+        _ => false,
+    }
+}
+
+// Collects the names that the functions nested in a body refer to or assign, without regard
+// to scope, so that a name may be kept in a cell needlessly but never wrongly
+pub fn collect_captured(body: &ASTNode, names: &mut Vec<String>) {
+    let add = |name: &str, names: &mut Vec<String>| {
+        if !names.iter().any(|known| known == name) {
+            names.push(String::from(name));
+        }
+    };
+    body.walk(&mut |node| {
+        if let ASTNode::Function { ref body, .. } = *node {
+            body.walk(&mut |node| match *node {
+                ASTNode::Name(ref name, _) => add(name, names),
+                ASTNode::Binary { ref lhs, .. } => {
+                    if let ASTNode::Name(ref name, _) = **lhs {
+                        add(name, names);
+                    }
+                }
+                ASTNode::Let { ref name, .. } => {
+                    if let Some((name, _)) = name.binding() {
+                        add(name, names);
+                    }
+                }
+                _ => {}
+            });
+        }
+    });
+}
@@ -0,0 +1,1103 @@
+use super::backend::{self, Scope};
+use super::lexer::Token;
+use super::modules;
+use super::parser::{ASTNode, ExternFunction, MatchArm, Pattern, Variant};
+use super::vm;
+
+use super::util::{Error, Span};
+
+use std::convert::TryFrom;
+use std::fmt;
+
+// The instructions of a stack machine. Each is a byte followed by its operands, which are
+// little-endian: indices of slots, constants, globals and functions take two bytes, counts
+// of values one, and jump targets four.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instr {
+    Constant(u16),
+    True,
+    False,
+    Pop,
+    Dup,
+    GetLocal(u16),
+    SetLocal(u16),
+    // Replaces the value in a slot with a cell holding it
+    NewCell(u16),
+    GetCell(u16),
+    SetCell(u16),
+    GetCaptured(u16),
+    SetCaptured(u16),
+    GetGlobal(u16),
+    SetGlobal(u16),
+    // The closure of the function being run
+    Itself,
+    Native(u16),
+    Closure(u16),
+    Call(u8),
+    // Calls a function in place of the one being run, which returns what it returns
+    TailCall(u8),
+    Return,
+    Jump(u32),
+    JumpIfFalse(u32),
+    JumpUnlessFalse(u32),
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    List(u16),
+    // A constructor's data, from as many fields as its count
+    Data(u16, u8),
+    JumpUnlessTag(u16, u32),
+    Field(u8),
+    NoMatch,
+}
+
+impl Instr {
+    fn opcode(self) -> u8 {
+        match self {
+            Instr::Constant(_) => 0,
+            Instr::True => 1,
+            Instr::False => 2,
+            Instr::Pop => 3,
+            Instr::Dup => 4,
+            Instr::GetLocal(_) => 5,
+            Instr::SetLocal(_) => 6,
+            Instr::NewCell(_) => 7,
+            Instr::GetCell(_) => 8,
+            Instr::SetCell(_) => 9,
+            Instr::GetCaptured(_) => 10,
+            Instr::SetCaptured(_) => 11,
+            Instr::GetGlobal(_) => 12,
+            Instr::SetGlobal(_) => 13,
+            Instr::Itself => 14,
+            Instr::Native(_) => 15,
+            Instr::Closure(_) => 16,
+            Instr::Call(_) => 17,
+            Instr::TailCall(_) => 18,
+            Instr::Return => 19,
+            Instr::Jump(_) => 20,
+            Instr::JumpIfFalse(_) => 21,
+            Instr::JumpUnlessFalse(_) => 22,
+            Instr::Add => 23,
+            Instr::Subtract => 24,
+            Instr::Multiply => 25,
+            Instr::Divide => 26,
+            Instr::Remainder => 27,
+            Instr::Equal => 28,
+            Instr::NotEqual => 29,
+            Instr::Less => 30,
+            Instr::LessEqual => 31,
+            Instr::Greater => 32,
+            Instr::GreaterEqual => 33,
+            Instr::List(_) => 34,
+            Instr::Data(..) => 35,
+            Instr::JumpUnlessTag(..) => 36,
+            Instr::Field(_) => 37,
+            Instr::NoMatch => 38,
+        }
+    }
+
+    pub fn encode(self, out: &mut Vec<u8>) {
+        out.push(self.opcode());
+        match self {
+            Instr::Constant(index)
+            | Instr::GetLocal(index)
+            | Instr::SetLocal(index)
+            | Instr::NewCell(index)
+            | Instr::GetCell(index)
+            | Instr::SetCell(index)
+            | Instr::GetCaptured(index)
+            | Instr::SetCaptured(index)
+            | Instr::GetGlobal(index)
+            | Instr::SetGlobal(index)
+            | Instr::Native(index)
+            | Instr::Closure(index)
+            | Instr::List(index) => out.extend_from_slice(&index.to_le_bytes()),
+            Instr::Call(count) | Instr::TailCall(count) | Instr::Field(count) => out.push(count),
+            Instr::Jump(target) | Instr::JumpIfFalse(target) | Instr::JumpUnlessFalse(target) => {
+                out.extend_from_slice(&target.to_le_bytes())
+            }
+            Instr::Data(constructor, count) => {
+                out.extend_from_slice(&constructor.to_le_bytes());
+                out.push(count);
+            }
+            Instr::JumpUnlessTag(constructor, target) => {
+                out.extend_from_slice(&constructor.to_le_bytes());
+                out.extend_from_slice(&target.to_le_bytes());
+            }
+            _ => {}
+        }
+    }
+
+    // The instruction at an offset in some code, and the offset of the next
+    #[inline]
+    pub fn decode(code: &[u8], at: usize) -> (Instr, usize) {
+        let short = |at: usize| u16::from_le_bytes([code[at], code[at + 1]]);
+        let long =
+            |at: usize| u32::from_le_bytes([code[at], code[at + 1], code[at + 2], code[at + 3]]);
+        let next = at + 1;
+        match code[at] {
+            0 => (Instr::Constant(short(next)), next + 2),
+            1 => (Instr::True, next),
+            2 => (Instr::False, next),
+            3 => (Instr::Pop, next),
+            4 => (Instr::Dup, next),
+            5 => (Instr::GetLocal(short(next)), next + 2),
+            6 => (Instr::SetLocal(short(next)), next + 2),
+            7 => (Instr::NewCell(short(next)), next + 2),
+            8 => (Instr::GetCell(short(next)), next + 2),
+            9 => (Instr::SetCell(short(next)), next + 2),
+            10 => (Instr::GetCaptured(short(next)), next + 2),
+            11 => (Instr::SetCaptured(short(next)), next + 2),
+            12 => (Instr::GetGlobal(short(next)), next + 2),
+            13 => (Instr::SetGlobal(short(next)), next + 2),
+            14 => (Instr::Itself, next),
+            15 => (Instr::Native(short(next)), next + 2),
+            16 => (Instr::Closure(short(next)), next + 2),
+            17 => (Instr::Call(code[next]), next + 1),
+            18 => (Instr::TailCall(code[next]), next + 1),
+            19 => (Instr::Return, next),
+            20 => (Instr::Jump(long(next)), next + 4),
+            21 => (Instr::JumpIfFalse(long(next)), next + 4),
+            22 => (Instr::JumpUnlessFalse(long(next)), next + 4),
+            23 => (Instr::Add, next),
+            24 => (Instr::Subtract, next),
+            25 => (Instr::Multiply, next),
+            26 => (Instr::Divide, next),
+            27 => (Instr::Remainder, next),
+            28 => (Instr::Equal, next),
+            29 => (Instr::NotEqual, next),
+            30 => (Instr::Less, next),
+            31 => (Instr::LessEqual, next),
+            32 => (Instr::Greater, next),
+            33 => (Instr::GreaterEqual, next),
+            34 => (Instr::List(short(next)), next + 2),
+            35 => (Instr::Data(short(next), code[next + 2]), next + 3),
+            36 => (Instr::JumpUnlessTag(short(next), long(next + 2)), next + 6),
+            37 => (Instr::Field(code[next]), next + 1),
+            38 => (Instr::NoMatch, next),
+            opcode => panic!("{} isn't an opcode", opcode),
+        }
+    }
+}
+
+// Where a closure gets each of its cells from, when the function enclosing it makes it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Capture {
+    // A slot holding a cell
+    Cell(u16),
+    // One of the enclosing function's own cells
+    Captured(u16),
+    // A new cell holding the value of a slot
+    Local(u16),
+    // A new cell holding the enclosing function's closure
+    Itself,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Constant {
+    Number(f64),
+    String(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    pub name: String,
+    pub arity: usize,
+    // The slots of a call, which hold its arguments and then its locals
+    pub slots: usize,
+    pub captures: Vec<Capture>,
+    pub constants: Vec<Constant>,
+    pub code: Vec<u8>,
+}
+
+// An extern, which calls one of the VM's natives
+#[derive(Debug, Clone, PartialEq)]
+pub struct Native {
+    pub name: String,
+    pub js_name: String,
+    pub arity: usize,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Program {
+    pub functions: Vec<Function>,
+    pub globals: Vec<String>,
+    pub constructors: Vec<String>,
+    pub natives: Vec<Native>,
+    // The functions that run each module's top-level code, in order
+    pub inits: Vec<usize>,
+}
+
+// The disassembly of a program, with each function's instructions by offset
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, function) in self.functions.iter().enumerate() {
+            if index > 0 {
+                writeln!(f)?;
+            }
+            let name = if function.name.is_empty() {
+                "anonymous"
+            } else {
+                &function.name
+            };
+            write!(
+                f,
+                "function {} {} ({} arguments, {} slots",
+                index, name, function.arity, function.slots
+            )?;
+            if self.inits.contains(&index) {
+                write!(f, ", top-level")?;
+            }
+            writeln!(f, ")")?;
+            for (index, capture) in function.captures.iter().enumerate() {
+                let capture = match *capture {
+                    Capture::Cell(slot) => format!("the cell in slot {}", slot),
+                    Capture::Captured(index) => format!("captured cell {}", index),
+                    Capture::Local(slot) => format!("slot {}", slot),
+                    Capture::Itself => String::from("itself"),
+                };
+                writeln!(f, "  captures {} from {}", index, capture)?;
+            }
+
+            let mut at = 0;
+            while at < function.code.len() {
+                let (instr, next) = Instr::decode(&function.code, at);
+                write!(f, "  {:>5}  ", at)?;
+                self.write_instr(f, function, instr)?;
+                writeln!(f)?;
+                at = next;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Program {
+    fn write_instr(
+        &self,
+        f: &mut fmt::Formatter,
+        function: &Function,
+        instr: Instr,
+    ) -> fmt::Result {
+        let name = |names: &[String], index: u16| -> String {
+            names.get(usize::from(index)).cloned().unwrap_or_default()
+        };
+        match instr {
+            Instr::Constant(index) => match function.constants.get(usize::from(index)) {
+                Some(Constant::Number(val)) => write!(f, "constant {}  ; {}", index, val),
+                Some(Constant::String(val)) => write!(f, "constant {}  ; {:?}", index, val),
+                None => write!(f, "constant {}", index),
+            },
+            Instr::True => write!(f, "true"),
+            Instr::False => write!(f, "false"),
+            Instr::Pop => write!(f, "pop"),
+            Instr::Dup => write!(f, "dup"),
+            Instr::GetLocal(slot) => write!(f, "get-local {}", slot),
+            Instr::SetLocal(slot) => write!(f, "set-local {}", slot),
+            Instr::NewCell(slot) => write!(f, "new-cell {}", slot),
+            Instr::GetCell(slot) => write!(f, "get-cell {}", slot),
+            Instr::SetCell(slot) => write!(f, "set-cell {}", slot),
+            Instr::GetCaptured(index) => write!(f, "get-captured {}", index),
+            Instr::SetCaptured(index) => write!(f, "set-captured {}", index),
+            Instr::GetGlobal(index) => {
+                write!(f, "get-global {}  ; {}", index, name(&self.globals, index))
+            }
+            Instr::SetGlobal(index) => {
+                write!(f, "set-global {}  ; {}", index, name(&self.globals, index))
+            }
+            Instr::Itself => write!(f, "itself"),
+            Instr::Native(index) => match self.natives.get(usize::from(index)) {
+                Some(native) => write!(f, "native {}  ; {}", index, native.js_name),
+                None => write!(f, "native {}", index),
+            },
+            Instr::Closure(index) => match self.functions.get(usize::from(index)) {
+                Some(function) if !function.name.is_empty() => {
+                    write!(f, "closure {}  ; {}", index, function.name)
+                }
+                _ => write!(f, "closure {}", index),
+            },
+            Instr::Call(count) => write!(f, "call {}", count),
+            Instr::TailCall(count) => write!(f, "tail-call {}", count),
+            Instr::Return => write!(f, "return"),
+            Instr::Jump(target) => write!(f, "jump {}", target),
+            Instr::JumpIfFalse(target) => write!(f, "jump-if-false {}", target),
+            Instr::JumpUnlessFalse(target) => write!(f, "jump-unless-false {}", target),
+            Instr::Add => write!(f, "add"),
+            Instr::Subtract => write!(f, "subtract"),
+            Instr::Multiply => write!(f, "multiply"),
+            Instr::Divide => write!(f, "divide"),
+            Instr::Remainder => write!(f, "remainder"),
+            Instr::Equal => write!(f, "equal"),
+            Instr::NotEqual => write!(f, "not-equal"),
+            Instr::Less => write!(f, "less"),
+            Instr::LessEqual => write!(f, "less-equal"),
+            Instr::Greater => write!(f, "greater"),
+            Instr::GreaterEqual => write!(f, "greater-equal"),
+            Instr::List(count) => write!(f, "list {}", count),
+            Instr::Data(constructor, count) => write!(
+                f,
+                "data {} {}  ; {}",
+                constructor,
+                count,
+                name(&self.constructors, constructor)
+            ),
+            Instr::JumpUnlessTag(constructor, target) => write!(
+                f,
+                "jump-unless-tag {} {}  ; {}",
+                constructor,
+                target,
+                name(&self.constructors, constructor)
+            ),
+            Instr::Field(index) => write!(f, "field {}", index),
+            Instr::NoMatch => write!(f, "no-match"),
+        }
+    }
+}
+
+type Var = backend::Var<u16, u16>;
+type Source = backend::Source<u16>;
+
+// A function being compiled. The module's top-level code is compiled as a function too, at
+// the bottom of the stack of frames.
+struct Frame {
+    code: Vec<u8>,
+    constants: Vec<Constant>,
+    scope: Scope<u16, u16>,
+    // Slots are used like a stack, and the function has as many as are used at once
+    top: usize,
+    slots: usize,
+}
+
+impl Frame {
+    fn new(arity: usize) -> Frame {
+        Frame {
+            code: Vec::new(),
+            constants: Vec::new(),
+            scope: Scope::new(),
+            top: arity,
+            slots: arity,
+        }
+    }
+}
+
+impl backend::Frame<u16, u16> for Frame {
+    fn scope(&self) -> &Scope<u16, u16> {
+        &self.scope
+    }
+
+    fn scope_mut(&mut self) -> &mut Scope<u16, u16> {
+        &mut self.scope
+    }
+}
+
+struct Compiler {
+    program: Program,
+    sources: Vec<Source>,
+    current: usize,
+    frames: Vec<Frame>,
+    errors: Vec<Error>,
+}
+
+// Compiles a program and the prelude to bytecode, whose top-level functions run the
+// prelude and then each module in turn
+pub fn compile_program(
+    modules: &[modules::Module],
+    prelude: &modules::Module,
+) -> Result<Program, Vec<Error>> {
+    let mut compiler = Compiler {
+        program: Program::default(),
+        sources: Vec::new(),
+        current: 0,
+        frames: Vec::new(),
+        errors: Vec::new(),
+    };
+
+    let sources: Vec<&modules::Module> = Some(prelude).into_iter().chain(modules).collect();
+    let root = modules.len();
+    for (index, source) in sources.iter().enumerate() {
+        // Globals are told apart by their module's name in disassembly
+        let prefix = if index == root {
+            String::new()
+        } else {
+            let stem = source.path.file_stem().map(|stem| stem.to_string_lossy());
+            format!("{}.", stem.unwrap_or_default())
+        };
+        compiler.compile_source(index, source, prefix);
+    }
+
+    let program = &compiler.program;
+    let counts = [
+        program.functions.len(),
+        program.globals.len(),
+        program.constructors.len(),
+        program.natives.len(),
+    ];
+    if counts.iter().any(|&count| count > usize::from(u16::MAX)) {
+        compiler.error(
+            Span::default(),
+            String::from("The program has too many definitions for the bytecode"),
+        );
+    }
+
+    if !compiler.errors.is_empty() {
+        return Err(compiler.errors);
+    }
+    Ok(compiler.program)
+}
+
+impl Compiler {
+    fn error(&mut self, span: Span, msg: String) {
+        self.errors.push(span.get_error(msg));
+    }
+
+    fn frame(&mut self) -> &mut Frame {
+        self.frames
+            .last_mut()
+            .expect("Code is only emitted into a frame")
+    }
+
+    fn emit(&mut self, instr: Instr) {
+        instr.encode(&mut self.frame().code);
+    }
+
+    // Emits a jump whose target is set once it's known, returning where to set it
+    fn jump(&mut self, instr: Instr) -> usize {
+        self.emit(instr);
+        let code = &self.frame().code;
+        code.len() - 4
+    }
+
+    fn land(&mut self, jump: usize) {
+        let code = &mut self.frame().code;
+        let target = code.len() as u32;
+        code[jump..jump + 4].copy_from_slice(&target.to_le_bytes());
+    }
+
+    // Takes the next slot, which is given back along with those above it by `release`
+    fn slot(&mut self) -> u16 {
+        let frame = self.frame();
+        let slot = frame.top;
+        frame.top += 1;
+        frame.slots = frame.slots.max(frame.top);
+        slot as u16
+    }
+
+    fn mark(&mut self) -> usize {
+        self.frame().top
+    }
+
+    fn release(&mut self, mark: usize) {
+        self.frame().top = mark;
+    }
+
+    fn constant(&mut self, constant: Constant) {
+        let constants = &mut self.frame().constants;
+        // Numbers are compared by their bits, so that NaN and -0 are kept apart
+        let index = constants.iter().position(|known| match (known, &constant) {
+            (&Constant::Number(a), &Constant::Number(b)) => a.to_bits() == b.to_bits(),
+            (a, b) => a == b,
+        });
+        let index = index.unwrap_or_else(|| {
+            constants.push(constant);
+            constants.len() - 1
+        });
+        match u16::try_from(index) {
+            Ok(index) => self.emit(Instr::Constant(index)),
+            Err(_) => self.error(
+                Span::default(),
+                String::from("A function has too many constants for the bytecode"),
+            ),
+        }
+    }
+
+    // Constructors are told apart by name, as they are in JS
+    fn constructor_tag(&mut self, name: &str) -> u16 {
+        let constructors = &mut self.program.constructors;
+        match constructors
+            .iter()
+            .position(|constructor| constructor == name)
+        {
+            Some(tag) => tag as u16,
+            None => {
+                constructors.push(String::from(name));
+                (constructors.len() - 1) as u16
+            }
+        }
+    }
+
+    fn compile_source(&mut self, index: usize, module: &modules::Module, prefix: String) {
+        let mut source = Source::new(module, prefix);
+        for name in backend::global_names(module) {
+            self.program
+                .globals
+                .push(format!("{}{}", source.prefix, name));
+            let global = (self.program.globals.len() - 1) as u16;
+            source.globals.insert(name, global);
+        }
+        self.sources.push(source);
+        self.current = index;
+
+        // Top-level code leaves the value of each expression on the stack until the next
+        self.frames.push(Frame::new(0));
+        backend::collect_captured(&module.ast, &mut self.frame().scope.captured);
+        match module.ast {
+            ASTNode::Sequence(ref exprs) if !exprs.is_empty() => {
+                for (i, expr) in exprs.iter().enumerate() {
+                    if i > 0 {
+                        self.emit(Instr::Pop);
+                    }
+                    self.element(expr, false);
+                }
+            }
+            ref ast => self.expr(ast, false),
+        }
+        self.emit(Instr::Return);
+
+        let frame = self.frames.pop().expect("The frame was pushed above");
+        let name = module.path.display().to_string();
+        let function = self.finish(name, 0, frame, Vec::new());
+        self.program.inits.push(function);
+    }
+
+    fn finish(
+        &mut self,
+        name: String,
+        arity: usize,
+        frame: Frame,
+        captures: Vec<Capture>,
+    ) -> usize {
+        self.program.functions.push(Function {
+            name,
+            arity,
+            slots: frame.slots,
+            captures,
+            constants: frame.constants,
+            code: frame.code,
+        });
+        self.program.functions.len() - 1
+    }
+
+    fn lookup(&mut self, name: &str) -> Option<Var> {
+        backend::lookup(&mut self.frames, &self.sources, self.current, name)
+    }
+
+    fn lookup_qualified(&self, module: &str, name: &str) -> Option<Var> {
+        backend::lookup_qualified(&self.sources, self.current, module, name)
+    }
+
+    fn get(&mut self, var: Var) {
+        self.emit(match var {
+            Var::Local(slot) => Instr::GetLocal(slot),
+            Var::Cell(slot) => Instr::GetCell(slot),
+            Var::Captured(index) => Instr::GetCaptured(index),
+            Var::Global(global) => Instr::GetGlobal(global),
+            Var::Itself => Instr::Itself,
+        });
+    }
+
+    // Stores the value on top of the stack in a name, leaving it there. A function's name
+    // within its body is bound to the function, as in JS.
+    fn assign(&mut self, name: &str, span: Span) {
+        match self.lookup(name) {
+            Some(Var::Local(slot)) => self.emit(Instr::SetLocal(slot)),
+            Some(Var::Cell(slot)) => self.emit(Instr::SetCell(slot)),
+            Some(Var::Captured(index)) => self.emit(Instr::SetCaptured(index)),
+            Some(Var::Global(global)) => self.emit(Instr::SetGlobal(global)),
+            Some(Var::Itself) => {}
+            None => self.error(span, format!("{} isn't defined", name)),
+        }
+    }
+
+    // Binds a name in the innermost scope to the value on top of the stack, which is
+    // popped. The value is put in a cell if a nested function refers to the name.
+    fn declare(&mut self, name: &str) {
+        let slot = self.slot();
+        self.emit(Instr::SetLocal(slot));
+        self.emit(Instr::Pop);
+        let var = if self.frame().scope.is_captured(name) {
+            self.emit(Instr::NewCell(slot));
+            Var::Cell(slot)
+        } else {
+            Var::Local(slot)
+        };
+        self.frame().scope.bind(name, var);
+    }
+
+    // Compiles an element of a sequence, in which a function declaration binds its name
+    fn element(&mut self, expr: &ASTNode, tail: bool) {
+        match (expr.function_name(), expr.declaration()) {
+            (Some((name, span)), ASTNode::Function { args, body, .. }) => {
+                self.closure(Some(name), args, body);
+                self.assign(name, span);
+            }
+            _ => self.expr(expr, tail),
+        }
+    }
+
+    // Compiles a node, leaving its value on the stack. A call in tail position replaces
+    // the function being run.
+    fn expr(&mut self, node: &ASTNode, tail: bool) {
+        let mark = self.mark();
+        match *node {
+            ASTNode::Integer(val, _) => self.constant(Constant::Number(f64::from(val))),
+            ASTNode::Float(val, _) => {
+                let val = val.to_string().parse().unwrap_or(f64::NAN);
+                self.constant(Constant::Number(val))
+            }
+            ASTNode::StringLiteral(ref val, _) => self.constant(Constant::String(val.clone())),
+            ASTNode::Boolean(true, _) => self.emit(Instr::True),
+            ASTNode::Boolean(false, _) => self.emit(Instr::False),
+            ASTNode::Name(ref name, span) => match self.lookup(name) {
+                Some(var) => self.get(var),
+                None => {
+                    let msg = if self.sources[self.current].imports.contains_key(name) {
+                        format!("The module {0} isn't a value, so only its exports like {0}.name can be used", name)
+                    } else {
+                        format!("{} isn't defined", name)
+                    };
+                    self.error(span, msg);
+                    self.emit(Instr::False);
+                }
+            },
+            ASTNode::Qualified {
+                ref module,
+                ref name,
+                span,
+            } => match self.lookup_qualified(module, name) {
+                Some(var) => self.get(var),
+                None => {
+                    self.error(span, format!("{}.{} isn't defined", module, name));
+                    self.emit(Instr::False);
+                }
+            },
+            ASTNode::Function {
+                ref args, ref body, ..
+            } => {
+                let name = node.function_name().map(|(name, _)| name);
+                self.closure(name, args, body);
+            }
+            ASTNode::Invocation {
+                ref func,
+                ref args,
+                span,
+            } => {
+                self.expr(func, false);
+                for arg in args.iter() {
+                    self.expr(arg, false);
+                }
+                match u8::try_from(args.len()) {
+                    Ok(count) if tail => self.emit(Instr::TailCall(count)),
+                    Ok(count) => self.emit(Instr::Call(count)),
+                    Err(_) => self.error(span, String::from("A call has too many arguments")),
+                }
+            }
+            ASTNode::Conditional {
+                ref cond,
+                ref if_body,
+                ref else_body,
+                ..
+            } => {
+                self.expr(cond, false);
+                let otherwise = self.jump(Instr::JumpIfFalse(0));
+                self.expr(if_body, tail);
+                let end = self.jump(Instr::Jump(0));
+                self.land(otherwise);
+                match **else_body {
+                    Some(ref else_body) => self.expr(else_body, tail),
+                    None => self.emit(Instr::False),
+                }
+                self.land(end);
+            }
+            ASTNode::Binary {
+                ref op,
+                ref lhs,
+                ref rhs,
+                span,
+            } => self.binary(op, lhs, rhs, span, tail),
+            ASTNode::Sequence(ref exprs) => {
+                if exprs.is_empty() {
+                    self.emit(Instr::False);
+                }
+                for (i, expr) in exprs.iter().enumerate() {
+                    if i > 0 {
+                        self.emit(Instr::Pop);
+                    }
+                    self.element(expr, tail && i + 1 == exprs.len());
+                }
+            }
+            ASTNode::List(ref elements, _) => {
+                for element in elements.iter() {
+                    self.expr(element, false);
+                }
+                self.emit(Instr::List(elements.len() as u16));
+            }
+            ASTNode::Let {
+                ref name,
+                ref value,
+                span,
+            } => {
+                self.expr(value, false);
+                match name.binding() {
+                    Some((name, _)) => self.assign(name, span),
+                    None => self.error(span, String::from("Only a name can be bound by let")),
+                }
+            }
+            ASTNode::Annotated { ref expr, .. } => self.expr(expr, tail),
+            ASTNode::TypeDeclaration { ref variants, .. } => {
+                for variant in variants.iter() {
+                    self.variant(variant);
+                }
+                self.emit(Instr::False);
+            }
+            ASTNode::Match {
+                ref subject,
+                ref arms,
+                ..
+            } => self.match_arms(subject, arms, tail),
+            ASTNode::Export { ref decl, .. } => self.element(decl, tail),
+            ASTNode::Extern {
+                ref module,
+                ref functions,
+                ..
+            } => {
+                for function in functions.iter() {
+                    self.extern_binding(module, function);
+                }
+                self.emit(Instr::False);
+            }
+            // The modules a module imports are run before it
+            ASTNode::Import { .. } => self.emit(Instr::False),
+        }
+        self.release(mark);
+    }
+
+    fn binary(&mut self, op: &Token, lhs: &ASTNode, rhs: &ASTNode, span: Span, tail: bool) {
+        let op = match *op {
+            Token::Operator(ref op) => op.as_str(),
+            _ => return self.error(span, String::from("Malformed binary node")),
+        };
+
+        match op {
+            "=" => {
+                self.expr(rhs, false);
+                match *lhs {
+                    ASTNode::Name(ref name, span) => self.assign(name, span),
+                    _ => self.error(span, String::from("Only a name can be assigned to")),
+                }
+            }
+            // The left side is the value unless it decides the result, as in JS
+            "&&" | "||" => {
+                self.expr(lhs, false);
+                self.emit(Instr::Dup);
+                let end = if op == "&&" {
+                    self.jump(Instr::JumpIfFalse(0))
+                } else {
+                    self.jump(Instr::JumpUnlessFalse(0))
+                };
+                self.emit(Instr::Pop);
+                self.expr(rhs, tail);
+                self.land(end);
+            }
+            _ => {
+                let instr = match op {
+                    "+" => Instr::Add,
+                    "-" => Instr::Subtract,
+                    "*" => Instr::Multiply,
+                    "/" => Instr::Divide,
+                    "%" => Instr::Remainder,
+                    "==" => Instr::Equal,
+                    "!=" => Instr::NotEqual,
+                    "<" => Instr::Less,
+                    "<=" => Instr::LessEqual,
+                    ">" => Instr::Greater,
+                    ">=" => Instr::GreaterEqual,
+                    _ => return self.error(span, format!("{} isn't an operator on values", op)),
+                };
+                self.expr(lhs, false);
+                self.expr(rhs, false);
+                self.emit(instr);
+            }
+        }
+    }
+
+    // Compiles a function, leaving its closure on the stack
+    fn closure(&mut self, name: Option<&str>, args: &[ASTNode], body: &ASTNode) {
+        let arity = args.len();
+        let params: Vec<String> = args
+            .iter()
+            .map(|arg| String::from(arg.binding().map_or("_", |(arg, _)| arg)))
+            .collect();
+        let source = &self.sources[self.current];
+        let locals = backend::function_locals(&self.frames, source, name, &params, body);
+
+        let mut frame = Frame::new(arity);
+        backend::collect_captured(body, &mut frame.scope.captured);
+        if let Some(name) = name {
+            frame.scope.bind(name, Var::Itself);
+        }
+        frame.scope.enter();
+        self.frames.push(frame);
+
+        // Arguments are in the first slots. Parameters that nested functions capture are
+        // moved into cells.
+        for (i, param) in params.iter().enumerate() {
+            let var = if self.frame().scope.is_captured(param) {
+                self.emit(Instr::NewCell(i as u16));
+                Var::Cell(i as u16)
+            } else {
+                Var::Local(i as u16)
+            };
+            self.frame().scope.bind(param, var);
+        }
+        for local in locals.iter() {
+            self.emit(Instr::False);
+            self.declare(local);
+        }
+
+        self.expr(body, true);
+        self.emit(Instr::Return);
+
+        let frame = self.frames.pop().expect("The frame was pushed above");
+        let mut captures = Vec::new();
+        for name in frame.scope.captures.iter() {
+            let var = self
+                .lookup(name)
+                .expect("A captured name is bound in an enclosing function");
+            captures.push(match var {
+                Var::Local(slot) => Capture::Local(slot),
+                Var::Cell(slot) => Capture::Cell(slot),
+                Var::Captured(index) => Capture::Captured(index),
+                Var::Global(_) | Var::Itself => Capture::Itself,
+            });
+        }
+        let function = self.finish(String::from(name.unwrap_or("")), arity, frame, captures);
+        self.emit(Instr::Closure(function as u16));
+    }
+
+    // Binds a constructor. A constructor without fields is a single value, and one with
+    // fields a function.
+    fn variant(&mut self, variant: &Variant) {
+        let tag = self.constructor_tag(&variant.name);
+        let arity = variant.fields.len();
+        if arity == 0 {
+            self.emit(Instr::Data(tag, 0));
+        } else {
+            let mut frame = Frame::new(arity);
+            for i in 0..arity {
+                Instr::GetLocal(i as u16).encode(&mut frame.code);
+            }
+            Instr::Data(tag, arity as u8).encode(&mut frame.code);
+            Instr::Return.encode(&mut frame.code);
+            let function = self.finish(variant.name.clone(), arity, frame, Vec::new());
+            self.emit(Instr::Closure(function as u16));
+        }
+        self.assign(&variant.name, variant.span);
+        self.emit(Instr::Pop);
+    }
+
+    // Binds an extern's name to the native of its JS name
+    fn extern_binding(&mut self, module: &Option<String>, function: &ExternFunction) {
+        let (module, js_name) = backend::extern_target(module, function);
+        if module != "host" || vm::native(&js_name).is_none() {
+            return self.error(
+                function.span,
+                format!("{} isn't one of the VM's natives", js_name),
+            );
+        }
+
+        self.program.natives.push(Native {
+            name: function.name.clone(),
+            js_name,
+            arity: function.args.len(),
+        });
+        let native = (self.program.natives.len() - 1) as u16;
+        self.emit(Instr::Native(native));
+        self.assign(&function.name, function.span);
+        self.emit(Instr::Pop);
+    }
+
+    // Tests the subject against each arm's constructor in turn, until a wildcard
+    fn match_arms(&mut self, subject: &ASTNode, arms: &[MatchArm], tail: bool) {
+        self.expr(subject, false);
+        let slot = self.slot();
+        self.emit(Instr::SetLocal(slot));
+        self.emit(Instr::Pop);
+
+        let mut ends = Vec::new();
+        let mut exhaustive = false;
+        for arm in arms.iter() {
+            match arm.pattern {
+                Pattern::Wildcard(_) => {
+                    self.expr(&arm.body, tail);
+                    exhaustive = true;
+                    break;
+                }
+                Pattern::Constructor {
+                    ref name,
+                    ref bindings,
+                    ..
+                } => {
+                    let tag = self.constructor_tag(name);
+                    self.emit(Instr::GetLocal(slot));
+                    let next = self.jump(Instr::JumpUnlessTag(tag, 0));
+
+                    let mark = self.mark();
+                    self.frame().scope.enter();
+                    for (i, binding) in bindings.iter().enumerate() {
+                        if let Some((binding, _)) = binding.binding() {
+                            if binding != "_" {
+                                self.emit(Instr::GetLocal(slot));
+                                self.emit(Instr::Field(i as u8));
+                                self.declare(binding);
+                            }
+                        }
+                    }
+                    self.expr(&arm.body, tail);
+                    self.frame().scope.leave();
+                    self.release(mark);
+
+                    ends.push(self.jump(Instr::Jump(0)));
+                    self.land(next);
+                }
+            }
+        }
+
+        if !exhaustive {
+            self.emit(Instr::GetLocal(slot));
+            self.emit(Instr::NoMatch);
+        }
+        for end in ends {
+            self.land(end);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use prelude;
+    use std::path::Path;
+
+    fn compile(inp: &str) -> Result<Program, Vec<Error>> {
+        let modules =
+            modules::load_with(Path::new("main.silver"), |_| Ok(String::from(inp))).unwrap();
+        compile_program(&modules, &prelude::module())
+    }
+
+    // The disassembly of one function, from its header to the blank line after it
+    fn disassembly(program: &Program, name: &str) -> String {
+        let text = program.to_string();
+        let start = text
+            .lines()
+            .position(|line| {
+                line.starts_with("function ") && line.contains(&format!(" {} (", name))
+            })
+            .unwrap();
+        let lines: Vec<&str> = text
+            .lines()
+            .skip(start)
+            .take_while(|line| !line.is_empty())
+            .collect();
+        lines.join("\n")
+    }
+
+    #[test]
+    fn test_instructions_round_trip() {
+        let instrs = [
+            Instr::Constant(300),
+            Instr::Pop,
+            Instr::TailCall(2),
+            Instr::Jump(70000),
+            Instr::Data(4, 3),
+            Instr::JumpUnlessTag(1, 9),
+        ];
+        let mut code = Vec::new();
+        for instr in instrs.iter() {
+            instr.encode(&mut code);
+        }
+        let mut decoded = Vec::new();
+        let mut at = 0;
+        while at < code.len() {
+            let (instr, next) = Instr::decode(&code, at);
+            decoded.push(instr);
+            at = next;
+        }
+        assert_eq!(decoded, instrs);
+        assert_eq!(code.len(), 3 + 1 + 2 + 5 + 4 + 7);
+    }
+
+    #[test]
+    fn test_disassembly() {
+        let program = compile("fn f(a, b) { a || b }; f(0, 5)").unwrap();
+        let f = disassembly(&program, "f");
+        assert!(f.starts_with("function "));
+        assert_eq!(
+            &f[f.find(" f ").unwrap()..],
+            " f (2 arguments, 2 slots)\n      \
+             0  get-local 0\n      \
+             3  dup\n      \
+             4  jump-unless-false 13\n      \
+             9  pop\n     \
+             10  get-local 1\n     \
+             13  return"
+        );
+        let init = disassembly(&program, "main.silver");
+        assert!(init.contains("top-level)\n"));
+        assert!(init.contains("constant 1  ; 5\n"));
+        assert!(init.contains("  ; f\n"));
+    }
+
+    #[test]
+    fn test_tail_calls() {
+        let program = compile(
+            "fn even?(n) { if n == 0 then true else odd?(n - 1) };
+             fn odd?(n) { if n == 0 then false else even?(n - 1) };
+             fn f(n) { 1 + f(n) };
+             fn loop(n) { n == 0 || loop(n - 1) }",
+        )
+        .unwrap();
+        assert!(disassembly(&program, "even?").contains("tail-call 1\n"));
+        assert!(disassembly(&program, "odd?").contains("tail-call 1\n"));
+        assert!(disassembly(&program, "loop").contains("tail-call 1\n"));
+        let f = disassembly(&program, "f");
+        assert!(f.contains("call 1\n"));
+        assert!(!f.contains("tail-call"));
+    }
+
+    #[test]
+    fn test_captured_names_are_in_cells() {
+        let program = compile("fn counter() { n = 0; fn () { n = n + 1; n } }").unwrap();
+        let counter = disassembly(&program, "counter");
+        assert!(counter.contains("new-cell 0\n"));
+
+        // A nested function is compiled before the one enclosing it
+        let index = program.functions.iter().position(|f| f.name == "counter");
+        let inner = index.unwrap() - 1;
+        assert!(counter.contains(&format!("closure {}\n", inner)));
+        assert_eq!(program.functions[inner].captures, vec![Capture::Cell(0)]);
+        let inner = program.to_string();
+        assert!(inner.contains("  captures 0 from the cell in slot 0\n"));
+        assert!(inner.contains("get-captured 0\n"));
+        assert!(inner.contains("set-captured 0\n"));
+    }
+
+    #[test]
+    fn test_undefined_names() {
+        let errors = compile("fn f() { missing }; lib.g").unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].msg, "missing isn't defined");
+        assert_eq!(errors[1].msg, "lib.g isn't defined");
+
+        let errors = compile("extern fn hypot(x, y) = \"Math.hypot\"").unwrap_err();
+        assert_eq!(errors[0].msg, "Math.hypot isn't one of the VM's natives");
+    }
+}
@@ -0,0 +1,1378 @@
+use super::backend::{self, Direct, Scope};
+use super::lexer::Token;
+use super::modules;
+use super::parser::{ASTNode, ExternFunction, MatchArm, Pattern, Variant};
+
+use super::util::{Error, Span};
+
+use std::collections::HashMap;
+
+// The runtime that every program's code follows, which defines its values and the natives
+// that externs are bound to
+const RUNTIME: &str = include_str!("runtime.c");
+
+// The flags a compiled program is linked with, since the runtime uses C's math library
+pub const LINK_FLAGS: &str = "-lm";
+
+// The runtime's natives for the JS names of externs, which are the prelude's and a few of
+// the JS globals. Other externs are bound to C functions of their JS names, which are
+// declared for the program to be linked with.
+fn native(js_name: &str) -> Option<&'static str> {
+    match js_name {
+        "$length" => Some("sv_native_length"),
+        "$get" => Some("sv_native_get"),
+        "$append" => Some("sv_native_append"),
+        "$concat" => Some("sv_native_concat"),
+        "$range" => Some("sv_native_range"),
+        "$fold" => Some("sv_native_fold"),
+        "$map" => Some("sv_native_map"),
+        "$filter" => Some("sv_native_filter"),
+        "$substring" => Some("sv_native_substring"),
+        "$split" => Some("sv_native_split"),
+        "$join" => Some("sv_native_join"),
+        "String" => Some("sv_native_string"),
+        "Number" => Some("sv_native_number"),
+        "Math.floor" => Some("sv_native_floor"),
+        "Math.ceil" => Some("sv_native_ceil"),
+        "Math.round" => Some("sv_native_round"),
+        "Math.sqrt" => Some("sv_native_sqrt"),
+        "Math.pow" => Some("sv_native_pow"),
+        "console.log" => Some("sv_native_console_log"),
+        "console.error" => Some("sv_native_console_error"),
+        _ => None,
+    }
+}
+
+// Silver names can have characters that C's can't, which are written as an underscore and
+// their hex. Underscores are doubled, so that no two names are written the same.
+fn mangle(name: &str) -> String {
+    let mut mangled = String::new();
+    for ch in name.chars() {
+        match ch {
+            '_' => mangled.push_str("__"),
+            _ if ch.is_ascii_alphanumeric() => mangled.push(ch),
+            _ => {
+                let mut bytes = [0; 4];
+                for byte in ch.encode_utf8(&mut bytes).bytes() {
+                    mangled.push_str(&format!("_{:02x}", byte));
+                }
+            }
+        }
+    }
+    mangled
+}
+
+fn is_identifier(name: &str) -> bool {
+    name.chars()
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && name
+            .chars()
+            .all(|ch| ch.is_ascii_alphanumeric() || ch == '_')
+}
+
+// A C string literal. Question marks are escaped so that they can't start trigraphs.
+fn quote(string: &str) -> String {
+    let mut quoted = String::from("\"");
+    for byte in string.bytes() {
+        match byte {
+            b'"' | b'\\' | b'?' => {
+                quoted.push('\\');
+                quoted.push(byte as char);
+            }
+            0x20..=0x7E => quoted.push(byte as char),
+            _ => quoted.push_str(&format!("\\{:03o}", byte)),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn double(val: f64) -> String {
+    if val.is_nan() {
+        String::from("NAN")
+    } else if val.is_infinite() {
+        format!("{}HUGE_VAL", if val < 0.0 { "-" } else { "" })
+    } else {
+        format!("{:?}", val)
+    }
+}
+
+fn number(val: f64) -> String {
+    format!("sv_number({})", double(val))
+}
+
+fn literal(node: &ASTNode) -> Option<f64> {
+    match *node {
+        ASTNode::Integer(val, _) => Some(f64::from(val)),
+        ASTNode::Float(val, _) => Some(val.to_string().parse().unwrap_or(f64::NAN)),
+        _ => None,
+    }
+}
+
+// An operand of arithmetic as a double, which a number literal already is
+fn to_double(node: &ASTNode, operand: &str) -> String {
+    match literal(node) {
+        Some(val) => double(val),
+        None => format!("sv_to_double({})", operand),
+    }
+}
+
+type GlobalName = backend::GlobalName<usize>;
+type Var = backend::Var<usize, GlobalName>;
+type Source = backend::Source<GlobalName>;
+
+// A C function for a Silver function, and the static closure of it if it captures nothing
+struct Function {
+    name: String,
+    closure: String,
+    definition: String,
+}
+
+// A function being compiled. The module's top-level code is compiled as a function too, at
+// the bottom of the stack of frames.
+struct Frame {
+    code: String,
+    depth: usize,
+    scope: Scope<usize, GlobalName>,
+    function: Option<usize>,
+    arity: usize,
+    // Values are kept in the slots of the function, which are used like a stack. The
+    // number of slots is the most that are used at once.
+    top: usize,
+    slots: usize,
+    // Whether calls to the function itself in tail position jump back to the start of its
+    // body, which is after the code up to `start`, and whether any do
+    loops: bool,
+    start: usize,
+    jumped: bool,
+    uses_self: bool,
+}
+
+impl Frame {
+    fn new(function: Option<usize>, arity: usize) -> Frame {
+        Frame {
+            code: String::new(),
+            depth: 1,
+            scope: Scope::new(),
+            function,
+            arity,
+            top: 0,
+            slots: 0,
+            loops: false,
+            start: 0,
+            jumped: false,
+            uses_self: false,
+        }
+    }
+
+    // The slots' declaration, which C requires to have at least one
+    fn declaration(&self) -> String {
+        format!(
+            "    sv_value s[{}] = {{{{0}}}};\n    SV_ENTER(s);\n",
+            self.slots.max(1)
+        )
+    }
+}
+
+impl backend::Frame<usize, GlobalName> for Frame {
+    fn scope(&self) -> &Scope<usize, GlobalName> {
+        &self.scope
+    }
+
+    fn scope_mut(&mut self) -> &mut Scope<usize, GlobalName> {
+        &mut self.scope
+    }
+}
+
+struct Compiler {
+    functions: Vec<Function>,
+    inits: Vec<String>,
+    globals: Vec<String>,
+    // The natives and external functions that externs are bound to, and the declarations of
+    // the external ones
+    externs: Vec<String>,
+    declarations: String,
+    // The static strings, closures and data of the program
+    statics: String,
+    strings: HashMap<String, String>,
+    data: Vec<String>,
+    constructors: Vec<String>,
+    sources: Vec<Source>,
+    current: usize,
+    // The functions of the declarations in the current module that calls go to directly,
+    // reserved before the module is compiled so that calls can come before the declaration
+    reserved: HashMap<String, usize>,
+    frames: Vec<Frame>,
+    errors: Vec<Error>,
+}
+
+// Compiles a program and the prelude to a C program, whose main function runs the prelude
+// and then each module in turn. It only needs the C standard library and its math library.
+pub fn compile_program(
+    modules: &[modules::Module],
+    prelude: &modules::Module,
+) -> Result<String, Vec<Error>> {
+    let mut compiler = Compiler {
+        functions: Vec::new(),
+        inits: Vec::new(),
+        globals: Vec::new(),
+        externs: Vec::new(),
+        declarations: String::new(),
+        statics: String::new(),
+        strings: HashMap::new(),
+        data: Vec::new(),
+        constructors: Vec::new(),
+        sources: Vec::new(),
+        current: 0,
+        reserved: HashMap::new(),
+        frames: Vec::new(),
+        errors: Vec::new(),
+    };
+
+    let sources: Vec<&modules::Module> = Some(prelude).into_iter().chain(modules).collect();
+    let root = modules.len();
+    let mut inits = Vec::new();
+    for (index, source) in sources.iter().enumerate() {
+        let prefix = match index {
+            0 => String::from("prelude_"),
+            _ if index == root => String::new(),
+            _ => format!("module{}_", index - 1),
+        };
+        inits.push(compiler.compile_source(index, source, prefix));
+    }
+
+    if !compiler.errors.is_empty() {
+        return Err(compiler.errors);
+    }
+    Ok(compiler.program(&inits))
+}
+
+impl Compiler {
+    fn program(self, inits: &[String]) -> String {
+        let mut out = String::from(RUNTIME);
+        out.push_str("\n/* The program */\n\n");
+        if !self.declarations.is_empty() {
+            out.push_str(&self.declarations);
+            out.push('\n');
+        }
+
+        out.push_str("static const char *const sv_constructor_names[] = {");
+        for constructor in self.constructors.iter() {
+            out.push_str(&quote(constructor));
+            out.push_str(", ");
+        }
+        out.push_str("NULL};\n\n");
+
+        for global in self.globals.iter() {
+            out.push_str(&format!("static sv_value {};\n", global));
+        }
+        out.push_str("static sv_value *const sv_global_roots[] = {");
+        for global in self.globals.iter() {
+            out.push_str(&format!("&{}, ", global));
+        }
+        out.push_str("NULL};\n\n");
+
+        for function in self.functions.iter() {
+            out.push_str(&format!(
+                "static sv_value {}(sv_closure *self, sv_value *args);\n",
+                function.name
+            ));
+        }
+        out.push('\n');
+        out.push_str(&self.statics);
+        for function in self.functions.iter() {
+            out.push('\n');
+            out.push_str(&function.definition);
+        }
+        for init in self.inits.iter() {
+            out.push('\n');
+            out.push_str(init);
+        }
+
+        out.push_str("\nint main(void) {\n");
+        out.push_str("    sv_globals = sv_global_roots;\n");
+        out.push_str("    sv_constructors = sv_constructor_names;\n");
+        for init in inits.iter() {
+            out.push_str(&format!("    {}();\n", init));
+        }
+        out.push_str("    return 0;\n}\n");
+        out
+    }
+
+    fn error(&mut self, span: Span, msg: String) {
+        self.errors.push(span.get_error(msg));
+    }
+
+    fn frame(&mut self) -> &mut Frame {
+        self.frames
+            .last_mut()
+            .expect("Code is only emitted into a frame")
+    }
+
+    fn line(&mut self, line: &str) {
+        let frame = self.frame();
+        for _ in 0..frame.depth {
+            frame.code.push_str("    ");
+        }
+        frame.code.push_str(line);
+        frame.code.push('\n');
+    }
+
+    fn open(&mut self, line: &str) {
+        self.line(line);
+        self.frame().depth += 1;
+    }
+
+    fn close(&mut self, line: &str) {
+        self.frame().depth -= 1;
+        self.line(line);
+    }
+
+    // Takes the next slot, which is given back along with those above it by `release`
+    fn slot(&mut self) -> usize {
+        self.slots(1)
+    }
+
+    fn slots(&mut self, count: usize) -> usize {
+        let frame = self.frame();
+        let base = frame.top;
+        frame.top += count;
+        frame.slots = frame.slots.max(frame.top);
+        base
+    }
+
+    fn mark(&mut self) -> usize {
+        self.frame().top
+    }
+
+    fn release(&mut self, mark: usize) {
+        self.frame().top = mark;
+    }
+
+    // Stores a value, or evaluates it for its effects if there's nowhere to store it
+    fn result(&mut self, dest: Option<&str>, value: &str, effects: bool) {
+        match dest {
+            Some(dest) => self.line(&format!("{} = {};", dest, value)),
+            None if effects => self.line(&format!("{};", value)),
+            None => {}
+        }
+    }
+
+    // Adds a function with a unique name, to be defined later
+    fn add_function(&mut self, name: &str) -> usize {
+        let base = format!("f_{}{}", self.sources[self.current].prefix, mangle(name));
+        let mut unique = base.clone();
+        let mut count = 1;
+        while self
+            .functions
+            .iter()
+            .any(|function| function.name == unique)
+        {
+            count += 1;
+            unique = format!("{}_{}", base, count);
+        }
+        self.functions.push(Function {
+            closure: format!("c{}", &unique[1..]),
+            name: unique,
+            definition: String::new(),
+        });
+        self.functions.len() - 1
+    }
+
+    // The static closure of a function that captures nothing
+    fn static_closure(&mut self, function: usize, arity: usize, name: &str) -> String {
+        let function = &self.functions[function];
+        self.statics.push_str(&format!(
+            "static sv_closure {} = {{{{NULL, 0, SV_CLOSURE, 1}}, {}, {}, {}, 0, {{{{0}}}}}};\n",
+            function.closure,
+            function.name,
+            arity,
+            quote(name)
+        ));
+        format!("sv_object_value(&{}.header)", function.closure)
+    }
+
+    fn string(&mut self, string: &str) -> String {
+        if let Some(name) = self.strings.get(string) {
+            return name.clone();
+        }
+
+        let name = format!("k_{}", self.strings.len());
+        self.statics.push_str(&format!(
+            "static sv_string {} = {{{{NULL, 0, SV_STRING, 1}}, {}, {}}};\n",
+            name,
+            string.len(),
+            quote(string)
+        ));
+        let value = format!("sv_object_value(&{}.header)", name);
+        self.strings.insert(String::from(string), value.clone());
+        value
+    }
+
+    // Constructors are told apart by name, as they are in JS
+    fn constructor_tag(&mut self, name: &str) -> usize {
+        match self
+            .constructors
+            .iter()
+            .position(|constructor| constructor == name)
+        {
+            Some(tag) => tag,
+            None => {
+                self.constructors.push(String::from(name));
+                self.constructors.len() - 1
+            }
+        }
+    }
+
+    // The function an extern calls, declaring it if it's external. Only a C identifier can
+    // name an external function.
+    fn extern_function(
+        &mut self,
+        module: &Option<String>,
+        function: &ExternFunction,
+    ) -> Option<usize> {
+        let (module, field) = backend::extern_target(module, function);
+        let name = match native(&field) {
+            Some(native) if module == "host" => String::from(native),
+            _ if is_identifier(&field) => field,
+            _ => return None,
+        };
+
+        if let Some(index) = self.externs.iter().position(|known| *known == name) {
+            return Some(index);
+        }
+        if !name.starts_with("sv_native_") {
+            self.declarations.push_str(&format!(
+                "sv_value {}(size_t count, sv_value *args);\n",
+                name
+            ));
+        }
+        self.externs.push(name);
+        Some(self.externs.len() - 1)
+    }
+
+    fn compile_source(&mut self, index: usize, module: &modules::Module, prefix: String) -> String {
+        let mut source = Source::new(module, prefix);
+        let exprs: &[ASTNode] = match module.ast {
+            ASTNode::Sequence(ref exprs) => exprs,
+            _ => &[],
+        };
+        for name in backend::global_names(module) {
+            self.globals
+                .push(format!("g_{}{}", source.prefix, mangle(&name)));
+            let global = GlobalName {
+                index: self.globals.len() - 1,
+                direct: None,
+            };
+            source.globals.insert(name, global);
+        }
+        self.sources.push(source);
+        self.current = index;
+
+        // Calls to a declaration whose name is bound nowhere else go straight to it
+        self.reserved.clear();
+        let counts = backend::bindings(&module.ast);
+        let once = |name: &str| counts.get(name) == Some(&1);
+        for expr in exprs.iter() {
+            let mut direct = Vec::new();
+            match *expr.declaration() {
+                ASTNode::Function { ref args, .. } => {
+                    if let Some((name, _)) = expr.function_name() {
+                        if once(name) {
+                            let function = self.add_function(name);
+                            direct.push((name, Direct::Function(function, args.len())));
+                        }
+                    }
+                }
+                ASTNode::TypeDeclaration { ref variants, .. } => {
+                    for variant in variants.iter() {
+                        if !variant.fields.is_empty() && once(&variant.name) {
+                            let function = self.add_function(&variant.name);
+                            let arity = variant.fields.len();
+                            direct.push((&variant.name, Direct::Function(function, arity)));
+                        }
+                    }
+                }
+                ASTNode::Extern {
+                    ref module,
+                    ref functions,
+                    ..
+                } => {
+                    for function in functions.iter().filter(|function| once(&function.name)) {
+                        if let Some(target) = self.extern_function(module, function) {
+                            let arity = function.args.len();
+                            direct.push((&function.name, Direct::Extern(target, arity)));
+                        }
+                    }
+                }
+                _ => {}
+            }
+
+            let source = &mut self.sources[index];
+            for (name, call) in direct {
+                if let Direct::Function(function, _) = call {
+                    self.reserved.insert(String::from(name), function);
+                }
+                if let Some(global) = source.globals.get_mut(name) {
+                    global.direct = Some(call);
+                }
+            }
+        }
+
+        let init = match index {
+            0 => String::from("init_prelude"),
+            _ if self.sources[index].prefix.is_empty() => String::from("init_main"),
+            _ => format!("init_{}", self.sources[index].prefix.trim_end_matches('_')),
+        };
+        self.frames.push(Frame::new(None, 0));
+        backend::collect_captured(&module.ast, &mut self.frame().scope.captured);
+        match module.ast {
+            ASTNode::Sequence(ref exprs) => {
+                for expr in exprs.iter() {
+                    self.element(expr, None, false);
+                }
+            }
+            ref ast => self.into(ast, None, false),
+        }
+
+        let frame = self.frames.pop().expect("The frame was pushed above");
+        self.inits.push(format!(
+            "static void {}(void) {{\n{}{}    SV_LEAVE();\n}}\n",
+            init,
+            frame.declaration(),
+            frame.code
+        ));
+        init
+    }
+
+    fn lookup(&mut self, name: &str) -> Option<Var> {
+        backend::lookup(&mut self.frames, &self.sources, self.current, name)
+    }
+
+    fn lookup_qualified(&self, module: &str, name: &str) -> Option<Var> {
+        backend::lookup_qualified(&self.sources, self.current, module, name)
+    }
+
+    // The C expression for a name's value, which is also where it's stored
+    fn var(&mut self, var: Var) -> String {
+        match var {
+            Var::Local(slot) => format!("s[{}]", slot),
+            Var::Cell(slot) => format!("SV_CELL(s[{}])", slot),
+            Var::Captured(index) => {
+                self.frame().uses_self = true;
+                format!("SV_CELL(self->cells[{}])", index)
+            }
+            Var::Global(global) => self.globals[global.index].clone(),
+            Var::Itself => {
+                self.frame().uses_self = true;
+                String::from("sv_object_value(&self->header)")
+            }
+        }
+    }
+
+    // The cell a name is kept in. The function itself is only in a cell once a closure
+    // captures it.
+    fn cell(&mut self, var: Var) -> String {
+        match var {
+            Var::Cell(slot) => format!("s[{}]", slot),
+            Var::Captured(index) => {
+                self.frame().uses_self = true;
+                format!("self->cells[{}]", index)
+            }
+            Var::Local(_) | Var::Global(_) | Var::Itself => {
+                format!("sv_cell_new({})", self.var(var))
+            }
+        }
+    }
+
+    // Stores a value in a name. A function's name within its body is bound to the
+    // function, as in JS.
+    fn assign(&mut self, name: &str, value: &str, span: Span) {
+        match self.lookup(name) {
+            Some(Var::Itself) => {}
+            Some(var) => {
+                let var = self.var(var);
+                self.line(&format!("{} = {};", var, value));
+            }
+            None => self.error(span, format!("{} isn't defined", name)),
+        }
+    }
+
+    // Binds a name in the innermost scope, in a cell if a nested function refers to it
+    fn declare(&mut self, name: &str, value: &str) -> Var {
+        let slot = self.slot();
+        let var = if self.frame().scope.is_captured(name) {
+            self.line(&format!("s[{}] = sv_cell_new({});", slot, value));
+            Var::Cell(slot)
+        } else {
+            if value != "sv_boolean(0)" {
+                self.line(&format!("s[{}] = {};", slot, value));
+            }
+            Var::Local(slot)
+        };
+        self.frame().scope.bind(name, var);
+        var
+    }
+
+    // Compiles an element of a sequence, in which a function declaration binds its name
+    fn element(&mut self, expr: &ASTNode, dest: Option<&str>, tail: bool) {
+        match (expr.function_name(), expr.declaration()) {
+            (Some((name, span)), ASTNode::Function { args, body, .. }) => {
+                // The only top-level declaration of a name may have had its function reserved
+                let reserved = if self.frames.len() == 1 {
+                    self.reserved.remove(name)
+                } else {
+                    None
+                };
+                let mark = self.mark();
+                let closure = self.closure(Some(name), args, body, reserved);
+                self.assign(name, &closure, span);
+                self.result(dest, &closure, false);
+                self.release(mark);
+            }
+            _ => self.into(expr, dest, tail),
+        }
+    }
+
+    fn is_simple(node: &ASTNode) -> bool {
+        matches!(
+            *node,
+            ASTNode::Integer(..)
+                | ASTNode::Float(..)
+                | ASTNode::StringLiteral(..)
+                | ASTNode::Boolean(..)
+                | ASTNode::Name(..)
+                | ASTNode::Qualified { .. }
+        )
+    }
+
+    // A C expression for a node's value, which is in a slot unless the node is a literal or
+    // a name. The slot is taken until it's released.
+    fn operand(&mut self, node: &ASTNode) -> String {
+        match *node {
+            ASTNode::Integer(..) | ASTNode::Float(..) => number(literal(node).unwrap_or(f64::NAN)),
+            ASTNode::StringLiteral(ref val, _) => self.string(val),
+            ASTNode::Boolean(val, _) => format!("sv_boolean({})", val as u8),
+            ASTNode::Name(ref name, span) => match self.lookup(name) {
+                Some(var) => self.var(var),
+                None => {
+                    let msg = if self.sources[self.current].imports.contains_key(name) {
+                        format!("The module {0} isn't a value, so only its exports like {0}.name can be used", name)
+                    } else {
+                        format!("{} isn't defined", name)
+                    };
+                    self.error(span, msg);
+                    String::from("sv_boolean(0)")
+                }
+            },
+            ASTNode::Qualified {
+                ref module,
+                ref name,
+                span,
+            } => match self.lookup_qualified(module, name) {
+                Some(var) => self.var(var),
+                None => {
+                    self.error(span, format!("{}.{} isn't defined", module, name));
+                    String::from("sv_boolean(0)")
+                }
+            },
+            _ => {
+                let slot = self.slot();
+                let dest = format!("s[{}]", slot);
+                self.into(node, Some(&dest), false);
+                dest
+            }
+        }
+    }
+
+    // The values of nodes evaluated in order. A name's value is copied if something after
+    // it might assign to it.
+    fn operands(&mut self, nodes: &[&ASTNode]) -> Vec<String> {
+        let mut operands = Vec::new();
+        for (i, node) in nodes.iter().enumerate() {
+            let later = nodes[i + 1..].iter().any(|node| !Compiler::is_simple(node));
+            operands.push(self.pinned(node, later));
+        }
+        operands
+    }
+
+    fn pinned(&mut self, node: &ASTNode, later: bool) -> String {
+        let operand = self.operand(node);
+        if later && matches!(*node, ASTNode::Name(..) | ASTNode::Qualified { .. }) {
+            let slot = self.slot();
+            self.line(&format!("s[{}] = {};", slot, operand));
+            return format!("s[{}]", slot);
+        }
+        operand
+    }
+
+    // A C condition for a node's truthiness, which a comparison already is
+    fn condition(&mut self, node: &ASTNode) -> String {
+        if let ASTNode::Binary {
+            op: Token::Operator(ref op),
+            ref lhs,
+            ref rhs,
+            ..
+        } = *node
+        {
+            if let Some(test) = self.comparison(op, lhs, rhs) {
+                return test;
+            }
+        }
+        match *node {
+            ASTNode::Boolean(val, _) => String::from(if val { "1" } else { "0" }),
+            _ => format!("sv_truthy({})", self.operand(node)),
+        }
+    }
+
+    // Comparisons give -1, 0 or 1, or 2 if the operands are unordered
+    fn comparison(&mut self, op: &str, lhs: &ASTNode, rhs: &ASTNode) -> Option<String> {
+        if !matches!(op, "==" | "!=" | "<" | "<=" | ">" | ">=") {
+            return None;
+        }
+        let operands = self.operands(&[lhs, rhs]);
+        let (a, b) = (&operands[0], &operands[1]);
+        Some(match op {
+            "==" => format!("sv_equals({}, {})", a, b),
+            "!=" => format!("!sv_equals({}, {})", a, b),
+            "<" => format!("sv_compare({}, {}) == -1", a, b),
+            "<=" => format!("sv_compare({}, {}) <= 0", a, b),
+            ">" => format!("sv_compare({}, {}) == 1", a, b),
+            _ => format!("sv_at_least({}, {})", a, b),
+        })
+    }
+
+    // The values of arguments in consecutive slots, as a pointer to the first
+    fn arguments(&mut self, nodes: &[ASTNode]) -> String {
+        if nodes.is_empty() {
+            return String::from("NULL");
+        }
+        format!("&s[{}]", self.block(nodes))
+    }
+
+    // Evaluates nodes into consecutive slots, returning the first
+    fn block(&mut self, nodes: &[ASTNode]) -> usize {
+        let base = self.slots(nodes.len());
+        for (i, node) in nodes.iter().enumerate() {
+            let mark = self.mark();
+            self.into(node, Some(&format!("s[{}]", base + i)), false);
+            self.release(mark);
+        }
+        base
+    }
+
+    // Stores the value of a node, or evaluates it for its effects if there's nowhere to
+    // store it. A node in tail position may jump back to the start of the function instead,
+    // if it's a call to the function.
+    fn into(&mut self, node: &ASTNode, dest: Option<&str>, tail: bool) {
+        let mark = self.mark();
+        match *node {
+            ASTNode::Function {
+                ref args, ref body, ..
+            } => {
+                let name = node.function_name().map(|(name, _)| name);
+                let closure = self.closure(name, args, body, None);
+                self.result(dest, &closure, false);
+            }
+            ASTNode::Invocation {
+                ref func, ref args, ..
+            } => self.invocation(func, args, dest, tail),
+            ASTNode::Conditional {
+                ref cond,
+                ref if_body,
+                ref else_body,
+                ..
+            } => {
+                let cond = self.condition(cond);
+                self.open(&format!("if ({}) {{", cond));
+                self.into(if_body, dest, tail);
+                match **else_body {
+                    Some(ref else_body) => {
+                        self.close("} else {");
+                        self.frame().depth += 1;
+                        self.into(else_body, dest, tail);
+                    }
+                    None if dest.is_some() => {
+                        self.close("} else {");
+                        self.frame().depth += 1;
+                        self.result(dest, "sv_boolean(0)", false);
+                    }
+                    None => {}
+                }
+                self.close("}");
+            }
+            ASTNode::Binary {
+                ref op,
+                ref lhs,
+                ref rhs,
+                span,
+            } => self.binary(op, lhs, rhs, dest, span),
+            ASTNode::Sequence(ref exprs) => {
+                if exprs.is_empty() {
+                    self.result(dest, "sv_boolean(0)", false);
+                }
+                for (i, expr) in exprs.iter().enumerate() {
+                    let last = i + 1 == exprs.len();
+                    self.element(expr, if last { dest } else { None }, tail && last);
+                }
+            }
+            ASTNode::List(ref elements, _) => {
+                let elements_at = self.arguments(elements);
+                let list = format!("sv_list_new({}, {})", elements.len(), elements_at);
+                self.result(dest, &list, false);
+            }
+            ASTNode::Let {
+                ref name,
+                ref value,
+                span,
+            } => {
+                let value = self.operand(value);
+                match name.binding() {
+                    Some((name, _)) => self.assign(name, &value, span),
+                    None => self.error(span, String::from("Only a name can be bound by let")),
+                }
+                self.result(dest, &value, false);
+            }
+            ASTNode::Annotated { ref expr, .. } => self.into(expr, dest, tail),
+            ASTNode::TypeDeclaration { ref variants, .. } => {
+                for variant in variants.iter() {
+                    self.variant(variant);
+                }
+                self.result(dest, "sv_boolean(0)", false);
+            }
+            ASTNode::Match {
+                ref subject,
+                ref arms,
+                ..
+            } => self.match_arms(subject, arms, dest, tail),
+            ASTNode::Export { ref decl, .. } => self.element(decl, dest, tail),
+            ASTNode::Extern {
+                ref module,
+                ref functions,
+                ..
+            } => {
+                for function in functions.iter() {
+                    self.extern_binding(module, function);
+                }
+                self.result(dest, "sv_boolean(0)", false);
+            }
+            // The modules a module imports are compiled before it
+            ASTNode::Import { .. } => self.result(dest, "sv_boolean(0)", false),
+            _ => {
+                let value = self.operand(node);
+                self.result(dest, &value, false);
+            }
+        }
+        self.release(mark);
+    }
+
+    fn binary(&mut self, op: &Token, lhs: &ASTNode, rhs: &ASTNode, dest: Option<&str>, span: Span) {
+        let op = match *op {
+            Token::Operator(ref op) => op.as_str(),
+            _ => {
+                self.error(span, String::from("Malformed binary node"));
+                return;
+            }
+        };
+
+        match op {
+            "=" => {
+                let value = self.operand(rhs);
+                match *lhs {
+                    ASTNode::Name(ref name, span) => self.assign(name, &value, span),
+                    _ => self.error(span, String::from("Only a name can be assigned to")),
+                }
+                self.result(dest, &value, false);
+            }
+            "&&" => {
+                let lhs = self.condition(lhs);
+                self.open(&format!("if ({}) {{", lhs));
+                self.into(rhs, dest, false);
+                if dest.is_some() {
+                    self.close("} else {");
+                    self.frame().depth += 1;
+                    self.result(dest, "sv_boolean(0)", false);
+                }
+                self.close("}");
+            }
+            "||" => {
+                let lhs = self.operand(lhs);
+                if let Some(dest) = dest {
+                    self.open(&format!("if (sv_truthy({})) {{", lhs));
+                    self.line(&format!("{} = {};", dest, lhs));
+                    self.close("} else {");
+                } else {
+                    self.line(&format!("if (!sv_truthy({})) {{", lhs));
+                }
+                self.frame().depth += 1;
+                self.into(rhs, dest, false);
+                self.close("}");
+            }
+            _ => {
+                if let Some(test) = self.comparison(op, lhs, rhs) {
+                    return self.result(dest, &format!("sv_boolean({})", test), false);
+                }
+                let operands = self.operands(&[lhs, rhs]);
+                let (a, b) = (to_double(lhs, &operands[0]), to_double(rhs, &operands[1]));
+                let value = match op {
+                    "-" | "*" | "/" => format!("sv_number({} {} {})", a, op, b),
+                    "%" => format!("sv_number(fmod({}, {}))", a, b),
+                    "+" => format!("sv_add({}, {})", operands[0], operands[1]),
+                    _ => {
+                        self.error(span, format!("{} isn't an operator on values", op));
+                        return;
+                    }
+                };
+                self.result(dest, &value, op == "+");
+            }
+        }
+    }
+
+    fn invocation(&mut self, func: &ASTNode, args: &[ASTNode], dest: Option<&str>, tail: bool) {
+        let callee = match *func {
+            ASTNode::Name(ref name, _) => self.lookup(name),
+            ASTNode::Qualified {
+                ref module,
+                ref name,
+                ..
+            } => self.lookup_qualified(module, name),
+            _ => None,
+        };
+
+        let count = args.len();
+        let call = match callee {
+            Some(Var::Itself) if count == self.frame().arity => {
+                if tail && self.frame().loops {
+                    return self.jump(args);
+                }
+                let args = self.arguments(args);
+                let function = self.frame().function.expect("Only a function is itself");
+                self.frame().uses_self = true;
+                format!("{}(self, {})", self.functions[function].name, args)
+            }
+            Some(Var::Global(GlobalName {
+                direct: Some(Direct::Function(function, arity)),
+                ..
+            })) if arity == count => {
+                let args = self.arguments(args);
+                let function = &self.functions[function];
+                format!("{}(&{}, {})", function.name, function.closure, args)
+            }
+            Some(Var::Global(GlobalName {
+                direct: Some(Direct::Extern(function, arity)),
+                ..
+            })) if arity == count => {
+                let args = self.arguments(args);
+                format!("{}({}, {})", self.externs[function], count, args)
+            }
+            _ => {
+                let impure = args.iter().any(|arg| !Compiler::is_simple(arg));
+                let callee = self.pinned(func, impure);
+                let args = self.arguments(args);
+                format!("sv_call({}, {}, {})", callee, count, args)
+            }
+        };
+        self.result(dest, &call, true);
+    }
+
+    // A call to the function itself in tail position rebinds its parameters and starts its
+    // body over. The arguments are all evaluated first, since they may refer to each other.
+    fn jump(&mut self, args: &[ASTNode]) {
+        let base = self.block(args);
+        for i in 0..args.len() {
+            self.line(&format!("s[{}] = s[{}];", i, base + i));
+        }
+        self.line("goto start;");
+        self.frame().jumped = true;
+    }
+
+    // Compiles a function, returning its closure
+    fn closure(
+        &mut self,
+        name: Option<&str>,
+        args: &[ASTNode],
+        body: &ASTNode,
+        reserved: Option<usize>,
+    ) -> String {
+        let arity = args.len();
+        let function = match reserved {
+            Some(function) => function,
+            None => self.add_function(name.unwrap_or("anonymous")),
+        };
+
+        let params: Vec<String> = args
+            .iter()
+            .map(|arg| String::from(arg.binding().map_or("_", |(arg, _)| arg)))
+            .collect();
+        let source = &self.sources[self.current];
+        let locals = backend::function_locals(&self.frames, source, name, &params, body);
+
+        let mut frame = Frame::new(Some(function), arity);
+        backend::collect_captured(body, &mut frame.scope.captured);
+        if let Some(name) = name {
+            frame.scope.bind(name, Var::Itself);
+        }
+        frame.scope.enter();
+        frame.loops = name.is_some_and(|name| {
+            !params.iter().any(|param| param == name)
+                && !locals.contains(&String::from(name))
+                && backend::calls_itself(name, arity, body)
+        });
+        self.frames.push(frame);
+
+        // The arguments are copied to the first slots, and the body starts after that.
+        // Parameters that nested functions capture are then copied to cells.
+        self.slots(arity);
+        for i in 0..arity {
+            self.line(&format!("s[{}] = args[{}];", i, i));
+        }
+        let start = self.frame().code.len();
+        self.frame().start = start;
+        for (i, param) in params.iter().enumerate() {
+            if self.frame().scope.is_captured(param) {
+                self.declare(param, &format!("s[{}]", i));
+            } else {
+                self.frame().scope.bind(param, Var::Local(i));
+            }
+        }
+        for local in locals.iter() {
+            self.declare(local, "sv_boolean(0)");
+        }
+
+        let result = self.slot();
+        self.into(body, Some(&format!("s[{}]", result)), true);
+
+        let frame = self.frames.pop().expect("The frame was pushed above");
+        let mut definition = format!(
+            "static sv_value {}(sv_closure *self, sv_value *args) {{\n{}",
+            self.functions[function].name,
+            frame.declaration()
+        );
+        if !frame.uses_self {
+            definition.push_str("    (void)self;\n");
+        }
+        if arity == 0 {
+            definition.push_str("    (void)args;\n");
+        }
+        definition.push_str(&frame.code[..frame.start]);
+        if frame.jumped {
+            definition.push_str("start:\n");
+        }
+        definition.push_str(&frame.code[frame.start..]);
+        definition.push_str(&format!("    SV_LEAVE();\n    return s[{}];\n}}\n", result));
+        self.functions[function].definition = definition;
+
+        let display = name.unwrap_or("");
+        if frame.scope.captures.is_empty() {
+            return self.static_closure(function, arity, display);
+        }
+
+        let slot = self.slot();
+        self.line(&format!(
+            "s[{}] = sv_closure_new({}, {}, {}, {});",
+            slot,
+            self.functions[function].name,
+            arity,
+            quote(display),
+            frame.scope.captures.len()
+        ));
+        for (index, name) in frame.scope.captures.iter().enumerate() {
+            let var = self
+                .lookup(name)
+                .expect("A captured name is bound in an enclosing function");
+            let cell = self.cell(var);
+            self.line(&format!(
+                "SV_CLOSURE_OF(s[{}])->cells[{}] = {};",
+                slot, index, cell
+            ));
+        }
+        format!("s[{}]", slot)
+    }
+
+    // Binds a constructor. A constructor without fields is a single value, and one with
+    // fields a function.
+    fn variant(&mut self, variant: &Variant) {
+        let tag = self.constructor_tag(&variant.name);
+        let arity = variant.fields.len();
+        if arity == 0 {
+            // Each declaration makes its own value, even of a constructor declared before
+            let base = format!(
+                "d_{}{}",
+                self.sources[self.current].prefix,
+                mangle(&variant.name)
+            );
+            let mut name = base.clone();
+            while self.data.contains(&name) {
+                name = format!("{}_{}", base, self.data.len() + 1);
+            }
+            self.data.push(name.clone());
+            self.statics.push_str(&format!(
+                "static sv_data {} = {{{{NULL, 0, SV_DATA, 1}}, {}, 0, {{{{0}}}}}};\n",
+                name, tag
+            ));
+            let value = format!("sv_object_value(&{}.header)", name);
+            return self.assign(&variant.name, &value, variant.span);
+        }
+
+        let function = if self.frames.len() == 1 {
+            self.reserved.remove(&variant.name)
+        } else {
+            None
+        };
+        let function = match function {
+            Some(function) => function,
+            None => self.add_function(&variant.name),
+        };
+        self.functions[function].definition = format!(
+            "static sv_value {}(sv_closure *self, sv_value *args) {{\n    (void)self;\n    \
+             return sv_data_new({}, {}, args);\n}}\n",
+            self.functions[function].name, tag, arity
+        );
+
+        let closure = self.static_closure(function, arity, &variant.name);
+        self.assign(&variant.name, &closure, variant.span);
+    }
+
+    // Binds an extern's name to a function that calls the native or external function with
+    // its arguments
+    fn extern_binding(&mut self, module: &Option<String>, function: &ExternFunction) {
+        let target = match self.extern_function(module, function) {
+            Some(target) => target,
+            None => {
+                let (_, field) = backend::extern_target(module, function);
+                return self.error(
+                    function.span,
+                    format!("{} isn't a C function, so it can't be called from C", field),
+                );
+            }
+        };
+        let arity = function.args.len();
+        let wrapper = self.add_function(&function.name);
+        self.functions[wrapper].definition = format!(
+            "static sv_value {}(sv_closure *self, sv_value *args) {{\n    (void)self;\n    \
+             return {}({}, args);\n}}\n",
+            self.functions[wrapper].name, self.externs[target], arity
+        );
+
+        let closure = self.static_closure(wrapper, arity, &function.name);
+        self.assign(&function.name, &closure, function.span);
+    }
+
+    // Switches on the subject's constructor, with a case for each arm until a wildcard
+    fn match_arms(&mut self, subject: &ASTNode, arms: &[MatchArm], dest: Option<&str>, tail: bool) {
+        let subject = self.operand(subject);
+        self.open(&format!("switch (sv_tag_of({})) {{", subject));
+
+        let mut cases = Vec::new();
+        let mut exhaustive = false;
+        for arm in arms.iter() {
+            match arm.pattern {
+                Pattern::Wildcard(_) => {
+                    self.open("default: {");
+                    self.into(&arm.body, dest, tail);
+                    exhaustive = true;
+                }
+                Pattern::Constructor {
+                    ref name,
+                    ref bindings,
+                    ..
+                } => {
+                    // A later arm for the same constructor is never reached
+                    let tag = self.constructor_tag(name);
+                    if cases.contains(&tag) {
+                        continue;
+                    }
+                    cases.push(tag);
+
+                    self.open(&format!("case {}: {{", tag));
+                    let mark = self.mark();
+                    self.frame().scope.enter();
+                    for (i, binding) in bindings.iter().enumerate() {
+                        if let Some((binding, _)) = binding.binding() {
+                            if binding != "_" {
+                                let field = format!("SV_DATA_OF({})->fields[{}]", subject, i);
+                                self.declare(binding, &field);
+                            }
+                        }
+                    }
+                    self.into(&arm.body, dest, tail);
+                    self.frame().scope.leave();
+                    self.release(mark);
+                }
+            }
+            self.line("break;");
+            self.close("}");
+            if exhaustive {
+                break;
+            }
+        }
+
+        if !exhaustive {
+            self.open("default:");
+            self.line(&format!("sv_no_match({});", subject));
+            self.frame().depth -= 1;
+        }
+        self.close("}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use prelude;
+    use std::path::Path;
+
+    fn compile(inp: &str) -> Result<String, Vec<Error>> {
+        let modules =
+            modules::load_with(Path::new("main.silver"), |_| Ok(String::from(inp))).unwrap();
+        compile_program(&modules, &prelude::module())
+    }
+
+    // The definition of one function, up to its closing brace
+    fn func(c: &str, name: &str) -> String {
+        let start = c
+            .find(&format!(
+                "static sv_value {}(sv_closure *self, sv_value *args) {{",
+                name
+            ))
+            .or_else(|| c.find(&format!("static void {}(void) {{", name)))
+            .unwrap();
+        let end = c[start..].find("\n}\n").unwrap();
+        String::from(&c[start..start + end])
+    }
+
+    #[test]
+    fn test_names_are_mangled() {
+        assert_eq!(mangle("empty?"), "empty_3f");
+        assert_eq!(mangle("to-string"), "to_2dstring");
+        assert_eq!(mangle("a_b"), "a__b");
+        assert_eq!(quote("a\"?\n"), "\"a\\\"\\?\\012\"");
+        assert!(compile("fn empty?() { 1 }")
+            .unwrap()
+            .contains("g_empty_3f = "));
+    }
+
+    #[test]
+    fn test_arithmetic_is_inline() {
+        let f = func(&compile("fn f(a, b) { a * b - 1 }").unwrap(), "f_f");
+        assert!(f.contains("= sv_number(sv_to_double(s[0]) * sv_to_double(s[1]));"));
+        assert!(f.contains("- 1.0);"));
+        assert!(!f.contains("sv_call"));
+    }
+
+    #[test]
+    fn test_tail_self_calls_jump() {
+        let c = compile("fn count(n, acc) { if n == 0 then acc else count(n - 1, acc + 1) }");
+        let f = func(&c.unwrap(), "f_count");
+        assert!(f.contains("start:\n    if (sv_equals(s[0], sv_number(0.0))) {"));
+        assert!(f.contains("s[0] = s[3];\n        s[1] = s[4];\n        goto start;"));
+        assert!(!f.contains("f_count(self"));
+    }
+
+    #[test]
+    fn test_calls_to_stable_functions_are_direct() {
+        let init = func(&compile("fn f(x) { x }; f(1)").unwrap(), "init_main");
+        assert!(init.contains("f_f(&c_f, &s[0]);"));
+        assert!(!init.contains("sv_call"));
+
+        let init = func(
+            &compile("fn f(x) { x }; f = fn (x) { 2 }; f(1)").unwrap(),
+            "init_main",
+        );
+        assert!(init.contains("sv_call(g_f, 1, &s[0]);"));
+    }
+
+    #[test]
+    fn test_captured_names_are_in_cells() {
+        let c = compile("fn counter() { n = 0; fn () { n = n + 1 } }").unwrap();
+        let counter = func(&c, "f_counter");
+        assert!(counter.contains("s[0] = sv_cell_new(sv_boolean(0));"));
+        assert!(counter.contains("SV_CLOSURE_OF(s[2])->cells[0] = s[0];"));
+        let anonymous = func(&c, "f_anonymous");
+        assert!(anonymous.contains("SV_CELL(self->cells[0]) = s[1];"));
+    }
+
+    #[test]
+    fn test_match_switches_on_tags() {
+        let c = compile(
+            "type Shape = Circle(r) | Empty;
+             fn area(s) { match s { Circle(r) => r * r, Empty => 0 } }",
+        )
+        .unwrap();
+        let area = func(&c, "f_area");
+        assert!(area.contains("switch (sv_tag_of(s[0])) {"));
+        assert!(area.contains("case 0: {\n            s[2] = SV_DATA_OF(s[0])->fields[0];"));
+        assert!(area.contains("default:\n            sv_no_match(s[0]);"));
+        assert!(c.contains("static sv_data d_Empty = {{NULL, 0, SV_DATA, 1}, 1, 0, {{0}}};"));
+        assert!(c.contains("\"Circle\", \"Empty\", NULL};"));
+    }
+
+    #[test]
+    fn test_externs_call_c_functions() {
+        let c = compile(
+            "extern fn print(x) = \"console.log\";
+             extern fn twice(x) = \"twice\";
+             print(twice(1))",
+        )
+        .unwrap();
+        assert!(c.contains("sv_value twice(size_t count, sv_value *args);"));
+        assert!(c.contains("sv_native_console_log(1, &s[0]);"));
+
+        let errors = compile("extern fn hypot(x, y) = \"Math.hypot\"").unwrap_err();
+        assert_eq!(
+            errors[0].msg,
+            "Math.hypot isn't a C function, so it can't be called from C"
+        );
+    }
+
+    #[test]
+    fn test_undefined_names_are_errors() {
+        let errors = compile("x = 1; y(x)").unwrap_err();
+        assert_eq!(
+            errors,
+            vec![Error {
+                msg: String::from("y isn't defined"),
+                line: 1,
+                col: 7,
+            }]
+        );
+    }
+
+    // Compiled programs are built and run with the system's C compiler, when there's one
+    #[test]
+    fn test_compiled_programs_run() {
+        use std::fs;
+        use std::process::Command;
+
+        if Command::new("cc").arg("--version").output().is_err() {
+            return;
+        }
+        let inp = "extern fn print(x) = \"console.log\";
+                   fn count(n, total) { if n == 0 then total else count(n - 1, total + n) };
+                   print(count(1000000, 0));
+                   fn counter() { let n = 0; fn () { n = n + 1; n } };
+                   let next = counter(); next(); print(next());
+                   fn adder(x) { fn (y) { x + y } }; let add = adder(1); print(add(2) * 3);
+                   print(\"a b\" + to-string(sum([1, 2, 3])) + \"!\");
+                   let x = 1; fn shadow() { let x = 2; x }; print(shadow()); print(x)";
+
+        let dir = std::env::temp_dir().join(format!("silver-c-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("out.c"), compile(inp).unwrap()).unwrap();
+        let built = Command::new("cc")
+            .current_dir(&dir)
+            .args(["-std=c99", "-o", "out", "out.c", LINK_FLAGS])
+            .output()
+            .unwrap();
+        let output = Command::new(dir.join("out")).output();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(String::from_utf8_lossy(&built.stderr), "");
+        assert_eq!(
+            String::from_utf8(output.unwrap().stdout).unwrap(),
+            "500000500000\n2\n9\na b6!\n2\n1\n"
+        );
+    }
+}
@@ -1,129 +1,3276 @@
 use super::lexer::Token;
-use super::parser::ASTNode;
+use super::modules::Module;
+use super::parser::{ASTNode, ExternFunction, MatchArm, Pattern, Variant};
+use super::prelude;
+use super::util::Span;
 
+use std::collections::HashSet;
 use std::fmt;
+use std::io;
+use std::slice;
 
 #[derive(Debug, PartialEq)]
 pub struct Error {
     pub msg: String,
+    // Where the problem is, unless it's in writing the output
+    pub span: Option<Span>,
+    // The nodes enclosing the problem, innermost first, along with where each starts
+    pub trail: Vec<(String, Span)>,
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Error: {}", self.msg)
+        write!(f, "Error: {}", self.msg)?;
+        if let Some(span) = self.span {
+            write!(f, "\nLine: {}, Column: {}\n", span.line, span.col)?;
+        }
+        for &(ref node, span) in self.trail.iter() {
+            writeln!(
+                f,
+                "  in {} at line {}, column {}",
+                node, span.line, span.col
+            )?;
+        }
+        Ok(())
     }
 }
 
-pub fn emit(ast: ASTNode) -> Result<String, Error> {
-    match ast {
-        ASTNode::Integer(val) => Ok(val.to_string()),
-        ASTNode::Float(val) => Ok(val.to_string()),
-        ASTNode::StringLiteral(val) => Ok(format!("\"{}\"", val)),
-        ASTNode::Boolean(val) => Ok(val.to_string()),
-        ASTNode::Name(val) => Ok(val),
-        ASTNode::Function { name, args, body } => emit_function(name, args, body),
-        ASTNode::Invocation { func, args } => emit_invocation(func, args),
+#[derive(Debug, Default)]
+pub struct Options {
+    // Makes every function check the number of arguments it's called with. Calls to known
+    // functions are checked statically, so this only matters for dynamic calls.
+    pub arity_guards: bool,
+    // Makes tail calls between different top-level functions return to a trampoline instead
+    // of growing the stack. Tail calls to the calling function itself always become loops.
+    pub trampoline: bool,
+    pub module: ModuleFormat,
+    pub style: Style,
+}
+
+// How a program is packaged. With none, it's a single expression whose top-level names are
+// globals. Otherwise its top-level names are declared, and with esm or cjs its top-level
+// named functions and explicit exports are exported.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ModuleFormat {
+    #[default]
+    None,
+    Esm,
+    Cjs,
+    Iife,
+}
+
+impl ModuleFormat {
+    pub fn from_name(name: &str) -> Option<ModuleFormat> {
+        match name {
+            "none" => Some(ModuleFormat::None),
+            "esm" => Some(ModuleFormat::Esm),
+            "cjs" => Some(ModuleFormat::Cjs),
+            "iife" => Some(ModuleFormat::Iife),
+            _ => None,
+        }
+    }
+}
+
+// How the output is laid out
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Style {
+    // Everything on one line, with every operation parenthesized
+    #[default]
+    Compact,
+    // One statement per line, with function bodies indented, conditionals whose values are
+    // unused as if statements, and only the parentheses JS's precedence rules need
+    Pretty,
+    // The compact layout without the whitespace and parentheses JS doesn't need, and with
+    // the names that aren't visible outside a module shortened
+    Minified,
+}
+
+// JS's precedence levels for the expressions the emitter produces, from the loosest binding
+// to the tightest
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+enum Precedence {
+    Comma,
+    Assignment,
+    Conditional,
+    Equality,
+    Relational,
+    Additive,
+    Multiplicative,
+    Unary,
+    Call,
+}
+
+impl Precedence {
+    fn of_operator(op: &str) -> Precedence {
+        match op {
+            "=" => Precedence::Assignment,
+            "==" | "!=" => Precedence::Equality,
+            "<" | "<=" | ">" | ">=" => Precedence::Relational,
+            "+" | "-" => Precedence::Additive,
+            _ => Precedence::Multiplicative,
+        }
+    }
+
+    // The precedence the right operand of a left associative operator needs
+    fn tighter(self) -> Precedence {
+        match self {
+            Precedence::Comma => Precedence::Assignment,
+            Precedence::Assignment => Precedence::Conditional,
+            Precedence::Conditional => Precedence::Equality,
+            Precedence::Equality => Precedence::Relational,
+            Precedence::Relational => Precedence::Additive,
+            Precedence::Additive => Precedence::Multiplicative,
+            Precedence::Multiplicative => Precedence::Unary,
+            Precedence::Unary | Precedence::Call => Precedence::Call,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Context<'a> {
+    options: &'a Options,
+    // The names bound where the node being emitted is. Assigning to a name bound outside
+    // the function being emitted updates it, while assigning to any other name defines a
+    // local.
+    scope: &'a Scope<'a>,
+    // The names that shortened names mustn't collide with, which is empty unless the output
+    // is minified
+    taken: &'a HashSet<String>,
+    // The JS modules used by externs, each imported as $extern0, $extern1, ...
+    externs: &'a [String],
+    // The top-level functions wrapped in a trampoline, which is empty inside functions
+    trampolined: &'a [String],
+}
+
+impl<'a> Context<'a> {
+    fn pretty(&self) -> bool {
+        self.options.style == Style::Pretty
+    }
+
+    fn minified(&self) -> bool {
+        self.options.style == Style::Minified
+    }
+
+    // A scope nested in this context's, with nothing bound in it yet
+    fn nested(&self) -> Scope<'a> {
+        Scope {
+            names: Vec::new(),
+            parent: Some(self.scope),
+            next: self.scope.next,
+        }
+    }
+
+    // This context with the names bound in a nested scope
+    fn with_scope<'b>(&self, scope: &'b Scope<'b>) -> Context<'b>
+    where
+        'a: 'b,
+    {
+        Context { scope, ..*self }
+    }
+
+    // Writes the name that a name refers to is emitted as
+    fn name(&self, name: &str, out: &mut Output) -> fmt::Result {
+        match self.scope.lookup(name) {
+            Some(emitted) => out.write(emitted),
+            None => out.name(name),
+        }
+    }
+
+    // The name that a name refers to is emitted as
+    fn emitted(&self, name: &str) -> String {
+        match self.scope.lookup(name) {
+            Some(emitted) => String::from(emitted),
+            None => mangle(name),
+        }
+    }
+
+    // The separator between arguments, parameters and list elements
+    fn comma(&self) -> &'static str {
+        if self.pretty() {
+            ", "
+        } else {
+            ","
+        }
+    }
+}
+
+// The names bound in a scope and the names they're emitted as, with the enclosing scopes
+// borrowed rather than copied. Minified output renames each name to the shortest name that
+// nothing in the module uses. Nested scopes number their names after the enclosing scopes',
+// so a renamed name never shadows another.
+struct Scope<'a> {
+    names: Vec<(String, String)>,
+    parent: Option<&'a Scope<'a>>,
+    // The number of the next shortened name
+    next: usize,
+}
+
+impl<'a> Scope<'a> {
+    fn lookup(&self, name: &str) -> Option<&str> {
+        match self.names.iter().rev().find(|&(bound, _)| bound == name) {
+            Some((_, emitted)) => Some(emitted),
+            None => self.parent.and_then(|parent| parent.lookup(name)),
+        }
+    }
+
+    fn contains(&self, name: &str) -> bool {
+        self.lookup(name).is_some()
+    }
+
+    // Binds a name, shortening it if the output is minified, and returns the name it's
+    // emitted as
+    fn bind(&mut self, name: &str, context: &Context) -> &str {
+        let emitted = if context.minified() && name != "_" {
+            self.fresh(context.taken)
+        } else {
+            mangle(name)
+        };
+        self.names.push((String::from(name), emitted));
+        &self.names[self.names.len() - 1].1
+    }
+
+    // Binds a name to the name it's emitted as
+    fn keep(&mut self, name: &str, emitted: String) {
+        self.names.push((String::from(name), emitted));
+    }
+
+    fn fresh(&mut self, taken: &HashSet<String>) -> String {
+        const LETTERS: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+
+        loop {
+            let mut name = Vec::new();
+            let mut n = self.next;
+            self.next += 1;
+            loop {
+                name.push(LETTERS[n % LETTERS.len()]);
+                n /= LETTERS.len();
+                if n == 0 {
+                    break;
+                }
+                n -= 1;
+            }
+
+            let name = String::from_utf8(name).unwrap();
+            if !taken.contains(&name) {
+                return name;
+            }
+        }
+    }
+}
+
+// Where emitted code goes. The layout of each style is applied as the code passes through,
+// so pretty output is indented and minified output stripped of whitespace without the
+// program ever being built up in memory.
+struct Output<'w, 'n> {
+    sink: &'w mut dyn fmt::Write,
+    style: Style,
+    // The nodes being emitted, innermost last, and the problems found with them so far.
+    // Emitting carries on past a node it can't emit, so that every problem is found.
+    trail: Vec<&'n ASTNode>,
+    errors: Vec<Error>,
+    // The expression statements being written, innermost last, and whether each is
+    // parenthesized, which is known once its first text is written
+    statements: Vec<Option<bool>>,
+    // How many blocks deep pretty output is, and whether it's at the start of a line
+    depth: usize,
+    line_start: bool,
+    // The last character of minified output, whether whitespace or a semicolon has been
+    // left out after it, and whether it's inside a string literal
+    last: Option<char>,
+    space: bool,
+    semicolon: bool,
+    string: bool,
+    escaped: bool,
+}
+
+impl<'w, 'n> Output<'w, 'n> {
+    fn new(sink: &'w mut dyn fmt::Write, style: Style) -> Output<'w, 'n> {
+        Output {
+            sink,
+            style,
+            trail: Vec::new(),
+            errors: Vec::new(),
+            statements: Vec::new(),
+            depth: 0,
+            line_start: true,
+            last: None,
+            space: false,
+            semicolon: false,
+            string: false,
+            escaped: false,
+        }
+    }
+
+    fn write(&mut self, text: &str) -> fmt::Result {
+        fmt::Write::write_str(self, text)
+    }
+
+    fn write_fmt(&mut self, args: fmt::Arguments) -> fmt::Result {
+        fmt::Write::write_fmt(self, args)
+    }
+
+    fn name(&mut self, name: &str) -> fmt::Result {
+        write_mangled(name, self)
+    }
+
+    // A failure to write ends emitting, so a node only needs leaving once it's emitted
+    fn enter(&mut self, node: &'n ASTNode) {
+        self.trail.push(node);
+    }
+
+    fn leave(&mut self) {
+        self.trail.pop();
+    }
+
+    // Notes a problem found while emitting, with the nodes enclosing it as its trail
+    fn error(&mut self, span: Span, msg: String) {
+        let trail = self
+            .trail
+            .iter()
+            .rev()
+            .filter_map(|node| describe(node).map(|description| (description, node.span())))
+            .collect();
+        self.errors.push(Error {
+            msg,
+            span: Some(span),
+            trail,
+        });
+    }
+
+    // The problems found, or the failure to write if writing failed
+    fn finish(self, result: fmt::Result) -> Result<(), Vec<Error>> {
+        if result.is_err() {
+            return Err(vec![write_error()]);
+        }
+        if !self.errors.is_empty() {
+            return Err(self.errors);
+        }
+        Ok(())
+    }
+
+    // Writes the contents of a JS string literal
+    fn escaped(&mut self, string: &str) -> fmt::Result {
+        for ch in string.chars() {
+            match ch {
+                '\\' => self.write("\\\\")?,
+                '"' => self.write("\\\"")?,
+                _ => fmt::Write::write_char(self, ch)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn quoted(&mut self, string: &str) -> fmt::Result {
+        self.write("\"")?;
+        self.escaped(string)?;
+        self.write("\"")
+    }
+
+    // A block of statements in braces, which pretty output indents
+    fn open_block(&mut self) -> fmt::Result {
+        if self.style == Style::Pretty {
+            self.depth += 1;
+            self.write(" {\n")
+        } else {
+            self.write(" { ")
+        }
+    }
+
+    fn close_block(&mut self) -> fmt::Result {
+        if self.style == Style::Pretty {
+            self.depth -= 1;
+        }
+        self.write("}")
+    }
+
+    // Compact output separates statements with spaces, and pretty output puts each on its
+    // own line
+    fn end_line(&mut self) -> fmt::Result {
+        if self.style == Style::Pretty {
+            self.write("\n")
+        } else {
+            self.write(" ")
+        }
+    }
+
+    // A statement ending with a semicolon
+    fn end_statement(&mut self) -> fmt::Result {
+        self.write(";")?;
+        self.end_line()
+    }
+
+    // The last statement before a closing brace, where compact output leaves out the
+    // semicolon
+    fn end_last_statement(&mut self) -> fmt::Result {
+        if self.style == Style::Pretty {
+            self.write(";\n")
+        } else {
+            self.write(" ")
+        }
+    }
+
+    // A statement starting with `function` or `{` would be a declaration or a block, so
+    // such an expression needs parentheses to be a statement
+    fn begin_expression_statement(&mut self) {
+        self.statements.push(None);
+    }
+
+    // Whether the expression statement wrote anything
+    fn end_expression_statement(&mut self) -> Result<bool, fmt::Error> {
+        match self.statements.pop() {
+            Some(Some(true)) => self.write(")").map(|_| true),
+            Some(Some(false)) => Ok(true),
+            _ => Ok(false),
+        }
+    }
+
+    fn put(&mut self, ch: char) -> fmt::Result {
+        match self.style {
+            Style::Compact => self.sink.write_char(ch),
+            Style::Pretty => {
+                if ch == '\n' {
+                    self.line_start = true;
+                } else if self.line_start {
+                    self.line_start = false;
+                    for _ in 0..self.depth {
+                        self.sink.write_str("    ")?;
+                    }
+                }
+                self.sink.write_char(ch)
+            }
+            Style::Minified => self.put_minified(ch),
+        }
+    }
+
+    // Leaves out the whitespace that JS doesn't need outside of string literals, keeping
+    // only spaces between characters that would otherwise run together into one token.
+    // Semicolons are held back, since they aren't needed before closing braces or at the end.
+    fn put_minified(&mut self, ch: char) -> fmt::Result {
+        if self.string {
+            if self.escaped {
+                self.escaped = false;
+            } else if ch == '\\' {
+                self.escaped = true;
+            } else if ch == '"' {
+                self.string = false;
+            }
+            return self.sink.write_char(ch);
+        }
+        if ch.is_whitespace() {
+            self.space = true;
+            return Ok(());
+        }
+
+        if self.semicolon {
+            self.semicolon = false;
+            if ch != '}' {
+                self.sink.write_char(';')?;
+                self.last = Some(';');
+            }
+        }
+        let joins = |a: char, b: char| {
+            let identifier = |ch: char| ch.is_alphanumeric() || ch == '_' || ch == '$';
+            (identifier(a) && identifier(b)) || (a == b && (a == '+' || a == '-'))
+        };
+        if self.space && self.last.is_some_and(|last| joins(last, ch)) {
+            self.sink.write_char(' ')?;
+        }
+        self.space = false;
+
+        if ch == ';' {
+            self.semicolon = true;
+            return Ok(());
+        }
+        self.last = Some(ch);
+        self.string = ch == '"';
+        self.sink.write_char(ch)
+    }
+}
+
+impl<'w, 'n> fmt::Write for Output<'w, 'n> {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        if text.is_empty() {
+            return Ok(());
+        }
+
+        let mut parenthesized = false;
+        if let Some(statement) = self.statements.last_mut() {
+            if statement.is_none() {
+                parenthesized = text.starts_with("function") || text.starts_with('{');
+                *statement = Some(parenthesized);
+            }
+        }
+        if parenthesized {
+            self.put('(')?;
+        }
+
+        match self.style {
+            Style::Compact => self.sink.write_str(text),
+            _ => text.chars().try_for_each(|ch| self.put(ch)),
+        }
+    }
+}
+
+fn write_error() -> Error {
+    Error {
+        msg: String::from("Couldn't write the output"),
+        span: None,
+        trail: Vec::new(),
+    }
+}
+
+// How a node is described in the trail of a problem it encloses, if it is at all
+fn describe(node: &ASTNode) -> Option<String> {
+    match *node {
+        ASTNode::Function { ref name, .. } => {
+            Some(match (**name).as_ref().and_then(ASTNode::binding) {
+                Some((name, _)) => format!("function {}", name),
+                None => String::from("anonymous function"),
+            })
+        }
+        ASTNode::Invocation { ref func, .. } => Some(match **func {
+            ASTNode::Name(ref name, _) => format!("call to {}", name),
+            ASTNode::Qualified {
+                ref module,
+                ref name,
+                ..
+            } => format!("call to {}.{}", module, name),
+            _ => String::from("call"),
+        }),
+        ASTNode::Conditional { .. } => Some(String::from("conditional")),
+        ASTNode::Binary {
+            op: Token::Operator(ref op),
+            ..
+        } => Some(format!("{} operation", op)),
+        ASTNode::Let { ref name, .. } => name
+            .binding()
+            .map(|(name, _)| format!("let binding of {}", name)),
+        ASTNode::TypeDeclaration { ref name, .. } => Some(format!("type {}", name)),
+        ASTNode::Match { .. } => Some(String::from("match")),
+        ASTNode::Extern { .. } => Some(String::from("extern declaration")),
+        _ => None,
+    }
+}
+
+// Adapts an io::Write to the fmt::Write the emitter writes to, keeping the io error that a
+// fmt::Error can't carry
+struct IoWriter<W: io::Write> {
+    inner: W,
+    error: Option<io::Error>,
+}
+
+impl<W: io::Write> fmt::Write for IoWriter<W> {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        self.inner.write_all(text.as_bytes()).map_err(|err| {
+            self.error = Some(err);
+            fmt::Error
+        })
+    }
+}
+
+impl<W: io::Write> IoWriter<W> {
+    // The result of writing, with the io error in place of the emitter's if there was one
+    fn finish(mut self, result: Result<(), Vec<Error>>) -> Result<(), Vec<Error>> {
+        if let Some(err) = self.error {
+            return Err(vec![io_error(&err)]);
+        }
+        result?;
+        self.inner.flush().map_err(|err| vec![io_error(&err)])
+    }
+}
+
+fn io_error(err: &io::Error) -> Error {
+    Error {
+        msg: format!("Couldn't write the output: {}", err),
+        span: None,
+        trail: Vec::new(),
+    }
+}
+
+// The trampoline runs a function, and then each tail call it returns in turn. Trampolined
+// functions keep their unwrapped versions as $tail for the tail calls to refer to.
+const TRAMPOLINE: &str = "var $Tail = function (f, args) { this.f = f; this.args = args };
+var $trampoline = function (f) { var trampolined = function () { var result = f.apply(null, \
+arguments); while (result instanceof $Tail) { result = result.f.apply(null, result.args) } \
+return result }; trampolined.$tail = f; return trampolined };
+";
+
+// An if without an else evaluates to false
+static FALSE: ASTNode = ASTNode::Boolean(false, Span { line: 0, col: 0 });
+
+pub fn emit(ast: &ASTNode, options: &Options) -> Result<String, Vec<Error>> {
+    let mut program = String::new();
+    emit_to(ast, options, &mut program)?;
+    Ok(program)
+}
+
+// Emits a program without any module system as it goes, rather than building it up first
+pub fn emit_to<W: fmt::Write>(
+    ast: &ASTNode,
+    options: &Options,
+    out: &mut W,
+) -> Result<(), Vec<Error>> {
+    // Without a module format, the top-level names are globals, so only the rest are
+    // shortened
+    let mut globals = Vec::new();
+    collect_definitions(ast, &mut globals);
+    let (scope, taken) = module_scope(&body_exprs(ast), &globals, options);
+    let mut externs = Vec::new();
+    collect_extern_modules(ast, &mut externs);
+
+    let trampolined = if options.trampoline {
+        trampolined_functions(ast)
+    } else {
+        Vec::new()
+    };
+    let context = Context {
+        options,
+        scope: &scope,
+        taken: &taken,
+        externs: &externs,
+        trampolined: &trampolined,
+    };
+
+    let mut out = Output::new(out, options.style);
+    let result = emit_script(ast, &context, &mut out);
+    out.finish(result)
+}
+
+fn emit_script<'n>(ast: &'n ASTNode, context: &Context, out: &mut Output<'_, 'n>) -> fmt::Result {
+    emit_extern_imports(context.externs, context.options, out)?;
+    if !context.trampolined.is_empty() {
+        out.write(TRAMPOLINE)?;
+    }
+
+    if context.pretty() {
+        emit_statements(ast, false, context, out)
+    } else {
+        emit_body(ast, context, out)
+    }
+}
+
+fn emit_node<'n>(ast: &'n ASTNode, context: &Context, out: &mut Output<'_, 'n>) -> fmt::Result {
+    emit_operand(ast, Precedence::Assignment, context, out)
+}
+
+// Emits an expression in a position that needs at least the given precedence. Compact output
+// parenthesizes every operation wherever it is, while pretty output only adds the parentheses
+// that the position needs.
+fn emit_operand<'n>(
+    ast: &'n ASTNode,
+    required: Precedence,
+    context: &Context,
+    out: &mut Output<'_, 'n>,
+) -> fmt::Result {
+    parenthesize(precedence(ast), required, context, out, |out| {
+        emit_expression(ast, context, out)
+    })
+}
+
+fn parenthesize<'n, F>(
+    precedence: Precedence,
+    required: Precedence,
+    context: &Context,
+    out: &mut Output<'_, 'n>,
+    emit: F,
+) -> fmt::Result
+where
+    F: FnOnce(&mut Output<'_, 'n>) -> fmt::Result,
+{
+    let parenthesized = if context.pretty() || context.minified() {
+        precedence < required
+    } else {
+        precedence < Precedence::Unary
+    };
+
+    if parenthesized {
+        out.write("(")?;
+    }
+    emit(out)?;
+    if parenthesized {
+        out.write(")")?;
+    }
+    Ok(())
+}
+
+// The precedence a condition needs to be compared with false. Equality would do, but pretty
+// output keeps the parentheses around a comparison that's compared again.
+fn condition_precedence(context: &Context) -> Precedence {
+    if context.minified() {
+        Precedence::Equality
+    } else {
+        Precedence::Relational
+    }
+}
+
+// The precedence of the expression emit_expression writes for a node, which is known
+// before it's written
+fn precedence(ast: &ASTNode) -> Precedence {
+    match *ast {
+        // A negative number is a negation in JS
+        ASTNode::Integer(val, _) if val < 0 => Precedence::Unary,
+        ASTNode::Float(val, _) if val.is_sign_negative() => Precedence::Unary,
+        ASTNode::Conditional { .. } => Precedence::Conditional,
+        ASTNode::Binary {
+            op: Token::Operator(ref op),
+            ..
+        } => match op.as_str() {
+            "&&" => Precedence::Conditional,
+            "||" => Precedence::Call,
+            op => Precedence::of_operator(op),
+        },
+        // A sequence is a comma expression
+        ASTNode::Sequence(ref exprs) => match exprs.len() {
+            0 => Precedence::Call,
+            1 => precedence(&exprs[0]),
+            _ => Precedence::Comma,
+        },
+        ASTNode::Let { .. } => Precedence::Assignment,
+        ASTNode::Annotated { ref expr, .. } | ASTNode::Export { decl: ref expr, .. } => {
+            precedence(expr)
+        }
+        ASTNode::TypeDeclaration { .. } => Precedence::Comma,
+        ASTNode::Extern {
+            ref module,
+            ref functions,
+            ..
+        } => {
+            if functions
+                .iter()
+                .any(|function| extern_is_bound(module, function))
+            {
+                Precedence::Comma
+            } else {
+                Precedence::Call
+            }
+        }
+        _ => Precedence::Call,
+    }
+}
+
+// Emits an expression without parentheses of its own
+fn emit_expression<'n>(
+    ast: &'n ASTNode,
+    context: &Context,
+    out: &mut Output<'_, 'n>,
+) -> fmt::Result {
+    out.enter(ast);
+    let result = match *ast {
+        ASTNode::Integer(val, _) => write!(out, "{}", val),
+        ASTNode::Float(val, _) => write!(out, "{}", val),
+        ASTNode::StringLiteral(ref val, _) => write!(out, "\"{}\"", val),
+        ASTNode::Boolean(val, _) => write!(out, "{}", val),
+        ASTNode::Name(ref val, _) => context.name(val, out),
+        ASTNode::Function {
+            ref name,
+            ref args,
+            ref body,
+            ..
+        } => emit_function((**name).as_ref(), args, body, context, out),
+        ASTNode::Invocation {
+            ref func, ref args, ..
+        } => emit_invocation(func, args, context, out),
         ASTNode::Conditional {
-            cond,
-            if_body,
-            else_body,
-        } => emit_conditional(cond, if_body, else_body),
-        ASTNode::Binary { op, lhs, rhs } => emit_binary(op, lhs, rhs),
-        ASTNode::Sequence(vec) => emit_sequence(vec),
+            ref cond,
+            ref if_body,
+            ref else_body,
+            ..
+        } => emit_conditional(cond, if_body, (**else_body).as_ref(), context, out),
+        ASTNode::Binary {
+            ref op,
+            ref lhs,
+            ref rhs,
+            span,
+        } => emit_binary(op, lhs, rhs, span, context, out),
+        ASTNode::Sequence(ref exprs) => match exprs.len() {
+            0 => Ok(()),
+            1 => emit_expression(&exprs[0], context, out),
+            _ => emit_sequence(exprs, context, out),
+        },
+        ASTNode::List(ref elements, _) => {
+            out.write("[")?;
+            emit_list(elements, context, out)?;
+            out.write("]")
+        }
+        ASTNode::Let {
+            ref name,
+            ref value,
+            ..
+        } => {
+            emit_node(name, context, out)?;
+            out.write(" = ")?;
+            emit_node(value, context, out)
+        }
+        // Annotations are erased
+        ASTNode::Annotated { ref expr, .. } => emit_expression(expr, context, out),
+        // Like an empty sequence, a declaration evaluates to false
+        ASTNode::TypeDeclaration { ref variants, .. } => {
+            for variant in variants.iter() {
+                emit_variant(variant, context, out)?;
+                out.write(", ")?;
+            }
+            out.write("false")
+        }
+        ASTNode::Match {
+            ref subject,
+            ref arms,
+            ..
+        } => emit_match(subject, arms, context, out),
+        // Imported modules are bound to their aliases before the importing module runs, so
+        // an import is left out of its sequence. Like a declaration, it evaluates to false.
+        ASTNode::Import { .. } => out.write("false"),
+        ASTNode::Export { ref decl, .. } => emit_expression(decl, context, out),
+        ASTNode::Qualified {
+            ref module,
+            ref name,
+            ..
+        } => {
+            out.name(module)?;
+            out.write(".")?;
+            out.name(name)
+        }
+        // Like a type declaration, an extern evaluates to false
+        ASTNode::Extern {
+            ref module,
+            ref functions,
+            span,
+        } => {
+            if check_extern_module(module, span, context, out) {
+                for function in functions.iter() {
+                    if check_extern(module, function, out) && extern_is_bound(module, function) {
+                        emit_extern_binding(module, function, context, out)?;
+                        out.write(", ")?;
+                    }
+                }
+            }
+            out.write("false")
+        }
+    };
+    out.leave();
+    result
+}
+
+// Externs must be at the top level, so only the top-level sequence is searched
+fn collect_extern_modules(ast: &ASTNode, externs: &mut Vec<String>) {
+    if let ASTNode::Sequence(ref exprs) = *ast {
+        for expr in exprs.iter() {
+            if let ASTNode::Extern {
+                module: Some(ref module),
+                ..
+            } = *expr
+            {
+                if !externs.contains(module) {
+                    externs.push(module.clone());
+                }
+            }
+        }
+    }
+}
+
+// ES modules import the modules used by externs, while other formats require them
+fn emit_extern_imports(externs: &[String], options: &Options, out: &mut Output) -> fmt::Result {
+    for (i, module) in externs.iter().enumerate() {
+        if options.module == ModuleFormat::Esm {
+            write!(out, "import * as $extern{} from ", i)?;
+            out.quoted(module)?;
+            out.write(";\n")?;
+        } else {
+            write!(out, "var $extern{} = require(", i)?;
+            out.quoted(module)?;
+            out.write(");\n")?;
+        }
     }
+
+    Ok(())
+}
+
+// Whether an extern has to be bound to a name, rather than referring directly to a global
+// of the same name
+fn extern_is_bound(module: &Option<String>, function: &ExternFunction) -> bool {
+    module.is_some()
+        || function
+            .js_name
+            .as_ref()
+            .is_some_and(|js_name| *js_name != mangle(&function.name))
 }
 
-fn emit_function(
-    name: Box<Option<ASTNode>>,
-    args: Vec<ASTNode>,
-    body: Box<ASTNode>,
-) -> Result<String, Error> {
-    let mut function = String::from("function ");
-    if let Some(ASTNode::Name(ref name_str)) = *name {
-        function.push_str(name_str);
+// The JS expression an extern is bound to. A global's JS name is used verbatim, so it can
+// be a path like `console.log`.
+fn emit_extern_target(
+    module: &Option<String>,
+    function: &ExternFunction,
+    context: &Context,
+    out: &mut Output,
+) -> fmt::Result {
+    let js_name = function.js_name.as_ref().unwrap_or(&function.name);
+
+    match *module {
+        Some(ref module) => {
+            let index = context
+                .externs
+                .iter()
+                .position(|extern_module| extern_module == module)
+                .unwrap_or_default();
+            write!(out, "$extern{}[", index)?;
+            out.quoted(js_name)?;
+            out.write("]")
+        }
+        None => out.write(js_name),
     }
-    function.push('(');
-    function.push_str(emit_map_helper(args, String::from(","))?.as_str());
-    function.push_str(") { return (");
-    function.push_str(emit(*body)?.as_str());
-    function.push_str(") }");
+}
 
-    Ok(function)
+// Bundles a program's modules into a single script, in which each imported module is
+// wrapped in a function that declares its top-level names and returns its exports. The
+// program itself is packaged according to the module format. The parts of the prelude
+// that the program uses come first, wrapped in the same way.
+pub fn emit_program(
+    modules: &[Module],
+    prelude: &Module,
+    options: &Options,
+) -> Result<String, Vec<Error>> {
+    let mut program = String::new();
+    emit_program_to(modules, prelude, options, &mut program)?;
+    Ok(program)
 }
 
-fn emit_invocation(func: Box<ASTNode>, args: Vec<ASTNode>) -> Result<String, Error> {
-    let mut invocation = String::new();
+// Like emit_program, but writes the program to an io::Write as it goes
+pub fn write_program<W: io::Write>(
+    modules: &[Module],
+    prelude: &Module,
+    options: &Options,
+    out: W,
+) -> Result<(), Vec<Error>> {
+    let mut writer = IoWriter {
+        inner: out,
+        error: None,
+    };
+    let result = emit_program_to(modules, prelude, options, &mut writer);
+    writer.finish(result)
+}
 
-    invocation.push_str(emit(*func)?.as_str());
-    invocation.push('(');
-    invocation.push_str(emit_map_helper(args, String::from(","))?.as_str());
-    invocation.push(')');
+// Like emit_program, but writes the program to a fmt::Write as it goes
+pub fn emit_program_to<W: fmt::Write>(
+    modules: &[Module],
+    prelude: &Module,
+    options: &Options,
+    out: &mut W,
+) -> Result<(), Vec<Error>> {
+    let uses: Vec<Vec<String>> = modules
+        .iter()
+        .map(|module| prelude_uses(module, &prelude.exports))
+        .collect();
+    let mut used: Vec<String> = Vec::new();
+    for name in uses.iter().flatten() {
+        if !used.contains(name) {
+            used.push(name.clone());
+        }
+    }
+    let prelude = if used.is_empty() {
+        None
+    } else {
+        Some(prelude_body(prelude, &used))
+    };
 
-    Ok(invocation)
+    let mut out = Output::new(out, options.style);
+    let result = emit_bundle(modules, prelude.as_deref(), &uses, options, &mut out);
+    out.finish(result)
 }
 
-// Maps emit over a vector of nodes, joining with the delimiter as a separator
-fn emit_map_helper(nodes: Vec<ASTNode>, delimiter: String) -> Result<String, Error> {
-    let mut err = false;
-    let name_vec: Vec<String> = nodes
+fn emit_bundle<'n>(
+    modules: &'n [Module],
+    prelude: Option<&[&'n ASTNode]>,
+    uses: &[Vec<String>],
+    options: &Options,
+    out: &mut Output<'_, 'n>,
+) -> fmt::Result {
+    let mut externs = Vec::new();
+    for module in modules.iter() {
+        collect_extern_modules(&module.ast, &mut externs);
+    }
+    let trampolined: Vec<Vec<String>> = modules
         .iter()
-        .map(|node| {
-            if let Ok(res) = emit(node.clone()) {
-                return res;
+        .map(|module| {
+            if options.trampoline {
+                trampolined_functions(&module.ast)
             } else {
-                err = true;
-                return String::new();
+                Vec::new()
             }
         })
         .collect();
 
-    if err {
-        return Err(Error {
-            msg: String::from("Emit failed for a node in the list"),
-        });
+    // An iife declares everything it bundles inside its function, so that none of it leaks
+    // into the global scope. It can't import JS modules, so it has no extern imports.
+    let iife = options.module == ModuleFormat::Iife;
+    if iife {
+        out.write("(function ()")?;
+        out.open_block()?;
+    } else {
+        emit_extern_imports(&externs, options, out)?;
+    }
+    if trampolined
+        .iter()
+        .any(|trampolined| !trampolined.is_empty())
+    {
+        out.write(TRAMPOLINE)?;
+    }
+    if let Some(prelude) = prelude {
+        emit_prelude(prelude, options, out)?;
+    }
+    let root = modules.len() - 1;
+
+    for ((index, module), trampolined) in modules.iter().enumerate().zip(trampolined.iter()) {
+        let ast = &module.ast;
+        let mut declarations: Vec<String> = module
+            .imports
+            .iter()
+            .map(|&(ref alias, import)| format!("{} = $module{}", mangle(alias), import))
+            .collect();
+        for name in uses[index].iter() {
+            declarations.push(format!("{0} = $prelude.{0}", mangle(name)));
+        }
+
+        let exprs = body_exprs(ast);
+        let (scope, taken) = module_scope(&exprs, &kept_names(modules, index, options), options);
+        let context = Context {
+            options,
+            scope: &scope,
+            taken: &taken,
+            externs: &externs,
+            trampolined,
+        };
+
+        if index == root && options.module == ModuleFormat::None {
+            if !declarations.is_empty() {
+                writeln!(out, "var {};", declarations.join(", "))?;
+            }
+            return emit_module_body(ast, false, &context, out);
+        }
+
+        for (name, emitted) in scope.names.iter() {
+            if !module.imports.iter().any(|(alias, _)| alias == name) {
+                declarations.push(emitted.clone());
+            }
+        }
+
+        let mut exports: Vec<String> = module.exports.iter().map(|name| mangle(name)).collect();
+        if index == root {
+            if let ASTNode::Sequence(ref exprs) = *ast {
+                for (name, _) in exprs.iter().filter_map(ASTNode::function_name) {
+                    if !exports.contains(&mangle(name)) {
+                        exports.push(mangle(name));
+                    }
+                }
+            }
+        }
+
+        if index < root {
+            write!(out, "var $module{} = ", index)?;
+            emit_module_function(&declarations, &exprs, &exports, &context, out)?;
+            out.write(";\n")?;
+            continue;
+        }
+
+        match options.module {
+            ModuleFormat::Iife => {
+                emit_declarations(&declarations, out)?;
+                if context.pretty() {
+                    emit_module_body(ast, true, &context, out)?;
+                } else {
+                    returned(&context, out, |out| {
+                        emit_module_body(ast, false, &context, out)
+                    })?;
+                    out.end_last_statement()?;
+                }
+            }
+            _ => {
+                if !declarations.is_empty() {
+                    writeln!(out, "var {};", declarations.join(", "))?;
+                }
+                if context.pretty() {
+                    emit_module_body(ast, false, &context, out)?;
+                } else if !emits_nothing(ast) {
+                    emit_module_body(ast, false, &context, out)?;
+                    out.write(";\n")?;
+                }
+
+                if options.module == ModuleFormat::Esm {
+                    writeln!(out, "export {{ {} }};", exports.join(", "))?;
+                } else {
+                    out.write("module.exports = {")?;
+                    emit_exports(&exports, out)?;
+                    out.write("};\n")?;
+                }
+            }
+        }
     }
 
-    Ok(name_vec.join(delimiter.as_str()))
+    if iife {
+        out.close_block()?;
+        out.write(")();\n")?;
+    }
+    Ok(())
 }
 
-// Because conditional is an expression, it is equivalent to JS ternary
-fn emit_conditional(
-    cond: Box<ASTNode>,
-    if_body: Box<ASTNode>,
-    else_body: Box<Option<ASTNode>>,
-) -> Result<String, Error> {
-    let mut conditional = String::from("(");
+// The names in a module that minified output doesn't shorten because they're visible
+// outside it: those it exports, and if it's the program itself, its top-level names when
+// they're globals or exported
+fn kept_names(modules: &[Module], index: usize, options: &Options) -> Vec<String> {
+    let module = &modules[index];
+    let mut kept = module.exports.clone();
+    if index == modules.len() - 1 {
+        match options.module {
+            ModuleFormat::None => collect_definitions(&module.ast, &mut kept),
+            ModuleFormat::Esm | ModuleFormat::Cjs => {
+                if let ASTNode::Sequence(ref exprs) = module.ast {
+                    for (name, _) in exprs.iter().filter_map(ASTNode::function_name) {
+                        kept.push(String::from(name));
+                    }
+                }
+            }
+            ModuleFormat::Iife => {}
+        }
+    }
+    kept
+}
 
-    conditional.push_str(emit(*cond)?.as_str());
+// A function that declares a module's top-level names, evaluates its body and returns its
+// exports
+fn emit_module_function<'n>(
+    declarations: &[String],
+    exprs: &[&'n ASTNode],
+    exports: &[String],
+    context: &Context,
+    out: &mut Output<'_, 'n>,
+) -> fmt::Result {
+    out.write("(function ()")?;
+    out.open_block()?;
+    emit_declarations(declarations, out)?;
 
-    // Only false is falsey
-    conditional.push_str("!== false ? ");
+    if context.pretty() {
+        for expr in exprs.iter() {
+            emit_statement(expr, context, out)?;
+        }
+        out.write("return {")?;
+        emit_exports(exports, out)?;
+        out.write("}")?;
+        out.end_statement()?;
+    } else {
+        returned(context, out, |out| {
+            if !exprs.iter().all(|expr| emits_nothing(expr)) {
+                emit_sequence(exprs.iter().cloned(), context, out)?;
+                out.write(", ")?;
+            }
+            out.write("{")?;
+            emit_exports(exports, out)?;
+            out.write("}")
+        })?;
+        out.end_last_statement()?;
+    }
 
-    conditional.push_str(emit(*if_body)?.as_str());
+    out.close_block()?;
+    out.write(")()")
+}
+
+fn emit_declarations(declarations: &[String], out: &mut Output) -> fmt::Result {
+    if declarations.is_empty() {
+        return Ok(());
+    }
+    write!(out, "var {}", declarations.join(", "))?;
+    out.end_statement()
+}
 
-    conditional.push_str(" : ");
+// The properties of an exports object, each named after the name it holds
+fn emit_exports(exports: &[String], out: &mut Output) -> fmt::Result {
+    for (i, name) in exports.iter().enumerate() {
+        if i > 0 {
+            out.write(", ")?;
+        }
+        write!(out, "{0}: {0}", name)?;
+    }
+    Ok(())
+}
 
-    if let Some(node) = *else_body {
-        conditional.push_str(emit(node)?.as_str());
+// Emits the body of a program or module, which is a comma expression in compact output and
+// a statement per line in pretty output
+fn emit_module_body<'n>(
+    ast: &'n ASTNode,
+    returned: bool,
+    context: &Context,
+    out: &mut Output<'_, 'n>,
+) -> fmt::Result {
+    if context.pretty() {
+        emit_statements(ast, returned, context, out)
     } else {
-        conditional.push_str(emit(ASTNode::Boolean(false))?.as_str());
+        emit_body(ast, context, out)
+    }
+}
+
+// Whether a node emits no code at all, as an empty sequence doesn't
+fn emits_nothing(ast: &ASTNode) -> bool {
+    match *ast {
+        ASTNode::Sequence(ref exprs) => {
+            exprs.is_empty() || (exprs.len() == 1 && emits_nothing(&exprs[0]))
+        }
+        ASTNode::Annotated { ref expr, .. } | ASTNode::Export { decl: ref expr, .. } => {
+            emits_nothing(expr)
+        }
+        _ => false,
+    }
+}
+
+// The prelude's exports that a module refers to without defining them itself. References
+// are found without regard to scope, so a local sharing a prelude name may include it
+// needlessly, but never wrongly.
+pub fn prelude_uses(module: &Module, exports: &[String]) -> Vec<String> {
+    let mut defined = Vec::new();
+    collect_definitions(&module.ast, &mut defined);
+    if let ASTNode::Sequence(ref exprs) = module.ast {
+        for expr in exprs.iter() {
+            defined.extend(expr.declared_names().into_iter().map(String::from));
+        }
     }
+    defined.extend(module.imports.iter().map(|(alias, _)| alias.clone()));
 
-    conditional.push(')');
+    let mut references = Vec::new();
+    collect_references(&module.ast, &mut references);
+    references.retain(|name| exports.contains(name) && !defined.contains(name));
+    references
+}
+
+// The parts of the prelude that the program uses, with the declarations they depend on
+fn prelude_body<'n>(prelude: &'n Module, used: &[String]) -> Vec<&'n ASTNode> {
+    let exprs: &[ASTNode] = match prelude.ast {
+        ASTNode::Sequence(ref exprs) => exprs,
+        _ => &[],
+    };
+
+    let mut needed = used.to_vec();
+    let mut included = vec![false; exprs.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (i, expr) in exprs.iter().enumerate() {
+            if !included[i]
+                && expr
+                    .declared_names()
+                    .iter()
+                    .any(|name| needed.iter().any(|n| n == name))
+            {
+                included[i] = true;
+                changed = true;
+                collect_references(expr, &mut needed);
+            }
+        }
+    }
 
-    Ok(conditional)
+    exprs
+        .iter()
+        .zip(included)
+        .filter_map(|(expr, included)| if included { Some(expr) } else { None })
+        .collect()
 }
 
-fn emit_binary(op: Token, lhs: Box<ASTNode>, rhs: Box<ASTNode>) -> Result<String, Error> {
-    if let Token::Operator(op) = op {
-        return Ok(format!("({} {} {})", emit(*lhs)?, op, emit(*rhs)?));
+// Emits the shims and declarations of the parts of the prelude a program uses as `$prelude`
+fn emit_prelude<'n>(
+    exprs: &[&'n ASTNode],
+    options: &Options,
+    out: &mut Output<'_, 'n>,
+) -> fmt::Result {
+    let mut shims: Vec<&str> = Vec::new();
+    let mut exported = Vec::new();
+    for expr in exprs.iter() {
+        if let ASTNode::Extern { ref functions, .. } = *expr.declaration() {
+            for js_name in functions
+                .iter()
+                .filter_map(|function| function.js_name.as_ref())
+            {
+                if let Some(shim) = prelude::shim(js_name) {
+                    if !shims.contains(&js_name.as_str()) {
+                        shims.push(js_name);
+                        writeln!(out, "var {} = {};", js_name, shim)?;
+                    }
+                }
+            }
+        }
+        exported.extend(
+            expr.exported_names()
+                .into_iter()
+                .map(|(name, _)| String::from(name)),
+        );
     }
 
-    Err(Error {
-        msg: String::from("Malformed binary node"),
-    })
+    let (scope, taken) = module_scope(exprs, &exported, options);
+    let declarations: Vec<String> = scope
+        .names
+        .iter()
+        .map(|(_, emitted)| emitted.clone())
+        .collect();
+    let exports: Vec<String> = exported.iter().map(|name| mangle(name)).collect();
+    let context = Context {
+        options,
+        scope: &scope,
+        taken: &taken,
+        externs: &[],
+        // The prelude's functions don't call each other in tail position
+        trampolined: &[],
+    };
+
+    out.write("var $prelude = ")?;
+    emit_module_function(&declarations, exprs, &exports, &context, out)?;
+    out.write(";\n")
+}
+
+// Collects the names a node refers to
+fn collect_references(node: &ASTNode, names: &mut Vec<String>) {
+    node.walk(&mut |node| {
+        if let ASTNode::Name(ref name, _) = *node {
+            if !names.contains(name) {
+                names.push(name.clone());
+            }
+        }
+    });
+}
+
+// Collects the names a module defines outside of any function: declared functions and
+// constructors, let bindings, and assignments (which define a name if it's unbound)
+pub fn collect_definitions(node: &ASTNode, names: &mut Vec<String>) {
+    collect_locals(node, &|_| false, names)
+}
+
+// Collects the names local to a function body. A let or declaration always binds a new
+// name, while an assignment only does if the name isn't bound outside the function.
+pub fn collect_locals(node: &ASTNode, is_outer: &dyn Fn(&str) -> bool, names: &mut Vec<String>) {
+    let define = |name: &str, names: &mut Vec<String>| {
+        if !names.iter().any(|defined| defined == name) {
+            names.push(String::from(name));
+        }
+    };
+
+    match *node {
+        ASTNode::Sequence(ref exprs) => {
+            for expr in exprs.iter() {
+                if let Some((name, _)) = expr.function_name() {
+                    define(name, names);
+                }
+                collect_locals(expr, is_outer, names);
+            }
+        }
+        ASTNode::Let {
+            ref name,
+            ref value,
+            ..
+        } => {
+            if let Some((name, _)) = name.binding() {
+                define(name, names);
+            }
+            collect_locals(value, is_outer, names);
+        }
+        ASTNode::TypeDeclaration { ref variants, .. } => {
+            for variant in variants.iter() {
+                define(&variant.name, names);
+            }
+        }
+        ASTNode::Binary {
+            ref op,
+            ref lhs,
+            ref rhs,
+            ..
+        } => {
+            match **lhs {
+                ASTNode::Name(ref name, _) if *op == Token::Operator(String::from("=")) => {
+                    if !is_outer(name) {
+                        define(name, names)
+                    }
+                }
+                _ => collect_locals(lhs, is_outer, names),
+            }
+            collect_locals(rhs, is_outer, names);
+        }
+        ASTNode::Invocation {
+            ref func, ref args, ..
+        } => {
+            collect_locals(func, is_outer, names);
+            for arg in args.iter() {
+                collect_locals(arg, is_outer, names);
+            }
+        }
+        ASTNode::Conditional {
+            ref cond,
+            ref if_body,
+            ref else_body,
+            ..
+        } => {
+            collect_locals(cond, is_outer, names);
+            collect_locals(if_body, is_outer, names);
+            if let Some(ref else_body) = **else_body {
+                collect_locals(else_body, is_outer, names);
+            }
+        }
+        ASTNode::List(ref elements, _) => {
+            for element in elements.iter() {
+                collect_locals(element, is_outer, names);
+            }
+        }
+        ASTNode::Annotated { ref expr, .. } => collect_locals(expr, is_outer, names),
+        ASTNode::Export { ref decl, .. } => collect_locals(decl, is_outer, names),
+        ASTNode::Extern {
+            ref module,
+            ref functions,
+            ..
+        } => {
+            for function in functions.iter() {
+                if extern_is_bound(module, function) {
+                    define(&function.name, names);
+                }
+            }
+        }
+        // Assignments in an arm are declared with the enclosing function's, since the arm
+        // doesn't introduce a JS function scope of its own
+        ASTNode::Match {
+            ref subject,
+            ref arms,
+            ..
+        } => {
+            collect_locals(subject, is_outer, names);
+            for arm in arms.iter() {
+                collect_locals(&arm.body, is_outer, names);
+            }
+        }
+        _ => {}
+    }
+}
+
+// The expressions in the body of a program or module
+fn body_exprs(ast: &ASTNode) -> Vec<&ASTNode> {
+    match *ast {
+        ASTNode::Sequence(ref exprs) => exprs.iter().collect(),
+        _ => vec![ast],
+    }
 }
 
-fn emit_sequence(exprs: Vec<ASTNode>) -> Result<String, Error> {
-    Ok(emit_map_helper(exprs, String::from(","))?)
+// The scope of a module's top-level names, and the names that shortened names in the module
+// mustn't collide with. Minified output shortens the names the module binds, except for the
+// top-level names in kept, externs, which may refer to JS globals by their own names, and
+// constructors, whose names are also their tags.
+fn module_scope(
+    exprs: &[&ASTNode],
+    kept: &[String],
+    options: &Options,
+) -> (Scope<'static>, HashSet<String>) {
+    let mut definitions = Vec::new();
+    for expr in exprs.iter() {
+        if let Some((name, _)) = expr.function_name() {
+            if !definitions.iter().any(|definition| definition == name) {
+                definitions.push(String::from(name));
+            }
+        }
+        collect_definitions(expr, &mut definitions);
+    }
+
+    let mut scope = Scope {
+        names: Vec::new(),
+        parent: None,
+        next: 0,
+    };
+    if options.style != Style::Minified {
+        for definition in definitions.iter() {
+            scope.keep(definition, mangle(definition));
+        }
+        return (scope, HashSet::new());
+    }
+
+    let mut taken: HashSet<String> = JS_RESERVED.iter().map(|word| String::from(*word)).collect();
+    let mut fixed = Vec::new();
+    for expr in exprs.iter() {
+        expr.walk(&mut |node| match *node {
+            ASTNode::Name(ref name, _)
+            | ASTNode::Import {
+                alias: ref name, ..
+            } => {
+                taken.insert(mangle(name));
+            }
+            ASTNode::Qualified { ref module, .. } => {
+                taken.insert(mangle(module));
+            }
+            ASTNode::Extern { ref functions, .. } => {
+                for function in functions.iter() {
+                    taken.insert(mangle(&function.name));
+                    if let Some(ref js_name) = function.js_name {
+                        taken.extend(js_name.split('.').next().map(String::from));
+                    }
+                }
+            }
+            ASTNode::TypeDeclaration { ref variants, .. } => {
+                taken.extend(variants.iter().map(|variant| mangle(&variant.name)));
+            }
+            _ => {}
+        });
+
+        match *expr.declaration() {
+            ASTNode::TypeDeclaration { .. } | ASTNode::Extern { .. } => {
+                collect_definitions(expr, &mut fixed)
+            }
+            _ => {}
+        }
+    }
+    fixed.extend(kept.iter().cloned());
+    taken.extend(fixed.iter().map(|name| mangle(name)));
+
+    for definition in definitions.iter() {
+        let emitted = if fixed.contains(definition) {
+            mangle(definition)
+        } else {
+            scope.fresh(&taken)
+        };
+        scope.keep(definition, emitted);
+    }
+    (scope, taken)
+}
+
+// Words that can't be used as JS binding names (including strict mode restrictions)
+const JS_RESERVED: &[&str] = &[
+    "arguments", "await", "break", "case", "catch", "class", "const", "continue", "debugger",
+    "default", "delete", "do", "else", "enum", "eval", "export", "extends", "false", "finally",
+    "for", "function", "if", "implements", "import", "in", "instanceof", "interface", "let",
+    "new", "null", "package", "private", "protected", "public", "return", "static", "super",
+    "switch", "this", "throw", "true", "try", "typeof", "undefined", "var", "void", "while",
+    "with", "yield",
+];
+
+// Silver identifiers may contain characters that JS identifiers can't. Since `$` never
+// appears in a Silver identifier, it's used as an escape: each special character becomes
+// `$` followed by a letter, and names colliding with JS reserved words get a trailing `$`.
+// Every `$` in the output is therefore followed by an escape letter or ends the name, so
+// the scheme is reversible.
+pub fn mangle(name: &str) -> String {
+    let mut mangled = String::with_capacity(name.len());
+    // Writing to a string can't fail
+    let _ = write_mangled(name, &mut mangled);
+    mangled
+}
+
+fn write_mangled<W: fmt::Write + ?Sized>(name: &str, out: &mut W) -> fmt::Result {
+    for ch in name.chars() {
+        match ch {
+            '?' => out.write_str("$q")?,
+            '!' => out.write_str("$b")?,
+            '-' => out.write_str("$d")?,
+            '<' => out.write_str("$l")?,
+            '>' => out.write_str("$g")?,
+            '=' => out.write_str("$e")?,
+            _ => out.write_char(ch)?,
+        }
+    }
+
+    // Reserved words have no special characters, so they're unchanged up to here
+    if JS_RESERVED.contains(&name) {
+        out.write_char('$')?;
+    }
+
+    Ok(())
+}
+
+fn emit_function<'n>(
+    name: Option<&'n ASTNode>,
+    args: &'n [ASTNode],
+    body: &'n ASTNode,
+    context: &Context,
+    out: &mut Output<'_, 'n>,
+) -> fmt::Result {
+    let name = match name {
+        Some(ASTNode::Name(name, _)) => Some(name.as_str()),
+        _ => None,
+    };
+    let trampolined = name.is_some_and(|name| {
+        context
+            .trampolined
+            .iter()
+            .any(|trampolined| trampolined == name)
+    });
+
+    // Everything the body declares is local to the function, as is everything it assigns
+    // to other than parameters and names from enclosing scopes
+    let mut scope = context.nested();
+    if let Some(name) = name {
+        scope.keep(name, context.emitted(name));
+    }
+    for arg in args.iter() {
+        if let Some((arg, _)) = arg.binding() {
+            scope.bind(arg, context);
+        }
+    }
+    let mut locals = Vec::new();
+    collect_locals(body, &|name| scope.contains(name), &mut locals);
+    for local in locals.iter() {
+        scope.bind(local, context);
+    }
+    let inner = Context {
+        trampolined: &[],
+        ..context.with_scope(&scope)
+    };
+
+    // Tail calls only need handling if the function calls itself or is trampolined
+    let mut calls = Vec::new();
+    collect_tail_calls(body, &mut calls);
+    let recursive = match name {
+        Some(name) if calls.iter().any(|call| call == name) && can_loop(name, args, body) => {
+            Some((
+                String::from(name),
+                args.iter()
+                    .filter_map(|arg| arg.binding().map(|(arg, _)| String::from(arg)))
+                    .collect::<Vec<String>>(),
+            ))
+        }
+        _ => None,
+    };
+    let tail = if recursive.is_some() || trampolined {
+        let mut bounce = context.trampolined.to_vec();
+        bounce.retain(|name| {
+            !locals.contains(name)
+                && !args
+                    .iter()
+                    .any(|arg| arg.binding().map(|(arg, _)| arg) == Some(name.as_str()))
+        });
+        if !trampolined {
+            bounce.clear();
+        }
+        Some(Tail { recursive, bounce })
+    } else {
+        None
+    };
+
+    let mut locals: Vec<String> = locals.iter().map(|local| inner.emitted(local)).collect();
+    if let Some(Tail {
+        recursive: Some((_, ref params)),
+        ..
+    }) = tail
+    {
+        if params.len() > 1 {
+            locals.extend((0..params.len()).map(|i| format!("$next{}", i)));
+        }
+    }
+
+    // A trampolined function's own name refers to its wrapper, so the function itself is
+    // anonymous
+    if trampolined {
+        out.write("$trampoline(")?;
+    }
+    out.write("function ")?;
+    if let Some(name) = name {
+        if !trampolined {
+            inner.name(name, out)?;
+        }
+    }
+    out.write("(")?;
+    emit_list(args, &inner, out)?;
+    out.write(")")?;
+
+    out.open_block()?;
+    emit_declarations(&locals, out)?;
+    if context.options.arity_guards {
+        emit_arity_guard(args.len(), name.unwrap_or("anonymous function"), out)?;
+        out.end_line()?;
+    }
+    match tail {
+        Some(ref tail) if tail.recursive.is_some() => {
+            out.write("while (true)")?;
+            out.open_block()?;
+            emit_tail(body, tail, &inner, out)?;
+            out.close_block()?;
+            out.end_line()?;
+        }
+        Some(ref tail) => emit_tail(body, tail, &inner, out)?,
+        None => emit_return(body, &inner, out)?,
+    }
+    out.close_block()?;
+
+    if trampolined {
+        out.write(")")?;
+    }
+    Ok(())
+}
+
+// How a function's calls in tail position are emitted, so that they don't grow the stack
+#[derive(Clone)]
+struct Tail {
+    // The function's name and parameters, if calls to itself become a loop
+    recursive: Option<(String, Vec<String>)>,
+    // The trampolined functions that tail calls return to the trampoline instead of calling,
+    // if this function is itself trampolined
+    bounce: Vec<String>,
+}
+
+// Calls to the function itself can become a loop unless something in its body rebinds its
+// name, or a closure might capture a parameter that the loop reassigns
+fn can_loop(name: &str, args: &[ASTNode], body: &ASTNode) -> bool {
+    let mut definitions = Vec::new();
+    collect_definitions(body, &mut definitions);
+    if definitions.iter().any(|definition| definition == name) {
+        return false;
+    }
+
+    let mut can_loop = args.iter().all(|arg| match arg.binding() {
+        Some((arg, _)) => arg != name,
+        None => false,
+    });
+    body.walk(&mut |node| match *node {
+        ASTNode::Function { .. } => can_loop = false,
+        ASTNode::Match { ref arms, .. } => {
+            for arm in arms.iter() {
+                if let Pattern::Constructor { ref bindings, .. } = arm.pattern {
+                    if bindings
+                        .iter()
+                        .any(|binding| binding.binding().map(|(binding, _)| binding) == Some(name))
+                    {
+                        can_loop = false;
+                    }
+                }
+            }
+        }
+        _ => {}
+    });
+    can_loop
+}
+
+// Collects the names of the functions a node calls in tail position: as the node itself, or
+// in the tail position of a conditional's branches, a sequence's last expression, the rhs of
+// && or || or a match arm's body
+fn collect_tail_calls(node: &ASTNode, names: &mut Vec<String>) {
+    match *node {
+        ASTNode::Invocation { ref func, .. } => {
+            if let ASTNode::Name(ref name, _) = **func {
+                if !names.contains(name) {
+                    names.push(name.clone());
+                }
+            }
+        }
+        ASTNode::Conditional {
+            ref if_body,
+            ref else_body,
+            ..
+        } => {
+            collect_tail_calls(if_body, names);
+            if let Some(ref else_body) = **else_body {
+                collect_tail_calls(else_body, names);
+            }
+        }
+        ASTNode::Sequence(ref exprs) => {
+            if let Some(last) = exprs.last() {
+                collect_tail_calls(last, names);
+            }
+        }
+        ASTNode::Binary {
+            op: Token::Operator(ref op),
+            ref rhs,
+            ..
+        } if op == "&&" || op == "||" => collect_tail_calls(rhs, names),
+        ASTNode::Annotated { ref expr, .. } => collect_tail_calls(expr, names),
+        ASTNode::Match { ref arms, .. } => {
+            for arm in arms.iter() {
+                collect_tail_calls(&arm.body, names);
+            }
+        }
+        _ => {}
+    }
+}
+
+// The top-level functions that make tail calls through a trampoline: those making or
+// receiving tail calls between different top-level functions. Functions that are reassigned
+// are left out, since their names might not refer to the trampolined function.
+fn trampolined_functions(ast: &ASTNode) -> Vec<String> {
+    let exprs = match *ast {
+        ASTNode::Sequence(ref exprs) => exprs,
+        _ => return Vec::new(),
+    };
+
+    let mut assigned = Vec::new();
+    ast.walk(&mut |node| {
+        if let ASTNode::Binary {
+            op: Token::Operator(ref op),
+            ref lhs,
+            ..
+        } = *node
+        {
+            if let Some((name, _)) = lhs.binding() {
+                if op == "=" {
+                    assigned.push(name);
+                }
+            }
+        }
+    });
+
+    let mut functions = Vec::new();
+    for expr in exprs.iter() {
+        if let Some((name, _)) = expr.function_name() {
+            if let ASTNode::Function { ref body, .. } = *expr.declaration() {
+                if !assigned.contains(&name) {
+                    let mut calls = Vec::new();
+                    collect_tail_calls(body, &mut calls);
+                    functions.push((name, calls));
+                }
+            }
+        }
+    }
+
+    let mut trampolined = Vec::new();
+    for &(name, ref calls) in functions.iter() {
+        for call in calls.iter() {
+            if call != name && functions.iter().any(|&(function, _)| function == call) {
+                for name in [name, call.as_str()].iter() {
+                    if !trampolined.iter().any(|trampolined| trampolined == name) {
+                        trampolined.push(String::from(*name));
+                    }
+                }
+            }
+        }
+    }
+    trampolined
+}
+
+// Emits a function body as statements that return its value, turning tail calls into jumps
+// to the top of the function's loop or returns to its trampoline
+fn emit_tail<'n>(
+    node: &'n ASTNode,
+    tail: &Tail,
+    context: &Context,
+    out: &mut Output<'_, 'n>,
+) -> fmt::Result {
+    match *node {
+        ASTNode::Invocation {
+            ref func, ref args, ..
+        } => {
+            if let ASTNode::Name(ref name, _) = **func {
+                if let Some((ref recursive, ref params)) = tail.recursive {
+                    if name == recursive && args.len() == params.len() {
+                        out.enter(node);
+                        emit_loop_call(args, params, context, out)?;
+                        out.leave();
+                        return Ok(());
+                    }
+                }
+                if tail.bounce.contains(name) {
+                    out.enter(node);
+                    out.write("return new $Tail(")?;
+                    context.name(name, out)?;
+                    out.write(".$tail, [")?;
+                    emit_list(args, context, out)?;
+                    out.write("])")?;
+                    out.leave();
+                    return out.end_statement();
+                }
+            }
+            emit_tail_return(node, context, out)
+        }
+        ASTNode::Conditional {
+            ref cond,
+            ref if_body,
+            ref else_body,
+            ..
+        } => {
+            out.enter(node);
+            out.write("if (")?;
+            emit_operand(cond, condition_precedence(context), context, out)?;
+            out.write(" !== false)")?;
+            out.open_block()?;
+            emit_tail(if_body, tail, context, out)?;
+            out.close_block()?;
+            out.write(" else")?;
+            out.open_block()?;
+            match **else_body {
+                Some(ref else_body) => emit_tail(else_body, tail, context, out)?,
+                None => emit_tail(&FALSE, tail, context, out)?,
+            }
+            out.close_block()?;
+            out.leave();
+            out.end_line()
+        }
+        ASTNode::Sequence(ref exprs) => match exprs.split_last() {
+            Some((last, init)) if !init.iter().all(is_import) => {
+                if context.pretty() {
+                    for expr in init.iter() {
+                        emit_statement(expr, context, out)?;
+                    }
+                } else if context.minified() {
+                    out.begin_expression_statement();
+                    emit_sequence(init, context, out)?;
+                    out.end_expression_statement()?;
+                    out.end_statement()?;
+                } else {
+                    out.write("(")?;
+                    emit_sequence(init, context, out)?;
+                    out.write(")")?;
+                    out.end_statement()?;
+                }
+                emit_tail(last, tail, context, out)
+            }
+            Some((last, _)) => emit_tail(last, tail, context, out),
+            None => emit_tail(&FALSE, tail, context, out),
+        },
+        // The rhs of && and || is in tail position, so it's emitted after the statement that
+        // returns early when the lhs decides the result
+        ASTNode::Binary {
+            op: Token::Operator(ref op),
+            ref lhs,
+            ref rhs,
+            ..
+        } if op == "&&" || op == "||" => {
+            out.enter(node);
+            if op == "&&" {
+                out.write("if (")?;
+                emit_operand(lhs, condition_precedence(context), context, out)?;
+                out.write(" === false)")?;
+                out.open_block()?;
+                emit_tail(&FALSE, tail, context, out)?;
+            } else {
+                out.write("var $or = ")?;
+                emit_node(lhs, context, out)?;
+                out.end_statement()?;
+                out.write("if ($or !== false)")?;
+                out.open_block()?;
+                returned(context, out, |out| out.write("$or"))?;
+                out.end_statement()?;
+            }
+            out.close_block()?;
+            out.end_line()?;
+            emit_tail(rhs, tail, context, out)?;
+            out.leave();
+            Ok(())
+        }
+        ASTNode::Annotated { ref expr, .. } => emit_tail(expr, tail, context, out),
+        ASTNode::Match {
+            ref subject,
+            ref arms,
+            ..
+        } => {
+            out.enter(node);
+            emit_tail_match(subject, arms, tail, context, out)?;
+            out.leave();
+            Ok(())
+        }
+        _ => emit_tail_return(node, context, out),
+    }
+}
+
+fn emit_tail_return<'n>(
+    node: &'n ASTNode,
+    context: &Context,
+    out: &mut Output<'_, 'n>,
+) -> fmt::Result {
+    returned(context, out, |out| {
+        emit_operand(node, Precedence::Comma, context, out)
+    })?;
+    out.end_statement()
+}
+
+// Evaluates the arguments of a call to the function itself, rebinds its parameters to them
+// and starts the loop over. With several parameters, the arguments are all evaluated before
+// any parameter changes, since they may refer to each other.
+fn emit_loop_call<'n>(
+    args: &'n [ASTNode],
+    params: &[String],
+    context: &Context,
+    out: &mut Output<'_, 'n>,
+) -> fmt::Result {
+    if params.len() == 1 {
+        context.name(&params[0], out)?;
+        out.write(" = ")?;
+        emit_node(&args[0], context, out)?;
+        out.end_statement()?;
+    } else {
+        for (i, arg) in args.iter().enumerate() {
+            write!(out, "$next{} = ", i)?;
+            emit_node(arg, context, out)?;
+            out.end_statement()?;
+        }
+        for (i, param) in params.iter().enumerate() {
+            context.name(param, out)?;
+            write!(out, " = $next{}", i)?;
+            out.end_statement()?;
+        }
+    }
+
+    out.write("continue")?;
+    out.end_statement()
+}
+
+// Like emit_match, except that the arms are statements in the function's body, so their
+// tail calls are in the function's tail position
+fn emit_tail_match<'n>(
+    subject: &'n ASTNode,
+    arms: &'n [MatchArm],
+    tail: &Tail,
+    context: &Context,
+    out: &mut Output<'_, 'n>,
+) -> fmt::Result {
+    out.write("var $match = ")?;
+    emit_node(subject, context, out)?;
+    out.end_statement()?;
+
+    for arm in arms.iter() {
+        match arm.pattern {
+            Pattern::Wildcard(_) => return emit_tail(&arm.body, tail, context, out),
+            Pattern::Constructor {
+                ref name,
+                ref bindings,
+                ..
+            } => {
+                let mut scope = context.nested();
+                let mut tail = tail.clone();
+
+                write!(out, "if ($match.$tag === \"{}\")", name)?;
+                out.open_block()?;
+                for (i, binding) in bindings.iter().enumerate() {
+                    if let Some((binding, _)) = binding.binding() {
+                        if binding != "_" {
+                            out.write("var ")?;
+                            out.write(scope.bind(binding, context))?;
+                            write!(out, " = $match.${}", i)?;
+                            out.end_statement()?;
+                            tail.bounce.retain(|name| name != binding);
+                        }
+                    }
+                }
+
+                emit_tail(&arm.body, &tail, &context.with_scope(&scope), out)?;
+                out.close_block()?;
+                out.end_line()?;
+            }
+        }
+    }
+
+    out.write("throw new Error(\"No match for \" + $match.$tag)")?;
+    out.end_statement()
+}
+
+fn emit_arity_guard(arity: usize, display_name: &str, out: &mut Output) -> fmt::Result {
+    write!(
+        out,
+        "if (arguments.length !== {}) {{ throw new TypeError(\"",
+        arity
+    )?;
+    out.escaped(display_name)?;
+    write!(
+        out,
+        " expects {} arguments, given \" + arguments.length) }}",
+        arity
+    )
+}
+
+// Emits the statement returning a function's value
+fn emit_return<'n>(body: &'n ASTNode, context: &Context, out: &mut Output<'_, 'n>) -> fmt::Result {
+    if context.pretty() {
+        emit_statements(body, true, context, out)
+    } else {
+        returned(context, out, |out| emit_body(body, context, out))?;
+        out.end_last_statement()
+    }
+}
+
+// Emits a body as one statement per line, ending by returning the value of its last
+// expression if it's returned
+fn emit_statements<'n>(
+    body: &'n ASTNode,
+    returned: bool,
+    context: &Context,
+    out: &mut Output<'_, 'n>,
+) -> fmt::Result {
+    let exprs = match *body {
+        ASTNode::Sequence(ref exprs) => &exprs[..],
+        _ => slice::from_ref(body),
+    };
+    let (exprs, last) = match exprs.split_last() {
+        Some((last, init)) if returned => (init, Some(last)),
+        _ => (exprs, None),
+    };
+
+    for expr in exprs.iter() {
+        emit_statement(expr, context, out)?;
+    }
+    if let Some(last) = last {
+        out.write("return ")?;
+        parenthesize(
+            element_precedence(last),
+            Precedence::Comma,
+            context,
+            out,
+            |out| emit_element(last, context, out),
+        )?;
+        out.end_statement()?;
+    }
+    Ok(())
+}
+
+// Emits an expression whose value is unused. Conditionals become if statements, and
+// declarations become a statement per name they bind.
+fn emit_statement<'n>(
+    expr: &'n ASTNode,
+    context: &Context,
+    out: &mut Output<'_, 'n>,
+) -> fmt::Result {
+    match *expr {
+        _ if expr.function_name().is_some() => {}
+        ASTNode::Import { .. } => return Ok(()),
+        ASTNode::Sequence(_) => return emit_statements(expr, false, context, out),
+        ASTNode::Conditional {
+            ref cond,
+            ref if_body,
+            ref else_body,
+            ..
+        } => {
+            out.enter(expr);
+            out.write("if (")?;
+            emit_operand(cond, condition_precedence(context), context, out)?;
+            out.write(" !== false)")?;
+            out.open_block()?;
+            emit_statements(if_body, false, context, out)?;
+            out.close_block()?;
+            if let Some(ref else_body) = **else_body {
+                out.write(" else")?;
+                out.open_block()?;
+                emit_statements(else_body, false, context, out)?;
+                out.close_block()?;
+            }
+            out.leave();
+            return out.end_line();
+        }
+        ASTNode::Annotated { ref expr, .. } | ASTNode::Export { decl: ref expr, .. } => {
+            return emit_statement(expr, context, out)
+        }
+        ASTNode::TypeDeclaration { ref variants, .. } => {
+            out.enter(expr);
+            for variant in variants.iter() {
+                emit_variant(variant, context, out)?;
+                out.end_statement()?;
+            }
+            out.leave();
+            return Ok(());
+        }
+        ASTNode::Extern {
+            ref module,
+            ref functions,
+            span,
+        } => {
+            out.enter(expr);
+            if check_extern_module(module, span, context, out) {
+                for function in functions.iter() {
+                    if check_extern(module, function, out) && extern_is_bound(module, function) {
+                        emit_extern_binding(module, function, context, out)?;
+                        out.end_statement()?;
+                    }
+                }
+            }
+            out.leave();
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    out.begin_expression_statement();
+    emit_element(expr, context, out)?;
+    if out.end_expression_statement()? {
+        out.end_statement()?;
+    }
+    Ok(())
+}
+
+// Compact output always parenthesizes a returned value
+fn returned<'n, F>(context: &Context, out: &mut Output<'_, 'n>, value: F) -> fmt::Result
+where
+    F: FnOnce(&mut Output<'_, 'n>) -> fmt::Result,
+{
+    if context.options.style == Style::Compact {
+        out.write("return (")?;
+        value(out)?;
+        out.write(")")
+    } else {
+        out.write("return ")?;
+        value(out)
+    }
+}
+
+// A global extern has to be a valid JS name to be used as it is
+fn check_extern(module: &Option<String>, function: &ExternFunction, out: &mut Output) -> bool {
+    if module.is_none() && function.js_name.is_none() && mangle(&function.name) != function.name {
+        out.error(
+            function.span,
+            format!(
+                "Extern {} isn't a valid JS name, so it needs one given with =",
+                function.name
+            ),
+        );
+        return false;
+    }
+
+    true
+}
+
+// An iife is a plain script, which has no way to import a JS module
+fn check_extern_module(
+    module: &Option<String>,
+    span: Span,
+    context: &Context,
+    out: &mut Output,
+) -> bool {
+    match *module {
+        Some(ref module) if context.options.module == ModuleFormat::Iife => {
+            out.error(
+                span,
+                format!(
+                    "An iife can't import the JS module {}, so its externs can only be JS globals",
+                    module
+                ),
+            );
+            false
+        }
+        _ => true,
+    }
+}
+
+fn emit_extern_binding(
+    module: &Option<String>,
+    function: &ExternFunction,
+    context: &Context,
+    out: &mut Output,
+) -> fmt::Result {
+    out.name(&function.name)?;
+    out.write(" = ")?;
+    emit_extern_target(module, function, context, out)
+}
+
+fn emit_invocation<'n>(
+    func: &'n ASTNode,
+    args: &'n [ASTNode],
+    context: &Context,
+    out: &mut Output<'_, 'n>,
+) -> fmt::Result {
+    emit_operand(func, Precedence::Call, context, out)?;
+    out.write("(")?;
+    emit_list(args, context, out)?;
+    out.write(")")
+}
+
+// Emits the arguments, parameters or elements of a list, separated by commas
+fn emit_list<'n>(nodes: &'n [ASTNode], context: &Context, out: &mut Output<'_, 'n>) -> fmt::Result {
+    for (i, node) in nodes.iter().enumerate() {
+        if i > 0 {
+            out.write(context.comma())?;
+        }
+        emit_node(node, context, out)?;
+    }
+
+    Ok(())
+}
+
+// Because conditional is an expression, it is equivalent to JS ternary
+fn emit_conditional<'n>(
+    cond: &'n ASTNode,
+    if_body: &'n ASTNode,
+    else_body: Option<&'n ASTNode>,
+    context: &Context,
+    out: &mut Output<'_, 'n>,
+) -> fmt::Result {
+    emit_operand(cond, condition_precedence(context), context, out)?;
+
+    // Only false is falsey
+    if context.pretty() {
+        out.write(" ")?;
+    }
+    out.write("!== false ? ")?;
+
+    emit_node(if_body, context, out)?;
+
+    out.write(" : ")?;
+
+    match else_body {
+        Some(node) => emit_node(node, context, out),
+        None => emit_node(&FALSE, context, out),
+    }
+}
+
+fn emit_binary<'n>(
+    op: &Token,
+    lhs: &'n ASTNode,
+    rhs: &'n ASTNode,
+    span: Span,
+    context: &Context,
+    out: &mut Output<'_, 'n>,
+) -> fmt::Result {
+    if let Token::Operator(ref op) = *op {
+        if op == "&&" || op == "||" {
+            return emit_logical(op, lhs, rhs, context, out);
+        }
+
+        // Assignment is right associative, and the other operators left associative
+        let precedence = Precedence::of_operator(op);
+        let (lhs_required, rhs_required) = if precedence == Precedence::Assignment {
+            (Precedence::Call, precedence)
+        } else {
+            (precedence, precedence.tighter())
+        };
+        emit_operand(lhs, lhs_required, context, out)?;
+        write!(out, " {} ", op)?;
+        return emit_operand(rhs, rhs_required, context, out);
+    }
+
+    out.error(span, String::from("Malformed binary node"));
+    Ok(())
+}
+
+// JS's && and || use JS truthiness, so Silver's are emitted as conditionals in which only
+// false is falsey. The rhs is only evaluated when needed, and the lhs exactly once.
+fn emit_logical<'n>(
+    op: &str,
+    lhs: &'n ASTNode,
+    rhs: &'n ASTNode,
+    context: &Context,
+    out: &mut Output<'_, 'n>,
+) -> fmt::Result {
+    if op == "&&" {
+        emit_operand(lhs, condition_precedence(context), context, out)?;
+        out.write(" !== false ? ")?;
+        emit_node(rhs, context, out)?;
+        out.write(" : false")
+    } else {
+        // Silver names can't start with `$`, so the temporary can't shadow anything
+        out.write("(function ($or) { return $or !== false ? $or : ")?;
+        emit_node(rhs, context, out)?;
+        out.write(" })(")?;
+        emit_node(lhs, context, out)?;
+        out.write(")")
+    }
+}
+
+// Values of data types are objects tagged with their constructor's name, holding their
+// fields in order as $0, $1, ... Constructors without fields are shared constants.
+fn emit_variant<'n>(
+    variant: &'n Variant,
+    context: &Context,
+    out: &mut Output<'_, 'n>,
+) -> fmt::Result {
+    out.name(&variant.name)?;
+    if variant.fields.is_empty() {
+        return write!(out, " = {{$tag: \"{}\"}}", variant.name);
+    }
+
+    out.write(" = function ")?;
+    out.name(&variant.name)?;
+    out.write("(")?;
+    emit_list(&variant.fields, context, out)?;
+    out.write(") { ")?;
+    if context.options.arity_guards {
+        emit_arity_guard(variant.fields.len(), &variant.name, out)?;
+        out.write(" ")?;
+    }
+
+    write!(out, "return {{$tag: \"{}\"", variant.name)?;
+    for (i, field) in variant.fields.iter().enumerate() {
+        write!(out, ", ${}: ", i)?;
+        emit_node(field, context, out)?;
+    }
+    out.write("} }")
+}
+
+// A match is a function of the subject that tests its tag against each arm in turn
+fn emit_match<'n>(
+    subject: &'n ASTNode,
+    arms: &'n [MatchArm],
+    context: &Context,
+    out: &mut Output<'_, 'n>,
+) -> fmt::Result {
+    let mut exhaustive = false;
+
+    out.write("(function ($match)")?;
+    out.open_block()?;
+    for arm in arms.iter() {
+        match arm.pattern {
+            Pattern::Wildcard(_) => {
+                emit_return(&arm.body, context, out)?;
+                exhaustive = true;
+                break;
+            }
+            Pattern::Constructor {
+                ref name,
+                ref bindings,
+                ..
+            } => {
+                let mut scope = context.nested();
+
+                write!(out, "if ($match.$tag === \"{}\")", name)?;
+                out.open_block()?;
+                for (i, binding) in bindings.iter().enumerate() {
+                    if let Some((binding, _)) = binding.binding() {
+                        if binding != "_" {
+                            out.write("var ")?;
+                            out.write(scope.bind(binding, context))?;
+                            write!(out, " = $match.${}", i)?;
+                            out.end_statement()?;
+                        }
+                    }
+                }
+
+                emit_return(&arm.body, &context.with_scope(&scope), out)?;
+                out.close_block()?;
+                out.end_line()?;
+            }
+        }
+    }
+
+    if !exhaustive {
+        out.write("throw new Error(\"No match for \" + $match.$tag)")?;
+        out.end_last_statement()?;
+    }
+    out.close_block()?;
+
+    out.write(")(")?;
+    emit_node(subject, context, out)?;
+    out.write(")")
+}
+
+// Emits the body of a program, module, function or match arm. A sequence there isn't an
+// operand, so it doesn't need parentheses of its own.
+fn emit_body<'n>(node: &'n ASTNode, context: &Context, out: &mut Output<'_, 'n>) -> fmt::Result {
+    match *node {
+        ASTNode::Sequence(ref exprs) => emit_sequence(exprs, context, out),
+        _ => emit_node(node, context, out),
+    }
+}
+
+// Functions declared in a sequence are assigned to their names, since a function expression
+// in the middle of a JS expression doesn't bind its name in the enclosing scope
+fn emit_sequence<'n, I>(exprs: I, context: &Context, out: &mut Output<'_, 'n>) -> fmt::Result
+where
+    I: IntoIterator<Item = &'n ASTNode>,
+{
+    let mut exprs = exprs.into_iter().peekable();
+    let mut first = true;
+    while let Some(expr) = exprs.next() {
+        if is_import(expr) && exprs.peek().is_some() {
+            continue;
+        }
+        if !first {
+            out.write(context.comma())?;
+        }
+        first = false;
+        parenthesize(
+            element_precedence(expr),
+            Precedence::Assignment,
+            context,
+            out,
+            |out| emit_element(expr, context, out),
+        )?;
+    }
+
+    Ok(())
+}
+
+fn is_import(expr: &ASTNode) -> bool {
+    matches!(*expr, ASTNode::Import { .. })
+}
+
+fn emit_element<'n>(expr: &'n ASTNode, context: &Context, out: &mut Output<'_, 'n>) -> fmt::Result {
+    match expr.function_name() {
+        Some((name, _)) => {
+            context.name(name, out)?;
+            out.write(" = ")?;
+            emit_node(expr, context, out)
+        }
+        None => emit_expression(expr, context, out),
+    }
+}
+
+fn element_precedence(expr: &ASTNode) -> Precedence {
+    if expr.function_name().is_some() {
+        Precedence::Assignment
+    } else {
+        precedence(expr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use lexer;
+    use modules;
+    use parser::{Parser, TypeAnnotation};
+    use std::path::Path;
+    use util::Span;
+
+    // Inverse of mangle, used to check that the scheme is reversible
+    fn demangle(mangled: &str) -> Option<String> {
+        let mut name = String::new();
+        let mut chars = mangled.chars().peekable();
+
+        while let Some(ch) = chars.next() {
+            if ch != '$' {
+                name.push(ch);
+                continue;
+            }
+
+            match chars.next() {
+                Some('q') => name.push('?'),
+                Some('b') => name.push('!'),
+                Some('d') => name.push('-'),
+                Some('l') => name.push('<'),
+                Some('g') => name.push('>'),
+                Some('e') => name.push('='),
+                None if JS_RESERVED.contains(&name.as_str()) => {}
+                _ => return None,
+            }
+        }
+
+        Some(name)
+    }
+
+    #[test]
+    fn test_mangle_special_characters() {
+        assert_eq!(mangle("empty?"), "empty$q");
+        assert_eq!(mangle("set-x!"), "set$dx$b");
+        assert_eq!(mangle("a->b<=c"), "a$d$gb$l$ec");
+        assert_eq!(mangle("plain_name"), "plain_name");
+    }
+
+    #[test]
+    fn test_mangle_reserved_words() {
+        assert_eq!(mangle("class"), "class$");
+        assert_eq!(mangle("new"), "new$");
+        assert_eq!(mangle("this"), "this$");
+        assert_eq!(mangle("classy"), "classy");
+    }
+
+    #[test]
+    fn test_mangle_round_trip() {
+        for name in &["empty?", "set-x!", "class", "this", "a=b", "café", "x", "new?"] {
+            assert_eq!(demangle(&mangle(name)), Some(String::from(*name)));
+        }
+    }
+
+    #[test]
+    fn test_emit_logical_operators() {
+        let and = ASTNode::Binary {
+            op: Token::Operator(String::from("&&")),
+            lhs: Box::new(ASTNode::Name(String::from("a"), Span::default())),
+            rhs: Box::new(ASTNode::Name(String::from("b"), Span::default())),
+            span: Span::default(),
+        };
+        let or = ASTNode::Binary {
+            op: Token::Operator(String::from("||")),
+            lhs: Box::new(ASTNode::Integer(0, Span::default())),
+            rhs: Box::new(ASTNode::Name(String::from("b"), Span::default())),
+            span: Span::default(),
+        };
+
+        assert_eq!(
+            emit(&and, &Options::default()).unwrap(),
+            "(a !== false ? b : false)"
+        );
+        assert_eq!(
+            emit(&or, &Options::default()).unwrap(),
+            "(function ($or) { return $or !== false ? $or : b })(0)"
+        );
+    }
+
+    #[test]
+    fn test_mangle_definitions_and_uses() {
+        let ast = ASTNode::Sequence(vec![
+            ASTNode::Function {
+                name: Box::new(Some(ASTNode::Name(String::from("empty?"), Span::default()))),
+                args: vec![ASTNode::Name(String::from("new"), Span::default())],
+                body: Box::new(ASTNode::Name(String::from("new"), Span::default())),
+                doc: None,
+                span: Span::default(),
+            },
+            ASTNode::Invocation {
+                func: Box::new(ASTNode::Name(String::from("empty?"), Span::default())),
+                args: vec![ASTNode::Integer(1, Span::default())],
+                span: Span::default(),
+            },
+        ]);
+
+        assert_eq!(
+            emit(&ast, &Options::default()).unwrap(),
+            "(empty$q = function empty$q(new$) { return (new$) }),empty$q(1)"
+        );
+    }
+
+    #[test]
+    fn test_erase_annotations() {
+        let annotated = |name: &str| ASTNode::Annotated {
+            expr: Box::new(ASTNode::Name(String::from(name), Span::default())),
+            annotation: TypeAnnotation::Named(String::from("Int"), Span::default()),
+            span: Span::default(),
+        };
+        let ast = ASTNode::Sequence(vec![
+            ASTNode::Function {
+                name: Box::new(Some(ASTNode::Name(String::from("f"), Span::default()))),
+                args: vec![annotated("a")],
+                body: Box::new(annotated("a")),
+                doc: None,
+                span: Span::default(),
+            },
+            ASTNode::Let {
+                name: Box::new(annotated("x")),
+                value: Box::new(ASTNode::Integer(1, Span::default())),
+                span: Span::default(),
+            },
+        ]);
+
+        assert_eq!(
+            emit(&ast, &Options::default()).unwrap(),
+            "(f = function f(a) { return (a) }),(x = 1)"
+        );
+    }
+
+    #[test]
+    fn test_arity_guards() {
+        let function = ASTNode::Function {
+            name: Box::new(Some(ASTNode::Name(String::from("f"), Span::default()))),
+            args: vec![ASTNode::Name(String::from("a"), Span::default())],
+            body: Box::new(ASTNode::Name(String::from("a"), Span::default())),
+            doc: None,
+            span: Span::default(),
+        };
+
+        assert_eq!(
+            emit(&function, &Options::default()).unwrap(),
+            "function f(a) { return (a) }"
+        );
+        assert_eq!(
+            emit(
+                &function,
+                &Options {
+                    arity_guards: true,
+                    ..Options::default()
+                }
+            )
+            .unwrap(),
+            "function f(a) { if (arguments.length !== 1) { throw new TypeError(\"f expects 1 \
+             arguments, given \" + arguments.length) } return (a) }"
+        );
+    }
+
+    #[test]
+    fn test_emit_data_types() {
+        let inp = "type Shape = Circle(r) | Empty; match s { Circle(r) => r, Empty => 0 }";
+        let mut parser = Parser {
+            lexer: lexer::Lexer::new(inp),
+        };
+        let ast = parser.parse_top_level().unwrap();
+
+        assert_eq!(
+            emit(&ast, &Options::default()).unwrap(),
+            "(Circle = function Circle(r) { return {$tag: \"Circle\", $0: r} }, \
+             Empty = {$tag: \"Empty\"}, false),\
+             (function ($match) { if ($match.$tag === \"Circle\") { var r = $match.$0; return (r) } \
+             if ($match.$tag === \"Empty\") { return (0) } \
+             throw new Error(\"No match for \" + $match.$tag) })(s)"
+        );
+    }
+
+    #[test]
+    fn test_emit_program() {
+        let modules = modules::load_with(Path::new("main.silver"), |path| {
+            Ok(String::from(match path.to_str() {
+                Some("main.silver") => "import \"lib.silver\" as lib; lib.f(1)",
+                _ => "fn helper() { 1 }; export fn f(x) { x + helper() }",
+            }))
+        })
+        .unwrap();
+
+        assert_eq!(
+            emit_program(&modules, &prelude::module(), &Options::default()).unwrap(),
+            "var $module0 = (function () { var helper, f; return ((helper = function helper() \
+             { return (1) }),(f = function f(x) { return ((x + helper())) }), {f: f}) })();\n\
+             var lib = $module0;\n\
+             lib.f(1)"
+        );
+    }
+
+    #[test]
+    fn test_iife_program() {
+        let load = |main: &'static str| {
+            modules::load_with(Path::new("main.silver"), move |path| {
+                Ok(String::from(match path.to_str() {
+                    Some("main.silver") => main,
+                    _ => "export fn f(x) { x }",
+                }))
+            })
+            .unwrap()
+        };
+        let options = Options {
+            module: ModuleFormat::Iife,
+            ..Options::default()
+        };
+
+        // The bundled modules and the prelude are bound within the iife
+        let modules = load(
+            "import \"lib.silver\" as lib; extern fn log(x) = \"console.log\"; \
+             log(lib.f(length([1])))",
+        );
+        let output = emit_program(&modules, &prelude::module(), &options).unwrap();
+        assert!(output.starts_with("(function () { var $length = "));
+        assert!(output.contains("\nvar $prelude = "));
+        assert!(output.contains("\nvar $module0 = "));
+        assert!(output.ends_with("})();\n"));
+
+        // It has nowhere to import a JS module from
+        let modules = load("extern \"node:path\" { fn join(a, b) }; join(\"a\", \"b\")");
+        let errors = emit_program(&modules, &prelude::module(), &options).unwrap_err();
+        assert_eq!(
+            errors[0].msg,
+            "An iife can't import the JS module node:path, so its externs can only be JS globals"
+        );
+    }
+
+    #[test]
+    fn test_write_program() {
+        struct Failing;
+        impl io::Write for Failing {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::Error::other("disk full"))
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let inp = "fn f(x) { x }; (fn (x) { x })(1); f(if f(true) then 2 else 3)";
+        let modules =
+            modules::load_with(Path::new("main.silver"), |_| Ok(String::from(inp))).unwrap();
+        let prelude = prelude::module();
+
+        // Writing as the program is emitted gives the same output, in every style
+        for style in [Style::Compact, Style::Pretty, Style::Minified].iter() {
+            let options = Options {
+                style: *style,
+                ..Options::default()
+            };
+            let mut written = Vec::new();
+            write_program(&modules, &prelude, &options, &mut written).unwrap();
+            assert_eq!(
+                String::from_utf8(written).unwrap(),
+                emit_program(&modules, &prelude, &options).unwrap()
+            );
+        }
+
+        // A statement starting with a function is parenthesized once its start is written
+        let options = Options {
+            style: Style::Pretty,
+            ..Options::default()
+        };
+        assert!(emit_program(&modules, &prelude, &options)
+            .unwrap()
+            .contains("\n(function (x) {\n    return x;\n}(1));\n"));
+
+        assert_eq!(
+            write_program(&modules, &prelude, &options, Failing),
+            Err(vec![Error {
+                msg: String::from("Couldn't write the output: disk full"),
+                span: None,
+                trail: Vec::new(),
+            }])
+        );
+    }
+
+    #[test]
+    fn test_emit_errors() {
+        // Stands in for a node the parser would never build
+        fn corrupt(node: ASTNode) -> ASTNode {
+            match node {
+                ASTNode::Binary { lhs, rhs, span, .. } => ASTNode::Binary {
+                    op: Token::Delimiter(';'),
+                    lhs,
+                    rhs,
+                    span,
+                },
+                node => node.map_children(&mut corrupt),
+            }
+        }
+
+        let inp = "extern fn is-ok(x);\nfn f(x) { if is-ok(x) then x + 1 else 0 }";
+        let ast = Parser {
+            lexer: lexer::Lexer::new(inp),
+        }
+        .parse_top_level()
+        .unwrap();
+
+        // Emitting carries on past each problem, so every one is reported
+        let errors = emit(&corrupt(ast), &Options::default()).unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(
+            errors[0].msg,
+            "Extern is-ok isn't a valid JS name, so it needs one given with ="
+        );
+        assert_eq!(errors[1].msg, "Malformed binary node");
+        let trail: Vec<&str> = errors[1]
+            .trail
+            .iter()
+            .map(|(node, _)| node.as_str())
+            .collect();
+        assert_eq!(trail, vec!["conditional", "function f"]);
+        assert_eq!(errors[1].span.map(|span| span.line), Some(2));
+        assert!(errors[1].to_string().ends_with(
+            "\n  in conditional at line 2, column 10\n  in function f at line 2, column 0\n"
+        ));
+    }
+    fn emit_str(inp: &str, options: &Options) -> String {
+        let modules =
+            modules::load_with(Path::new("main.silver"), |_| Ok(String::from(inp))).unwrap();
+        emit_program(&modules, &prelude::module(), options).unwrap()
+    }
+
+    #[test]
+    fn test_function_locals() {
+        let inp = "x = 1; fn f(a) { x = a; y = a; fn g() { y = 2; z = 3 } }";
+
+        assert_eq!(
+            emit_str(inp, &Options::default()),
+            "(x = 1),(f = function f(a) { var y, g; return ((x = a),(y = a),(g = function g() \
+             { var z; return ((y = 2),(z = 3)) })) })"
+        );
+    }
+
+    #[test]
+    fn test_let_shadows_outer_name() {
+        let inp = "let x = 1; fn f() { let x = 2; x }; f(); x";
+
+        assert_eq!(
+            emit_str(inp, &Options::default()),
+            "(x = 1),(f = function f() { var x; return ((x = 2),x) }),f(),x"
+        );
+    }
+
+    #[test]
+    fn test_module_formats() {
+        let inp = "fn f() { 1 }; export let x = f()";
+        let format = |module| {
+            emit_str(
+                inp,
+                &Options {
+                    module,
+                    ..Options::default()
+                },
+            )
+        };
+
+        assert_eq!(
+            format(ModuleFormat::None),
+            "(f = function f() { return (1) }),(x = f())"
+        );
+        assert_eq!(
+            format(ModuleFormat::Iife),
+            "(function () { var f, x; return ((f = function f() { return (1) }),(x = f())) })();\n"
+        );
+        assert_eq!(
+            format(ModuleFormat::Cjs),
+            "var f, x;\n(f = function f() { return (1) }),(x = f());\n\
+             module.exports = {x: x, f: f};\n"
+        );
+        assert_eq!(
+            format(ModuleFormat::Esm),
+            "var f, x;\n(f = function f() { return (1) }),(x = f());\nexport { x, f };\n"
+        );
+    }
+
+    #[test]
+    fn test_emit_externs() {
+        let inp = "extern fn log(x) = \"console.log\";
+                   extern fn parseInt(s);
+                   extern \"node:path\" { fn join(a, b); fn base-name(p) = \"basename\" };
+                   log(parseInt(base-name(join(\"a\", \"1\"))))";
+        let options = |module| Options {
+            module,
+            ..Options::default()
+        };
+
+        assert_eq!(
+            emit_str(inp, &options(ModuleFormat::Cjs)),
+            "var $extern0 = require(\"node:path\");\n\
+             var log, join, base$dname;\n\
+             (log = console.log, false),false,(join = $extern0[\"join\"], \
+             base$dname = $extern0[\"basename\"], false),log(parseInt(base$dname(join(\"a\",\"1\"))));\n\
+             module.exports = {};\n"
+        );
+        assert!(emit_str(inp, &options(ModuleFormat::Esm))
+            .starts_with("import * as $extern0 from \"node:path\";\n"));
+    }
+
+    #[test]
+    fn test_prelude_inclusion() {
+        // Only the declarations used are included, along with those they use
+        assert_eq!(
+            emit_str("empty?([])", &Options::default()),
+            "var $length = function (xs) { return xs.length };\n\
+             var $prelude = (function () { var length, empty$q; return ((length = $length, false),\
+             (empty$q = function empty$q(xs) { return ((length(xs) == 0)) }), \
+             {length: length, empty$q: empty$q}) })();\n\
+             var empty$q = $prelude.empty$q;\n\
+             empty$q([])"
+        );
+
+        // Names the program defines itself shadow the prelude
+        assert_eq!(
+            emit_str("fn length(xs) { 0 }; length([1])", &Options::default()),
+            "(length = function length(xs) { return (0) }),length([1])"
+        );
+        assert!(!emit_str(
+            "extern fn max(a, b) = \"Math.max\"; max(1, 2)",
+            &Options::default()
+        )
+        .contains("$prelude"));
+    }
+
+    #[test]
+    fn test_tail_calls_become_loops() {
+        assert_eq!(
+            emit_str(
+                "fn count(n) { if n == 0 then 0 else count(n - 1) }",
+                &Options::default()
+            ),
+            "(count = function count(n) { while (true) { if ((n == 0) !== false) { return (0); } \
+             else { n = (n - 1); continue; } } })"
+        );
+
+        // Arguments are evaluated before any parameter changes
+        assert_eq!(
+            emit_str("fn f(a, b) { g(); f(b, a) }", &Options::default()),
+            "(f = function f(a,b) { var $next0, $next1; while (true) { (g()); $next0 = b; \
+             $next1 = a; a = $next0; b = $next1; continue; } })"
+        );
+
+        let inp = "type T = A(x) | B; fn f(t) { match t { A(x) => f(x), B => 1 } }";
+        assert!(emit_str(inp, &Options::default()).contains(
+            "while (true) { var $match = t; if ($match.$tag === \"A\") { var x = $match.$0; \
+             t = x; continue; }"
+        ));
+
+        // The rhs of || and && is in tail position
+        assert_eq!(
+            emit_str("fn loop(n) { n == 0 || loop(n - 1) }", &Options::default()),
+            "(loop = function loop(n) { while (true) { var $or = (n == 0); \
+             if ($or !== false) { return ($or); } n = (n - 1); continue; } })"
+        );
+        assert_eq!(
+            emit_str("fn loop(n) { n > 0 && loop(n - 1) }", &Options::default()),
+            "(loop = function loop(n) { while (true) { if ((n > 0) === false) \
+             { return (false); } n = (n - 1); continue; } })"
+        );
+    }
+
+    #[test]
+    fn test_tail_calls_left_alone() {
+        // Calls that aren't in tail position, that the function's name doesn't refer to, or
+        // that a closure could observe stay ordinary calls
+        for inp in [
+            "fn f(n) { 1 + f(n) }",
+            "fn f(n) { g = fn (f) { f(n) }; 1 }",
+            "fn f(n) { f = g; f(n) }",
+            "fn f(n) { h(fn () { n }); f(n - 1) }",
+        ]
+        .iter()
+        {
+            assert!(
+                !emit_str(inp, &Options::default()).contains("while"),
+                "{}",
+                inp
+            );
+        }
+    }
+
+    #[test]
+    fn test_trampoline() {
+        let inp = "fn even?(n) { if n == 0 then true else odd?(n - 1) };
+                   fn odd?(n) { if n == 0 then false else even?(n - 1) };
+                   fn other(n) { n }";
+        let options = Options {
+            trampoline: true,
+            ..Options::default()
+        };
+        let output = emit_str(inp, &options);
+
+        assert!(output.starts_with(TRAMPOLINE));
+        assert!(output.contains(
+            "(odd$q = $trampoline(function (n) { if ((n == 0) !== false) { return (false); } \
+             else { return new $Tail(even$q.$tail, [(n - 1)]); } }))"
+        ));
+        assert!(output.contains("(other = function other(n) { return (n) })"));
+
+        // Without mutual tail calls, there's no trampoline
+        assert_eq!(
+            emit_str("fn f(n) { n }", &options),
+            "(f = function f(n) { return (n) })"
+        );
+    }
+
+    #[test]
+    fn test_pretty_output() {
+        let options = Options {
+            style: Style::Pretty,
+            ..Options::default()
+        };
+        let inp = "fn f(a, b) { if a > b then log(a) else log(b); c = (a + b) * 2; a - (b - c) };
+                   f(1, 2)";
+        assert_eq!(
+            emit_str(inp, &options),
+            "f = function f(a, b) {
+    var c;
+    if (a > b !== false) {
+        log(a);
+    } else {
+        log(b);
+    }
+    c = (a + b) * 2;
+    return a - (b - c);
+};
+f(1, 2);
+"
+        );
+
+        // Conditionals whose values are used stay expressions
+        assert_eq!(
+            emit_str("x = if a then b else c + 1", &options),
+            "x = a !== false ? b : c + 1;\n"
+        );
+    }
+
+    #[test]
+    fn test_pretty_modules() {
+        let options = Options {
+            style: Style::Pretty,
+            module: ModuleFormat::Iife,
+            ..Options::default()
+        };
+        assert_eq!(
+            emit_str(
+                "type T = A(x) | B; fn f(t) { match t { A(x) => x, _ => 0 } }; f(B)",
+                &options
+            ),
+            "(function () {
+    var A, B, f;
+    A = function A(x) { return {$tag: \"A\", $0: x} };
+    B = {$tag: \"B\"};
+    f = function f(t) {
+        return (function ($match) {
+            if ($match.$tag === \"A\") {
+                var x = $match.$0;
+                return x;
+            }
+            return 0;
+        })(t);
+    };
+    return f(B);
+})();
+"
+        );
+    }
+
+    #[test]
+    fn test_minified_output() {
+        let options = Options {
+            style: Style::Minified,
+            ..Options::default()
+        };
+        assert_eq!(
+            emit_str(
+                "fn f(first, second) { total = first - 1; if total > second then total else \"a b\" }",
+                &options
+            ),
+            "f=function f(a,b){var c;return c=a-1,c>b!==false?c:\"a b\"}"
+        );
+
+        // Names visible outside a module keep theirs
+        let options = Options {
+            module: ModuleFormat::Esm,
+            ..options
+        };
+        assert_eq!(
+            emit_str("hidden = 1; export let shown = hidden", &options),
+            "var a,shown;a=1,shown=a;export{shown}"
+        );
+
+        // Spaces are kept where tokens would run together
+        let mut stripped = String::new();
+        Output::new(&mut stripped, Style::Minified)
+            .write("x = a - -1 + +b; return c; }")
+            .unwrap();
+        assert_eq!(stripped, "x=a- -1+ +b;return c}");
+    }
+
+    #[test]
+    fn test_shorten_names() {
+        // Shortened names never shadow names from enclosing scopes or collide with names
+        // that are kept
+        let inp = "fn f(x) { g = fn (y) { x + y + a }; match x { A(z) => g(z), _ => 0 } }";
+        let ast = Parser {
+            lexer: lexer::Lexer::new(inp),
+        }
+        .parse_top_level()
+        .unwrap();
+        let options = Options {
+            style: Style::Minified,
+            ..Options::default()
+        };
+        assert_eq!(
+            emit(&ast, &options).unwrap(),
+            "f=function f(b){var c;return c=function(d){return b+d+a},(function($match){\
+             if($match.$tag===\"A\"){var d=$match.$0;return c(d)}return 0})(b)}"
+        );
+    }
+
+    // Each name in a program's compact output paired with the one in its minified output,
+    // which has the same identifiers in the same order
+    fn shortened(modules: &[Module], module: ModuleFormat) -> Vec<(String, String)> {
+        let identifiers = |style| {
+            let options = Options {
+                style,
+                module,
+                ..Options::default()
+            };
+            let js = emit_program(modules, &prelude::module(), &options).unwrap();
+            js.split(|ch: char| !(ch.is_alphanumeric() || ch == '$' || ch == '_'))
+                .filter(|word| !word.is_empty() && !word.starts_with(|ch: char| ch.is_numeric()))
+                .map(String::from)
+                .collect::<Vec<_>>()
+        };
+        let (names, short) = (identifiers(Style::Compact), identifiers(Style::Minified));
+        assert_eq!(names.len(), short.len());
+        names.into_iter().zip(short).collect()
+    }
+
+    #[test]
+    fn test_shortened_names_dont_collide() {
+        // The short names a minifier would pick first are already kept
+        let inp = "extern fn c(x); extern fn log(x) = \"console.log\"; export let b = 1; a = 2;
+                   fn f(first, second) {
+                       let third = first + second;
+                       fn (fourth) { log(third + fourth + a + b + c(first)) }
+                   };
+                   let g = f(1, 2); g(3)";
+        let modules =
+            modules::load_with(Path::new("main.silver"), |_| Ok(String::from(inp))).unwrap();
+        let globals = ["a", "b", "c", "log", "f", "g"];
+
+        // Top-level names are visible throughout the program, so no other name may be
+        // shortened to the name any of them is emitted as
+        for module in [
+            ModuleFormat::None,
+            ModuleFormat::Esm,
+            ModuleFormat::Cjs,
+            ModuleFormat::Iife,
+        ]
+        .iter()
+        {
+            let pairs = shortened(&modules, *module);
+            for (global, emitted) in pairs.iter() {
+                if !globals.contains(&global.as_str()) {
+                    continue;
+                }
+                for (name, short) in pairs.iter() {
+                    assert!(
+                        name == global || short != emitted,
+                        "{} and {} are both emitted as {} in {:?}",
+                        global,
+                        name,
+                        emitted,
+                        module
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_minified_kept_names() {
+        let modules = modules::load_with(Path::new("main.silver"), |path| {
+            Ok(String::from(match path.to_str() {
+                Some("main.silver") => {
+                    "import \"lib.silver\" as lib; extern fn log(x) = \"console.log\";
+                     export let shown = 1; hidden = 2;
+                     fn helper(value) { let doubled = value * 2; fn (extra) { doubled + extra } };
+                     export fn api(input) { let add = helper(input); add(lib.twice(hidden)) };
+                     log(api(shown))"
+                }
+                _ => "fn inner(n) { n + 1 }; export fn twice(n) { inner(n) * 2 }",
+            }))
+        })
+        .unwrap();
+
+        // Exports and externs are always kept. The program's other top-level names are
+        // kept when they're globals, and its functions when it's a module.
+        let always = ["twice", "log", "shown", "api"];
+        let locals = ["inner", "n", "value", "doubled", "extra", "input", "add"];
+        let formats = [
+            (ModuleFormat::None, vec!["hidden", "helper"], vec![]),
+            (ModuleFormat::Esm, vec!["helper"], vec!["hidden"]),
+            (ModuleFormat::Cjs, vec!["helper"], vec!["hidden"]),
+            (ModuleFormat::Iife, vec![], vec!["hidden", "helper"]),
+        ];
+        for &(module, ref kept, ref hidden) in formats.iter() {
+            for (name, short) in shortened(&modules, module) {
+                if always.contains(&name.as_str()) || kept.contains(&name.as_str()) {
+                    assert_eq!(short, name, "in {:?}", module);
+                } else if hidden.contains(&name.as_str()) || locals.contains(&name.as_str()) {
+                    assert_ne!(short, name, "in {:?}", module);
+                }
+            }
+        }
+    }
+
+    // There's no interpreter for Silver itself, so minified output is checked against
+    // compact output by running both with node, when it's on the PATH
+    #[test]
+    fn test_minified_round_trip() {
+        use std::io::Write;
+        use std::process::{Command, Stdio};
+
+        if Command::new("node").arg("--version").output().is_err() {
+            return;
+        }
+        let run = |js: &str| {
+            let mut node = Command::new("node")
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()
+                .ok()?;
+            node.stdin.take()?.write_all(js.as_bytes()).ok()?;
+            String::from_utf8(node.wait_with_output().ok()?.stdout).ok()
+        };
+
+        let programs = [
+            "fn count(n, total) { if n == 0 then total else count(n - 1, total + n) };
+             print(count(100000, 0))",
+            "type Shape = Circle(r) | Square(side);
+             fn area(s) { match s { Circle(r) => 3 * r * r, Square(side) => side * side } };
+             print(area(Circle(2)) - area(Square(3)))",
+            "fn adder(x) { fn (y) { x + y } }; add = adder(1); print(add(2) * (3 - 1));
+             print(\"a  b\" + to-string(sum([1, 2, 3])) + \";}\")",
+            "fn even?(n) { if n == 0 then true else odd?(n - 1) };
+             fn odd?(n) { if n == 0 then false else even?(n - 1) }; print(odd?(7) || false)",
+        ];
+        for program in programs.iter() {
+            let inp = format!("extern fn print(x) = \"console.log\"; {}", program);
+            for module in [ModuleFormat::None, ModuleFormat::Iife, ModuleFormat::Cjs].iter() {
+                let compact = Options {
+                    module: *module,
+                    trampoline: true,
+                    ..Options::default()
+                };
+                let minified = Options {
+                    style: Style::Minified,
+                    module: *module,
+                    trampoline: true,
+                    ..Options::default()
+                };
+
+                let expected = run(&emit_str(&inp, &compact)).expect("Couldn't run node");
+                assert!(!expected.is_empty(), "{}", inp);
+                assert_eq!(
+                    run(&emit_str(&inp, &minified)).unwrap(),
+                    expected,
+                    "{}",
+                    inp
+                );
+            }
+        }
+    }
 }
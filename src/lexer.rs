@@ -1,4 +1,11 @@
-use super::util::Error;
+use super::util::{Error, Span};
+
+use unicode_xid::UnicodeXID;
+
+// All operators the lexer recognises
+const OPERATORS: &[&str] = &[
+    "=", "||", "&&", "<", "<=", ">", ">=", "==", "!=", "+", "-", "*", "/", "%", "->", "=>", "|",
+];
 
 pub struct Lexer<'a> {
     input: Vec<char>,
@@ -6,15 +13,18 @@ pub struct Lexer<'a> {
     line: u32,
     col: u32,
     keywords: Vec<&'a str>,
-    peeked: Option<Token>,
+    peeked: Option<(Token, Span)>,
+    span: Span, // start of the most recently returned token
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum Token {
     Variable(String),
     Operator(String),
     Keyword(String),
     StringLiteral(String),
+    DocComment(String),
     Integral(i32),
     FloatingPoint(f32),
     Delimiter(char),
@@ -22,14 +32,18 @@ pub enum Token {
 }
 
 impl<'a> Lexer<'a> {
-    pub fn new(input: &'a str) -> Lexer {
+    pub fn new(input: &'a str) -> Lexer<'a> {
         Lexer {
             input: input.chars().collect(),
             ind: 0,
             line: 1,
             col: 0,
-            keywords: vec!["fn", "true", "false", "if", "then", "else"],
+            keywords: vec![
+                "fn", "let", "type", "match", "import", "as", "export", "extern", "true", "false",
+                "if", "then", "else",
+            ],
             peeked: None,
+            span: Span::default(),
         }
     }
 
@@ -51,52 +65,89 @@ impl<'a> Lexer<'a> {
     }
 
     pub fn get_token(&mut self) -> Result<Token, Error> {
-        let ret: Result<Token, Error>;
-        if let Some(_) = self.peeked {
-            ret = Ok(self.peeked.clone().unwrap());
-            self.peeked = None;
-            return ret;
+        if let Some((token, span)) = self.peeked.take() {
+            self.span = span;
+            return Ok(token);
         }
 
         self.consume_whitespace();
+        self.span = Span {
+            line: self.line,
+            col: self.col,
+        };
         if self.eof() {
             return Ok(Token::EOF);
         }
 
         match self.input[self.ind] {
-            '#' => {
-                self.skip_comment();
-                self.get_token()
-            }
+            '#' => match self.input.get(self.ind + 1).cloned() {
+                Some('|') => {
+                    self.skip_block_comment()?;
+                    self.get_token()
+                }
+                Some('#') => Ok(self.read_doc_comment()),
+                _ => {
+                    self.skip_comment();
+                    self.get_token()
+                }
+            },
             '"' => self.read_string(),
-            '0'...'9' => self.read_number(),
-            'a'...'z' | '_' => self.read_identifier(),
-            ',' | ';' | '(' | ')' | '[' | ']' | '{' | '}' => Ok(Token::Delimiter(self.next_char())),
-            '=' | '+' | '-' | '*' | '/' | '%' | '&' | '<' | '>' | '!' => self.read_operator(),
+            '0'..='9' => self.read_number(),
+            ch if Self::is_identifier_start(ch) => self.read_identifier(),
+            ',' | ';' | ':' | '.' | '(' | ')' | '[' | ']' | '{' | '}' => {
+                Ok(Token::Delimiter(self.next_char()))
+            }
+            '=' | '+' | '-' | '*' | '/' | '%' | '&' | '|' | '<' | '>' | '!' => self.read_operator(),
             _ => Err(self.get_error(format!("Error reading character {}", self.input[self.ind]))),
         }
     }
 
     pub fn peek(&mut self) -> Result<Token, Error> {
-        if let None = self.peeked {
-            self.peeked = Some(self.get_token()?);
+        if self.peeked.is_none() {
+            // Peeking shouldn't change the span of the last token returned
+            let last_span = self.span;
+            let token = self.get_token()?;
+            self.peeked = Some((token, self.span));
+            self.span = last_span;
         }
-        Ok(self.peeked.clone().unwrap())
+        Ok(self.peeked.clone().unwrap().0)
     }
 
-    fn read_operator(&mut self) -> Result<Token, Error> {
-        let operator_chars = "=+-*/%&<>!";
-        let op_string = self.read_while(|ch| operator_chars.contains(ch));
+    // The span of the token most recently returned by get_token
+    pub fn span(&self) -> Span {
+        self.span
+    }
 
-        Ok(Token::Operator(op_string))
+    // The span of the token that the next call to get_token will return
+    pub fn peek_span(&mut self) -> Result<Span, Error> {
+        self.peek()?;
+        Ok(self.peeked.clone().unwrap().1)
+    }
+
+    // Uses maximal munch: the longest known operator that prefixes the input is taken,
+    // so `+-` lexes as `+` followed by `-` rather than as a single unknown operator
+    fn read_operator(&mut self) -> Result<Token, Error> {
+        let rest = &self.input[self.ind..];
+        let longest = OPERATORS
+            .iter()
+            .filter(|op| {
+                op.chars().count() <= rest.len() && op.chars().zip(rest.iter()).all(|(a, b)| a == *b)
+            })
+            .max_by_key(|op| op.len());
+
+        match longest {
+            Some(op) => {
+                for _ in 0..op.chars().count() {
+                    self.next_char();
+                }
+                Ok(Token::Operator(op.to_string()))
+            }
+            None => Err(self.get_error(format!("Unknown operator {}", self.input[self.ind]))),
+        }
     }
 
     fn read_identifier(&mut self) -> Result<Token, Error> {
-        let special_id_chars = "?!-<>=_";
-        let id = self.read_while(|ch| match ch {
-            '0'...'9' | 'a'...'z' | 'A'...'Z' => true,
-            _ => special_id_chars.contains(ch),
-        });
+        let id = self.read_while(Self::is_identifier_continue);
 
         if self.keywords.contains(&id.as_str()) {
             return Ok(Token::Keyword(id));
@@ -104,13 +155,25 @@ impl<'a> Lexer<'a> {
         Ok(Token::Variable(id))
     }
 
+    // Identifiers follow Unicode XID rules, with the addition of a leading underscore
+    fn is_identifier_start(ch: char) -> bool {
+        ch == '_' || UnicodeXID::is_xid_start(ch)
+    }
+
+    // Beyond XID_Continue, identifiers may contain a handful of punctuation characters
+    // (e.g. `empty?` or `set-x!`)
+    fn is_identifier_continue(ch: char) -> bool {
+        let special_id_chars = "?!-<>=";
+        UnicodeXID::is_xid_continue(ch) || special_id_chars.contains(ch)
+    }
+
     fn read_number(&mut self) -> Result<Token, Error> {
         let mut dotted = false;
         let mut digits = String::new();
 
         for (i, ch) in self.input[self.ind..].iter().enumerate() {
             match *ch {
-                '0'...'9' => digits.push(self.input[self.ind + i]),
+                '0'..='9' => digits.push(self.input[self.ind + i]),
                 '.' => {
                     if dotted {
                         break;
@@ -139,35 +202,68 @@ impl<'a> Lexer<'a> {
         }
     }
 
+    // Each char is consumed as it's read, so that the position stays on the char after the
+    // closing quote however many chars the escapes drop
     fn read_string(&mut self) -> Result<Token, Error> {
+        let start = self.get_error(String::from("Unterminated string literal"));
         let mut ret_str = String::new();
-        let mut escaped = false;
         self.next_char(); // consume opening '"'
 
-        for ch in self.input[self.ind..].iter() {
-            if escaped {
-                ret_str.push(*ch);
-                escaped = false;
-            } else if *ch == '\\' {
-                escaped = true;
-            } else if *ch == '"' {
-                break;
-            } else {
-                ret_str.push(*ch);
+        loop {
+            if self.eof() {
+                return Err(start);
+            }
+            match self.next_char() {
+                '"' => break,
+                '\\' if !self.eof() => ret_str.push(self.next_char()),
+                ch => ret_str.push(ch),
             }
         }
 
-        for _ in 0..ret_str.len() {
-            self.next_char();
-        }
-        self.next_char();
-
         Ok(Token::StringLiteral(ret_str))
     }
 
+    // The trailing newline is left to be consumed as whitespace, since the comment may be
+    // on the last line of the input
     fn skip_comment(&mut self) {
         self.read_while(|ch| ch != '\n');
-        self.next_char(); // consume newline
+    }
+
+    // Block comments are delimited by `#|` and `|#`, and may be nested
+    fn skip_block_comment(&mut self) -> Result<(), Error> {
+        let start = self.get_error(String::from("Unterminated block comment"));
+        let mut depth = 0;
+
+        loop {
+            if self.eof() {
+                return Err(start);
+            }
+
+            let ch = self.next_char();
+            let next = self.input.get(self.ind).cloned();
+            if ch == '#' && next == Some('|') {
+                self.next_char();
+                depth += 1;
+            } else if ch == '|' && next == Some('#') {
+                self.next_char();
+                depth -= 1;
+                if depth == 0 {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    // Doc comments start with `##` and run to the end of the line. A single space after
+    // the marker is dropped, so `## text` documents "text"
+    fn read_doc_comment(&mut self) -> Token {
+        self.next_char();
+        self.next_char();
+        if self.input.get(self.ind) == Some(&' ') {
+            self.next_char();
+        }
+
+        Token::DocComment(self.read_while(|ch| ch != '\n'))
     }
 
     fn consume_whitespace(&mut self) {
@@ -188,7 +284,7 @@ impl<'a> Lexer<'a> {
             }
         }
 
-        for _ in 0..ret_str.len() {
+        for _ in 0..ret_str.chars().count() {
             self.next_char();
         }
 
@@ -220,6 +316,30 @@ mod tests {
         assert_eq!(lexer.get_token().unwrap(), Token::EOF);
     }
 
+    #[test]
+    fn test_lex_unicode_variable() {
+        let mut lexer = Lexer::new("Foo café _Ωmega empty?");
+
+        assert_eq!(lexer.get_token().unwrap(), Token::Variable(String::from("Foo")));
+        assert_eq!(lexer.get_token().unwrap(), Token::Variable(String::from("café")));
+        assert_eq!(
+            lexer.get_token().unwrap(),
+            Token::Variable(String::from("_Ωmega"))
+        );
+        assert_eq!(
+            lexer.get_token().unwrap(),
+            Token::Variable(String::from("empty?"))
+        );
+        assert_eq!(lexer.get_token().unwrap(), Token::EOF);
+    }
+
+    #[test]
+    fn test_lex_invalid_identifier_start() {
+        let mut lexer = Lexer::new("·x");
+
+        assert!(lexer.get_token().is_err());
+    }
+
     #[test]
     fn test_lex_keyword() {
         let mut lexer = Lexer::new("if");
@@ -242,6 +362,53 @@ mod tests {
         assert_eq!(lexer.get_token().unwrap(), Token::EOF);
     }
 
+    #[test]
+    fn test_lex_unicode_string_literal() {
+        let mut lexer = Lexer::new("\"café\" x");
+
+        assert_eq!(
+            lexer.get_token().unwrap(),
+            Token::StringLiteral(String::from("café"))
+        );
+        assert_eq!(
+            lexer.get_token().unwrap(),
+            Token::Variable(String::from("x"))
+        );
+        assert_eq!((lexer.span().line, lexer.span().col), (1, 7));
+        assert_eq!(lexer.get_token().unwrap(), Token::EOF);
+    }
+
+    #[test]
+    fn test_lex_escaped_quote() {
+        let mut lexer = Lexer::new("\"a\\\"b\" c");
+
+        assert_eq!(
+            lexer.get_token().unwrap(),
+            Token::StringLiteral(String::from("a\"b"))
+        );
+        assert_eq!(
+            lexer.get_token().unwrap(),
+            Token::Variable(String::from("c"))
+        );
+        assert_eq!((lexer.span().line, lexer.span().col), (1, 7));
+        assert_eq!(lexer.get_token().unwrap(), Token::EOF);
+    }
+
+    #[test]
+    fn test_lex_unterminated_string_literal() {
+        let mut lexer = Lexer::new("a \"bc");
+
+        lexer.get_token().unwrap();
+        assert_eq!(
+            lexer.get_token(),
+            Err(Error {
+                msg: String::from("Unterminated string literal"),
+                line: 1,
+                col: 2,
+            })
+        );
+    }
+
     #[test]
     fn test_lex_integral() {
         let mut lexer = Lexer::new("22312");
@@ -271,6 +438,141 @@ mod tests {
         assert_eq!(lexer.get_token().unwrap(), Token::EOF);
     }
 
+    #[test]
+    fn test_lex_logical_operators() {
+        let mut lexer = Lexer::new("a || b && c");
+
+        assert_eq!(lexer.get_token().unwrap(), Token::Variable(String::from("a")));
+        assert_eq!(lexer.get_token().unwrap(), Token::Operator(String::from("||")));
+        assert_eq!(lexer.get_token().unwrap(), Token::Variable(String::from("b")));
+        assert_eq!(lexer.get_token().unwrap(), Token::Operator(String::from("&&")));
+        assert_eq!(lexer.get_token().unwrap(), Token::Variable(String::from("c")));
+        assert_eq!(lexer.get_token().unwrap(), Token::EOF);
+    }
+
+    #[test]
+    fn test_lex_operator_maximal_munch() {
+        let mut lexer = Lexer::new("a +- b <== c");
+
+        assert_eq!(lexer.get_token().unwrap(), Token::Variable(String::from("a")));
+        assert_eq!(lexer.get_token().unwrap(), Token::Operator(String::from("+")));
+        assert_eq!(lexer.get_token().unwrap(), Token::Operator(String::from("-")));
+        assert_eq!(lexer.get_token().unwrap(), Token::Variable(String::from("b")));
+        assert_eq!(lexer.get_token().unwrap(), Token::Operator(String::from("<=")));
+        assert_eq!(lexer.get_token().unwrap(), Token::Operator(String::from("=")));
+        assert_eq!(lexer.get_token().unwrap(), Token::Variable(String::from("c")));
+        assert_eq!(lexer.get_token().unwrap(), Token::EOF);
+    }
+
+    #[test]
+    fn test_lex_unknown_operator() {
+        let mut lexer = Lexer::new("a =! b");
+
+        assert_eq!(lexer.get_token().unwrap(), Token::Variable(String::from("a")));
+        assert_eq!(lexer.get_token().unwrap(), Token::Operator(String::from("=")));
+        assert_eq!(
+            lexer.get_token(),
+            Err(Error {
+                msg: String::from("Unknown operator !"),
+                line: 1,
+                col: 3,
+            })
+        );
+    }
+
+    #[test]
+    fn test_lex_comments() {
+        let mut lexer = Lexer::new("a # line comment\n#| block #| nested |# still |# b # last");
+
+        assert_eq!(
+            lexer.get_token().unwrap(),
+            Token::Variable(String::from("a"))
+        );
+        assert_eq!(
+            lexer.get_token().unwrap(),
+            Token::Variable(String::from("b"))
+        );
+        assert_eq!(lexer.get_token().unwrap(), Token::EOF);
+    }
+
+    #[test]
+    fn test_lex_unterminated_block_comment() {
+        let mut lexer = Lexer::new("a #| #| |#");
+
+        assert_eq!(
+            lexer.get_token().unwrap(),
+            Token::Variable(String::from("a"))
+        );
+        assert_eq!(
+            lexer.get_token(),
+            Err(Error {
+                msg: String::from("Unterminated block comment"),
+                line: 1,
+                col: 2,
+            })
+        );
+    }
+
+    #[test]
+    fn test_lex_doc_comment() {
+        let mut lexer = Lexer::new("## Adds things\n##up\nfn");
+
+        assert_eq!(
+            lexer.get_token().unwrap(),
+            Token::DocComment(String::from("Adds things"))
+        );
+        assert_eq!(
+            lexer.get_token().unwrap(),
+            Token::DocComment(String::from("up"))
+        );
+        assert_eq!(
+            lexer.get_token().unwrap(),
+            Token::Keyword(String::from("fn"))
+        );
+        assert_eq!(lexer.get_token().unwrap(), Token::EOF);
+    }
+
+    #[test]
+    fn test_token_spans() {
+        let mut lexer = Lexer::new("abc\n  12 \"s\"");
+
+        lexer.get_token().unwrap();
+        assert_eq!((lexer.span().line, lexer.span().col), (1, 0));
+
+        let peeked = lexer.peek_span().unwrap();
+        assert_eq!((peeked.line, peeked.col), (2, 2));
+        // Peeking doesn't move the span of the last token
+        assert_eq!((lexer.span().line, lexer.span().col), (1, 0));
+
+        lexer.get_token().unwrap();
+        assert_eq!((lexer.span().line, lexer.span().col), (2, 2));
+        lexer.get_token().unwrap();
+        assert_eq!((lexer.span().line, lexer.span().col), (2, 5));
+    }
+
+    #[test]
+    fn test_lex_type_annotation() {
+        let mut lexer = Lexer::new("(a: Int): (Int) -> Int");
+
+        let expected = vec![
+            Token::Delimiter('('),
+            Token::Variable(String::from("a")),
+            Token::Delimiter(':'),
+            Token::Variable(String::from("Int")),
+            Token::Delimiter(')'),
+            Token::Delimiter(':'),
+            Token::Delimiter('('),
+            Token::Variable(String::from("Int")),
+            Token::Delimiter(')'),
+            Token::Operator(String::from("->")),
+            Token::Variable(String::from("Int")),
+            Token::EOF,
+        ];
+        for token in expected {
+            assert_eq!(lexer.get_token().unwrap(), token);
+        }
+    }
+
     #[test]
     fn test_lex_empty() {
         let mut lexer = Lexer::new("");
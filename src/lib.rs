@@ -0,0 +1,18 @@
+extern crate unicode_xid;
+
+pub mod backend;
+pub mod bytecode;
+pub mod c;
+pub mod emitter;
+pub mod lexer;
+pub mod modules;
+pub mod optimizer;
+pub mod parser;
+pub mod prelude;
+pub mod resolver;
+pub mod shaker;
+pub mod typechecker;
+pub mod util;
+pub mod vm;
+pub mod wasm;
+pub mod wasm_encoder;
@@ -1,65 +1,323 @@
-mod util;
-mod lexer;
-mod parser;
-mod emitter;
+extern crate silver;
 
+use silver::{bytecode, c, emitter, modules, optimizer, prelude, resolver, shaker, typechecker,
+             vm, wasm, wasm_encoder};
+
+use std::collections::HashMap;
 use std::process::exit;
-use std::io::BufReader;
-use std::io::prelude::*;
-use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::fs::{self, File};
+use std::path::Path;
 use std::env;
 
-fn process_input_file(filename: &String) {
-    let mut contents = String::new();
+// What the program is compiled to
+#[derive(PartialEq)]
+enum Target {
+    Js,
+    // A binary module along with the JS that loads it
+    Wasm,
+    // A module in the text format, for reading
+    Wat,
+    // A C99 program with its runtime, for hosts without a JS engine
+    C,
+    // The disassembled bytecode, for debugging
+    Bytecode,
+    // Bytecode run by the compiler's own virtual machine
+    Vm,
+}
+
+impl Target {
+    fn from_name(name: &str) -> Option<Target> {
+        match name {
+            "js" => Some(Target::Js),
+            "wasm" => Some(Target::Wasm),
+            "wat" => Some(Target::Wat),
+            "c" => Some(Target::C),
+            "bytecode" => Some(Target::Bytecode),
+            "vm" => Some(Target::Vm),
+            _ => None,
+        }
+    }
+}
+
+struct Options {
+    filename: String,
+    target: Target,
+    typecheck: bool,
+    verbose: bool,
+    level: usize,
+    emitter: emitter::Options,
+}
 
-    {
-        let f = File::open(filename);
+fn parse_options(args: &[String]) -> Option<Options> {
+    let mut filename = None;
+    let mut target = Target::Js;
+    let mut typecheck = true;
+    let mut verbose = false;
+    let mut level = 1;
+    let mut emitter = emitter::Options::default();
 
-        if let Err(ref _e) = f {
-            println!("There was error opening the specified input file, aborting.");
+    for arg in args.iter() {
+        match arg.as_str() {
+            "--no-typecheck" => typecheck = false,
+            "--verbose" => verbose = true,
+            "--arity-guards" => emitter.arity_guards = true,
+            "--trampoline" => emitter.trampoline = true,
+            "--pretty" => emitter.style = emitter::Style::Pretty,
+            "--minify" => emitter.style = emitter::Style::Minified,
+            "-O0" => level = 0,
+            "-O1" => level = 1,
+            "-O2" => level = 2,
+            _ if arg.starts_with("--target=") => {
+                target = Target::from_name(&arg["--target=".len()..])?
+            }
+            _ if arg.starts_with("--module=") => {
+                emitter.module = emitter::ModuleFormat::from_name(&arg["--module=".len()..])?
+            }
+            _ if arg.starts_with('-') => return None,
+            _ if filename.is_none() => filename = Some(arg.clone()),
+            _ => return None,
+        }
+    }
+
+    filename.map(|filename| Options {
+        filename,
+        target,
+        typecheck,
+        verbose,
+        level,
+        emitter,
+    })
+}
+
+fn process_input_file(options: &Options) {
+    let modules = match modules::load(Path::new(&options.filename)) {
+        Ok(modules) => modules,
+        Err(err) => {
+            println!("{}", err);
             exit(1)
         }
+    };
 
-        let mut buf_reader = BufReader::new(f.unwrap());
+    // The prelude is part of the compiler, so it's only checked for its interface
+    let prelude = prelude::module();
+    let prelude_interface = if options.typecheck {
+        let checked =
+            typechecker::check_module(&prelude.ast, HashMap::new(), Default::default());
+        match checked {
+            Ok((_, interface)) => interface,
+            Err(errors) => {
+                for err in errors.iter() {
+                    println!("In {}:\n{}", prelude.path.display(), err);
+                }
+                exit(1)
+            }
+        }
+    } else {
+        typechecker::Interface::default()
+    };
 
-        if let Err(ref _e) = buf_reader.read_to_string(&mut contents) {
-            println!("There was an error reading from the file, aborting.");
+    // Modules are checked in order, so the interfaces of a module's imports are known
+    // before it's checked
+    let mut interfaces: Vec<typechecker::Interface> = Vec::new();
+    for module in modules.iter() {
+        // Diagnostics in imported modules say which file they're in
+        let location = if modules.len() > 1 {
+            format!("In {}:\n", module.path.display())
+        } else {
+            String::new()
+        };
+
+        let exports: HashMap<String, Vec<String>> = module
+            .imports
+            .iter()
+            .map(|&(ref alias, index)| (alias.clone(), modules[index].exports.clone()))
+            .collect();
+        let resolution = resolver::resolve_module(&module.ast, &exports, &prelude.exports);
+        for warning in resolution.warnings.iter() {
+            println!("{}{}", location, warning);
+        }
+        if !resolution.errors.is_empty() {
+            for err in resolution.errors.iter() {
+                println!("{}{}", location, err);
+            }
             exit(1)
         }
+
+        if options.typecheck {
+            let imports = module
+                .imports
+                .iter()
+                .map(|&(ref alias, index)| (alias.clone(), interfaces[index].clone()))
+                .collect();
+            match typechecker::check_module(&module.ast, imports, prelude_interface.clone()) {
+                Ok((_, interface)) => interfaces.push(interface),
+                Err(errors) => {
+                    for err in errors.iter() {
+                        println!("{}{}", location, err);
+                    }
+                    exit(1)
+                }
+            }
+        }
     }
 
-    let lexer = lexer::Lexer::new(&contents);
-    let mut parser = parser::Parser { lexer };
-    let parsed = parser.parse_top_level();
+    let optimizer = optimizer::Options {
+        level: options.level,
+        typed: options.typecheck,
+    };
+    let mut modules: Vec<modules::Module> = modules
+        .into_iter()
+        .map(|mut module| {
+            module.ast = optimizer::optimize(module.ast, &optimizer);
+            module
+        })
+        .collect();
 
-    if let Err(err) = parsed {
-        println!("{}", err);
-        exit(1)
+    // Unless optimizations are off, only what the program can reach is emitted
+    let mut prelude = prelude;
+    if options.level > 0 {
+        // A WebAssembly module, C program or bytecode only exports main
+        let exported = options.target == Target::Js
+            && matches!(
+                options.emitter.module,
+                emitter::ModuleFormat::Esm | emitter::ModuleFormat::Cjs
+            );
+        let removals = shaker::shake(&mut modules, &mut prelude, exported);
+        if options.verbose {
+            for removal in removals.iter() {
+                println!("{}", removal);
+            }
+        }
     }
 
-    let emission = emitter::emit(parsed.unwrap());
-    if let Err(err) = emission {
-        println!("{}", err);
-        exit(1)
+    match options.target {
+        Target::Js => write_js(&modules, &prelude, options),
+        Target::Wasm | Target::Wat => write_wasm(&modules, &prelude, options),
+        Target::C => write_c(&modules, &prelude),
+        Target::Bytecode | Target::Vm => run_bytecode(&modules, &prelude, options),
     }
+}
+
+fn create(path: &str) -> File {
+    match File::create(path) {
+        Ok(file) => file,
+        Err(_) => {
+            println!("There was error opening the output file, aborting.");
+            exit(1)
+        }
+    }
+}
+
+fn write_js(modules: &[modules::Module], prelude: &modules::Module, options: &Options) {
+    let file = create("out.js");
 
-    let f = File::create("out.js");
-    if let Err(ref _e) = f {
-        println!("There was error opening the output file, aborting.");
+    // The program is written as it's emitted, so a failure leaves an incomplete file
+    let emission =
+        emitter::write_program(modules, prelude, &options.emitter, BufWriter::new(file));
+    if let Err(errors) = emission {
+        for err in errors.iter() {
+            println!("{}", err);
+        }
+        let _ = fs::remove_file("out.js");
         exit(1)
     }
 
-    if let Err(ref _e) = f.unwrap().write_all(emission.unwrap().as_bytes()) {
-        println!("There was an error writing to the output file, aborting.");
+    println!("Output written to out.js");
+}
+
+// A binary module is run by the JS loader written next to it
+fn write_wasm(modules: &[modules::Module], prelude: &modules::Module, options: &Options) {
+    let module = match wasm::compile_program(modules, prelude) {
+        Ok(module) => module,
+        Err(errors) => {
+            for err in errors.iter() {
+                println!("{}", err);
+            }
+            exit(1)
+        }
+    };
+
+    let outputs = if options.target == Target::Wat {
+        vec![("out.wat", module.to_string().into_bytes())]
     } else {
-        println!("Output written to out.js");
+        vec![
+            ("out.wasm", wasm_encoder::encode(&module)),
+            ("out.js", wasm::loader(&module, "out.wasm").into_bytes()),
+        ]
+    };
+    for &(path, ref contents) in outputs.iter() {
+        if create(path).write_all(contents).is_err() {
+            println!("There was an error writing the output file, aborting.");
+            let _ = fs::remove_file(path);
+            exit(1)
+        }
+    }
+
+    let paths: Vec<&str> = outputs.iter().map(|&(path, _)| path).collect();
+    println!("Output written to {}", paths.join(" and "));
+}
+
+// A C program is written as a single file, which builds with any C99 compiler and links with
+// the math library
+fn write_c(modules: &[modules::Module], prelude: &modules::Module) {
+    let program = match c::compile_program(modules, prelude) {
+        Ok(program) => program,
+        Err(errors) => {
+            for err in errors.iter() {
+                println!("{}", err);
+            }
+            exit(1)
+        }
+    };
+
+    if create("out.c").write_all(program.as_bytes()).is_err() {
+        println!("There was an error writing the output file, aborting.");
+        let _ = fs::remove_file("out.c");
+        exit(1)
+    }
+
+    println!(
+        "Output written to out.c, which builds with: cc out.c {}",
+        c::LINK_FLAGS
+    );
+}
+
+// The program is run straight away, unless only its disassembly is wanted
+fn run_bytecode(modules: &[modules::Module], prelude: &modules::Module, options: &Options) {
+    let program = match bytecode::compile_program(modules, prelude) {
+        Ok(program) => program,
+        Err(errors) => {
+            for err in errors.iter() {
+                println!("{}", err);
+            }
+            exit(1)
+        }
+    };
+
+    if options.target == Target::Bytecode {
+        if create("out.bc").write_all(program.to_string().as_bytes()).is_err() {
+            println!("There was an error writing the output file, aborting.");
+            let _ = fs::remove_file("out.bc");
+            exit(1)
+        }
+        println!("Output written to out.bc");
+        return;
+    }
+
+    let stdout = std::io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    if let Err(msg) = vm::run(&program, &mut out) {
+        let _ = out.flush();
+        eprintln!("Error: {}", msg);
+        exit(1)
     }
 }
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    match args.len() {
-        2 => process_input_file(&args[1]),
-        _ => println!("Usage: cargo run filename"),
+    match parse_options(&args[1..]) {
+        Some(options) => process_input_file(&options),
+        None => println!("Usage: cargo run [--no-typecheck] [--verbose] [--arity-guards] [--trampoline] [--pretty] [--minify] [-O0|-O1|-O2] [--module=esm|cjs|iife|none] [--target=js|wasm|wat|c|bytecode|vm] filename"),
     }
 }
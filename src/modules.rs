@@ -0,0 +1,261 @@
+use super::lexer;
+use super::parser::{ASTNode, Parser};
+
+use super::util::{Error, Span};
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Component, Path, PathBuf};
+
+pub struct Module {
+    pub path: PathBuf,
+    pub ast: ASTNode,
+    // Each import alias, along with the index of the module it refers to
+    pub imports: Vec<(String, usize)>,
+    pub exports: Vec<String>,
+}
+
+// An error in one of a program's modules
+#[derive(Debug)]
+pub struct LoadError {
+    pub path: PathBuf,
+    pub error: Error,
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "In {}:\n{}", self.path.display(), self.error)
+    }
+}
+
+struct Loader<F> {
+    read: F,
+    modules: Vec<Module>,
+    indices: HashMap<PathBuf, usize>,
+    // The modules currently being loaded, each importing the next
+    stack: Vec<PathBuf>,
+}
+
+// Loads a program and every module it imports, ordered so that each module comes after the
+// modules it imports. The program itself is therefore the last module.
+pub fn load(path: &Path) -> Result<Vec<Module>, LoadError> {
+    load_with(path, |path| {
+        let mut contents = String::new();
+        File::open(path)?.read_to_string(&mut contents)?;
+        Ok(contents)
+    })
+}
+
+// Loads modules using the given function to read their source
+pub fn load_with<F>(path: &Path, read: F) -> Result<Vec<Module>, LoadError>
+where
+    F: Fn(&Path) -> io::Result<String>,
+{
+    let mut loader = Loader {
+        read,
+        modules: Vec::new(),
+        indices: HashMap::new(),
+        stack: Vec::new(),
+    };
+    loader.load(&normalize(path), None)?;
+
+    Ok(loader.modules)
+}
+
+// Removes `.` and `..` components, so that each module has a single path
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match normalized.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    normalized.pop();
+                }
+                _ => normalized.push(".."),
+            },
+            _ => normalized.push(component.as_os_str()),
+        }
+    }
+
+    normalized
+}
+
+impl<F> Loader<F>
+where
+    F: Fn(&Path) -> io::Result<String>,
+{
+    // Loads the module at path (if it hasn't been already), returning its index. Errors
+    // finding the module are reported at the import, in the importing module.
+    fn load(&mut self, path: &Path, import: Option<Span>) -> Result<usize, LoadError> {
+        if let Some(index) = self.indices.get(path) {
+            return Ok(*index);
+        }
+
+        let import_error = |loader: &Loader<F>, msg: String| LoadError {
+            path: loader
+                .stack
+                .last()
+                .cloned()
+                .unwrap_or_else(|| path.to_path_buf()),
+            error: import.unwrap_or_default().get_error(msg),
+        };
+
+        if let Some(start) = self.stack.iter().position(|module| module == path) {
+            let cycle: Vec<String> = self.stack[start..]
+                .iter()
+                .chain(Some(&path.to_path_buf()))
+                .map(|module| module.display().to_string())
+                .collect();
+            return Err(import_error(
+                self,
+                format!("Import cycle: {}", cycle.join(" -> ")),
+            ));
+        }
+
+        let contents = match (self.read)(path) {
+            Ok(contents) => contents,
+            Err(err) => {
+                return Err(import_error(
+                    self,
+                    format!("Cannot read module {}: {}", path.display(), err),
+                ))
+            }
+        };
+
+        let lexer = lexer::Lexer::new(&contents);
+        let mut parser = Parser { lexer };
+        let ast = parser.parse_top_level().map_err(|error| LoadError {
+            path: path.to_path_buf(),
+            error,
+        })?;
+
+        // Imports are resolved relative to the directory of the importing module
+        self.stack.push(path.to_path_buf());
+        let mut imports = Vec::new();
+        let mut exports = Vec::new();
+        if let ASTNode::Sequence(ref exprs) = ast {
+            for expr in exprs.iter() {
+                if let ASTNode::Import {
+                    path: ref import_path,
+                    ref alias,
+                    span,
+                } = *expr
+                {
+                    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+                    let index = self.load(&normalize(&dir.join(import_path)), Some(span))?;
+                    imports.push((alias.clone(), index));
+                }
+                for (name, _) in expr.exported_names() {
+                    exports.push(String::from(name));
+                }
+            }
+        }
+        self.stack.pop();
+
+        self.modules.push(Module {
+            path: path.to_path_buf(),
+            ast,
+            imports,
+            exports,
+        });
+        let index = self.modules.len() - 1;
+        self.indices.insert(path.to_path_buf(), index);
+
+        Ok(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn load_str(files: &[(&str, &str)], path: &str) -> Result<Vec<Module>, LoadError> {
+        let files: HashMap<PathBuf, String> = files
+            .iter()
+            .map(|&(path, contents)| (PathBuf::from(path), String::from(contents)))
+            .collect();
+
+        load_with(Path::new(path), |path| {
+            files
+                .get(path)
+                .cloned()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "not found"))
+        })
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize(Path::new("a/./b/../c")), PathBuf::from("a/c"));
+        assert_eq!(
+            normalize(Path::new("../a/../../b")),
+            PathBuf::from("../../b")
+        );
+    }
+
+    #[test]
+    fn test_load_relative_to_importer() {
+        let files = [
+            (
+                "main.silver",
+                "import \"lib/a.silver\" as a; import \"lib/b.silver\" as b; a.f(b.g)",
+            ),
+            (
+                "lib/a.silver",
+                "import \"b.silver\" as b; export fn f(x) { x }",
+            ),
+            ("lib/b.silver", "export let g = 1"),
+        ];
+        let modules = load_str(&files, "main.silver").unwrap();
+
+        let paths: Vec<PathBuf> = modules.iter().map(|module| module.path.clone()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                PathBuf::from("lib/b.silver"),
+                PathBuf::from("lib/a.silver"),
+                PathBuf::from("main.silver"),
+            ]
+        );
+        assert_eq!(
+            modules[2].imports,
+            vec![(String::from("a"), 1), (String::from("b"), 0)]
+        );
+        assert_eq!(modules[1].exports, vec![String::from("f")]);
+        assert_eq!(modules[0].exports, vec![String::from("g")]);
+    }
+
+    #[test]
+    fn test_import_cycle() {
+        let files = [
+            ("a.silver", "import \"b.silver\" as b"),
+            ("b.silver", "1;\nimport \"./a.silver\" as a"),
+        ];
+        let err = load_str(&files, "a.silver").err().unwrap();
+
+        assert_eq!(err.path, PathBuf::from("b.silver"));
+        assert_eq!(
+            err.error,
+            Error {
+                msg: String::from("Import cycle: a.silver -> b.silver -> a.silver"),
+                line: 2,
+                col: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_missing_module() {
+        let err = load_str(&[("a.silver", "import \"c.silver\" as c")], "a.silver")
+            .err()
+            .unwrap();
+
+        assert_eq!(err.path, PathBuf::from("a.silver"));
+        assert_eq!(err.error.msg, "Cannot read module c.silver: not found");
+    }
+}
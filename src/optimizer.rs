@@ -0,0 +1,727 @@
+use super::lexer::Token;
+use super::parser::{ASTNode, MatchArm, Pattern};
+
+use super::util::Span;
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+// The largest function body, counted in nodes, that's inlined at its call sites
+const INLINE_SIZE: usize = 16;
+
+#[derive(Debug, Default)]
+pub struct Options {
+    // 0 leaves the program as it is, 1 folds constants and removes dead code, and 2 also
+    // inlines small functions
+    pub level: usize,
+    // Whether the program has been type checked. Some simplifications rely on an operand's
+    // type being known from the other operand, which only holds in a well-typed program.
+    pub typed: bool,
+}
+
+// Rewrites a program into an equivalent one that does less work at runtime. Inlining
+// exposes more constants, so the program is folded again afterwards.
+pub fn optimize(ast: ASTNode, options: &Options) -> ASTNode {
+    if options.level == 0 {
+        return ast;
+    }
+
+    let ast = fold(ast, options);
+    if options.level >= 2 {
+        fold(inline(ast), options)
+    } else {
+        ast
+    }
+}
+
+// Folds a node's children before the node itself, so that folding can cascade upwards
+fn fold(node: ASTNode, options: &Options) -> ASTNode {
+    match node.map_children(&mut |child| fold(child, options)) {
+        ASTNode::Conditional {
+            cond,
+            if_body,
+            else_body,
+            span,
+        } => fold_conditional(*cond, *if_body, *else_body, span),
+        ASTNode::Binary { op, lhs, rhs, span } => fold_binary(op, *lhs, *rhs, span, options),
+        // The values of all but the last expression are discarded, so those without side
+        // effects can be removed
+        ASTNode::Sequence(exprs) => {
+            let last = exprs.len().saturating_sub(1);
+            ASTNode::Sequence(
+                exprs
+                    .into_iter()
+                    .enumerate()
+                    .filter(|&(i, ref expr)| i == last || !is_pure(expr))
+                    .map(|(_, expr)| expr)
+                    .collect(),
+            )
+        }
+        // Arms after a wildcard can never match
+        ASTNode::Match {
+            subject,
+            mut arms,
+            span,
+        } => {
+            if let Some(wildcard) = arms
+                .iter()
+                .position(|arm| matches!(arm.pattern, Pattern::Wildcard(_)))
+            {
+                arms.truncate(wildcard + 1);
+            }
+            ASTNode::Match {
+                subject,
+                arms,
+                span,
+            }
+        }
+        node => node,
+    }
+}
+
+// Whether evaluating a node can have no effect other than giving its value. Declarations
+// and assignments bind names, and invocations and matches may do anything (a match can
+// throw), so none of them are pure.
+fn is_pure(node: &ASTNode) -> bool {
+    match *node {
+        ASTNode::Integer(..)
+        | ASTNode::Float(..)
+        | ASTNode::StringLiteral(..)
+        | ASTNode::Boolean(..)
+        | ASTNode::Name(..)
+        | ASTNode::Qualified { .. } => true,
+        ASTNode::Function { ref name, .. } => name.is_none(),
+        ASTNode::Conditional {
+            ref cond,
+            ref if_body,
+            ref else_body,
+            ..
+        } => is_pure(cond) && is_pure(if_body) && else_body.as_ref().as_ref().is_none_or(is_pure),
+        ASTNode::Binary {
+            ref op,
+            ref lhs,
+            ref rhs,
+            ..
+        } => *op != Token::Operator(String::from("=")) && is_pure(lhs) && is_pure(rhs),
+        ASTNode::Sequence(ref exprs) | ASTNode::List(ref exprs, _) => exprs.iter().all(is_pure),
+        ASTNode::Annotated { ref expr, .. } => is_pure(expr),
+        _ => false,
+    }
+}
+
+// A top-level function whose calls can be replaced by its body
+struct Inlinable {
+    params: Vec<String>,
+    body: ASTNode,
+    // The names the body refers to besides its parameters, which must mean the same thing
+    // wherever it's inlined
+    free: Vec<String>,
+}
+
+struct Inliner {
+    functions: HashMap<String, Inlinable>,
+    // The names bound by the functions and match arms enclosing the node being inlined into
+    bound: Vec<String>,
+    temporaries: usize,
+}
+
+// Inlines calls to a module's small top-level functions. A function can be inlined if it's
+// never reassigned, it doesn't call itself, and its body binds no names, so that the only
+// names to substitute are its parameters.
+fn inline(ast: ASTNode) -> ASTNode {
+    let mut assigned = Vec::new();
+    ast.walk(&mut |node| {
+        if let ASTNode::Binary {
+            ref op, ref lhs, ..
+        } = *node
+        {
+            if let ASTNode::Name(ref name, _) = **lhs {
+                if *op == Token::Operator(String::from("=")) {
+                    assigned.push(name.clone());
+                }
+            }
+        }
+    });
+
+    let mut functions = HashMap::new();
+    if let ASTNode::Sequence(ref exprs) = ast {
+        for expr in exprs.iter() {
+            if let ASTNode::Function {
+                ref name,
+                ref args,
+                ref body,
+                ..
+            } = *expr.declaration()
+            {
+                let name = match **name {
+                    Some(ASTNode::Name(ref name, _)) => name,
+                    _ => continue,
+                };
+                let params: Vec<String> = args
+                    .iter()
+                    .filter_map(|arg| arg.binding().map(|(param, _)| String::from(param)))
+                    .collect();
+
+                let mut size = 0;
+                let mut free = Vec::new();
+                let mut binds = false;
+                body.walk(&mut |node| {
+                    size += 1;
+                    match *node {
+                        ASTNode::Name(ref name, _)
+                            if !params.contains(name) && !free.contains(name) =>
+                        {
+                            free.push(name.clone())
+                        }
+                        ASTNode::Function { .. }
+                        | ASTNode::Let { .. }
+                        | ASTNode::Match { .. }
+                        | ASTNode::TypeDeclaration { .. }
+                        | ASTNode::Extern { .. }
+                        | ASTNode::Import { .. }
+                        | ASTNode::Export { .. } => binds = true,
+                        ASTNode::Binary { ref op, .. } => {
+                            binds |= *op == Token::Operator(String::from("="))
+                        }
+                        _ => {}
+                    }
+                });
+
+                if params.len() == args.len()
+                    && size <= INLINE_SIZE
+                    && !binds
+                    && !free.contains(name)
+                    && !assigned.contains(name)
+                {
+                    functions.insert(
+                        name.clone(),
+                        Inlinable {
+                            params,
+                            body: (**body).clone(),
+                            free,
+                        },
+                    );
+                }
+            }
+        }
+    }
+
+    let mut inliner = Inliner {
+        functions,
+        bound: Vec::new(),
+        temporaries: 0,
+    };
+    inliner.inline(ast)
+}
+
+impl Inliner {
+    fn inline(&mut self, node: ASTNode) -> ASTNode {
+        match node {
+            // Everything a function binds is treated as bound throughout its body
+            ASTNode::Function { .. } => {
+                let depth = self.bound.len();
+                if let Some((name, _)) = node.function_name() {
+                    self.bound.push(String::from(name));
+                }
+                if let ASTNode::Function {
+                    ref args, ref body, ..
+                } = node
+                {
+                    for arg in args.iter() {
+                        if let Some((arg, _)) = arg.binding() {
+                            self.bound.push(String::from(arg));
+                        }
+                    }
+                    collect_bindings(body, &mut self.bound);
+                }
+
+                let node = node.map_children(&mut |child| self.inline(child));
+                self.bound.truncate(depth);
+                node
+            }
+            ASTNode::Match {
+                subject,
+                arms,
+                span,
+            } => {
+                let subject = self.inline(*subject);
+                let arms = arms
+                    .into_iter()
+                    .map(|arm| {
+                        let depth = self.bound.len();
+                        if let Pattern::Constructor { ref bindings, .. } = arm.pattern {
+                            for binding in bindings.iter() {
+                                if let Some((binding, _)) = binding.binding() {
+                                    self.bound.push(String::from(binding));
+                                }
+                            }
+                        }
+                        let body = self.inline(arm.body);
+                        self.bound.truncate(depth);
+                        MatchArm {
+                            pattern: arm.pattern,
+                            body,
+                        }
+                    })
+                    .collect();
+                ASTNode::Match {
+                    subject: Box::new(subject),
+                    arms,
+                    span,
+                }
+            }
+            ASTNode::Invocation { func, args, span } => {
+                let args: Vec<ASTNode> = args.into_iter().map(|arg| self.inline(arg)).collect();
+                match *func {
+                    ASTNode::Name(ref name, _) if self.can_inline(name, args.len()) => {
+                        self.inline_call(name, args)
+                    }
+                    _ => ASTNode::Invocation {
+                        func: Box::new(self.inline(*func)),
+                        args,
+                        span,
+                    },
+                }
+            }
+            node => node.map_children(&mut |child| self.inline(child)),
+        }
+    }
+
+    // A call can be inlined if the name still refers to the top-level function, and so do
+    // the names its body refers to
+    fn can_inline(&self, name: &str, args: usize) -> bool {
+        match self.functions.get(name) {
+            Some(function) => {
+                function.params.len() == args
+                    && !self.bound.iter().any(|bound| bound == name)
+                    && !function.free.iter().any(|free| self.bound.contains(free))
+            }
+            None => false,
+        }
+    }
+
+    // Literal arguments are substituted directly. Any other argument is assigned to a
+    // temporary first, so it's evaluated once, in order, before the body.
+    fn inline_call(&mut self, name: &str, args: Vec<ASTNode>) -> ASTNode {
+        let function = &self.functions[name];
+        let mut substitution = HashMap::new();
+        let mut exprs = Vec::new();
+
+        for (param, arg) in function.params.iter().zip(args) {
+            if is_literal(&arg) {
+                substitution.insert(param.clone(), arg);
+                continue;
+            }
+
+            // Silver names can't contain `$`, so temporaries can't shadow anything
+            let temporary = ASTNode::Name(format!("$inline{}", self.temporaries), Span::default());
+            self.temporaries += 1;
+            exprs.push(ASTNode::Binary {
+                op: Token::Operator(String::from("=")),
+                lhs: Box::new(temporary.clone()),
+                rhs: Box::new(arg),
+                span: Span::default(),
+            });
+            substitution.insert(param.clone(), temporary);
+        }
+
+        let body = substitute(function.body.clone(), &substitution);
+        if exprs.is_empty() {
+            return body;
+        }
+        exprs.push(body);
+        ASTNode::Sequence(exprs)
+    }
+}
+
+// Collects every name bound anywhere in a node, by parameters, assignments, let bindings,
+// function declarations and match arms
+fn collect_bindings(node: &ASTNode, names: &mut Vec<String>) {
+    node.walk(&mut |node| match *node {
+        ASTNode::Function { ref args, .. } => {
+            if let Some((name, _)) = node.function_name() {
+                names.push(String::from(name));
+            }
+            for arg in args.iter() {
+                if let Some((arg, _)) = arg.binding() {
+                    names.push(String::from(arg));
+                }
+            }
+        }
+        ASTNode::Binary {
+            ref op, ref lhs, ..
+        } if *op == Token::Operator(String::from("=")) => {
+            if let Some((name, _)) = lhs.binding() {
+                names.push(String::from(name));
+            }
+        }
+        ASTNode::Let { ref name, .. } => {
+            if let Some((name, _)) = name.binding() {
+                names.push(String::from(name));
+            }
+        }
+        ASTNode::Match { ref arms, .. } => {
+            for arm in arms.iter() {
+                if let Pattern::Constructor { ref bindings, .. } = arm.pattern {
+                    for binding in bindings.iter() {
+                        if let Some((binding, _)) = binding.binding() {
+                            names.push(String::from(binding));
+                        }
+                    }
+                }
+            }
+        }
+        _ => {}
+    });
+}
+
+// Replaces names in a body that binds none of its own
+fn substitute(node: ASTNode, substitution: &HashMap<String, ASTNode>) -> ASTNode {
+    match node {
+        ASTNode::Name(ref name, _) if substitution.contains_key(name) => substitution[name].clone(),
+        node => node.map_children(&mut |child| substitute(child, substitution)),
+    }
+}
+
+fn is_literal(node: &ASTNode) -> bool {
+    matches!(
+        *node,
+        ASTNode::Integer(..)
+            | ASTNode::Float(..)
+            | ASTNode::StringLiteral(..)
+            | ASTNode::Boolean(..)
+    )
+}
+
+fn is_false(node: &ASTNode) -> bool {
+    matches!(*node, ASTNode::Boolean(false, _))
+}
+
+// Only false is falsey, so any other literal condition selects the first branch
+fn fold_conditional(
+    cond: ASTNode,
+    if_body: ASTNode,
+    else_body: Option<ASTNode>,
+    span: Span,
+) -> ASTNode {
+    if !is_literal(&cond) {
+        return ASTNode::Conditional {
+            cond: Box::new(cond),
+            if_body: Box::new(if_body),
+            else_body: Box::new(else_body),
+            span,
+        };
+    }
+
+    if !is_false(&cond) {
+        if_body
+    } else {
+        else_body.unwrap_or(ASTNode::Boolean(false, span))
+    }
+}
+
+fn fold_binary(op: Token, lhs: ASTNode, rhs: ASTNode, span: Span, options: &Options) -> ASTNode {
+    if let Token::Operator(ref op) = op {
+        match op.as_str() {
+            "||" if is_literal(&lhs) => {
+                return if is_false(&lhs) { rhs } else { lhs };
+            }
+            // `a || false` is a, whatever a evaluates to
+            "||" if is_false(&rhs) => return lhs,
+            "&&" if is_literal(&lhs) => {
+                return if is_false(&lhs) { lhs } else { rhs };
+            }
+            "=" | "||" | "&&" => {}
+            op => {
+                let folded = fold_operator(op, &lhs, &rhs).or_else(|| {
+                    if options.typed {
+                        simplify(op, &lhs, &rhs)
+                    } else {
+                        None
+                    }
+                });
+                if let Some(folded) = folded {
+                    return folded;
+                }
+            }
+        }
+    }
+
+    ASTNode::Binary {
+        op,
+        lhs: Box::new(lhs),
+        rhs: Box::new(rhs),
+        span,
+    }
+}
+
+// Evaluates an operator applied to two literals of the same type. Ints are JS numbers at
+// runtime, so int arithmetic is only folded when its result is an int, and float arithmetic
+// only when its result can be written as a float literal. The result is placed where its
+// lhs was, which is where the operation starts.
+fn fold_operator(op: &str, lhs: &ASTNode, rhs: &ASTNode) -> Option<ASTNode> {
+    let span = lhs.span();
+    let integer = |val| ASTNode::Integer(val, span);
+    match (lhs, rhs) {
+        (&ASTNode::Integer(a, _), &ASTNode::Integer(b, _)) => match op {
+            "+" => a.checked_add(b).map(integer),
+            "-" => a.checked_sub(b).map(integer),
+            "*" => a.checked_mul(b).map(integer),
+            // Division is never truncating, so it gives a float
+            "/" => float_literal(f64::from(a) / f64::from(b), span),
+            "%" => a.checked_rem(b).map(integer),
+            _ => compare(op, a.cmp(&b), span),
+        },
+        (&ASTNode::Float(a, _), &ASTNode::Float(b, _)) => {
+            let (a, b) = (js_value(a), js_value(b));
+            match op {
+                "+" => float_literal(a + b, span),
+                "-" => float_literal(a - b, span),
+                "*" => float_literal(a * b, span),
+                "/" => float_literal(a / b, span),
+                "%" => float_literal(a % b, span),
+                _ => compare(op, a.partial_cmp(&b)?, span),
+            }
+        }
+        // JS compares strings by their UTF-16 code units
+        (ASTNode::StringLiteral(a, _), ASTNode::StringLiteral(b, _)) => match op {
+            "+" => Some(ASTNode::StringLiteral(format!("{}{}", a, b), span)),
+            _ => compare(op, a.encode_utf16().cmp(b.encode_utf16()), span),
+        },
+        (&ASTNode::Boolean(a, _), &ASTNode::Boolean(b, _)) => match op {
+            "==" | "!=" => compare(op, a.cmp(&b), span),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn compare(op: &str, ordering: Ordering, span: Span) -> Option<ASTNode> {
+    let result = match op {
+        "==" => ordering == Ordering::Equal,
+        "!=" => ordering != Ordering::Equal,
+        "<" => ordering == Ordering::Less,
+        "<=" => ordering != Ordering::Greater,
+        ">" => ordering == Ordering::Greater,
+        ">=" => ordering != Ordering::Less,
+        _ => return None,
+    };
+
+    Some(ASTNode::Boolean(result, span))
+}
+
+// The number JS reads from a float literal, which is emitted in its shortest form
+fn js_value(val: f32) -> f64 {
+    val.to_string().parse().unwrap_or(f64::NAN)
+}
+
+// A float literal for a JS number, if one is emitted as exactly that number
+fn float_literal(val: f64, span: Span) -> Option<ASTNode> {
+    let literal = val as f32;
+    if val.is_finite() && js_value(literal) == val {
+        Some(ASTNode::Float(literal, span))
+    } else {
+        None
+    }
+}
+
+// Removes operations that leave their other operand unchanged. In a well-typed program, an
+// operand of an arithmetic operator has the same type as the other, so adding int 0 can
+// only be applied to an int, and adding "" to a string.
+fn simplify(op: &str, lhs: &ASTNode, rhs: &ASTNode) -> Option<ASTNode> {
+    let identity = |node: &ASTNode| match (op, node) {
+        ("+", &ASTNode::Integer(0, _)) => true,
+        ("+", ASTNode::StringLiteral(s, _)) => s.is_empty(),
+        ("*", &ASTNode::Integer(1, _)) => true,
+        ("*", &ASTNode::Float(f, _)) => f == 1.0,
+        _ => false,
+    };
+
+    if identity(rhs) || (op == "-" && matches!(*rhs, ASTNode::Integer(0, _))) {
+        Some(lhs.clone())
+    } else if identity(lhs) {
+        Some(rhs.clone())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use emitter;
+    use lexer;
+    use parser::Parser;
+
+    fn optimize_str(inp: &str, typed: bool) -> String {
+        optimize_at(inp, &Options { level: 2, typed })
+    }
+
+    fn optimize_at(inp: &str, options: &Options) -> String {
+        let mut parser = Parser {
+            lexer: lexer::Lexer::new(inp),
+        };
+        let ast = optimize(parser.parse_top_level().unwrap(), options);
+        emitter::emit(&ast, &emitter::Options::default()).unwrap()
+    }
+
+    #[test]
+    fn test_fold_arithmetic() {
+        assert_eq!(optimize_str("60 * 60 * 24", true), "86400");
+        assert_eq!(optimize_str("7 % 3 - 10", true), "-9");
+        assert_eq!(optimize_str("1.5 * 2.0 + 0.25", true), "3.25");
+        assert_eq!(optimize_str("\"a\" + \"b\"", true), "\"ab\"");
+
+        // Int division gives a float
+        assert_eq!(optimize_str("6 / 4", true), "1.5");
+        assert_eq!(optimize_str("6 / 3", true), "2");
+    }
+
+    #[test]
+    fn test_unrepresentable_results() {
+        assert_eq!(optimize_str("2147483647 + 1", true), "(2147483647 + 1)");
+        assert_eq!(optimize_str("1 / 3", true), "(1 / 3)");
+        assert_eq!(optimize_str("1 / 0", true), "(1 / 0)");
+        assert_eq!(optimize_str("1 % 0", true), "(1 % 0)");
+        assert_eq!(optimize_str("0.1 + 0.2", true), "(0.1 + 0.2)");
+
+        // Mixed types are left to JS
+        assert_eq!(optimize_str("1 + 1.5", true), "(1 + 1.5)");
+    }
+
+    #[test]
+    fn test_fold_comparisons() {
+        assert_eq!(optimize_str("1 < 2", true), "true");
+        assert_eq!(optimize_str("2.5 >= 3.0", true), "false");
+        assert_eq!(optimize_str("\"b\" > \"a\"", true), "true");
+        assert_eq!(optimize_str("true == false", true), "false");
+        assert_eq!(optimize_str("true != false", true), "true");
+    }
+
+    #[test]
+    fn test_fold_conditionals() {
+        assert_eq!(optimize_str("if 1 < 2 then a else b", true), "a");
+        assert_eq!(optimize_str("if false then a else b", true), "b");
+        assert_eq!(optimize_str("if false then a", true), "false");
+        assert_eq!(optimize_str("if 0 then a else b", true), "a");
+        assert_eq!(
+            optimize_str("f(if true then { g(); b } else c)", true),
+            "f((g(),b))"
+        );
+    }
+
+    #[test]
+    fn test_fold_logical_operators() {
+        assert_eq!(optimize_str("false || a", true), "a");
+        assert_eq!(optimize_str("0 || a", true), "0");
+        assert_eq!(optimize_str("a || false", true), "a");
+        assert_eq!(optimize_str("false && a", true), "false");
+        assert_eq!(optimize_str("true && a", true), "a");
+    }
+
+    #[test]
+    fn test_simplify_identities() {
+        assert_eq!(
+            optimize_str("fn f(x) { x * 1 + 0 }", true),
+            "(f = function f(x) { return (x) })"
+        );
+        assert_eq!(
+            optimize_str("fn f(x) { \"\" + x }", true),
+            "(f = function f(x) { return (x) })"
+        );
+        assert_eq!(
+            optimize_str("fn f(x) { x - 0 }", true),
+            "(f = function f(x) { return (x) })"
+        );
+
+        // x could be a string if the program hasn't been type checked
+        assert_eq!(
+            optimize_str("fn f(x) { x + 0 }", false),
+            "(f = function f(x) { return ((x + 0)) })"
+        );
+    }
+
+    #[test]
+    fn test_remove_pure_expressions() {
+        assert_eq!(optimize_str("1; x; \"a\" + y; f(); 2", true), "f(),2");
+        assert_eq!(optimize_str("fn (x) { x }; [a, 1]; 3", true), "3");
+
+        // Declarations, assignments and calls stay
+        assert_eq!(
+            optimize_str("fn f() { 1 }; x = 1; 2 + g(); 3", true),
+            "(f = function f() { return (1) }),(x = 1),(2 + g()),3"
+        );
+        assert_eq!(
+            optimize_str("fn f(x) { x; x + 1 }", true),
+            "(f = function f(x) { return ((x + 1)) })"
+        );
+    }
+
+    #[test]
+    fn test_prune_match_arms() {
+        let pruned = optimize_str("match x { A => 1, _ => 2, B => 3 }", true);
+        assert!(pruned.contains("2"));
+        assert!(!pruned.contains("3"));
+    }
+
+    #[test]
+    fn test_inline_functions() {
+        assert_eq!(
+            optimize_str("fn double(x) { x * 2 }; double(21)", true),
+            "(double = function double(x) { return ((x * 2)) }),42"
+        );
+
+        // Other arguments are evaluated once, before the body
+        assert_eq!(
+            optimize_str("fn square(x) { x * x }; square(f())", true),
+            "(square = function square(x) { return ((x * x)) }),\
+             (($inline0 = f()),($inline0 * $inline0))"
+        );
+    }
+
+    #[test]
+    fn test_functions_not_inlined() {
+        // Recursive, reassigned, binding and shadowed functions keep their calls
+        for inp in [
+            "fn f(n) { f(n - 1) }; f(1)",
+            "fn f(n) { n }; f = g; f(1)",
+            "fn f(n) { m = n; m }; f(1)",
+            "fn f(n) { n }; fn g(f) { f(1) }",
+            "fn f(n) { y }; fn g(y) { f(1) }",
+        ]
+        .iter()
+        {
+            assert!(optimize_str(inp, true).contains("f(1)"), "{}", inp);
+        }
+    }
+
+    #[test]
+    fn test_optimization_levels() {
+        let inp = "fn double(x) { x * 2 }; 1; double(2 + 3)";
+        assert_eq!(
+            optimize_at(inp, &Options::default()),
+            "(double = function double(x) { return ((x * 2)) }),1,double((2 + 3))"
+        );
+        assert_eq!(
+            optimize_at(
+                inp,
+                &Options {
+                    level: 1,
+                    typed: true
+                }
+            ),
+            "(double = function double(x) { return ((x * 2)) }),double(5)"
+        );
+        assert_eq!(
+            optimize_at(
+                inp,
+                &Options {
+                    level: 2,
+                    typed: true
+                }
+            ),
+            "(double = function double(x) { return ((x * 2)) }),10"
+        );
+    }
+}
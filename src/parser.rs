@@ -1,41 +1,375 @@
 use super::lexer;
 use super::lexer::Token;
 
-use super::util::Error;
+use super::util::{Error, Span};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum ASTNode {
-    Integer(i32),
-    Float(f32),
-    StringLiteral(String),
-    Boolean(bool),
+    Integer(i32, Span),
+    Float(f32, Span),
+    StringLiteral(String, Span),
+    Boolean(bool, Span),
 
-    Name(String),
+    Name(String, Span),
 
     Function {
         name: Box<Option<ASTNode>>,
         args: Vec<ASTNode>,
         body: Box<ASTNode>,
+        doc: Option<String>,
+        span: Span,
     },
 
     Invocation {
         func: Box<ASTNode>,
         args: Vec<ASTNode>,
+        span: Span,
     },
 
     Conditional {
         cond: Box<ASTNode>,
         if_body: Box<ASTNode>,
         else_body: Box<Option<ASTNode>>,
+        span: Span,
     },
 
     Binary {
         op: Token,
         lhs: Box<ASTNode>,
         rhs: Box<ASTNode>,
+        span: Span,
     },
 
     Sequence(Vec<ASTNode>),
+
+    List(Vec<ASTNode>, Span),
+
+    // Introduces a new binding in the enclosing scope. The name may be annotated.
+    Let {
+        name: Box<ASTNode>,
+        value: Box<ASTNode>,
+        span: Span,
+    },
+
+    // An expression (or parameter) with a type annotation, which has no runtime effect
+    Annotated {
+        expr: Box<ASTNode>,
+        annotation: TypeAnnotation,
+        span: Span,
+    },
+
+    // Declares an algebraic data type, binding a constructor for each variant
+    TypeDeclaration {
+        name: String,
+        variants: Vec<Variant>,
+        span: Span,
+    },
+
+    Match {
+        subject: Box<ASTNode>,
+        arms: Vec<MatchArm>,
+        span: Span,
+    },
+
+    // Binds a name to the exports of another module, whose path is relative to this one
+    Import {
+        path: String,
+        alias: String,
+        span: Span,
+    },
+
+    // A named function or let binding made visible to importing modules
+    Export {
+        decl: Box<ASTNode>,
+        span: Span,
+    },
+
+    // A reference to an export of an imported module, like `lib.name`
+    Qualified {
+        module: String,
+        name: String,
+        span: Span,
+    },
+
+    // Binds functions defined in JS, either globals or the exports of a JS module
+    Extern {
+        module: Option<String>,
+        functions: Vec<ExternFunction>,
+        span: Span,
+    },
+}
+
+// The JS name defaults to the Silver name
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExternFunction {
+    pub name: String,
+    pub args: Vec<ASTNode>,
+    pub ret: Option<TypeAnnotation>,
+    pub js_name: Option<String>,
+    pub span: Span,
+}
+
+// A variant's fields are bound like parameters, and may be annotated
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variant {
+    pub name: String,
+    pub fields: Vec<ASTNode>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pattern {
+    Constructor {
+        name: String,
+        bindings: Vec<ASTNode>,
+        span: Span,
+    },
+    Wildcard(Span),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatchArm {
+    pub pattern: Pattern,
+    pub body: ASTNode,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TypeAnnotation {
+    Named(String, Span),
+    Function(Vec<TypeAnnotation>, Box<TypeAnnotation>),
+    List(Box<TypeAnnotation>),
+}
+
+impl ASTNode {
+    // The name bound by a parameter or let, which may be annotated
+    pub fn binding(&self) -> Option<(&str, Span)> {
+        match *self {
+            ASTNode::Name(ref name, span) => Some((name.as_str(), span)),
+            ASTNode::Annotated { ref expr, .. } => expr.binding(),
+            _ => None,
+        }
+    }
+
+    // The name bound by a function declaration (which may be exported), if it has one
+    pub fn function_name(&self) -> Option<(&str, Span)> {
+        match *self {
+            ASTNode::Function { ref name, .. } => match **name {
+                Some(ref name) => name.binding(),
+                None => None,
+            },
+            ASTNode::Export { ref decl, .. } => decl.function_name(),
+            _ => None,
+        }
+    }
+
+    // The names an export makes visible to importing modules
+    pub fn exported_names(&self) -> Vec<(&str, Span)> {
+        match *self {
+            ASTNode::Export { ref decl, .. } => match **decl {
+                ASTNode::Let { ref name, .. } => name.binding().into_iter().collect(),
+                ASTNode::Extern { ref functions, .. } => functions
+                    .iter()
+                    .map(|function| (function.name.as_str(), function.span))
+                    .collect(),
+                _ => decl.function_name().into_iter().collect(),
+            },
+            _ => Vec::new(),
+        }
+    }
+
+    // The declaration an export wraps, or the node itself if it isn't an export
+    pub fn declaration(&self) -> &ASTNode {
+        match *self {
+            ASTNode::Export { ref decl, .. } => decl,
+            _ => self,
+        }
+    }
+
+    // The names a top-level declaration binds: a function's name, or each of an extern's
+    pub fn declared_names(&self) -> Vec<&str> {
+        match *self.declaration() {
+            ASTNode::Extern { ref functions, .. } => functions
+                .iter()
+                .map(|function| function.name.as_str())
+                .collect(),
+            _ => self
+                .function_name()
+                .map(|(name, _)| name)
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    // Rebuilds this node with f applied to each of the nodes nested in it that walk visits,
+    // and also to the Name lhs of `=`
+    pub fn map_children<F>(self, f: &mut F) -> ASTNode
+    where
+        F: FnMut(ASTNode) -> ASTNode,
+    {
+        match self {
+            ASTNode::Function {
+                name,
+                args,
+                body,
+                doc,
+                span,
+            } => ASTNode::Function {
+                name,
+                args,
+                body: Box::new(f(*body)),
+                doc,
+                span,
+            },
+            ASTNode::Invocation { func, args, span } => ASTNode::Invocation {
+                func: Box::new(f(*func)),
+                args: args.into_iter().map(&mut *f).collect(),
+                span,
+            },
+            ASTNode::Conditional {
+                cond,
+                if_body,
+                else_body,
+                span,
+            } => ASTNode::Conditional {
+                cond: Box::new(f(*cond)),
+                if_body: Box::new(f(*if_body)),
+                else_body: Box::new(else_body.map(&mut *f)),
+                span,
+            },
+            ASTNode::Binary { op, lhs, rhs, span } => ASTNode::Binary {
+                op,
+                lhs: Box::new(f(*lhs)),
+                rhs: Box::new(f(*rhs)),
+                span,
+            },
+            ASTNode::Sequence(exprs) => ASTNode::Sequence(exprs.into_iter().map(&mut *f).collect()),
+            ASTNode::List(elements, span) => {
+                ASTNode::List(elements.into_iter().map(&mut *f).collect(), span)
+            }
+            ASTNode::Let { name, value, span } => ASTNode::Let {
+                name,
+                value: Box::new(f(*value)),
+                span,
+            },
+            ASTNode::Annotated {
+                expr,
+                annotation,
+                span,
+            } => ASTNode::Annotated {
+                expr: Box::new(f(*expr)),
+                annotation,
+                span,
+            },
+            ASTNode::Match {
+                subject,
+                arms,
+                span,
+            } => ASTNode::Match {
+                subject: Box::new(f(*subject)),
+                arms: arms
+                    .into_iter()
+                    .map(|arm| MatchArm {
+                        pattern: arm.pattern,
+                        body: f(arm.body),
+                    })
+                    .collect(),
+                span,
+            },
+            ASTNode::Export { decl, span } => ASTNode::Export {
+                decl: Box::new(f(*decl)),
+                span,
+            },
+            node => node,
+        }
+    }
+
+    // Calls f on this node and each node nested in it that's evaluated as an expression.
+    // Names that are only bound, like parameters and assigned names, aren't visited.
+    pub fn walk<'a, F>(&'a self, f: &mut F)
+    where
+        F: FnMut(&'a ASTNode),
+    {
+        f(self);
+        match *self {
+            ASTNode::Function { ref body, .. } => body.walk(f),
+            ASTNode::Invocation {
+                ref func, ref args, ..
+            } => {
+                func.walk(f);
+                for arg in args.iter() {
+                    arg.walk(f);
+                }
+            }
+            ASTNode::Conditional {
+                ref cond,
+                ref if_body,
+                ref else_body,
+                ..
+            } => {
+                cond.walk(f);
+                if_body.walk(f);
+                if let Some(ref else_body) = **else_body {
+                    else_body.walk(f);
+                }
+            }
+            ASTNode::Binary {
+                ref op,
+                ref lhs,
+                ref rhs,
+                ..
+            } => {
+                match **lhs {
+                    ASTNode::Name(..) if *op == Token::Operator(String::from("=")) => {}
+                    _ => lhs.walk(f),
+                }
+                rhs.walk(f);
+            }
+            ASTNode::Sequence(ref exprs) | ASTNode::List(ref exprs, _) => {
+                for expr in exprs.iter() {
+                    expr.walk(f);
+                }
+            }
+            ASTNode::Let { ref value, .. } => value.walk(f),
+            ASTNode::Annotated { ref expr, .. } => expr.walk(f),
+            ASTNode::Match {
+                ref subject,
+                ref arms,
+                ..
+            } => {
+                subject.walk(f);
+                for arm in arms.iter() {
+                    arm.body.walk(f);
+                }
+            }
+            ASTNode::Export { ref decl, .. } => decl.walk(f),
+            _ => {}
+        }
+    }
+
+    // Sequences don't record where they start, so they have a default span
+    pub fn span(&self) -> Span {
+        match *self {
+            ASTNode::Integer(_, span)
+            | ASTNode::Float(_, span)
+            | ASTNode::StringLiteral(_, span)
+            | ASTNode::Boolean(_, span)
+            | ASTNode::Name(_, span)
+            | ASTNode::List(_, span)
+            | ASTNode::Function { span, .. }
+            | ASTNode::Invocation { span, .. }
+            | ASTNode::Conditional { span, .. }
+            | ASTNode::Binary { span, .. }
+            | ASTNode::Let { span, .. }
+            | ASTNode::Annotated { span, .. }
+            | ASTNode::TypeDeclaration { span, .. }
+            | ASTNode::Match { span, .. }
+            | ASTNode::Import { span, .. }
+            | ASTNode::Export { span, .. }
+            | ASTNode::Qualified { span, .. }
+            | ASTNode::Extern { span, .. } => span,
+            _ => Span::default(),
+        }
+    }
 }
 
 pub struct Parser<'a> {
@@ -124,6 +458,7 @@ impl<'a> Parser<'a> {
     }
 
     fn parse_conditional(&mut self) -> Result<ASTNode, Error> {
+        let span = self.lexer.peek_span()?;
         self.consume(Token::Keyword(String::from("if")))?;
 
         let condition = self.parse_expression()?;
@@ -158,6 +493,7 @@ impl<'a> Parser<'a> {
             cond: Box::new(condition),
             if_body: Box::new(if_body),
             else_body: Box::new(else_body),
+            span,
         })
     }
 
@@ -175,19 +511,55 @@ impl<'a> Parser<'a> {
                 exp
             }
             Token::Delimiter('{') => self.parse_sequence(),
+            Token::Delimiter('[') => {
+                let span = self.lexer.peek_span()?;
+                let elements = self.parse_delimited(
+                    Token::Delimiter('['),
+                    Token::Delimiter(','),
+                    Token::Delimiter(']'),
+                    Self::parse_expression,
+                )?;
+                Ok(ASTNode::List(elements, span))
+            }
+            Token::DocComment(_) => self.parse_documented_declaration(),
             Token::Keyword(ref kw) => match kw.as_str() {
                 "if" => self.parse_conditional(),
                 "true" | "false" => self.parse_bool(),
-                "fn" => self.parse_declaration(),
+                "fn" => self.parse_declaration(None),
+                "let" => self.parse_let(),
+                "type" => self.parse_type_declaration(),
+                "match" => self.parse_match(),
+                "import" => self.parse_import(),
+                "export" => self.parse_export(),
+                "extern" => self.parse_extern(),
                 _ => Err(self.lexer.get_error(format!("Unexpected keyword {}", kw))),
             },
             _ => {
                 let next = self.lexer.get_token();
                 match next? {
-                    Token::Variable(ref name) => Ok(ASTNode::Name(name.clone())),
-                    Token::Integral(val) => Ok(ASTNode::Integer(val)),
-                    Token::FloatingPoint(val) => Ok(ASTNode::Float(val)),
-                    Token::StringLiteral(ref val) => Ok(ASTNode::StringLiteral(val.clone())),
+                    Token::Variable(ref name) => {
+                        let span = self.lexer.span();
+                        if Token::Delimiter('.') != self.lexer.peek()? {
+                            return Ok(ASTNode::Name(name.clone(), span));
+                        }
+
+                        self.consume(Token::Delimiter('.'))?;
+                        match self.lexer.get_token()? {
+                            Token::Variable(member) => Ok(ASTNode::Qualified {
+                                module: name.clone(),
+                                name: member,
+                                span,
+                            }),
+                            e => Err(self
+                                .lexer
+                                .get_error(format!("Expected name after ., got {:?}", e))),
+                        }
+                    }
+                    Token::Integral(val) => Ok(ASTNode::Integer(val, self.lexer.span())),
+                    Token::FloatingPoint(val) => Ok(ASTNode::Float(val, self.lexer.span())),
+                    Token::StringLiteral(ref val) => {
+                        Ok(ASTNode::StringLiteral(val.clone(), self.lexer.span()))
+                    }
                     _ => Err(self.lexer
                         .get_error(String::from("Unexpected element in parse_atom"))),
                 }
@@ -200,10 +572,18 @@ impl<'a> Parser<'a> {
     fn parse_binary(&mut self, lhs: ASTNode, lhs_prec: u32) -> Result<ASTNode, Error> {
         let next = self.lexer.peek();
         if let Token::Operator(ref op) = next? {
-            self.lexer.get_token()?; // advance
+            let rhs_prec = match Self::get_precedence(op) {
+                Some(prec) => prec,
+                None => {
+                    return Err(self.lexer
+                        .get_error(format!("Unknown operator {} on binary expression", op)))
+                }
+            };
 
-            let rhs_prec = Self::get_precedence(op);
             if rhs_prec > lhs_prec {
+                self.lexer.get_token()?; // advance past the operator
+                let span = self.lexer.span();
+
                 // Parse the next atom, which follows the rhs operator
                 let next_atom = self.parse_atom()?;
                 // Parse for subsequent binary. Either left has higher precedence, or we
@@ -215,6 +595,7 @@ impl<'a> Parser<'a> {
                         op: Token::Operator(op.clone()),
                         lhs: Box::new(lhs),
                         rhs: Box::new(next_binary),
+                        span,
                     },
                     lhs_prec,
                 );
@@ -226,14 +607,52 @@ impl<'a> Parser<'a> {
         Ok(lhs)
     }
 
-    fn parse_declaration(&mut self) -> Result<ASTNode, Error> {
+    // Consecutive doc comment lines are joined and attached to the declaration that follows
+    fn parse_documented_declaration(&mut self) -> Result<ASTNode, Error> {
+        let mut lines: Vec<String> = Vec::new();
+
+        while let Token::DocComment(line) = self.lexer.peek()? {
+            self.lexer.get_token()?;
+            lines.push(line);
+        }
+
+        // Exported functions are documented before the export keyword
+        if self.lexer.peek()? == Token::Keyword(String::from("export")) {
+            let span = self.lexer.peek_span()?;
+            self.consume(Token::Keyword(String::from("export")))?;
+            let export = ASTNode::Export {
+                decl: Box::new(self.parse_documented_function(lines)?),
+                span,
+            };
+
+            if export.exported_names().is_empty() {
+                return Err(span.get_error(String::from("Exported functions must be named")));
+            }
+            return Ok(export);
+        }
+
+        self.parse_documented_function(lines)
+    }
+
+    fn parse_documented_function(&mut self, lines: Vec<String>) -> Result<ASTNode, Error> {
+        if self.lexer.peek()? != Token::Keyword(String::from("fn")) {
+            return Err(self.lexer.get_error(String::from(
+                "Doc comment must be followed by a function declaration",
+            )));
+        }
+
+        self.parse_declaration(Some(lines.join("\n")))
+    }
+
+    fn parse_declaration(&mut self, doc: Option<String>) -> Result<ASTNode, Error> {
+        let span = self.lexer.peek_span()?;
         self.consume(Token::Keyword(String::from("fn")))?;
 
         Ok(ASTNode::Function {
             name: Box::new(match self.lexer.peek()? {
                 Token::Variable(ref name) => {
                     self.lexer.get_token()?; // Consume the name
-                    Some(ASTNode::Name(name.clone()))
+                    Some(ASTNode::Name(name.clone(), self.lexer.span()))
                 }
                 _ => None,
             }),
@@ -245,15 +664,327 @@ impl<'a> Parser<'a> {
                     Self::parse_variable_name,
                 )?
             },
-            body: Box::new(self.parse_sequence()?),
+            body: Box::new({
+                // A return type annotation applies to the body
+                if Token::Delimiter(':') == self.lexer.peek()? {
+                    self.consume(Token::Delimiter(':'))?;
+                    let annotation_span = self.lexer.peek_span()?;
+                    let annotation = self.parse_type()?;
+
+                    ASTNode::Annotated {
+                        expr: Box::new(self.parse_sequence()?),
+                        annotation,
+                        span: annotation_span,
+                    }
+                } else {
+                    self.parse_sequence()?
+                }
+            }),
+            doc,
+            span,
+        })
+    }
+
+    fn parse_let(&mut self) -> Result<ASTNode, Error> {
+        let span = self.lexer.peek_span()?;
+        self.consume(Token::Keyword(String::from("let")))?;
+
+        let name = self.parse_variable_name()?;
+        self.consume(Token::Operator(String::from("=")))?;
+
+        Ok(ASTNode::Let {
+            name: Box::new(name),
+            value: Box::new(self.parse_expression()?),
+            span,
+        })
+    }
+
+    fn parse_import(&mut self) -> Result<ASTNode, Error> {
+        let span = self.lexer.peek_span()?;
+        self.consume(Token::Keyword(String::from("import")))?;
+
+        let path = match self.lexer.get_token()? {
+            Token::StringLiteral(path) => path,
+            e => {
+                return Err(self
+                    .lexer
+                    .get_error(format!("Expected module path, got {:?}", e)))
+            }
+        };
+        self.consume(Token::Keyword(String::from("as")))?;
+
+        match self.lexer.get_token()? {
+            Token::Variable(alias) => Ok(ASTNode::Import { path, alias, span }),
+            e => Err(self
+                .lexer
+                .get_error(format!("Expected module name, got {:?}", e))),
+        }
+    }
+
+    fn parse_export(&mut self) -> Result<ASTNode, Error> {
+        let span = self.lexer.peek_span()?;
+        self.consume(Token::Keyword(String::from("export")))?;
+
+        let decl = match self.lexer.peek()? {
+            Token::Keyword(ref kw) if kw == "fn" => self.parse_declaration(None)?,
+            Token::Keyword(ref kw) if kw == "let" => self.parse_let()?,
+            Token::Keyword(ref kw) if kw == "extern" => self.parse_extern()?,
+            e => {
+                return Err(self.lexer.get_error(format!(
+                    "Expected function, let binding or extern after export, got {:?}",
+                    e
+                )))
+            }
+        };
+
+        let export = ASTNode::Export {
+            decl: Box::new(decl),
+            span,
+        };
+        if export.exported_names().is_empty() {
+            return Err(span.get_error(String::from("Exported functions must be named")));
+        }
+        Ok(export)
+    }
+
+    // Functions from a JS module are declared in braces, separated by semicolons
+    fn parse_extern(&mut self) -> Result<ASTNode, Error> {
+        let span = self.lexer.peek_span()?;
+        self.consume(Token::Keyword(String::from("extern")))?;
+
+        let module = match self.lexer.peek()? {
+            Token::StringLiteral(module) => {
+                self.lexer.get_token()?;
+                module
+            }
+            _ => {
+                return Ok(ASTNode::Extern {
+                    module: None,
+                    functions: vec![self.parse_extern_function()?],
+                    span,
+                })
+            }
+        };
+
+        self.consume(Token::Delimiter('{'))?;
+        let mut functions = Vec::new();
+        while self.lexer.peek()? != Token::Delimiter('}') {
+            if !functions.is_empty() {
+                self.consume(Token::Delimiter(';'))?;
+                if self.lexer.peek()? == Token::Delimiter('}') {
+                    break;
+                }
+            }
+            functions.push(self.parse_extern_function()?);
+        }
+        self.consume(Token::Delimiter('}'))?;
+
+        Ok(ASTNode::Extern {
+            module: Some(module),
+            functions,
+            span,
+        })
+    }
+
+    fn parse_extern_function(&mut self) -> Result<ExternFunction, Error> {
+        self.consume(Token::Keyword(String::from("fn")))?;
+        let (name, span) = match self.lexer.get_token()? {
+            Token::Variable(name) => (name, self.lexer.span()),
+            e => {
+                return Err(self.lexer
+                    .get_error(format!("Expected function name, got {:?}", e)))
+            }
+        };
+
+        let args = self.parse_delimited(
+            Token::Delimiter('('),
+            Token::Delimiter(','),
+            Token::Delimiter(')'),
+            Self::parse_variable_name,
+        )?;
+
+        let ret = if Token::Delimiter(':') == self.lexer.peek()? {
+            self.consume(Token::Delimiter(':'))?;
+            Some(self.parse_type()?)
+        } else {
+            None
+        };
+
+        let js_name = if Token::Operator(String::from("=")) == self.lexer.peek()? {
+            self.consume(Token::Operator(String::from("=")))?;
+            match self.lexer.get_token()? {
+                Token::StringLiteral(js_name) => Some(js_name),
+                e => {
+                    return Err(self.lexer
+                        .get_error(format!("Expected JS name, got {:?}", e)))
+                }
+            }
+        } else {
+            None
+        };
+
+        Ok(ExternFunction {
+            name,
+            args,
+            ret,
+            js_name,
+            span,
+        })
+    }
+
+    fn parse_type_declaration(&mut self) -> Result<ASTNode, Error> {
+        let span = self.lexer.peek_span()?;
+        self.consume(Token::Keyword(String::from("type")))?;
+
+        let name = match self.lexer.get_token()? {
+            Token::Variable(name) => name,
+            e => {
+                return Err(self.lexer
+                    .get_error(format!("Expected type name, got {:?}", e)))
+            }
+        };
+        self.consume(Token::Operator(String::from("=")))?;
+
+        let mut variants = vec![self.parse_variant()?];
+        while Token::Operator(String::from("|")) == self.lexer.peek()? {
+            self.lexer.get_token()?;
+            variants.push(self.parse_variant()?);
+        }
+
+        Ok(ASTNode::TypeDeclaration {
+            name,
+            variants,
+            span,
+        })
+    }
+
+    fn parse_variant(&mut self) -> Result<Variant, Error> {
+        let (name, span) = self.parse_constructor_name()?;
+
+        let fields = if Token::Delimiter('(') == self.lexer.peek()? {
+            self.parse_delimited(
+                Token::Delimiter('('),
+                Token::Delimiter(','),
+                Token::Delimiter(')'),
+                Self::parse_variable_name,
+            )?
+        } else {
+            Vec::new()
+        };
+
+        Ok(Variant { name, fields, span })
+    }
+
+    fn parse_constructor_name(&mut self) -> Result<(String, Span), Error> {
+        match self.lexer.get_token()? {
+            Token::Variable(name) => Ok((name, self.lexer.span())),
+            e => Err(self.lexer
+                .get_error(format!("Expected constructor, got {:?}", e))),
+        }
+    }
+
+    // Arms are separated by commas, and a trailing comma is allowed
+    fn parse_match(&mut self) -> Result<ASTNode, Error> {
+        let span = self.lexer.peek_span()?;
+        self.consume(Token::Keyword(String::from("match")))?;
+
+        let subject = self.parse_expression()?;
+        self.consume(Token::Delimiter('{'))?;
+
+        let mut arms = Vec::new();
+        while self.lexer.peek()? != Token::Delimiter('}') {
+            if !arms.is_empty() {
+                self.consume(Token::Delimiter(','))?;
+                if self.lexer.peek()? == Token::Delimiter('}') {
+                    break;
+                }
+            }
+            arms.push(self.parse_match_arm()?);
+        }
+        self.consume(Token::Delimiter('}'))?;
+
+        Ok(ASTNode::Match {
+            subject: Box::new(subject),
+            arms,
+            span,
         })
     }
 
+    fn parse_match_arm(&mut self) -> Result<MatchArm, Error> {
+        let (name, span) = self.parse_constructor_name()?;
+
+        let pattern = if name == "_" {
+            Pattern::Wildcard(span)
+        } else {
+            Pattern::Constructor {
+                name,
+                bindings: if Token::Delimiter('(') == self.lexer.peek()? {
+                    self.parse_delimited(
+                        Token::Delimiter('('),
+                        Token::Delimiter(','),
+                        Token::Delimiter(')'),
+                        Self::parse_variable_name,
+                    )?
+                } else {
+                    Vec::new()
+                },
+                span,
+            }
+        };
+        self.consume(Token::Operator(String::from("=>")))?;
+
+        Ok(MatchArm {
+            pattern,
+            body: self.parse_expression()?,
+        })
+    }
+
+    // Parses a type, either a name like `Int` or `a`, or a function type like `(Int) -> a`
+    fn parse_type(&mut self) -> Result<TypeAnnotation, Error> {
+        match self.lexer.peek()? {
+            Token::Delimiter('(') => {
+                let args = self.parse_delimited_types()?;
+                self.consume(Token::Operator(String::from("->")))?;
+
+                Ok(TypeAnnotation::Function(args, Box::new(self.parse_type()?)))
+            }
+            Token::Delimiter('[') => {
+                self.consume(Token::Delimiter('['))?;
+                let element = self.parse_type()?;
+                self.consume(Token::Delimiter(']'))?;
+
+                Ok(TypeAnnotation::List(Box::new(element)))
+            }
+            Token::Variable(ref name) => {
+                self.lexer.get_token()?;
+                Ok(TypeAnnotation::Named(name.clone(), self.lexer.span()))
+            }
+            e => Err(self.lexer.get_error(format!("Expected type, got {:?}", e))),
+        }
+    }
+
+    // parse_delimited is specialised to nodes, so argument types are gathered here
+    fn parse_delimited_types(&mut self) -> Result<Vec<TypeAnnotation>, Error> {
+        self.consume(Token::Delimiter('('))?;
+
+        let mut types = Vec::new();
+        while self.lexer.peek()? != Token::Delimiter(')') {
+            if !types.is_empty() {
+                self.consume(Token::Delimiter(','))?;
+            }
+            types.push(self.parse_type()?);
+        }
+
+        self.consume(Token::Delimiter(')'))?;
+        Ok(types)
+    }
+
     // Returns either an invocation or an expression, depending on what follows
     fn parse_inv_or_expr<F>(&mut self, parse_function: F) -> Result<ASTNode, Error>
     where
         F: Fn(&mut Parser<'a>) -> Result<ASTNode, Error>,
     {
+        let span = self.lexer.peek_span()?;
         let expr = parse_function(self);
 
         if Token::Delimiter('(') == self.lexer.peek()? {
@@ -265,25 +996,42 @@ impl<'a> Parser<'a> {
                     Token::Delimiter(')'),
                     Self::parse_expression,
                 )?,
+                span,
             });
         }
 
         expr
     }
 
+    // Parses a name being bound, along with its type annotation if there is one
     fn parse_variable_name(&mut self) -> Result<ASTNode, Error> {
-        match self.lexer.get_token()? {
-            Token::Variable(ref name) => Ok(ASTNode::Name(name.clone())),
-            e => Err(self.lexer
-                .get_error(format!("Expected type variable, got {:?}", e))),
+        let name = match self.lexer.get_token()? {
+            Token::Variable(ref name) => ASTNode::Name(name.clone(), self.lexer.span()),
+            e => {
+                return Err(self.lexer
+                    .get_error(format!("Expected type variable, got {:?}", e)))
+            }
+        };
+
+        if Token::Delimiter(':') == self.lexer.peek()? {
+            self.consume(Token::Delimiter(':'))?;
+            let span = self.lexer.peek_span()?;
+
+            return Ok(ASTNode::Annotated {
+                expr: Box::new(name),
+                annotation: self.parse_type()?,
+                span,
+            });
         }
+
+        Ok(name)
     }
 
     fn parse_bool(&mut self) -> Result<ASTNode, Error> {
         match self.lexer.get_token()? {
             Token::Keyword(ref val) => match val.as_str() {
-                "true" => Ok(ASTNode::Boolean(true)),
-                "false" => Ok(ASTNode::Boolean(false)),
+                "true" => Ok(ASTNode::Boolean(true, self.lexer.span())),
+                "false" => Ok(ASTNode::Boolean(false, self.lexer.span())),
                 e => Err(self.lexer
                     .get_error(format!("Expected type boolean, got {:?}", e))),
             },
@@ -293,6 +1041,7 @@ impl<'a> Parser<'a> {
     }
 
     fn parse_sequence(&mut self) -> Result<ASTNode, Error> {
+        let span = self.lexer.peek_span()?;
         let sequence = self.parse_delimited(
             Token::Delimiter('{'),
             Token::Delimiter(';'),
@@ -301,21 +1050,21 @@ impl<'a> Parser<'a> {
         )?;
 
         match sequence.len() {
-            0 => Ok(ASTNode::Boolean(false)), // empty sequences are falsey
+            0 => Ok(ASTNode::Boolean(false, span)), // empty sequences are falsey
             1 => Ok(sequence[0].clone()),
             _ => Ok(ASTNode::Sequence(sequence)),
         }
     }
 
-    fn get_precedence(op: &str) -> u32 {
+    fn get_precedence(op: &str) -> Option<u32> {
         match op {
-            "=" => 1,
-            "||" => 2,
-            "&&" => 3,
-            "<" | "<=" | ">" | ">=" | "==" | "!=" => 4,
-            "+" | "-" => 5,
-            "*" | "/" | "%" => 6,
-            _ => panic!("Unexpected operator on binary ASTNode"),
+            "=" => Some(1),
+            "||" => Some(2),
+            "&&" => Some(3),
+            "<" | "<=" | ">" | ">=" | "==" | "!=" => Some(4),
+            "+" | "-" => Some(5),
+            "*" | "/" | "%" => Some(6),
+            _ => None,
         }
     }
 }
@@ -325,10 +1074,139 @@ mod tests {
 
     use super::*;
 
+    // Parsed nodes record where they start, which the expected trees below don't spell
+    // out, so spans are reset before comparing
+    fn without_spans(node: ASTNode) -> ASTNode {
+        let strip_all = |nodes: Vec<ASTNode>| nodes.into_iter().map(without_spans).collect();
+        let span = Span::default();
+
+        match node {
+            ASTNode::Name(name, _) => ASTNode::Name(name, span),
+            ASTNode::Function {
+                name,
+                args,
+                body,
+                doc,
+                ..
+            } => ASTNode::Function {
+                name: Box::new(name.map(without_spans)),
+                args: strip_all(args),
+                body: Box::new(without_spans(*body)),
+                doc,
+                span,
+            },
+            ASTNode::Invocation { func, args, .. } => ASTNode::Invocation {
+                func: Box::new(without_spans(*func)),
+                args: strip_all(args),
+                span,
+            },
+            ASTNode::Conditional {
+                cond,
+                if_body,
+                else_body,
+                ..
+            } => ASTNode::Conditional {
+                cond: Box::new(without_spans(*cond)),
+                if_body: Box::new(without_spans(*if_body)),
+                else_body: Box::new(else_body.map(without_spans)),
+                span,
+            },
+            ASTNode::Binary { op, lhs, rhs, .. } => ASTNode::Binary {
+                op,
+                lhs: Box::new(without_spans(*lhs)),
+                rhs: Box::new(without_spans(*rhs)),
+                span,
+            },
+            ASTNode::Sequence(exprs) => ASTNode::Sequence(strip_all(exprs)),
+            ASTNode::Integer(val, _) => ASTNode::Integer(val, span),
+            ASTNode::Float(val, _) => ASTNode::Float(val, span),
+            ASTNode::StringLiteral(val, _) => ASTNode::StringLiteral(val, span),
+            ASTNode::Boolean(val, _) => ASTNode::Boolean(val, span),
+            ASTNode::List(elements, _) => ASTNode::List(strip_all(elements), span),
+            ASTNode::Let { name, value, .. } => ASTNode::Let {
+                name: Box::new(without_spans(*name)),
+                value: Box::new(without_spans(*value)),
+                span,
+            },
+            ASTNode::Annotated {
+                expr, annotation, ..
+            } => ASTNode::Annotated {
+                expr: Box::new(without_spans(*expr)),
+                annotation: annotation_without_spans(annotation),
+                span,
+            },
+            ASTNode::TypeDeclaration { name, variants, .. } => ASTNode::TypeDeclaration {
+                name,
+                variants: variants
+                    .into_iter()
+                    .map(|variant| Variant {
+                        name: variant.name,
+                        fields: strip_all(variant.fields),
+                        span,
+                    })
+                    .collect(),
+                span,
+            },
+            ASTNode::Match { subject, arms, .. } => ASTNode::Match {
+                subject: Box::new(without_spans(*subject)),
+                arms: arms
+                    .into_iter()
+                    .map(|arm| MatchArm {
+                        pattern: match arm.pattern {
+                            Pattern::Constructor { name, bindings, .. } => Pattern::Constructor {
+                                name,
+                                bindings: strip_all(bindings),
+                                span,
+                            },
+                            Pattern::Wildcard(_) => Pattern::Wildcard(span),
+                        },
+                        body: without_spans(arm.body),
+                    })
+                    .collect(),
+                span,
+            },
+            ASTNode::Import { path, alias, .. } => ASTNode::Import { path, alias, span },
+            ASTNode::Export { decl, .. } => ASTNode::Export {
+                decl: Box::new(without_spans(*decl)),
+                span,
+            },
+            ASTNode::Qualified { module, name, .. } => ASTNode::Qualified { module, name, span },
+            ASTNode::Extern {
+                module, functions, ..
+            } => ASTNode::Extern {
+                module,
+                functions: functions
+                    .into_iter()
+                    .map(|function| ExternFunction {
+                        name: function.name,
+                        args: strip_all(function.args),
+                        ret: function.ret.map(annotation_without_spans),
+                        js_name: function.js_name,
+                        span,
+                    })
+                    .collect(),
+                span,
+            },
+        }
+    }
+
+    fn annotation_without_spans(annotation: TypeAnnotation) -> TypeAnnotation {
+        match annotation {
+            TypeAnnotation::Named(name, _) => TypeAnnotation::Named(name, Span::default()),
+            TypeAnnotation::Function(args, ret) => TypeAnnotation::Function(
+                args.into_iter().map(annotation_without_spans).collect(),
+                Box::new(annotation_without_spans(*ret)),
+            ),
+            TypeAnnotation::List(element) => {
+                TypeAnnotation::List(Box::new(annotation_without_spans(*element)))
+            }
+        }
+    }
+
     #[test]
     fn test_parse_empty() {
         let inp = "";
-        let mut lexer = lexer::Lexer::new(inp);
+        let lexer = lexer::Lexer::new(inp);
         let mut parser = Parser { lexer };
 
         if let Ok(ASTNode::Sequence(ref vec)) = parser.parse_top_level() {
@@ -341,19 +1219,19 @@ mod tests {
     #[test]
     fn test_parse_primative_sequence() {
         let inp = "3; 3.1; \"stringliteralwow\"; true; false";
-        let mut lexer = lexer::Lexer::new(inp);
+        let lexer = lexer::Lexer::new(inp);
         let mut parser = Parser { lexer };
 
         let expected: ASTNode = ASTNode::Sequence(vec![
-            ASTNode::Integer(3),
-            ASTNode::Float(3.1),
-            ASTNode::StringLiteral(String::from("stringliteralwow")),
-            ASTNode::Boolean(true),
-            ASTNode::Boolean(false),
+            ASTNode::Integer(3, Span::default()),
+            ASTNode::Float(3.1, Span::default()),
+            ASTNode::StringLiteral(String::from("stringliteralwow"), Span::default()),
+            ASTNode::Boolean(true, Span::default()),
+            ASTNode::Boolean(false, Span::default()),
         ]);
 
         if let Ok(res) = parser.parse_top_level() {
-            assert_eq!(res, expected);
+            assert_eq!(without_spans(res), expected);
         } else {
             panic!("Primative sequence failed to parse");
         }
@@ -363,17 +1241,17 @@ mod tests {
     fn test_parse_name_sequence() {
         // Lots of extra whitespace, and none
         let inp = "foo;          bar;baz";
-        let mut lexer = lexer::Lexer::new(inp);
+        let lexer = lexer::Lexer::new(inp);
         let mut parser = Parser { lexer };
 
         let expected = ASTNode::Sequence(vec![
-            ASTNode::Name(String::from("foo")),
-            ASTNode::Name(String::from("bar")),
-            ASTNode::Name(String::from("baz")),
+            ASTNode::Name(String::from("foo"), Span::default()),
+            ASTNode::Name(String::from("bar"), Span::default()),
+            ASTNode::Name(String::from("baz"), Span::default()),
         ]);
 
         if let Ok(res) = parser.parse_top_level() {
-            assert_eq!(res, expected);
+            assert_eq!(without_spans(res), expected);
         } else {
             panic!("Name failed to parse");
         }
@@ -382,19 +1260,20 @@ mod tests {
     #[test]
     fn test_parse_if() {
         let inp = "if x then y";
-        let mut lexer = lexer::Lexer::new(inp);
+        let lexer = lexer::Lexer::new(inp);
         let mut parser = Parser { lexer };
 
         let expected = ASTNode::Sequence(vec![
             ASTNode::Conditional {
-                cond: Box::new(ASTNode::Name(String::from("x"))),
-                if_body: Box::new(ASTNode::Name(String::from("y"))),
+                cond: Box::new(ASTNode::Name(String::from("x"), Span::default())),
+                if_body: Box::new(ASTNode::Name(String::from("y"), Span::default())),
                 else_body: Box::new(None),
+                span: Span::default(),
             },
         ]);
 
         if let Ok(res) = parser.parse_top_level() {
-            assert_eq!(res, expected);
+            assert_eq!(without_spans(res), expected);
         } else {
             panic!("Conditional failed to parse");
         }
@@ -403,22 +1282,23 @@ mod tests {
     #[test]
     fn test_parse_invocation() {
         let inp = "x(a,b,   c)";
-        let mut lexer = lexer::Lexer::new(inp);
+        let lexer = lexer::Lexer::new(inp);
         let mut parser = Parser { lexer };
 
         let expected = ASTNode::Sequence(vec![
             ASTNode::Invocation {
-                func: Box::new(ASTNode::Name(String::from("x"))),
+                func: Box::new(ASTNode::Name(String::from("x"), Span::default())),
                 args: vec![
-                    ASTNode::Name(String::from("a")),
-                    ASTNode::Name(String::from("b")),
-                    ASTNode::Name(String::from("c")),
+                    ASTNode::Name(String::from("a"), Span::default()),
+                    ASTNode::Name(String::from("b"), Span::default()),
+                    ASTNode::Name(String::from("c"), Span::default()),
                 ],
+                span: Span::default(),
             },
         ]);
 
         if let Ok(res) = parser.parse_top_level() {
-            assert_eq!(res, expected);
+            assert_eq!(without_spans(res), expected);
         } else {
             panic!("Invocation failed to parse");
         }
@@ -427,50 +1307,118 @@ mod tests {
     #[test]
     fn test_parse_simple_binary() {
         let inp = "x = y";
-        let mut lexer = lexer::Lexer::new(inp);
+        let lexer = lexer::Lexer::new(inp);
         let mut parser = Parser { lexer };
 
         let expected = ASTNode::Sequence(vec![
             ASTNode::Binary {
                 op: lexer::Token::Operator(String::from("=")),
-                lhs: Box::new(ASTNode::Name(String::from("x"))),
-                rhs: Box::new(ASTNode::Name(String::from("y"))),
+                lhs: Box::new(ASTNode::Name(String::from("x"), Span::default())),
+                rhs: Box::new(ASTNode::Name(String::from("y"), Span::default())),
+                span: Span::default(),
             },
         ]);
 
         if let Ok(res) = parser.parse_top_level() {
-            assert_eq!(res, expected);
+            assert_eq!(without_spans(res), expected);
         } else {
-            panic!(format!("Simple binary failed to parse"));
+            panic!("Simple binary failed to parse");
         }
     }
 
     #[test]
     fn test_parse_complex_parenthesized_binary() {
         let inp = "a = (b + c) * d";
-        let mut lexer = lexer::Lexer::new(inp);
+        let lexer = lexer::Lexer::new(inp);
         let mut parser = Parser { lexer };
 
         let expected = ASTNode::Sequence(vec![
             ASTNode::Binary {
                 op: lexer::Token::Operator(String::from("=")),
-                lhs: Box::new(ASTNode::Name(String::from("a"))),
+                lhs: Box::new(ASTNode::Name(String::from("a"), Span::default())),
                 rhs: Box::new(ASTNode::Binary {
                     op: lexer::Token::Operator(String::from("*")),
                     lhs: Box::new(ASTNode::Binary {
                         op: lexer::Token::Operator(String::from("+")),
-                        lhs: Box::new(ASTNode::Name(String::from("b"))),
-                        rhs: Box::new(ASTNode::Name(String::from("c"))),
+                        lhs: Box::new(ASTNode::Name(String::from("b"), Span::default())),
+                        rhs: Box::new(ASTNode::Name(String::from("c"), Span::default())),
+                        span: Span::default(),
                     }),
-                    rhs: Box::new(ASTNode::Name(String::from("d"))),
+                    rhs: Box::new(ASTNode::Name(String::from("d"), Span::default())),
+                    span: Span::default(),
+                }),
+                span: Span::default(),
+            },
+        ]);
+
+        if let Ok(res) = parser.parse_top_level() {
+            assert_eq!(without_spans(res), expected);
+        } else {
+            panic!("Simple binary failed to parse");
+        }
+    }
+
+    #[test]
+    fn test_parse_logical_binary() {
+        let inp = "a || b && c";
+        let lexer = lexer::Lexer::new(inp);
+        let mut parser = Parser { lexer };
+
+        let expected = ASTNode::Sequence(vec![
+            ASTNode::Binary {
+                op: lexer::Token::Operator(String::from("||")),
+                lhs: Box::new(ASTNode::Name(String::from("a"), Span::default())),
+                rhs: Box::new(ASTNode::Binary {
+                    op: lexer::Token::Operator(String::from("&&")),
+                    lhs: Box::new(ASTNode::Name(String::from("b"), Span::default())),
+                    rhs: Box::new(ASTNode::Name(String::from("c"), Span::default())),
+                    span: Span::default(),
+                }),
+                span: Span::default(),
+            },
+        ]);
+
+        if let Ok(res) = parser.parse_top_level() {
+            assert_eq!(without_spans(res), expected);
+        } else {
+            panic!("Logical binary failed to parse");
+        }
+    }
+
+    #[test]
+    fn test_parse_mixed_precedence_binary() {
+        let inp = "a * b + c";
+        let lexer = lexer::Lexer::new(inp);
+        let mut parser = Parser { lexer };
+
+        let expected = ASTNode::Sequence(vec![
+            ASTNode::Binary {
+                op: lexer::Token::Operator(String::from("+")),
+                lhs: Box::new(ASTNode::Binary {
+                    op: lexer::Token::Operator(String::from("*")),
+                    lhs: Box::new(ASTNode::Name(String::from("a"), Span::default())),
+                    rhs: Box::new(ASTNode::Name(String::from("b"), Span::default())),
+                    span: Span::default(),
                 }),
+                rhs: Box::new(ASTNode::Name(String::from("c"), Span::default())),
+                span: Span::default(),
             },
         ]);
 
         if let Ok(res) = parser.parse_top_level() {
-            assert_eq!(res, expected);
+            assert_eq!(without_spans(res), expected);
         } else {
-            panic!(format!("Simple binary failed to parse"));
+            panic!("Mixed precedence binary failed to parse");
+        }
+    }
+
+    #[test]
+    fn test_parse_unknown_operator() {
+        for inp in &["a +- b", "a =! b", "a | b"] {
+            let lexer = lexer::Lexer::new(inp);
+            let mut parser = Parser { lexer };
+
+            assert!(parser.parse_top_level().is_err());
         }
     }
 
@@ -484,39 +1432,367 @@ mod tests {
                        };
                        b
                    }";
-        let mut lexer = lexer::Lexer::new(inp);
+        let lexer = lexer::Lexer::new(inp);
         let mut parser = Parser { lexer };
 
         let expected = ASTNode::Sequence(vec![
             ASTNode::Function {
-                name: Box::new(Some(ASTNode::Name(String::from("a")))),
+                name: Box::new(Some(ASTNode::Name(String::from("a"), Span::default()))),
                 args: vec![
-                    ASTNode::Name(String::from("b")),
-                    ASTNode::Name(String::from("c")),
+                    ASTNode::Name(String::from("b"), Span::default()),
+                    ASTNode::Name(String::from("c"), Span::default()),
                 ],
                 body: Box::new(ASTNode::Sequence(vec![
                     ASTNode::Conditional {
-                        cond: Box::new(ASTNode::Name(String::from("b"))),
+                        cond: Box::new(ASTNode::Name(String::from("b"), Span::default())),
                         if_body: Box::new(ASTNode::Binary {
                             op: lexer::Token::Operator(String::from("=")),
-                            lhs: Box::new(ASTNode::Name(String::from("c"))),
-                            rhs: Box::new(ASTNode::Name(String::from("b"))),
+                            lhs: Box::new(ASTNode::Name(String::from("c"), Span::default())),
+                            rhs: Box::new(ASTNode::Name(String::from("b"), Span::default())),
+                            span: Span::default(),
                         }),
                         else_body: Box::new(Some(ASTNode::Binary {
                             op: lexer::Token::Operator(String::from("=")),
-                            lhs: Box::new(ASTNode::Name(String::from("b"))),
-                            rhs: Box::new(ASTNode::Name(String::from("c"))),
+                            lhs: Box::new(ASTNode::Name(String::from("b"), Span::default())),
+                            rhs: Box::new(ASTNode::Name(String::from("c"), Span::default())),
+                            span: Span::default(),
                         })),
+                        span: Span::default(),
                     },
-                    ASTNode::Name(String::from("b")),
+                    ASTNode::Name(String::from("b"), Span::default()),
                 ])),
+                doc: None,
+                span: Span::default(),
+            },
+        ]);
+
+        if let Ok(res) = parser.parse_top_level() {
+            assert_eq!(without_spans(res), expected);
+        } else {
+            panic!("Function declaration failed to parse");
+        }
+    }
+
+    #[test]
+    fn test_documented_function_declaration() {
+        let inp = "## Returns its argument
+                   ## unchanged
+                   fn id (x) { x }";
+        let lexer = lexer::Lexer::new(inp);
+        let mut parser = Parser { lexer };
+
+        let expected = ASTNode::Sequence(vec![
+            ASTNode::Function {
+                name: Box::new(Some(ASTNode::Name(String::from("id"), Span::default()))),
+                args: vec![ASTNode::Name(String::from("x"), Span::default())],
+                body: Box::new(ASTNode::Name(String::from("x"), Span::default())),
+                doc: Some(String::from("Returns its argument\nunchanged")),
+                span: Span::default(),
+            },
+        ]);
+
+        if let Ok(res) = parser.parse_top_level() {
+            assert_eq!(without_spans(res), expected);
+        } else {
+            panic!("Documented function declaration failed to parse");
+        }
+    }
+
+    #[test]
+    fn test_doc_comment_without_declaration() {
+        let inp = "## Not a function\nx";
+        let lexer = lexer::Lexer::new(inp);
+        let mut parser = Parser { lexer };
+
+        assert!(parser.parse_top_level().is_err());
+    }
+
+    #[test]
+    fn test_annotated_function_declaration() {
+        let inp = "fn add(a: Int, b) : (Int) -> Int { a }";
+        let lexer = lexer::Lexer::new(inp);
+        let mut parser = Parser { lexer };
+
+        let expected = ASTNode::Sequence(vec![
+            ASTNode::Function {
+                name: Box::new(Some(ASTNode::Name(String::from("add"), Span::default()))),
+                args: vec![
+                    ASTNode::Annotated {
+                        expr: Box::new(ASTNode::Name(String::from("a"), Span::default())),
+                        annotation: TypeAnnotation::Named(String::from("Int"), Span::default()),
+                        span: Span::default(),
+                    },
+                    ASTNode::Name(String::from("b"), Span::default()),
+                ],
+                body: Box::new(ASTNode::Annotated {
+                    expr: Box::new(ASTNode::Name(String::from("a"), Span::default())),
+                    annotation: TypeAnnotation::Function(
+                        vec![TypeAnnotation::Named(String::from("Int"), Span::default())],
+                        Box::new(TypeAnnotation::Named(String::from("Int"), Span::default())),
+                    ),
+                    span: Span::default(),
+                }),
+                doc: None,
+                span: Span::default(),
+            },
+        ]);
+
+        if let Ok(res) = parser.parse_top_level() {
+            assert_eq!(without_spans(res), expected);
+        } else {
+            panic!("Annotated function declaration failed to parse");
+        }
+    }
+
+    #[test]
+    fn test_parse_let() {
+        let inp = "let x: Bool = y; let z = 1";
+        let lexer = lexer::Lexer::new(inp);
+        let mut parser = Parser { lexer };
+
+        let expected = ASTNode::Sequence(vec![
+            ASTNode::Let {
+                name: Box::new(ASTNode::Annotated {
+                    expr: Box::new(ASTNode::Name(String::from("x"), Span::default())),
+                    annotation: TypeAnnotation::Named(String::from("Bool"), Span::default()),
+                    span: Span::default(),
+                }),
+                value: Box::new(ASTNode::Name(String::from("y"), Span::default())),
+                span: Span::default(),
+            },
+            ASTNode::Let {
+                name: Box::new(ASTNode::Name(String::from("z"), Span::default())),
+                value: Box::new(ASTNode::Integer(1, Span::default())),
+                span: Span::default(),
+            },
+        ]);
+
+        if let Ok(res) = parser.parse_top_level() {
+            assert_eq!(without_spans(res), expected);
+        } else {
+            panic!("Let failed to parse");
+        }
+    }
+
+    #[test]
+    fn test_parse_malformed_annotation() {
+        for inp in &["fn f(a:) { a }", "let x: (Int) = 1", "let x 1"] {
+            let lexer = lexer::Lexer::new(inp);
+            let mut parser = Parser { lexer };
+
+            assert!(parser.parse_top_level().is_err());
+        }
+    }
+
+    #[test]
+    fn test_parse_type_declaration() {
+        let inp = "type Shape = Circle(r: Float) | Rect(w, h) | Empty";
+        let lexer = lexer::Lexer::new(inp);
+        let mut parser = Parser { lexer };
+
+        let expected = ASTNode::Sequence(vec![
+            ASTNode::TypeDeclaration {
+                name: String::from("Shape"),
+                variants: vec![
+                    Variant {
+                        name: String::from("Circle"),
+                        fields: vec![ASTNode::Annotated {
+                            expr: Box::new(ASTNode::Name(String::from("r"), Span::default())),
+                            annotation: TypeAnnotation::Named(String::from("Float"), Span::default()),
+                            span: Span::default(),
+                        }],
+                        span: Span::default(),
+                    },
+                    Variant {
+                        name: String::from("Rect"),
+                        fields: vec![
+                            ASTNode::Name(String::from("w"), Span::default()),
+                            ASTNode::Name(String::from("h"), Span::default()),
+                        ],
+                        span: Span::default(),
+                    },
+                    Variant {
+                        name: String::from("Empty"),
+                        fields: vec![],
+                        span: Span::default(),
+                    },
+                ],
+                span: Span::default(),
+            },
+        ]);
+
+        if let Ok(res) = parser.parse_top_level() {
+            assert_eq!(without_spans(res), expected);
+        } else {
+            panic!("Type declaration failed to parse");
+        }
+    }
+
+    #[test]
+    fn test_parse_match() {
+        let inp = "match s { Circle(r) => r, _ => 0, }";
+        let lexer = lexer::Lexer::new(inp);
+        let mut parser = Parser { lexer };
+
+        let expected = ASTNode::Sequence(vec![
+            ASTNode::Match {
+                subject: Box::new(ASTNode::Name(String::from("s"), Span::default())),
+                arms: vec![
+                    MatchArm {
+                        pattern: Pattern::Constructor {
+                            name: String::from("Circle"),
+                            bindings: vec![ASTNode::Name(String::from("r"), Span::default())],
+                            span: Span::default(),
+                        },
+                        body: ASTNode::Name(String::from("r"), Span::default()),
+                    },
+                    MatchArm {
+                        pattern: Pattern::Wildcard(Span::default()),
+                        body: ASTNode::Integer(0, Span::default()),
+                    },
+                ],
+                span: Span::default(),
+            },
+        ]);
+
+        if let Ok(res) = parser.parse_top_level() {
+            assert_eq!(without_spans(res), expected);
+        } else {
+            panic!("Match failed to parse");
+        }
+    }
+
+    #[test]
+    fn test_parse_modules() {
+        let inp = "import \"lib/math.silver\" as math; export fn f(x) { math.square(x) }";
+        let lexer = lexer::Lexer::new(inp);
+        let mut parser = Parser { lexer };
+
+        let expected = ASTNode::Sequence(vec![
+            ASTNode::Import {
+                path: String::from("lib/math.silver"),
+                alias: String::from("math"),
+                span: Span::default(),
+            },
+            ASTNode::Export {
+                decl: Box::new(ASTNode::Function {
+                    name: Box::new(Some(ASTNode::Name(String::from("f"), Span::default()))),
+                    args: vec![ASTNode::Name(String::from("x"), Span::default())],
+                    body: Box::new(ASTNode::Invocation {
+                        func: Box::new(ASTNode::Qualified {
+                            module: String::from("math"),
+                            name: String::from("square"),
+                            span: Span::default(),
+                        }),
+                        args: vec![ASTNode::Name(String::from("x"), Span::default())],
+                        span: Span::default(),
+                    }),
+                    doc: None,
+                    span: Span::default(),
+                }),
+                span: Span::default(),
+            },
+        ]);
+
+        if let Ok(res) = parser.parse_top_level() {
+            assert_eq!(without_spans(res), expected);
+        } else {
+            panic!("Modules failed to parse");
+        }
+    }
+
+    #[test]
+    fn test_parse_anonymous_export() {
+        let lexer = lexer::Lexer::new("export fn (x) { x }");
+        let mut parser = Parser { lexer };
+
+        assert!(parser.parse_top_level().is_err());
+    }
+
+    #[test]
+    fn test_parse_externs() {
+        let inp = "extern fn log(x) = \"console.log\";
+                   extern \"node:fs\" { fn read(path: String): String = \"readFileSync\"; fn exists(p); }";
+        let lexer = lexer::Lexer::new(inp);
+        let mut parser = Parser { lexer };
+
+        let expected = ASTNode::Sequence(vec![
+            ASTNode::Extern {
+                module: None,
+                functions: vec![ExternFunction {
+                    name: String::from("log"),
+                    args: vec![ASTNode::Name(String::from("x"), Span::default())],
+                    ret: None,
+                    js_name: Some(String::from("console.log")),
+                    span: Span::default(),
+                }],
+                span: Span::default(),
+            },
+            ASTNode::Extern {
+                module: Some(String::from("node:fs")),
+                functions: vec![
+                    ExternFunction {
+                        name: String::from("read"),
+                        args: vec![ASTNode::Annotated {
+                            expr: Box::new(ASTNode::Name(String::from("path"), Span::default())),
+                            annotation: TypeAnnotation::Named(String::from("String"), Span::default()),
+                            span: Span::default(),
+                        }],
+                        ret: Some(TypeAnnotation::Named(String::from("String"), Span::default())),
+                        js_name: Some(String::from("readFileSync")),
+                        span: Span::default(),
+                    },
+                    ExternFunction {
+                        name: String::from("exists"),
+                        args: vec![ASTNode::Name(String::from("p"), Span::default())],
+                        ret: None,
+                        js_name: None,
+                        span: Span::default(),
+                    },
+                ],
+                span: Span::default(),
+            },
+        ]);
+
+        if let Ok(res) = parser.parse_top_level() {
+            assert_eq!(without_spans(res), expected);
+        } else {
+            panic!("Externs failed to parse");
+        }
+    }
+
+    #[test]
+    fn test_parse_lists() {
+        let inp = "let xs: [Int] = [1, 2]; []";
+        let lexer = lexer::Lexer::new(inp);
+        let mut parser = Parser { lexer };
+
+        let expected = ASTNode::Sequence(vec![
+            ASTNode::Let {
+                name: Box::new(ASTNode::Annotated {
+                    expr: Box::new(ASTNode::Name(String::from("xs"), Span::default())),
+                    annotation: TypeAnnotation::List(Box::new(TypeAnnotation::Named(
+                        String::from("Int"),
+                        Span::default(),
+                    ))),
+                    span: Span::default(),
+                }),
+                value: Box::new(ASTNode::List(
+                    vec![
+                        ASTNode::Integer(1, Span::default()),
+                        ASTNode::Integer(2, Span::default()),
+                    ],
+                    Span::default(),
+                )),
+                span: Span::default(),
             },
+            ASTNode::List(vec![], Span::default()),
         ]);
 
         if let Ok(res) = parser.parse_top_level() {
-            assert_eq!(res, expected);
+            assert_eq!(without_spans(res), expected);
         } else {
-            panic!(String::from("Function declaration failed to parse"));
+            panic!("Lists failed to parse");
         }
     }
 }
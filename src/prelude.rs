@@ -0,0 +1,136 @@
+use super::lexer;
+use super::modules::Module;
+use super::parser::{ASTNode, Parser};
+
+use std::path::PathBuf;
+
+const SOURCE: &str = include_str!("prelude.silver");
+
+// The JS functions implementing the prelude's externs, by JS name. Lists are JS arrays,
+// which the shims never mutate.
+const SHIMS: &[(&str, &str)] = &[
+    ("$length", "function (xs) { return xs.length }"),
+    (
+        "$get",
+        "function (xs, i) { if (i < 0 || i >= xs.length) { throw new RangeError(\"Index \" + i \
+         + \" is out of bounds for a list of length \" + xs.length) } return xs[i] }",
+    ),
+    ("$append", "function (xs, x) { return xs.concat([x]) }"),
+    ("$concat", "function (xs, ys) { return xs.concat(ys) }"),
+    (
+        "$range",
+        "function (from, to) { var xs = []; for (var i = from; i < to; i++) { xs.push(i) } \
+         return xs }",
+    ),
+    (
+        "$fold",
+        "function (xs, init, f) { var acc = init; for (var i = 0; i < xs.length; i++) \
+         { acc = f(acc, xs[i]) } return acc }",
+    ),
+    (
+        "$map",
+        "function (xs, f) { var ys = []; for (var i = 0; i < xs.length; i++) \
+         { ys.push(f(xs[i])) } return ys }",
+    ),
+    (
+        "$filter",
+        "function (xs, keep) { var ys = []; for (var i = 0; i < xs.length; i++) \
+         { if (keep(xs[i])) { ys.push(xs[i]) } } return ys }",
+    ),
+    (
+        "$substring",
+        "function (s, from, to) { return s.substring(from, to) }",
+    ),
+    (
+        "$split",
+        "function (s, separator) { return s.split(separator) }",
+    ),
+    (
+        "$join",
+        "function (xs, separator) { return xs.join(separator) }",
+    ),
+];
+
+// Parses the prelude. Its source is part of the compiler, so it's expected to be valid.
+pub fn module() -> Module {
+    let lexer = lexer::Lexer::new(SOURCE);
+    let mut parser = Parser { lexer };
+    let ast = parser
+        .parse_top_level()
+        .expect("The prelude failed to parse");
+
+    let mut exports = Vec::new();
+    if let ASTNode::Sequence(ref exprs) = ast {
+        for expr in exprs.iter() {
+            for (name, _) in expr.exported_names() {
+                exports.push(String::from(name));
+            }
+        }
+    }
+
+    Module {
+        path: PathBuf::from("prelude.silver"),
+        ast,
+        imports: Vec::new(),
+        exports,
+    }
+}
+
+// The shim defining the given JS name, if it's one of the prelude's
+pub fn shim(js_name: &str) -> Option<&'static str> {
+    SHIMS
+        .iter()
+        .find(|&&(name, _)| name == js_name)
+        .map(|&(_, shim)| shim)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use resolver;
+    use std::collections::HashMap;
+    use typechecker::{self, Type};
+
+    #[test]
+    fn test_prelude_checks() {
+        let prelude = module();
+
+        let resolution = resolver::resolve(&prelude.ast);
+        assert!(resolution.errors.is_empty());
+        assert!(resolution.warnings.is_empty());
+
+        let (_, interface) = typechecker::check_module(
+            &prelude.ast,
+            HashMap::new(),
+            typechecker::Interface::default(),
+        )
+        .unwrap();
+        for name in prelude.exports.iter() {
+            assert!(interface.get(name).is_some(), "{} has no type", name);
+        }
+        assert_eq!(
+            interface.get("sum"),
+            Some(&Type::Function(
+                vec![Type::List(Box::new(Type::Int))],
+                Box::new(Type::Int)
+            ))
+        );
+    }
+
+    #[test]
+    fn test_externs_have_shims() {
+        let prelude = module();
+
+        if let ASTNode::Sequence(ref exprs) = prelude.ast {
+            for expr in exprs.iter() {
+                if let ASTNode::Extern { ref functions, .. } = *expr.declaration() {
+                    for function in functions.iter() {
+                        let js_name = function.js_name.as_ref().unwrap();
+                        assert!(!js_name.starts_with('$') || shim(js_name).is_some());
+                    }
+                }
+            }
+        }
+    }
+}
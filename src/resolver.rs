@@ -0,0 +1,676 @@
+use super::lexer::Token;
+use super::parser::{ASTNode, ExternFunction, Pattern, Variant};
+
+use super::util::{Error, Span, Warning};
+
+use std::collections::HashMap;
+
+// A use of a name, linked to the definition it refers to
+#[derive(Debug, PartialEq)]
+pub struct Link {
+    pub name: String,
+    pub used: Span,
+    pub defined: Span,
+}
+
+#[derive(Debug)]
+pub struct Resolution {
+    pub links: Vec<Link>,
+    pub errors: Vec<Error>,
+    pub warnings: Vec<Warning>,
+}
+
+struct Definition {
+    name: String,
+    span: Span,
+    used: bool,
+    warn_unused: bool,
+    // The number of parameters, if this defines a function
+    arity: Option<usize>,
+    reassigned: bool,
+    // Whether this is the alias of an imported module
+    import: bool,
+}
+
+// An invocation of a name, along with the number of arguments it was given
+struct Call {
+    definition: usize,
+    args: usize,
+    span: Span,
+}
+
+struct Resolver<'a> {
+    // The names exported by each imported module, by alias
+    exports: &'a HashMap<String, Vec<String>>,
+    // The names exported by the prelude, which are in scope unless shadowed
+    prelude: &'a [String],
+    definitions: Vec<Definition>,
+    // Each scope maps names to indices into definitions
+    scopes: Vec<HashMap<String, usize>>,
+    calls: Vec<Call>,
+    links: Vec<Link>,
+    errors: Vec<Error>,
+    warnings: Vec<Warning>,
+}
+
+// Links every use of a name to its definition. Scopes are introduced by sequences, which
+// bind the functions declared in them, by let bindings and assignments to unbound names,
+// and by function parameters.
+pub fn resolve(ast: &ASTNode) -> Resolution {
+    resolve_module(ast, &HashMap::new(), &[])
+}
+
+// Resolves a module, given the names exported by each module it imports and by the prelude
+pub fn resolve_module(
+    ast: &ASTNode,
+    exports: &HashMap<String, Vec<String>>,
+    prelude: &[String],
+) -> Resolution {
+    let mut resolver = Resolver {
+        exports,
+        prelude,
+        definitions: Vec::new(),
+        scopes: Vec::new(),
+        calls: Vec::new(),
+        links: Vec::new(),
+        errors: Vec::new(),
+        warnings: Vec::new(),
+    };
+    resolver.resolve(ast);
+    resolver.check_arity();
+
+    Resolution {
+        links: resolver.links,
+        errors: resolver.errors,
+        warnings: resolver.warnings,
+    }
+}
+
+impl<'a> Resolver<'a> {
+    fn lookup(&self, name: &str) -> Option<usize> {
+        self.scopes
+            .iter()
+            .rev()
+            .filter_map(|scope| scope.get(name))
+            .next()
+            .cloned()
+    }
+
+    fn define(&mut self, name: &str, span: Span, warn_unused: bool) {
+        self.define_function(name, span, warn_unused, None)
+    }
+
+    fn define_function(&mut self, name: &str, span: Span, warn_unused: bool, arity: Option<usize>) {
+        if let Some(index) = self.lookup(name) {
+            let shadowed = &self.definitions[index].span;
+            self.warnings.push(span.get_warning(format!(
+                "{} shadows the definition at line {}, column {}",
+                name, shadowed.line, shadowed.col
+            )));
+        }
+
+        // Top-level definitions make up the program's interface, so they may go unused.
+        // By convention, names starting with an underscore are unused on purpose.
+        let warn_unused = warn_unused && self.scopes.len() > 1 && !name.starts_with('_');
+
+        self.definitions.push(Definition {
+            name: String::from(name),
+            span,
+            used: false,
+            warn_unused,
+            arity,
+            reassigned: false,
+            import: false,
+        });
+        let index = self.definitions.len() - 1;
+        self.scopes
+            .last_mut()
+            .unwrap()
+            .insert(String::from(name), index);
+    }
+
+    // Links a reference to the definition in scope, returning false if there isn't one.
+    // Writes are linked, but don't count as uses.
+    fn reference(&mut self, name: &str, span: Span, read: bool) -> bool {
+        match self.lookup(name) {
+            Some(index) => {
+                if read {
+                    self.definitions[index].used = true;
+                } else {
+                    self.definitions[index].reassigned = true;
+                }
+                self.links.push(Link {
+                    name: String::from(name),
+                    used: span,
+                    defined: self.definitions[index].span,
+                });
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn push_scope(&mut self) {
+        self.scopes.push(HashMap::new());
+    }
+
+    fn pop_scope(&mut self) {
+        let scope = self.scopes.pop().unwrap();
+        let mut indices: Vec<usize> = scope.values().cloned().collect();
+        indices.sort();
+
+        for index in indices {
+            let definition = &self.definitions[index];
+            if definition.warn_unused && !definition.used {
+                self.warnings.push(
+                    definition
+                        .span
+                        .get_warning(format!("{} is never used", definition.name)),
+                );
+            }
+        }
+    }
+
+    fn resolve(&mut self, node: &ASTNode) {
+        match *node {
+            ASTNode::Integer(..)
+            | ASTNode::Float(..)
+            | ASTNode::StringLiteral(..)
+            | ASTNode::Boolean(..) => {}
+            ASTNode::Name(ref name, span) => {
+                if !self.reference(name, span, true) && !self.prelude.contains(name) {
+                    self.errors
+                        .push(span.get_error(format!("Undefined name {}", name)));
+                }
+            }
+            ASTNode::Function { .. } => self.resolve_function(node, false),
+            ASTNode::Invocation {
+                ref func,
+                ref args,
+                span,
+            } => {
+                self.resolve(func);
+                if let ASTNode::Name(ref name, _) = **func {
+                    if let Some(definition) = self.lookup(name) {
+                        self.calls.push(Call {
+                            definition,
+                            args: args.len(),
+                            span,
+                        });
+                    }
+                }
+                for arg in args.iter() {
+                    self.resolve(arg);
+                }
+            }
+            ASTNode::Conditional {
+                ref cond,
+                ref if_body,
+                ref else_body,
+                ..
+            } => {
+                self.resolve(cond);
+                self.resolve(if_body);
+                if let Some(ref else_body) = **else_body {
+                    self.resolve(else_body);
+                }
+            }
+            ASTNode::Binary {
+                ref op,
+                ref lhs,
+                ref rhs,
+                ..
+            } => match **lhs {
+                // Assigning to an unbound name defines it
+                ASTNode::Name(ref name, span) if *op == Token::Operator(String::from("=")) => {
+                    self.resolve(rhs);
+                    if !self.reference(name, span, false) {
+                        self.define(name, span, true);
+                    }
+                }
+                _ => {
+                    self.resolve(lhs);
+                    self.resolve(rhs);
+                }
+            },
+            ASTNode::Sequence(ref exprs) => {
+                self.push_scope();
+
+                // Functions and constructors declared in a sequence are visible throughout it
+                for expr in exprs.iter() {
+                    if let Some((name, span)) = expr.function_name() {
+                        self.define_function(name, span, true, Self::arity(expr.declaration()));
+                    }
+                    match *expr.declaration() {
+                        ASTNode::TypeDeclaration { ref variants, .. } => {
+                            self.define_constructors(variants)
+                        }
+                        ASTNode::Extern {
+                            ref functions,
+                            span,
+                            ..
+                        } => {
+                            self.check_top_level("Externs", span);
+                            self.define_externs(functions);
+                        }
+                        _ => {}
+                    }
+                }
+                for expr in exprs.iter() {
+                    if let ASTNode::Export { span, .. } = *expr {
+                        self.check_top_level("Exports", span);
+                    }
+                    let decl = expr.declaration();
+                    match *decl {
+                        ASTNode::Function { .. } => self.resolve_function(decl, true),
+                        ASTNode::TypeDeclaration { .. } | ASTNode::Extern { .. } => {}
+                        _ => self.resolve(decl),
+                    }
+                }
+
+                self.pop_scope();
+            }
+            ASTNode::Let {
+                ref name,
+                ref value,
+                span,
+            } => {
+                self.resolve(value);
+                match name.binding() {
+                    Some((name, name_span)) => {
+                        self.define_function(name, name_span, true, Self::arity(value))
+                    }
+                    None => self
+                        .errors
+                        .push(span.get_error(String::from("Invalid let binding"))),
+                }
+            }
+            ASTNode::Annotated { ref expr, .. } => self.resolve(expr),
+            ASTNode::List(ref elements, _) => {
+                for element in elements.iter() {
+                    self.resolve(element);
+                }
+            }
+            ASTNode::TypeDeclaration { ref variants, .. } => self.define_constructors(variants),
+            ASTNode::Match {
+                ref subject,
+                ref arms,
+                ..
+            } => {
+                self.resolve(subject);
+                for arm in arms.iter() {
+                    self.push_scope();
+                    if let Pattern::Constructor {
+                        ref name,
+                        ref bindings,
+                        span,
+                    } = arm.pattern
+                    {
+                        if !self.reference(name, span, true) {
+                            self.errors
+                                .push(span.get_error(format!("Undefined constructor {}", name)));
+                        }
+                        for binding in bindings.iter() {
+                            match binding.binding() {
+                                Some(("_", _)) => {}
+                                Some((name, span)) => self.define(name, span, true),
+                                None => {}
+                            }
+                        }
+                    }
+                    self.resolve(&arm.body);
+                    self.pop_scope();
+                }
+            }
+            ASTNode::Import {
+                ref alias, span, ..
+            } => {
+                self.check_top_level("Imports", span);
+                self.define(alias, span, true);
+                self.definitions.last_mut().unwrap().import = true;
+            }
+            ASTNode::Extern {
+                ref functions,
+                span,
+                ..
+            } => {
+                self.check_top_level("Externs", span);
+                self.define_externs(functions);
+            }
+            ASTNode::Export { ref decl, span } => {
+                self.check_top_level("Exports", span);
+                self.resolve(decl);
+            }
+            ASTNode::Qualified {
+                ref module,
+                ref name,
+                span,
+            } => {
+                let defined = self.reference(module, span, true);
+                if !defined && !self.prelude.contains(module) {
+                    self.errors
+                        .push(span.get_error(format!("Undefined name {}", module)));
+                } else if !defined || !self.definitions[self.lookup(module).unwrap()].import {
+                    self.errors
+                        .push(span.get_error(format!("{} is not a module", module)));
+                } else if !self
+                    .exports
+                    .get(module)
+                    .is_some_and(|exports| exports.contains(name))
+                {
+                    self.errors
+                        .push(span.get_error(format!("Module {} has no export {}", module, name)));
+                }
+            }
+        }
+    }
+
+    // The top level is the outermost sequence, whose scope is the only one
+    fn check_top_level(&mut self, what: &str, span: Span) {
+        if self.scopes.len() != 1 {
+            self.errors
+                .push(span.get_error(format!("{} must be at the top level", what)));
+        }
+    }
+
+    fn define_externs(&mut self, functions: &[ExternFunction]) {
+        for function in functions.iter() {
+            self.define_function(
+                &function.name,
+                function.span,
+                true,
+                Some(function.args.len()),
+            );
+        }
+    }
+
+    // Constructors with fields are functions, while the others are plain values
+    fn define_constructors(&mut self, variants: &[Variant]) {
+        for variant in variants.iter() {
+            let arity = if variant.fields.is_empty() {
+                None
+            } else {
+                Some(variant.fields.len())
+            };
+            self.define_function(&variant.name, variant.span, true, arity);
+        }
+    }
+
+    // Functions declared in a sequence have already had their name defined there. Otherwise,
+    // the name is only visible inside the function, for recursion.
+    fn resolve_function(&mut self, node: &ASTNode, declared: bool) {
+        if let ASTNode::Function {
+            ref args, ref body, ..
+        } = *node
+        {
+            self.push_scope();
+            if !declared {
+                if let Some((name, span)) = node.function_name() {
+                    self.define_function(name, span, false, Self::arity(node));
+                }
+            }
+
+            self.push_scope();
+            for arg in args.iter() {
+                if let Some((name, span)) = arg.binding() {
+                    self.define(name, span, true);
+                }
+            }
+            self.resolve(body);
+            self.pop_scope();
+
+            self.pop_scope();
+        }
+    }
+
+    fn arity(node: &ASTNode) -> Option<usize> {
+        match *node {
+            ASTNode::Function { ref args, .. } => Some(args.len()),
+            _ => None,
+        }
+    }
+
+    // Calls can only be checked if the name they invoke is bound to a function that's
+    // never replaced
+    fn check_arity(&mut self) {
+        for call in self.calls.iter() {
+            let definition = &self.definitions[call.definition];
+            if let Some(arity) = definition.arity {
+                if arity != call.args && !definition.reassigned {
+                    self.errors.push(call.span.get_error(format!(
+                        "{} expects {} arguments, given {}",
+                        definition.name, arity, call.args
+                    )));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use lexer;
+    use parser::Parser;
+
+    fn resolve_str(inp: &str) -> Resolution {
+        let lexer = lexer::Lexer::new(inp);
+        let mut parser = Parser { lexer };
+
+        resolve(&parser.parse_top_level().unwrap())
+    }
+
+    #[test]
+    fn test_undefined_name() {
+        let resolution = resolve_str("x = 1;\ny + x");
+
+        assert_eq!(
+            resolution.errors,
+            vec![Error {
+                msg: String::from("Undefined name y"),
+                line: 2,
+                col: 0,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_links_uses_to_definitions() {
+        let resolution = resolve_str("fn f(a) {\n  a\n};\nf(1)");
+
+        assert!(resolution.errors.is_empty());
+        let links: Vec<(&str, u32, u32)> = resolution
+            .links
+            .iter()
+            .map(|link| (link.name.as_str(), link.used.line, link.defined.line))
+            .collect();
+        assert_eq!(links, vec![("a", 2, 1), ("f", 4, 1)]);
+    }
+
+    #[test]
+    fn test_functions_visible_throughout_sequence() {
+        let inp = "fn even?(n) { if n == 0 then true else odd?(n - 1) };
+                   fn odd?(n) { if n == 0 then false else even?(n - 1) }";
+
+        assert!(resolve_str(inp).errors.is_empty());
+    }
+
+    #[test]
+    fn test_parameters_are_local() {
+        let resolution = resolve_str("fn f(a) { a }; a");
+
+        assert_eq!(resolution.errors.len(), 1);
+    }
+
+    #[test]
+    fn test_let_is_not_recursive() {
+        let resolution = resolve_str("let x = x");
+
+        assert_eq!(resolution.errors.len(), 1);
+    }
+
+    #[test]
+    fn test_shadowing_warning() {
+        let resolution = resolve_str("x = 1;\nfn f(x) { x }");
+
+        assert!(resolution.errors.is_empty());
+        assert_eq!(
+            resolution.warnings,
+            vec![Warning {
+                msg: String::from("x shadows the definition at line 1, column 0"),
+                line: 2,
+                col: 5,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_unused_warnings() {
+        let resolution = resolve_str("fn f(a, b, _c) { let d = 1; e = 2; e = 3; a }");
+
+        let unused: Vec<&str> = resolution
+            .warnings
+            .iter()
+            .map(|warning| warning.msg.as_str())
+            .collect();
+        assert_eq!(
+            unused,
+            vec!["d is never used", "e is never used", "b is never used"]
+        );
+    }
+
+    #[test]
+    fn test_top_level_may_be_unused() {
+        let resolution = resolve_str("fn f() { 1 }; x = 2; let y = fn g() { 3 }");
+
+        assert!(resolution.warnings.is_empty());
+    }
+
+    #[test]
+    fn test_arity_mismatch() {
+        let resolution = resolve_str(
+            "fn f(a, b) { a + b };
+f(1);
+let g = fn (x) { x };
+g(1, 2)",
+        );
+
+        assert_eq!(
+            resolution.errors,
+            vec![
+                Error {
+                    msg: String::from("f expects 2 arguments, given 1"),
+                    line: 2,
+                    col: 0,
+                },
+                Error {
+                    msg: String::from("g expects 1 arguments, given 2"),
+                    line: 4,
+                    col: 0,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_arity_of_dynamic_calls_unchecked() {
+        // Parameters and reassigned functions can't be resolved to a single definition
+        let inp = "fn apply(f) { f(1, 2) };
+                   fn g(a) { a };
+                   g = fn (a, b) { a };
+                   g(1, 2)";
+
+        assert!(resolve_str(inp).errors.is_empty());
+    }
+
+    #[test]
+    fn test_match_resolves_constructors_and_bindings() {
+        let inp = "type Shape = Circle(r) | Rect(w, h);
+                   fn area(s) { match s { Circle(r) => r * r, Rect(w, _) => w, Square(x) => x } };
+                   area(Circle(1))";
+        let resolution = resolve_str(inp);
+
+        assert_eq!(resolution.errors.len(), 1);
+        assert_eq!(resolution.errors[0].msg, "Undefined constructor Square");
+        assert!(resolution.warnings.is_empty());
+    }
+
+    #[test]
+    fn test_constructor_arity() {
+        let resolution = resolve_str("type Shape = Circle(r) | Empty; Circle(1, 2)");
+
+        assert_eq!(resolution.errors.len(), 1);
+        assert_eq!(
+            resolution.errors[0].msg,
+            "Circle expects 1 arguments, given 2"
+        );
+    }
+
+    #[test]
+    fn test_qualified_names() {
+        let inp = "import \"lib.silver\" as lib;
+                   fn f(x) { lib.g(x) + lib.h + x.g };
+                   export fn g() { import \"other.silver\" as other }";
+        let mut exports = HashMap::new();
+        exports.insert(String::from("lib"), vec![String::from("g")]);
+
+        let lexer = lexer::Lexer::new(inp);
+        let mut parser = Parser { lexer };
+        let resolution = resolve_module(&parser.parse_top_level().unwrap(), &exports, &[]);
+
+        let errors: Vec<&str> = resolution
+            .errors
+            .iter()
+            .map(|error| error.msg.as_str())
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                "Module lib has no export h",
+                "x is not a module",
+                "Imports must be at the top level",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_externs() {
+        let inp = "fn f() { extern fn g() };
+                   extern \"m\" { fn h(a, b) };
+                   h(1)";
+        let errors: Vec<String> = resolve_str(inp)
+            .errors
+            .into_iter()
+            .map(|error| error.msg)
+            .collect();
+
+        assert_eq!(
+            errors,
+            vec![
+                "Externs must be at the top level",
+                "h expects 2 arguments, given 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_prelude_names() {
+        let lexer = lexer::Lexer::new("fn f(xs) { map(xs, f) }; map.x; f(length)");
+        let mut parser = Parser { lexer };
+        let prelude = [String::from("map")];
+        let resolution = resolve_module(
+            &parser.parse_top_level().unwrap(),
+            &HashMap::new(),
+            &prelude,
+        );
+
+        let errors: Vec<&str> = resolution
+            .errors
+            .iter()
+            .map(|error| error.msg.as_str())
+            .collect();
+        assert_eq!(errors, vec!["map is not a module", "Undefined name length"]);
+        assert!(resolution.warnings.is_empty());
+    }
+}
@@ -0,0 +1,1102 @@
+/* The runtime of Silver programs compiled to C, which comes before each program's code.
+ *
+ * Every value is a tagged sv_value. Booleans and numbers are held in the value itself, and
+ * anything else is an object on a heap that's collected by marking everything reachable
+ * from the globals and the slots of the functions being run. The compiled code keeps every
+ * value it's using in those slots, so an allocation can always collect. A value that's all
+ * zeros is false, so zeroed memory holds only valid values. Its numeric natives use the
+ * math library, so a program is linked with -lm. */
+
+#include <math.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+typedef enum {
+    SV_BOOLEAN,
+    SV_NUMBER,
+    SV_STRING,
+    SV_LIST,
+    SV_CLOSURE,
+    SV_DATA,
+    SV_CELL
+} sv_tag;
+
+/* Objects in the program's own code are static and start out marked, so they're never
+ * collected */
+typedef struct sv_object {
+    struct sv_object *next;
+    size_t size;
+    unsigned char tag;
+    unsigned char marked;
+} sv_object;
+
+typedef struct {
+    unsigned char tag;
+    union {
+        int boolean;
+        double number;
+        sv_object *object;
+    } as;
+} sv_value;
+
+/* Strings are UTF-8, and the characters of one made at runtime follow it */
+typedef struct {
+    sv_object header;
+    size_t length;
+    const char *chars;
+} sv_string;
+
+typedef struct {
+    sv_object header;
+    size_t length;
+    sv_value items[1];
+} sv_list;
+
+typedef struct sv_closure sv_closure;
+typedef sv_value (*sv_function)(sv_closure *self, sv_value *args);
+
+/* A function along with the cells of the variables it captures */
+struct sv_closure {
+    sv_object header;
+    sv_function function;
+    size_t arity;
+    const char *name;
+    size_t count;
+    sv_value cells[1];
+};
+
+/* A value made by one of a type's constructors, which are told apart by name */
+typedef struct {
+    sv_object header;
+    int constructor;
+    size_t count;
+    sv_value fields[1];
+} sv_data;
+
+typedef struct {
+    sv_object header;
+    sv_value value;
+} sv_cell;
+
+#define SV_STRING_OF(v) ((sv_string *)(v).as.object)
+#define SV_LIST_OF(v) ((sv_list *)(v).as.object)
+#define SV_CLOSURE_OF(v) ((sv_closure *)(v).as.object)
+#define SV_DATA_OF(v) ((sv_data *)(v).as.object)
+#define SV_CELL(v) (((sv_cell *)(v).as.object)->value)
+
+/* The slots of the functions being run, which are roots of the heap */
+typedef struct sv_frame {
+    sv_value *slots;
+    size_t count;
+    struct sv_frame *previous;
+    size_t depth;
+} sv_frame;
+
+static sv_frame *sv_frames = NULL;
+
+/* Ends the program, as an uncaught error would end it in JS */
+void sv_fail(const char *message) {
+    fflush(stdout);
+    fprintf(stderr, "Error: %s\n", message);
+    exit(1);
+}
+
+/* Recursion is limited well before it could overflow the C stack, which would crash
+ * without a message */
+#define SV_MAX_DEPTH 10000
+
+static void sv_enter(sv_frame *frame) {
+    frame->previous = sv_frames;
+    frame->depth = sv_frames != NULL ? sv_frames->depth + 1 : 1;
+    if (frame->depth > SV_MAX_DEPTH) {
+        sv_fail("Maximum call stack size exceeded");
+    }
+    sv_frames = frame;
+}
+
+#define SV_ENTER(slots) \
+    sv_frame sv_frame_ = {slots, sizeof(slots) / sizeof(sv_value), NULL, 0}; \
+    sv_enter(&sv_frame_)
+#define SV_LEAVE() (sv_frames = sv_frame_.previous)
+
+/* Set by the program: the addresses of its globals and the names of its constructors, each
+ * ending with NULL */
+static sv_value *const *sv_globals = NULL;
+static const char *const *sv_constructors = NULL;
+
+static sv_object *sv_heap = NULL;
+static size_t sv_allocated = 0;
+static size_t sv_threshold = 1 << 20;
+
+/* The objects that have been marked but whose contents haven't */
+static sv_object **sv_gray = NULL;
+static size_t sv_gray_count = 0;
+static size_t sv_gray_capacity = 0;
+
+
+sv_value sv_boolean(int boolean) {
+    sv_value value;
+    value.tag = SV_BOOLEAN;
+    value.as.object = NULL;
+    value.as.boolean = boolean != 0;
+    return value;
+}
+
+sv_value sv_number(double number) {
+    sv_value value;
+    value.tag = SV_NUMBER;
+    value.as.number = number;
+    return value;
+}
+
+sv_value sv_object_value(sv_object *object) {
+    sv_value value;
+    value.tag = object->tag;
+    value.as.object = object;
+    return value;
+}
+
+static void sv_mark(sv_value value) {
+    sv_object *object;
+    if (value.tag == SV_BOOLEAN || value.tag == SV_NUMBER) {
+        return;
+    }
+    object = value.as.object;
+    if (object->marked) {
+        return;
+    }
+    object->marked = 1;
+
+    if (sv_gray_count == sv_gray_capacity) {
+        sv_gray_capacity = sv_gray_capacity ? sv_gray_capacity * 2 : 256;
+        sv_gray = realloc(sv_gray, sv_gray_capacity * sizeof(sv_object *));
+        if (sv_gray == NULL) {
+            sv_fail("Out of memory");
+        }
+    }
+    sv_gray[sv_gray_count++] = object;
+}
+
+static void sv_mark_all(sv_value *values, size_t count) {
+    size_t i;
+    for (i = 0; i < count; i++) {
+        sv_mark(values[i]);
+    }
+}
+
+static void sv_collect(void) {
+    sv_frame *frame;
+    sv_object **link;
+    size_t i;
+
+    for (frame = sv_frames; frame != NULL; frame = frame->previous) {
+        sv_mark_all(frame->slots, frame->count);
+    }
+    for (i = 0; sv_globals != NULL && sv_globals[i] != NULL; i++) {
+        sv_mark(*sv_globals[i]);
+    }
+
+    /* Marking is done with a stack rather than recursion, since lists of data can be
+     * arbitrarily deep */
+    while (sv_gray_count > 0) {
+        sv_object *object = sv_gray[--sv_gray_count];
+        switch (object->tag) {
+        case SV_LIST:
+            sv_mark_all(((sv_list *)object)->items, ((sv_list *)object)->length);
+            break;
+        case SV_CLOSURE:
+            sv_mark_all(((sv_closure *)object)->cells, ((sv_closure *)object)->count);
+            break;
+        case SV_DATA:
+            sv_mark_all(((sv_data *)object)->fields, ((sv_data *)object)->count);
+            break;
+        case SV_CELL:
+            sv_mark(((sv_cell *)object)->value);
+            break;
+        }
+    }
+
+    link = &sv_heap;
+    while (*link != NULL) {
+        sv_object *object = *link;
+        if (object->marked) {
+            object->marked = 0;
+            link = &object->next;
+        } else {
+            *link = object->next;
+            sv_allocated -= object->size;
+            free(object);
+        }
+    }
+    if (sv_allocated * 2 > sv_threshold) {
+        sv_threshold = sv_allocated * 2;
+    }
+}
+
+/* Allocates a zeroed object, collecting first once enough has been allocated since the
+ * last collection */
+static sv_object *sv_alloc(size_t size, sv_tag tag) {
+    sv_object *object;
+    if (sv_allocated + size > sv_threshold) {
+        sv_collect();
+    }
+    object = calloc(1, size);
+    if (object == NULL) {
+        sv_collect();
+        object = calloc(1, size);
+        if (object == NULL) {
+            sv_fail("Out of memory");
+        }
+    }
+    object->next = sv_heap;
+    object->size = size;
+    object->tag = (unsigned char)tag;
+    sv_heap = object;
+    sv_allocated += size;
+    return object;
+}
+
+sv_value sv_string_new(const char *chars, size_t length) {
+    sv_string *string = (sv_string *)sv_alloc(sizeof(sv_string) + length + 1, SV_STRING);
+    char *copy = (char *)(string + 1);
+    memcpy(copy, chars, length);
+    string->length = length;
+    string->chars = copy;
+    return sv_object_value(&string->header);
+}
+
+/* A list of the given values, or of false if there are none */
+sv_value sv_list_new(size_t length, const sv_value *items) {
+    sv_list *list = (sv_list *)sv_alloc(sizeof(sv_list) + length * sizeof(sv_value), SV_LIST);
+    list->length = length;
+    if (items != NULL && length > 0) {
+        memcpy(list->items, items, length * sizeof(sv_value));
+    }
+    return sv_object_value(&list->header);
+}
+
+/* The cells are false until they're filled in */
+sv_value sv_closure_new(sv_function function, size_t arity, const char *name, size_t count) {
+    sv_closure *closure =
+        (sv_closure *)sv_alloc(sizeof(sv_closure) + count * sizeof(sv_value), SV_CLOSURE);
+    closure->function = function;
+    closure->arity = arity;
+    closure->name = name;
+    closure->count = count;
+    return sv_object_value(&closure->header);
+}
+
+sv_value sv_data_new(int constructor, size_t count, const sv_value *fields) {
+    sv_data *data = (sv_data *)sv_alloc(sizeof(sv_data) + count * sizeof(sv_value), SV_DATA);
+    data->constructor = constructor;
+    data->count = count;
+    if (count > 0) {
+        memcpy(data->fields, fields, count * sizeof(sv_value));
+    }
+    return sv_object_value(&data->header);
+}
+
+sv_value sv_cell_new(sv_value value) {
+    sv_cell *cell = (sv_cell *)sv_alloc(sizeof(sv_cell), SV_CELL);
+    cell->value = value;
+    return sv_object_value(&cell->header);
+}
+
+/* Only false is falsey */
+int sv_truthy(sv_value value) {
+    return value.tag != SV_BOOLEAN || value.as.boolean;
+}
+
+/* Arithmetic on anything but numbers gives NaN */
+double sv_to_double(sv_value value) {
+    return value.tag == SV_NUMBER ? value.as.number : NAN;
+}
+
+int sv_tag_of(sv_value value) {
+    return value.tag == SV_DATA ? SV_DATA_OF(value)->constructor : -1;
+}
+
+void sv_no_match(sv_value value) {
+    char message[256];
+    if (value.tag == SV_DATA) {
+        sprintf(message, "No match for %.200s", sv_constructors[SV_DATA_OF(value)->constructor]);
+        sv_fail(message);
+    }
+    sv_fail("No match for a value that isn't data");
+}
+
+sv_value sv_call(sv_value callee, size_t count, sv_value *args) {
+    sv_closure *closure;
+    char message[128];
+    if (callee.tag != SV_CLOSURE) {
+        sv_fail("Called a value that isn't a function");
+    }
+    closure = SV_CLOSURE_OF(callee);
+    if (closure->arity != count) {
+        sprintf(message, "Called a function that takes %lu arguments with %lu",
+                (unsigned long)closure->arity, (unsigned long)count);
+        sv_fail(message);
+    }
+    return closure->function(closure, args);
+}
+
+/* A growing buffer of characters, for building strings */
+typedef struct {
+    char *chars;
+    size_t length;
+    size_t capacity;
+} sv_buffer;
+
+static void sv_append(sv_buffer *buffer, const char *chars, size_t length) {
+    if (buffer->length + length + 1 > buffer->capacity) {
+        buffer->capacity = (buffer->length + length + 1) * 2;
+        buffer->chars = realloc(buffer->chars, buffer->capacity);
+        if (buffer->chars == NULL) {
+            sv_fail("Out of memory");
+        }
+    }
+    memcpy(buffer->chars + buffer->length, chars, length);
+    buffer->length += length;
+    buffer->chars[buffer->length] = '\0';
+}
+
+static void sv_append_chars(sv_buffer *buffer, const char *chars) {
+    sv_append(buffer, chars, strlen(chars));
+}
+
+/* Numbers are written as JS writes them: the shortest digits that read back as the same
+ * number, with an exponent only if they're very large or small */
+static void sv_append_number(sv_buffer *buffer, double number) {
+    char formatted[32], digits[32];
+    int precision, exponent, count, i;
+
+    if (number != number) {
+        sv_append_chars(buffer, "NaN");
+        return;
+    }
+    if (number == 0) {
+        sv_append_chars(buffer, "0");
+        return;
+    }
+    if (number < 0) {
+        sv_append_chars(buffer, "-");
+        number = -number;
+    }
+    if (number == HUGE_VAL) {
+        sv_append_chars(buffer, "Infinity");
+        return;
+    }
+
+    for (precision = 1; precision <= 17; precision++) {
+        sprintf(formatted, "%.*e", precision - 1, number);
+        if (strtod(formatted, NULL) == number) {
+            break;
+        }
+    }
+    count = 0;
+    for (i = 0; formatted[i] != 'e'; i++) {
+        if (formatted[i] != '.') {
+            digits[count++] = formatted[i];
+        }
+    }
+    while (count > 1 && digits[count - 1] == '0') {
+        count--;
+    }
+    digits[count] = '\0';
+    /* The position of the decimal point relative to the digits */
+    exponent = atoi(formatted + i + 1) + 1;
+
+    if (count <= exponent && exponent <= 21) {
+        sv_append(buffer, digits, count);
+        for (i = count; i < exponent; i++) {
+            sv_append_chars(buffer, "0");
+        }
+    } else if (0 < exponent && exponent <= 21) {
+        sv_append(buffer, digits, exponent);
+        sv_append_chars(buffer, ".");
+        sv_append(buffer, digits + exponent, count - exponent);
+    } else if (-6 < exponent && exponent <= 0) {
+        sv_append_chars(buffer, "0.");
+        for (i = exponent; i < 0; i++) {
+            sv_append_chars(buffer, "0");
+        }
+        sv_append(buffer, digits, count);
+    } else {
+        sv_append(buffer, digits, 1);
+        if (count > 1) {
+            sv_append_chars(buffer, ".");
+            sv_append(buffer, digits + 1, count - 1);
+        }
+        sprintf(formatted, "e%c%d", exponent > 0 ? '+' : '-', abs(exponent - 1));
+        sv_append_chars(buffer, formatted);
+    }
+}
+
+/* The string JS's String gives for a value */
+static void sv_append_string(sv_buffer *buffer, sv_value value) {
+    size_t i;
+    switch (value.tag) {
+    case SV_BOOLEAN:
+        sv_append_chars(buffer, value.as.boolean ? "true" : "false");
+        break;
+    case SV_NUMBER:
+        sv_append_number(buffer, value.as.number);
+        break;
+    case SV_STRING:
+        sv_append(buffer, SV_STRING_OF(value)->chars, SV_STRING_OF(value)->length);
+        break;
+    case SV_LIST:
+        for (i = 0; i < SV_LIST_OF(value)->length; i++) {
+            if (i > 0) {
+                sv_append_chars(buffer, ",");
+            }
+            sv_append_string(buffer, SV_LIST_OF(value)->items[i]);
+        }
+        break;
+    case SV_CLOSURE:
+        sv_append_chars(buffer, "function ");
+        sv_append_chars(buffer, SV_CLOSURE_OF(value)->name);
+        sv_append_chars(buffer, "() { [native code] }");
+        break;
+    default:
+        sv_append_chars(buffer, "[object Object]");
+    }
+}
+
+/* Strings are measured and indexed in UTF-16 code units, as they are in JS */
+static size_t sv_units(const char *chars, size_t length) {
+    size_t units = 0, i;
+    for (i = 0; i < length; i++) {
+        unsigned char byte = (unsigned char)chars[i];
+        if ((byte & 0xC0) != 0x80) {
+            units += byte >= 0xF0 ? 2 : 1;
+        }
+    }
+    return units;
+}
+
+/* Values are printed the way Node's util.inspect shows them by default. Lists and data are
+ * on one line while they fit in 80 columns, long lists of short items are laid out in
+ * columns, and anything nested more than two levels deep is left out. */
+static size_t sv_indentation;
+/* The depth of the list or data last inspected, which decides whether an enclosing one
+ * may be on one line */
+static size_t sv_inspected_depth;
+
+static size_t sv_width(const sv_buffer *buffer) {
+    return sv_units(buffer->chars, buffer->length);
+}
+
+static void sv_append_spaces(sv_buffer *buffer, size_t count) {
+    while (count-- > 0) {
+        sv_append_chars(buffer, " ");
+    }
+}
+
+/* Quotes a string with whichever of ', " and ` it doesn't contain, escaping control
+ * characters */
+static void sv_append_quoted(sv_buffer *buffer, const char *chars, size_t length) {
+    char quote = '\'', escape[8];
+    size_t i;
+    if (memchr(chars, '\'', length) != NULL) {
+        if (memchr(chars, '"', length) == NULL) {
+            quote = '"';
+        } else if (memchr(chars, '`', length) == NULL) {
+            quote = '`';
+            for (i = 0; i + 1 < length; i++) {
+                if (chars[i] == '$' && chars[i + 1] == '{') {
+                    quote = '\'';
+                }
+            }
+        }
+    }
+
+    sv_append(buffer, &quote, 1);
+    for (i = 0; i < length; i++) {
+        unsigned char byte = (unsigned char)chars[i];
+        const char *named = NULL;
+        switch (byte) {
+        case '\b': named = "\\b"; break;
+        case '\t': named = "\\t"; break;
+        case '\n': named = "\\n"; break;
+        case '\f': named = "\\f"; break;
+        case '\r': named = "\\r"; break;
+        case '\\': named = "\\\\"; break;
+        case '\'': named = quote == '\'' ? "\\'" : NULL; break;
+        }
+        if (named != NULL) {
+            sv_append_chars(buffer, named);
+        } else if (byte < 0x20 || byte == 0x7F) {
+            sprintf(escape, "\\x%02X", byte);
+            sv_append_chars(buffer, escape);
+        } else if (byte == 0xC2 && i + 1 < length && (unsigned char)chars[i + 1] >= 0x80 &&
+                   (unsigned char)chars[i + 1] <= 0x9F) {
+            sprintf(escape, "\\x%02X", (unsigned char)chars[++i]);
+            sv_append_chars(buffer, escape);
+        } else {
+            sv_append(buffer, chars + i, 1);
+        }
+    }
+    sv_append(buffer, &quote, 1);
+}
+
+/* Long strings are split after their line breaks */
+static void sv_append_inspected_string(sv_buffer *buffer, sv_string *string) {
+    size_t start = 0, i;
+    size_t units = sv_units(string->chars, string->length);
+    if (units <= 16 || units + sv_indentation + 4 <= 80) {
+        sv_append_quoted(buffer, string->chars, string->length);
+        return;
+    }
+    for (i = 0; i < string->length; i++) {
+        if (string->chars[i] == '\n' && i + 1 < string->length) {
+            sv_append_quoted(buffer, string->chars + start, i + 1 - start);
+            sv_append_chars(buffer, " +\n");
+            sv_append_spaces(buffer, sv_indentation + 2);
+            start = i + 1;
+        }
+    }
+    sv_append_quoted(buffer, string->chars + start, string->length - start);
+}
+
+/* Lays out more than six items in as many columns as look square, if they're short enough
+ * to fit three to a line. Returns the number of lines, or 0 to leave them as they are. */
+static size_t sv_group(sv_buffer *entries, size_t count, size_t shown, int numbers) {
+    size_t total = 0, longest = 0, widest, columns, lines = 0, i, j;
+    size_t widths[101];
+    double bias, biased;
+    sv_buffer *line;
+
+    for (i = 0; i < shown; i++) {
+        size_t width = sv_width(&entries[i]);
+        total += width + 2;
+        longest = width > longest ? width : longest;
+    }
+    widest = longest + 2;
+    if (widest * 3 + sv_indentation >= 80 || (total / (double)widest <= 5 && longest > 6)) {
+        return 0;
+    }
+
+    bias = sqrt(widest - total / (double)count);
+    biased = widest - 3 - bias > 1 ? widest - 3 - bias : 1;
+    columns = (size_t)floor(sqrt(2.5 * biased * shown) / biased + 0.5);
+    columns = columns < (80 - sv_indentation) / widest ? columns : (80 - sv_indentation) / widest;
+    columns = columns < 12 ? columns : 12;
+    if (columns <= 1) {
+        return 0;
+    }
+
+    for (i = 0; i < columns; i++) {
+        widths[i] = 0;
+        for (j = i; j < shown; j += columns) {
+            size_t width = sv_width(&entries[j]);
+            widths[i] = width > widths[i] ? width : widths[i];
+        }
+        widths[i] += 2;
+    }
+
+    /* Each line is built in place of the first of its entries, which are all before it */
+    for (i = 0; i < shown; i += columns) {
+        sv_buffer built = {NULL, 0, 0};
+        size_t end = i + columns < shown ? i + columns : shown;
+        for (j = i; j < end; j++) {
+            size_t padding = widths[j - i] - sv_width(&entries[j]) - 2;
+            if (numbers) {
+                sv_append_spaces(&built, padding);
+            }
+            sv_append(&built, entries[j].chars, entries[j].length);
+            if (j + 1 < end) {
+                sv_append_chars(&built, ", ");
+                if (!numbers) {
+                    sv_append_spaces(&built, padding);
+                }
+            }
+        }
+        for (j = i; j < end; j++) {
+            free(entries[j].chars);
+        }
+        line = &entries[lines++];
+        *line = built;
+    }
+    if (shown < count) {
+        entries[lines++] = entries[shown];
+    }
+    return lines;
+}
+
+static void sv_append_inspected(sv_buffer *buffer, sv_value value, size_t depth);
+
+/* Inspects the items of a list or the fields of data, and joins them on one line or one
+ * to a line */
+static void sv_append_entries(sv_buffer *buffer, sv_value value, size_t depth) {
+    sv_buffer entries[101];
+    size_t count, shown, lines, width, i;
+    int list = value.tag == SV_LIST, numbers = 1, single = 0;
+    const char *open = list ? "[" : "{", *close = list ? "]" : "}";
+    char field[48];
+
+    count = list ? SV_LIST_OF(value)->length : SV_DATA_OF(value)->count + 1;
+    shown = count > 100 ? 100 : count;
+    for (i = 0; i < shown; i++) {
+        sv_buffer *entry = &entries[i];
+        sv_value item;
+        entry->chars = NULL;
+        entry->length = entry->capacity = 0;
+        if (list) {
+            item = SV_LIST_OF(value)->items[i];
+            numbers = numbers && item.tag == SV_NUMBER;
+        } else if (i == 0) {
+            sv_append_chars(entry, "'$tag': ");
+            sv_append_quoted(entry, sv_constructors[SV_DATA_OF(value)->constructor],
+                             strlen(sv_constructors[SV_DATA_OF(value)->constructor]));
+            continue;
+        } else {
+            sprintf(field, "'$%lu': ", (unsigned long)(i - 1));
+            sv_append_chars(entry, field);
+            item = SV_DATA_OF(value)->fields[i - 1];
+        }
+        sv_indentation += 2;
+        sv_append_inspected(entry, item, depth + 1);
+        sv_indentation -= 2;
+    }
+    lines = shown;
+    if (shown < count) {
+        entries[shown].chars = NULL;
+        entries[shown].length = entries[shown].capacity = 0;
+        sprintf(field, "... %lu more item%s", (unsigned long)(count - shown),
+                count - shown > 1 ? "s" : "");
+        sv_append_chars(&entries[shown], field);
+        lines++;
+        numbers = numbers && SV_LIST_OF(value)->items[shown].tag == SV_NUMBER;
+    }
+
+    if (list && lines > 6) {
+        size_t grouped = sv_group(entries, lines, shown, numbers);
+        lines = grouped > 0 ? grouped : lines;
+    }
+    if (sv_inspected_depth - depth < 3 && lines == (shown < count ? shown + 1 : shown)) {
+        width = lines + lines + sv_indentation + 1 + 10;
+        single = width + lines <= 80;
+        for (i = 0; i < lines && single; i++) {
+            width += sv_width(&entries[i]);
+            single = width <= 80 && memchr(entries[i].chars, '\n', entries[i].length) == NULL;
+        }
+    }
+
+    sv_append_chars(buffer, open);
+    for (i = 0; i < lines; i++) {
+        if (single) {
+            sv_append_chars(buffer, i > 0 ? ", " : " ");
+        } else {
+            sv_append_chars(buffer, i > 0 ? ",\n" : "\n");
+            sv_append_spaces(buffer, sv_indentation + 2);
+        }
+        sv_append(buffer, entries[i].chars, entries[i].length);
+        free(entries[i].chars);
+    }
+    if (single) {
+        sv_append_chars(buffer, " ");
+    } else {
+        sv_append_chars(buffer, "\n");
+        sv_append_spaces(buffer, sv_indentation);
+    }
+    sv_append_chars(buffer, close);
+}
+
+static void sv_append_inspected(sv_buffer *buffer, sv_value value, size_t depth) {
+    switch (value.tag) {
+    case SV_NUMBER:
+        if (value.as.number == 0 && signbit(value.as.number)) {
+            sv_append_chars(buffer, "-0");
+        } else {
+            sv_append_number(buffer, value.as.number);
+        }
+        break;
+    case SV_STRING:
+        sv_append_inspected_string(buffer, SV_STRING_OF(value));
+        break;
+    case SV_CLOSURE:
+        if (strcmp(SV_CLOSURE_OF(value)->name, "") == 0) {
+            sv_append_chars(buffer, "[Function (anonymous)]");
+        } else {
+            sv_append_chars(buffer, "[Function: ");
+            sv_append_chars(buffer, SV_CLOSURE_OF(value)->name);
+            sv_append_chars(buffer, "]");
+        }
+        break;
+    case SV_LIST:
+    case SV_DATA:
+        if (value.tag == SV_LIST && SV_LIST_OF(value)->length == 0) {
+            sv_append_chars(buffer, "[]");
+        } else if (depth > 2) {
+            sv_append_chars(buffer, value.tag == SV_LIST ? "[Array]" : "[Object]");
+        } else {
+            sv_inspected_depth = depth;
+            sv_append_entries(buffer, value, depth);
+        }
+        break;
+    default:
+        sv_append_string(buffer, value);
+    }
+}
+
+static sv_value sv_buffer_string(sv_buffer *buffer) {
+    sv_value string = sv_string_new(buffer->chars != NULL ? buffer->chars : "", buffer->length);
+    free(buffer->chars);
+    return string;
+}
+
+sv_value sv_add(sv_value a, sv_value b) {
+    sv_buffer buffer = {NULL, 0, 0};
+    if (a.tag == SV_NUMBER && b.tag == SV_NUMBER) {
+        return sv_number(a.as.number + b.as.number);
+    }
+    if (a.tag != SV_STRING || b.tag != SV_STRING) {
+        sv_fail("+ needs two numbers or two strings");
+    }
+    sv_append_string(&buffer, a);
+    sv_append_string(&buffer, b);
+    return sv_buffer_string(&buffer);
+}
+
+/* Strings are compared by their bytes, which orders them by code point */
+static int sv_compare_strings(sv_string *a, sv_string *b) {
+    size_t length = a->length < b->length ? a->length : b->length;
+    int order = memcmp(a->chars, b->chars, length);
+    if (order != 0) {
+        return order < 0 ? -1 : 1;
+    }
+    return a->length < b->length ? -1 : a->length > b->length;
+}
+
+/* Numbers and strings are equal by value, as are booleans. Anything else is equal only to
+ * itself, as JS compares objects. */
+int sv_equals(sv_value a, sv_value b) {
+    if (a.tag != b.tag) {
+        return 0;
+    }
+    switch (a.tag) {
+    case SV_BOOLEAN:
+        return a.as.boolean == b.as.boolean;
+    case SV_NUMBER:
+        return a.as.number == b.as.number;
+    case SV_STRING:
+        return sv_compare_strings(SV_STRING_OF(a), SV_STRING_OF(b)) == 0;
+    default:
+        return a.as.object == b.as.object;
+    }
+}
+
+/* -1, 0 or 1 for numbers or strings in order, and 2 for anything else */
+int sv_compare(sv_value a, sv_value b) {
+    if (a.tag == SV_NUMBER && b.tag == SV_NUMBER) {
+        if (a.as.number < b.as.number) {
+            return -1;
+        }
+        if (a.as.number > b.as.number) {
+            return 1;
+        }
+        return a.as.number == b.as.number ? 0 : 2;
+    }
+    if (a.tag == SV_STRING && b.tag == SV_STRING) {
+        return sv_compare_strings(SV_STRING_OF(a), SV_STRING_OF(b));
+    }
+    return 2;
+}
+
+int sv_at_least(sv_value a, sv_value b) {
+    int order = sv_compare(a, b);
+    return order == 0 || order == 1;
+}
+
+/* The natives that externs can be bound to, which take their arguments as an array */
+
+/* The byte offset of a code unit, which can't be inside a character */
+static size_t sv_offset(const char *chars, size_t length, size_t unit) {
+    size_t units = 0, i;
+    for (i = 0; i < length && units < unit; i++) {
+        unsigned char byte = (unsigned char)chars[i];
+        if ((byte & 0xC0) != 0x80) {
+            units += byte >= 0xF0 ? 2 : 1;
+        }
+    }
+    while (i < length && ((unsigned char)chars[i] & 0xC0) == 0x80) {
+        i++;
+    }
+    return i;
+}
+
+sv_value sv_native_length(size_t count, sv_value *args) {
+    (void)count;
+    if (args[0].tag == SV_STRING) {
+        return sv_number((double)sv_units(SV_STRING_OF(args[0])->chars,
+                                           SV_STRING_OF(args[0])->length));
+    }
+    return sv_number((double)SV_LIST_OF(args[0])->length);
+}
+
+sv_value sv_native_get(size_t count, sv_value *args) {
+    sv_list *list = SV_LIST_OF(args[0]);
+    double index = sv_to_double(args[1]);
+    sv_buffer buffer = {NULL, 0, 0};
+    (void)count;
+    if (!(index >= 0 && index < (double)list->length)) {
+        sv_append_chars(&buffer, "Index ");
+        sv_append_number(&buffer, index);
+        sv_append_chars(&buffer, " is out of bounds for a list of length ");
+        sv_append_number(&buffer, (double)list->length);
+        sv_fail(buffer.chars);
+    }
+    return list->items[(size_t)index];
+}
+
+sv_value sv_native_append(size_t count, sv_value *args) {
+    size_t length = SV_LIST_OF(args[0])->length;
+    sv_value list = sv_list_new(length + 1, NULL);
+    (void)count;
+    memcpy(SV_LIST_OF(list)->items, SV_LIST_OF(args[0])->items, length * sizeof(sv_value));
+    SV_LIST_OF(list)->items[length] = args[1];
+    return list;
+}
+
+sv_value sv_native_concat(size_t count, sv_value *args) {
+    size_t a = SV_LIST_OF(args[0])->length, b = SV_LIST_OF(args[1])->length;
+    sv_value list = sv_list_new(a + b, NULL);
+    (void)count;
+    memcpy(SV_LIST_OF(list)->items, SV_LIST_OF(args[0])->items, a * sizeof(sv_value));
+    memcpy(SV_LIST_OF(list)->items + a, SV_LIST_OF(args[1])->items, b * sizeof(sv_value));
+    return list;
+}
+
+/* The numbers from `from` up to but not including `to` */
+sv_value sv_native_range(size_t count, sv_value *args) {
+    double from = sv_to_double(args[0]), to = sv_to_double(args[1]);
+    size_t length = to > from ? (size_t)ceil(to - from) : 0, i;
+    sv_value list = sv_list_new(length, NULL);
+    (void)count;
+    for (i = 0; i < length; i++) {
+        SV_LIST_OF(list)->items[i] = sv_number(from + (double)i);
+    }
+    return list;
+}
+
+/* The natives that call closures keep what they're building in slots, since the closures
+ * can allocate */
+sv_value sv_native_fold(size_t count, sv_value *args) {
+    sv_value s[3] = {{0}};
+    size_t i;
+    SV_ENTER(s);
+    (void)count;
+    s[0] = args[1];
+    for (i = 0; i < SV_LIST_OF(args[0])->length; i++) {
+        s[1] = s[0];
+        s[2] = SV_LIST_OF(args[0])->items[i];
+        s[0] = sv_call(args[2], 2, &s[1]);
+    }
+    SV_LEAVE();
+    return s[0];
+}
+
+sv_value sv_native_map(size_t count, sv_value *args) {
+    sv_value s[2] = {{0}};
+    size_t i;
+    SV_ENTER(s);
+    (void)count;
+    s[0] = sv_list_new(SV_LIST_OF(args[0])->length, NULL);
+    for (i = 0; i < SV_LIST_OF(args[0])->length; i++) {
+        s[1] = SV_LIST_OF(args[0])->items[i];
+        s[1] = sv_call(args[1], 1, &s[1]);
+        SV_LIST_OF(s[0])->items[i] = s[1];
+    }
+    SV_LEAVE();
+    return s[0];
+}
+
+/* The list has room for every element, and is shortened to those that are kept */
+sv_value sv_native_filter(size_t count, sv_value *args) {
+    sv_value s[2] = {{0}};
+    size_t i, kept = 0;
+    SV_ENTER(s);
+    (void)count;
+    s[0] = sv_list_new(SV_LIST_OF(args[0])->length, NULL);
+    SV_LIST_OF(s[0])->length = 0;
+    for (i = 0; i < SV_LIST_OF(args[0])->length; i++) {
+        s[1] = SV_LIST_OF(args[0])->items[i];
+        if (sv_truthy(sv_call(args[1], 1, &s[1]))) {
+            SV_LIST_OF(s[0])->items[kept++] = SV_LIST_OF(args[0])->items[i];
+            SV_LIST_OF(s[0])->length = kept;
+        }
+    }
+    SV_LEAVE();
+    return s[0];
+}
+
+/* The indices are clamped and put in order, as JS's substring does */
+sv_value sv_native_substring(size_t count, sv_value *args) {
+    sv_string *string = SV_STRING_OF(args[0]);
+    double units = (double)sv_units(string->chars, string->length);
+    double from = sv_to_double(args[1]), to = sv_to_double(args[2]), swap;
+    size_t start, end;
+    (void)count;
+    from = from != from || from < 0 ? 0 : from > units ? units : from;
+    to = to != to || to < 0 ? 0 : to > units ? units : to;
+    if (from > to) {
+        swap = from;
+        from = to;
+        to = swap;
+    }
+    start = sv_offset(string->chars, string->length, (size_t)from);
+    end = sv_offset(string->chars, string->length, (size_t)to);
+    return sv_string_new(string->chars + start, end - start);
+}
+
+/* An empty separator splits a string into its characters */
+sv_value sv_native_split(size_t count, sv_value *args) {
+    sv_value s[2] = {{0}};
+    sv_string *string, *separator;
+    size_t start = 0, i = 0;
+    SV_ENTER(s);
+    (void)count;
+    s[0] = sv_list_new(0, NULL);
+    for (;;) {
+        string = SV_STRING_OF(args[0]);
+        separator = SV_STRING_OF(args[1]);
+        if (separator->length == 0) {
+            if (i == string->length) {
+                break;
+            }
+            i++;
+            while (i < string->length && ((unsigned char)string->chars[i] & 0xC0) == 0x80) {
+                i++;
+            }
+        } else {
+            while (i + separator->length <= string->length &&
+                   memcmp(string->chars + i, separator->chars, separator->length) != 0) {
+                i++;
+            }
+            if (i + separator->length > string->length) {
+                i = string->length;
+            }
+        }
+
+        s[1] = sv_string_new(string->chars + start, i - start);
+        s[1] = sv_native_append(2, s);
+        s[0] = s[1];
+        string = SV_STRING_OF(args[0]);
+        if (i >= string->length) {
+            break;
+        }
+        i += separator->length;
+        start = i;
+    }
+    SV_LEAVE();
+    return s[0];
+}
+
+sv_value sv_native_join(size_t count, sv_value *args) {
+    sv_buffer buffer = {NULL, 0, 0};
+    size_t i;
+    (void)count;
+    for (i = 0; i < SV_LIST_OF(args[0])->length; i++) {
+        if (i > 0) {
+            sv_append_string(&buffer, args[1]);
+        }
+        sv_append_string(&buffer, SV_LIST_OF(args[0])->items[i]);
+    }
+    return sv_buffer_string(&buffer);
+}
+
+sv_value sv_native_string(size_t count, sv_value *args) {
+    sv_buffer buffer = {NULL, 0, 0};
+    (void)count;
+    sv_append_string(&buffer, args[0]);
+    return sv_buffer_string(&buffer);
+}
+
+/* Blank strings are 0, and strings that aren't entirely a number are NaN */
+sv_value sv_native_number(size_t count, sv_value *args) {
+    sv_buffer buffer = {NULL, 0, 0};
+    double number;
+    char *end, *start;
+    (void)count;
+    switch (args[0].tag) {
+    case SV_NUMBER:
+        return args[0];
+    case SV_BOOLEAN:
+        return sv_number(args[0].as.boolean);
+    case SV_STRING:
+        sv_append_string(&buffer, args[0]);
+        start = buffer.chars != NULL ? buffer.chars : "";
+        while (*start == ' ' || (*start >= '\t' && *start <= '\r')) {
+            start++;
+        }
+        number = *start == '\0' ? 0 : strtod(start, &end);
+        if (*start != '\0') {
+            while (*end == ' ' || (*end >= '\t' && *end <= '\r')) {
+                end++;
+            }
+            if (*end != '\0') {
+                number = NAN;
+            }
+        }
+        free(buffer.chars);
+        return sv_number(number);
+    default:
+        return sv_number(NAN);
+    }
+}
+
+sv_value sv_native_floor(size_t count, sv_value *args) {
+    (void)count;
+    return sv_number(floor(sv_to_double(args[0])));
+}
+
+sv_value sv_native_ceil(size_t count, sv_value *args) {
+    (void)count;
+    return sv_number(ceil(sv_to_double(args[0])));
+}
+
+/* Halves round up, as they do in JS */
+sv_value sv_native_round(size_t count, sv_value *args) {
+    (void)count;
+    return sv_number(floor(sv_to_double(args[0]) + 0.5));
+}
+
+sv_value sv_native_sqrt(size_t count, sv_value *args) {
+    (void)count;
+    return sv_number(sqrt(sv_to_double(args[0])));
+}
+
+sv_value sv_native_pow(size_t count, sv_value *args) {
+    (void)count;
+    return sv_number(pow(sv_to_double(args[0]), sv_to_double(args[1])));
+}
+
+/* Prints the values separated by spaces, with strings printed as they are */
+static sv_value sv_print(FILE *file, size_t count, sv_value *args) {
+    sv_buffer buffer = {NULL, 0, 0};
+    size_t i;
+    for (i = 0; i < count; i++) {
+        if (i > 0) {
+            sv_append_chars(&buffer, " ");
+        }
+        if (args[i].tag == SV_STRING) {
+            sv_append_string(&buffer, args[i]);
+        } else {
+            sv_append_inspected(&buffer, args[i], 0);
+        }
+    }
+    sv_append_chars(&buffer, "\n");
+    fwrite(buffer.chars, 1, buffer.length, file);
+    free(buffer.chars);
+    return sv_boolean(0);
+}
+
+sv_value sv_native_console_log(size_t count, sv_value *args) {
+    return sv_print(stdout, count, args);
+}
+
+sv_value sv_native_console_error(size_t count, sv_value *args) {
+    return sv_print(stderr, count, args);
+}
@@ -0,0 +1,214 @@
+use super::emitter;
+use super::modules::Module;
+use super::parser::ASTNode;
+
+use std::fmt;
+use std::mem;
+use std::path::PathBuf;
+
+// A declaration left out of the output because nothing reaches it
+#[derive(Debug, PartialEq)]
+pub struct Removal {
+    pub path: PathBuf,
+    pub name: String,
+}
+
+impl fmt::Display for Removal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Removed unused {} from {}",
+            self.name,
+            self.path.display()
+        )
+    }
+}
+
+// Removes the top-level functions and externs that can't be reached from the top-level
+// expressions of a program's modules, both in the modules and in the prelude. Everything
+// else at the top level runs when its module is loaded, so it's always reachable. The root
+// module's named functions and exports are reachable from outside only if the output
+// format exports them.
+pub fn shake(modules: &mut [Module], prelude: &mut Module, exported: bool) -> Vec<Removal> {
+    let root = modules.len() - 1;
+    let uses: Vec<Vec<String>> = modules
+        .iter()
+        .map(|module| emitter::prelude_uses(module, &prelude.exports))
+        .collect();
+
+    // The prelude is treated as the module after the root
+    let mut reached: Vec<Vec<bool>> = {
+        let all: Vec<&Module> = modules.iter().chain(Some(&*prelude)).collect();
+        let mut reached: Vec<Vec<bool>> = all
+            .iter()
+            .map(|module| vec![false; elements(module).len()])
+            .collect();
+
+        let mut pending = Vec::new();
+        for (index, module) in all.iter().enumerate() {
+            for (i, expr) in elements(module).iter().enumerate() {
+                let exported = exported
+                    && index == root
+                    && (expr.function_name().is_some() || !expr.exported_names().is_empty());
+                if expr.declared_names().is_empty() || exported {
+                    pending.push((index, i));
+                }
+            }
+        }
+
+        while let Some((index, i)) = pending.pop() {
+            if reached[index][i] {
+                continue;
+            }
+            reached[index][i] = true;
+
+            let module = all[index];
+            elements(module)[i].walk(&mut |node| match *node {
+                ASTNode::Name(ref name, _) => {
+                    if let Some(j) = declaration(module, name) {
+                        pending.push((index, j));
+                    } else if index < all.len() - 1 && uses[index].contains(name) {
+                        if let Some(j) = declaration(all[all.len() - 1], name) {
+                            pending.push((all.len() - 1, j));
+                        }
+                    }
+                }
+                ASTNode::Qualified {
+                    module: ref alias,
+                    ref name,
+                    ..
+                } => {
+                    let import = module.imports.iter().find(|(import, _)| import == alias);
+                    if let Some(&(_, import)) = import {
+                        if let Some(j) = declaration(all[import], name) {
+                            pending.push((import, j));
+                        }
+                    }
+                }
+                _ => {}
+            });
+        }
+
+        reached
+    };
+
+    let mut removals = Vec::new();
+    let prelude_reached = reached.pop().unwrap_or_default();
+    for (module, reached) in modules.iter_mut().zip(reached) {
+        remove_unreached(module, &reached, &mut removals);
+    }
+    remove_unreached(prelude, &prelude_reached, &mut removals);
+
+    removals
+}
+
+fn elements(module: &Module) -> &[ASTNode] {
+    match module.ast {
+        ASTNode::Sequence(ref exprs) => exprs,
+        _ => &[],
+    }
+}
+
+// The index of the top-level declaration binding name, if there is one
+fn declaration(module: &Module, name: &str) -> Option<usize> {
+    elements(module)
+        .iter()
+        .position(|expr| expr.declared_names().contains(&name))
+}
+
+fn remove_unreached(module: &mut Module, reached: &[bool], removals: &mut Vec<Removal>) {
+    if let ASTNode::Sequence(ref mut exprs) = module.ast {
+        for (expr, reached) in mem::take(exprs).into_iter().zip(reached) {
+            if *reached {
+                exprs.push(expr);
+                continue;
+            }
+
+            for name in expr.declared_names() {
+                module.exports.retain(|export| export != name);
+                removals.push(Removal {
+                    path: module.path.clone(),
+                    name: String::from(name),
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use modules;
+    use prelude;
+    use std::path::Path;
+
+    fn shake_str(files: &[(&str, &str)], exported: bool) -> (Vec<Module>, Module, Vec<String>) {
+        let mut modules = modules::load_with(Path::new("main.silver"), |path| {
+            Ok(String::from(
+                files
+                    .iter()
+                    .find(|&&(name, _)| Path::new(name) == path)
+                    .unwrap()
+                    .1,
+            ))
+        })
+        .unwrap();
+        let mut prelude = prelude::module();
+
+        let removals = shake(&mut modules, &mut prelude, exported)
+            .iter()
+            .map(|removal| removal.to_string())
+            .collect();
+        (modules, prelude, removals)
+    }
+
+    #[test]
+    fn test_removes_unreachable_functions() {
+        let files = [
+            (
+                "main.silver",
+                "import \"lib.silver\" as lib; fn unused() { used() }; fn used() { 1 };
+                 fn f() { lib.g() }; f()",
+            ),
+            (
+                "lib.silver",
+                "fn helper() { 1 }; fn dead() { 2 }; export fn g() { helper() };
+                 export fn h() { dead() }",
+            ),
+        ];
+        let (modules, _, removals) = shake_str(&files, false);
+
+        // Functions only reachable from unreachable functions are removed too
+        assert_eq!(
+            removals[..4],
+            [
+                String::from("Removed unused dead from lib.silver"),
+                String::from("Removed unused h from lib.silver"),
+                String::from("Removed unused unused from main.silver"),
+                String::from("Removed unused used from main.silver"),
+            ]
+        );
+        assert_eq!(modules[0].exports, vec![String::from("g")]);
+        assert_eq!(elements(&modules[1]).len(), 3);
+
+        // Formats exporting the program's functions keep them
+        let (_, _, removals) = shake_str(&files, true);
+        assert!(!removals
+            .iter()
+            .any(|removal| removal.contains("main.silver")));
+    }
+
+    #[test]
+    fn test_removes_unused_prelude() {
+        let (_, prelude, _) = shake_str(&[("main.silver", "fn f(xs) { sum(xs) }; 1")], false);
+        assert!(elements(&prelude).is_empty());
+
+        let (_, prelude, removals) = shake_str(&[("main.silver", "sum([1])")], false);
+        assert_eq!(
+            prelude.exports,
+            vec![String::from("fold"), String::from("sum")]
+        );
+        assert!(removals.contains(&String::from("Removed unused map from prelude.silver")));
+    }
+}
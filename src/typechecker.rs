@@ -0,0 +1,1689 @@
+use super::lexer::Token;
+use super::parser::{ASTNode, ExternFunction, MatchArm, Pattern, TypeAnnotation, Variant};
+
+use super::util::{Error, Span};
+
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type {
+    Int,
+    Float,
+    String,
+    Bool,
+    Function(Vec<Type>, Box<Type>),
+    List(Box<Type>),
+    // A type declared with `type`, applied to the types of its parameters
+    Data(String, Vec<Type>),
+    Var(usize),
+}
+
+// Restricts the types a variable may be bound to, so that overloaded operators like `+` can
+// be given a single polymorphic type
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Class {
+    Any,
+    Ordered, // Int, Float or String
+    Numeric, // Int or Float
+}
+
+impl Class {
+    fn admits(self, ty: &Type) -> bool {
+        match self {
+            Class::Any => true,
+            Class::Ordered => matches!(*ty, Type::Int | Type::Float | Type::String),
+            Class::Numeric => matches!(*ty, Type::Int | Type::Float),
+        }
+    }
+
+    // The more restrictive of two classes (they're totally ordered)
+    fn meet(self, other: Class) -> Class {
+        match (self, other) {
+            (Class::Numeric, _) | (_, Class::Numeric) => Class::Numeric,
+            (Class::Ordered, _) | (_, Class::Ordered) => Class::Ordered,
+            _ => Class::Any,
+        }
+    }
+}
+
+// A type quantified over some of its variables, as given to let-bound functions
+#[derive(Debug, Clone)]
+struct Scheme {
+    vars: Vec<usize>,
+    ty: Type,
+}
+
+// The types of a module's exports, as seen by modules importing it. Each type's variables
+// are numbered from zero, indexing into its classes.
+#[derive(Debug, Clone, Default)]
+pub struct Interface {
+    exports: HashMap<String, (Type, Vec<Class>)>,
+}
+
+impl Interface {
+    pub fn get(&self, name: &str) -> Option<&Type> {
+        self.exports.get(name).map(|(ty, _)| ty)
+    }
+}
+
+// A data type's parameters are the variables in its constructors' field types, so that each
+// use of a constructor can give them different types. Until its constructors are defined,
+// its scheme is a single variable standing for the type.
+#[derive(Debug, Clone)]
+struct DataType {
+    constructors: Vec<String>,
+    scheme: Scheme,
+}
+
+// A constructor's scheme is quantified over its data type's parameters
+#[derive(Debug, Clone)]
+struct Constructor {
+    data: String,
+    scheme: Scheme,
+}
+
+struct TypeChecker {
+    // For each type variable, the type it has been unified with (if any) and its class
+    bindings: Vec<Option<Type>>,
+    classes: Vec<Class>,
+    // Variables named in annotations stand for any type, so they can't be bound (or given
+    // a class) while the declaration they annotate is being checked
+    rigid: HashSet<usize>,
+    scopes: Vec<HashMap<String, Scheme>>,
+    // Data types and constructors are scoped like the names in scopes, but are only
+    // declared by sequences
+    types: Vec<HashMap<String, DataType>>,
+    constructors: Vec<HashMap<String, Constructor>>,
+    imports: HashMap<String, Interface>,
+    // Names that aren't bound anywhere in the module fall back to the prelude's exports
+    prelude: Interface,
+    // The schemes of this module's exports, recorded as they're checked
+    exports: HashMap<String, Scheme>,
+    errors: Vec<Error>,
+}
+
+// Infers the type of a program, reporting every type error found
+pub fn check(ast: &ASTNode) -> Result<Type, Vec<Error>> {
+    check_module(ast, HashMap::new(), Interface::default()).map(|(ty, _)| ty)
+}
+
+// Checks a module, given the interfaces of the modules it imports by alias and of the
+// prelude, returning its type along with its own interface
+pub fn check_module(
+    ast: &ASTNode,
+    imports: HashMap<String, Interface>,
+    prelude: Interface,
+) -> Result<(Type, Interface), Vec<Error>> {
+    let mut checker = TypeChecker::new();
+    checker.imports = imports;
+    checker.prelude = prelude;
+    let ty = checker.infer(ast);
+    let ty = checker.resolve(&ty);
+
+    if checker.errors.is_empty() {
+        let interface = checker.interface();
+        Ok((ty, interface))
+    } else {
+        Err(checker.errors)
+    }
+}
+
+impl TypeChecker {
+    fn new() -> TypeChecker {
+        TypeChecker {
+            bindings: Vec::new(),
+            classes: Vec::new(),
+            rigid: HashSet::new(),
+            scopes: vec![HashMap::new()],
+            types: vec![HashMap::new()],
+            constructors: vec![HashMap::new()],
+            imports: HashMap::new(),
+            prelude: Interface::default(),
+            exports: HashMap::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    fn fresh(&mut self, class: Class) -> Type {
+        self.bindings.push(None);
+        self.classes.push(class);
+        Type::Var(self.bindings.len() - 1)
+    }
+
+    // Follows variable bindings until reaching a constructor or an unbound variable
+    fn prune(&self, ty: &Type) -> Type {
+        match *ty {
+            Type::Var(var) => match self.bindings[var] {
+                Some(ref bound) => self.prune(bound),
+                None => ty.clone(),
+            },
+            _ => ty.clone(),
+        }
+    }
+
+    // Fully applies the current substitution to a type
+    fn resolve(&self, ty: &Type) -> Type {
+        match self.prune(ty) {
+            Type::Function(args, ret) => Type::Function(
+                args.iter().map(|arg| self.resolve(arg)).collect(),
+                Box::new(self.resolve(&ret)),
+            ),
+            Type::List(element) => Type::List(Box::new(self.resolve(&element))),
+            Type::Data(name, params) => Type::Data(
+                name,
+                params.iter().map(|param| self.resolve(param)).collect(),
+            ),
+            pruned => pruned,
+        }
+    }
+
+    fn occurs(&self, var: usize, ty: &Type) -> bool {
+        match self.prune(ty) {
+            Type::Var(other) => var == other,
+            Type::Function(args, ret) => {
+                args.iter().any(|arg| self.occurs(var, arg)) || self.occurs(var, &ret)
+            }
+            Type::List(element) => self.occurs(var, &element),
+            Type::Data(_, params) => params.iter().any(|param| self.occurs(var, param)),
+            _ => false,
+        }
+    }
+
+    fn unify(&mut self, a: &Type, b: &Type) -> Result<(), ()> {
+        match (self.prune(a), self.prune(b)) {
+            (Type::Var(x), Type::Var(y)) => {
+                if x == y {
+                    return Ok(());
+                }
+                let (x, y) = if self.rigid.contains(&x) {
+                    (y, x)
+                } else {
+                    (x, y)
+                };
+                if self.rigid.contains(&x)
+                    || (self.rigid.contains(&y) && self.classes[x] != Class::Any)
+                {
+                    return Err(());
+                }
+                self.classes[y] = self.classes[y].meet(self.classes[x]);
+                self.bindings[x] = Some(Type::Var(y));
+                Ok(())
+            }
+            (Type::Var(var), ty) | (ty, Type::Var(var)) => self.bind(var, &ty),
+            (Type::Function(a_args, a_ret), Type::Function(b_args, b_ret)) => {
+                if a_args.len() != b_args.len() {
+                    return Err(());
+                }
+                for (a_arg, b_arg) in a_args.iter().zip(b_args.iter()) {
+                    self.unify(a_arg, b_arg)?;
+                }
+                self.unify(&a_ret, &b_ret)
+            }
+            (Type::List(a), Type::List(b)) => self.unify(&a, &b),
+            (Type::Data(a, a_params), Type::Data(b, b_params)) => {
+                if a != b {
+                    return Err(());
+                }
+                for (a_param, b_param) in a_params.iter().zip(b_params.iter()) {
+                    self.unify(a_param, b_param)?;
+                }
+                Ok(())
+            }
+            (a, b) => {
+                if a == b {
+                    Ok(())
+                } else {
+                    Err(())
+                }
+            }
+        }
+    }
+
+    fn bind(&mut self, var: usize, ty: &Type) -> Result<(), ()> {
+        if self.rigid.contains(&var) || self.occurs(var, ty) || !self.classes[var].admits(ty) {
+            return Err(());
+        }
+        self.bindings[var] = Some(ty.clone());
+        Ok(())
+    }
+
+    // Unifies the two types, recording a mismatch at the given span on failure
+    fn expect(&mut self, expected: &Type, found: &Type, span: Span, context: &str) {
+        let expected_str = self.describe(expected);
+        let found_str = self.describe(found);
+
+        if self.unify(expected, found).is_err() {
+            self.errors.push(span.get_error(format!(
+                "Type mismatch{}: expected {}, found {}",
+                context, expected_str, found_str
+            )));
+        }
+    }
+
+    // Renders a type for diagnostics, naming unconstrained variables a, b, c, ... in order
+    // of appearance
+    fn describe(&self, ty: &Type) -> String {
+        let mut names: Vec<usize> = Vec::new();
+        self.describe_helper(&self.resolve(ty), &mut names)
+    }
+
+    fn describe_helper(&self, ty: &Type, names: &mut Vec<usize>) -> String {
+        match *ty {
+            Type::Int => String::from("Int"),
+            Type::Float => String::from("Float"),
+            Type::String => String::from("String"),
+            Type::Bool => String::from("Bool"),
+            Type::Data(ref name, ref params) => {
+                if params.is_empty() {
+                    name.clone()
+                } else {
+                    let params: Vec<String> = params
+                        .iter()
+                        .map(|param| self.describe_helper(param, names))
+                        .collect();
+                    format!("{}<{}>", name, params.join(", "))
+                }
+            }
+            Type::Function(ref args, ref ret) => {
+                let args: Vec<String> = args
+                    .iter()
+                    .map(|arg| self.describe_helper(arg, names))
+                    .collect();
+                format!(
+                    "({}) -> {}",
+                    args.join(", "),
+                    self.describe_helper(ret, names)
+                )
+            }
+            Type::List(ref element) => format!("[{}]", self.describe_helper(element, names)),
+            Type::Var(var) => match self.classes[var] {
+                Class::Numeric => String::from("Numeric"),
+                Class::Ordered => String::from("Ordered"),
+                Class::Any => {
+                    let index = match names.iter().position(|name| *name == var) {
+                        Some(index) => index,
+                        None => {
+                            names.push(var);
+                            names.len() - 1
+                        }
+                    };
+                    let letter = (b'a' + (index % 26) as u8) as char;
+                    if index < 26 {
+                        letter.to_string()
+                    } else {
+                        format!("{}{}", letter, index / 26)
+                    }
+                }
+            },
+        }
+    }
+
+    // Every variable left in an exported type is treated as quantified, since nothing else
+    // can constrain it once the module has been checked
+    fn interface(&self) -> Interface {
+        let mut exports = HashMap::new();
+
+        for (name, scheme) in self.exports.iter() {
+            let mut vars = HashSet::new();
+            self.free_vars(&scheme.ty, &mut vars);
+            let mut vars: Vec<usize> = vars.into_iter().collect();
+            vars.sort();
+
+            let substitution = vars
+                .iter()
+                .enumerate()
+                .map(|(i, var)| (*var, Type::Var(i)))
+                .collect();
+            let ty = Self::substitute(&self.resolve(&scheme.ty), &substitution);
+            let classes = vars.iter().map(|var| self.classes[*var]).collect();
+            exports.insert(name.clone(), (ty, classes));
+        }
+
+        Interface { exports }
+    }
+
+    fn free_vars(&self, ty: &Type, vars: &mut HashSet<usize>) {
+        match self.prune(ty) {
+            Type::Var(var) => {
+                vars.insert(var);
+            }
+            Type::Function(args, ret) => {
+                for arg in args.iter() {
+                    self.free_vars(arg, vars);
+                }
+                self.free_vars(&ret, vars);
+            }
+            Type::List(element) => self.free_vars(&element, vars),
+            Type::Data(_, params) => {
+                for param in params.iter() {
+                    self.free_vars(param, vars);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Quantifies over the variables of a type that aren't free anywhere in the environment
+    fn generalize(&self, ty: &Type) -> Scheme {
+        let mut env_vars = HashSet::new();
+        for scope in self.scopes.iter() {
+            for scheme in scope.values() {
+                let mut vars = HashSet::new();
+                self.free_vars(&scheme.ty, &mut vars);
+                for var in scheme.vars.iter() {
+                    vars.remove(var);
+                }
+                env_vars.extend(vars);
+            }
+        }
+
+        let mut ty_vars = HashSet::new();
+        self.free_vars(ty, &mut ty_vars);
+        let mut vars: Vec<usize> = ty_vars.difference(&env_vars).cloned().collect();
+        vars.sort();
+
+        Scheme {
+            vars,
+            ty: self.resolve(ty),
+        }
+    }
+
+    fn instantiate(&mut self, scheme: &Scheme) -> Type {
+        let mut substitution = HashMap::new();
+        for var in scheme.vars.iter() {
+            let class = self.classes[*var];
+            substitution.insert(*var, self.fresh(class));
+        }
+
+        Self::substitute(&scheme.ty, &substitution)
+    }
+
+    fn substitute(ty: &Type, substitution: &HashMap<usize, Type>) -> Type {
+        match *ty {
+            Type::Var(var) => match substitution.get(&var) {
+                Some(replacement) => replacement.clone(),
+                None => ty.clone(),
+            },
+            Type::Function(ref args, ref ret) => Type::Function(
+                args.iter()
+                    .map(|arg| Self::substitute(arg, substitution))
+                    .collect(),
+                Box::new(Self::substitute(ret, substitution)),
+            ),
+            Type::List(ref element) => {
+                Type::List(Box::new(Self::substitute(element, substitution)))
+            }
+            Type::Data(ref name, ref params) => Type::Data(
+                name.clone(),
+                params
+                    .iter()
+                    .map(|param| Self::substitute(param, substitution))
+                    .collect(),
+            ),
+            _ => ty.clone(),
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Option<Scheme> {
+        self.scopes
+            .iter()
+            .rev()
+            .filter_map(|scope| scope.get(name))
+            .next()
+            .cloned()
+    }
+
+    fn lookup_type(&self, name: &str) -> Option<DataType> {
+        self.types
+            .iter()
+            .rev()
+            .filter_map(|scope| scope.get(name))
+            .next()
+            .cloned()
+    }
+
+    fn lookup_constructor(&self, name: &str) -> Option<Constructor> {
+        self.constructors
+            .iter()
+            .rev()
+            .filter_map(|scope| scope.get(name))
+            .next()
+            .cloned()
+    }
+
+    // Gives an exported type fresh variables of the same classes
+    fn instantiate_export(&mut self, export: &(Type, Vec<Class>)) -> Type {
+        let substitution = export
+            .1
+            .iter()
+            .enumerate()
+            .map(|(i, class)| (i, self.fresh(*class)))
+            .collect();
+        Self::substitute(&export.0, &substitution)
+    }
+
+    // Names the data types in an imported type after the module they're declared in, so they
+    // don't mix with this module's types of the same name
+    fn qualify(ty: &Type, module: &str) -> Type {
+        match *ty {
+            Type::Function(ref args, ref ret) => Type::Function(
+                args.iter().map(|arg| Self::qualify(arg, module)).collect(),
+                Box::new(Self::qualify(ret, module)),
+            ),
+            Type::List(ref element) => Type::List(Box::new(Self::qualify(element, module))),
+            Type::Data(ref name, ref params) => {
+                let name = if name.contains('.') {
+                    name.clone()
+                } else {
+                    format!("{}.{}", module, name)
+                };
+                let params = params
+                    .iter()
+                    .map(|param| Self::qualify(param, module))
+                    .collect();
+                Type::Data(name, params)
+            }
+            _ => ty.clone(),
+        }
+    }
+
+    fn bind_monomorphic(&mut self, name: &str, ty: Type) {
+        self.scopes.last_mut().unwrap().insert(
+            String::from(name),
+            Scheme {
+                vars: Vec::new(),
+                ty,
+            },
+        );
+    }
+
+    fn infer(&mut self, node: &ASTNode) -> Type {
+        match *node {
+            ASTNode::Integer(..) => Type::Int,
+            ASTNode::Float(..) => Type::Float,
+            ASTNode::StringLiteral(..) => Type::String,
+            ASTNode::Boolean(..) => Type::Bool,
+            // Unknown names are left to the JS environment, so they can have any type
+            ASTNode::Name(ref name, _) => match self.lookup(name) {
+                Some(scheme) => self.instantiate(&scheme),
+                None => match self.prelude.exports.get(name).cloned() {
+                    Some(export) => self.instantiate_export(&export),
+                    None => self.fresh(Class::Any),
+                },
+            },
+            ASTNode::Function {
+                ref name,
+                ref args,
+                ref body,
+                span,
+                ..
+            } => self.infer_function(name, args, body, span),
+            ASTNode::Invocation {
+                ref func,
+                ref args,
+                span,
+            } => self.infer_invocation(func, args, span),
+            ASTNode::Conditional {
+                ref cond,
+                ref if_body,
+                ref else_body,
+                span,
+            } => self.infer_conditional(cond, if_body, else_body, span, false),
+            ASTNode::Binary {
+                ref op,
+                ref lhs,
+                ref rhs,
+                span,
+            } => self.infer_binary(op, lhs, rhs, span, false),
+            ASTNode::Sequence(ref exprs) => self.infer_sequence(exprs),
+            ASTNode::Let {
+                ref name,
+                ref value,
+                span,
+            } => self.infer_let(name, value, span),
+            ASTNode::Annotated {
+                ref expr,
+                ref annotation,
+                span,
+            } => {
+                let ty = self.infer(expr);
+                let mut annotation_vars = HashMap::new();
+                let ty = self.check_annotation(ty, annotation, &mut annotation_vars, span);
+                self.release(&annotation_vars);
+                ty
+            }
+            ASTNode::TypeDeclaration {
+                ref name,
+                ref variants,
+                span,
+            } => {
+                if self.declare_type(name, variants, span) {
+                    self.define_constructors(&[(name.as_str(), variants.as_slice())]);
+                }
+                Type::Bool
+            }
+            ASTNode::Match {
+                ref subject,
+                ref arms,
+                span,
+            } => self.infer_match(subject, arms, span),
+            ASTNode::Extern { ref functions, .. } => {
+                self.define_externs(functions);
+                Type::Bool
+            }
+            // The module object itself is opaque, since its exports are accessed by name
+            ASTNode::Import { .. } => self.fresh(Class::Any),
+            ASTNode::Export { ref decl, .. } => self.infer(decl),
+            ASTNode::List(ref elements, _) => {
+                let element = self.fresh(Class::Any);
+                for expr in elements.iter() {
+                    let ty = self.infer(expr);
+                    self.expect(&element, &ty, expr.span(), " between list elements");
+                }
+                Type::List(Box::new(element))
+            }
+            ASTNode::Qualified {
+                ref module,
+                ref name,
+                ..
+            } => {
+                let export = self
+                    .imports
+                    .get(module)
+                    .and_then(|interface| interface.exports.get(name))
+                    .cloned();
+                match export {
+                    Some(export) => {
+                        let ty = self.instantiate_export(&export);
+                        Self::qualify(&ty, module)
+                    }
+                    None => self.fresh(Class::Any),
+                }
+            }
+        }
+    }
+
+    // Infers the type of an expression whose value is thrown away. The only difference is
+    // that a conditional's missing else branch doesn't constrain the other branch.
+    fn infer_discarded(&mut self, node: &ASTNode) -> Type {
+        match *node {
+            ASTNode::Conditional {
+                ref cond,
+                ref if_body,
+                ref else_body,
+                span,
+            } => self.infer_conditional(cond, if_body, else_body, span, true),
+            ASTNode::Binary {
+                ref op,
+                ref lhs,
+                ref rhs,
+                span,
+            } => self.infer_binary(op, lhs, rhs, span, true),
+            _ => self.infer(node),
+        }
+    }
+
+    fn infer_function(
+        &mut self,
+        name: &Option<ASTNode>,
+        args: &[ASTNode],
+        body: &ASTNode,
+        span: Span,
+    ) -> Type {
+        // A function's own name is visible in its body, and shadowed by its parameters
+        let self_ty = self.fresh(Class::Any);
+        self.scopes.push(HashMap::new());
+        if let Some(ASTNode::Name(ref name, _)) = *name {
+            self.bind_monomorphic(name, self_ty.clone());
+        }
+
+        // Type variables in annotations are shared across the whole signature
+        let mut annotation_vars = HashMap::new();
+
+        self.scopes.push(HashMap::new());
+        let mut arg_types = Vec::new();
+        for arg in args.iter() {
+            let arg_ty = match *arg {
+                ASTNode::Annotated { ref annotation, .. } => {
+                    self.annotation_type(annotation, &mut annotation_vars)
+                }
+                _ => self.fresh(Class::Any),
+            };
+            if let Some((arg_name, _)) = arg.binding() {
+                self.bind_monomorphic(arg_name, arg_ty.clone());
+            }
+            arg_types.push(arg_ty);
+        }
+
+        let ret = match *body {
+            ASTNode::Annotated {
+                ref expr,
+                ref annotation,
+                span,
+            } => {
+                let ty = self.infer(expr);
+                self.check_annotation(ty, annotation, &mut annotation_vars, span)
+            }
+            _ => self.infer(body),
+        };
+        self.scopes.pop();
+        self.scopes.pop();
+
+        let ty = Type::Function(arg_types, Box::new(ret));
+        self.expect(&ty, &self_ty, span, " in recursive use of function");
+        self.release(&annotation_vars);
+        ty
+    }
+
+    // Checks an inferred type against an annotation, returning the annotated type
+    fn check_annotation(
+        &mut self,
+        ty: Type,
+        annotation: &TypeAnnotation,
+        vars: &mut HashMap<String, Type>,
+        span: Span,
+    ) -> Type {
+        let annotated = self.annotation_type(annotation, vars);
+        self.expect(&annotated, &ty, span, " against annotation");
+        annotated
+    }
+
+    // Converts an annotation to a type. Lowercase names are type variables, which are looked
+    // up in (or added to) vars. New variables are rigid until released.
+    fn annotation_type(
+        &mut self,
+        annotation: &TypeAnnotation,
+        vars: &mut HashMap<String, Type>,
+    ) -> Type {
+        match *annotation {
+            TypeAnnotation::Named(ref name, name_span) => match name.as_str() {
+                "Int" => Type::Int,
+                "Float" => Type::Float,
+                "String" => Type::String,
+                "Bool" => Type::Bool,
+                _ if self.lookup_type(name).is_some() => {
+                    let data = self.lookup_type(name).unwrap();
+                    self.instantiate(&data.scheme)
+                }
+                _ if name.chars().next().is_some_and(char::is_lowercase) => {
+                    if let Some(ty) = vars.get(name) {
+                        return ty.clone();
+                    }
+                    let ty = self.fresh(Class::Any);
+                    if let Type::Var(var) = ty {
+                        self.rigid.insert(var);
+                    }
+                    vars.insert(name.clone(), ty.clone());
+                    ty
+                }
+                _ => {
+                    self.errors
+                        .push(name_span.get_error(format!("Unknown type {}", name)));
+                    self.fresh(Class::Any)
+                }
+            },
+            TypeAnnotation::Function(ref args, ref ret) => {
+                let args = args
+                    .iter()
+                    .map(|arg| self.annotation_type(arg, vars))
+                    .collect();
+                Type::Function(args, Box::new(self.annotation_type(ret, vars)))
+            }
+            TypeAnnotation::List(ref element) => {
+                Type::List(Box::new(self.annotation_type(element, vars)))
+            }
+        }
+    }
+
+    // Lets the variables of a checked declaration's annotations be bound like any others, so
+    // they can be generalized or instantiated by its uses
+    fn release(&mut self, vars: &HashMap<String, Type>) {
+        for ty in vars.values() {
+            if let Type::Var(var) = *ty {
+                self.rigid.remove(&var);
+            }
+        }
+    }
+
+    // Records a data type's name, returning false if it's already taken. Types can't be
+    // shadowed, since they're told apart by name.
+    fn declare_type(&mut self, name: &str, variants: &[Variant], span: Span) -> bool {
+        if matches!(name, "Int" | "Float" | "String" | "Bool") || self.lookup_type(name).is_some() {
+            self.errors
+                .push(span.get_error(format!("Type {} is already declared", name)));
+            return false;
+        }
+
+        let constructors = variants
+            .iter()
+            .map(|variant| variant.name.clone())
+            .collect();
+        let scheme = Scheme {
+            vars: Vec::new(),
+            ty: self.fresh(Class::Any),
+        };
+        self.types.last_mut().unwrap().insert(
+            String::from(name),
+            DataType {
+                constructors,
+                scheme,
+            },
+        );
+        true
+    }
+
+    // Binds a constructor for each variant of the declared types, as a function if it has
+    // fields and as a value of the data type otherwise. Types declared together may refer to
+    // each other, so all of their fields are converted before any parameters are known.
+    fn define_constructors(&mut self, declared: &[(&str, &[Variant])]) {
+        let placeholders: Vec<Type> = declared
+            .iter()
+            .map(|&(name, _)| self.lookup_type(name).unwrap().scheme.ty)
+            .collect();
+        let mut placeholder_vars = HashSet::new();
+        for placeholder in placeholders.iter() {
+            self.free_vars(placeholder, &mut placeholder_vars);
+        }
+
+        let mut fields: Vec<Vec<Vec<Type>>> = Vec::new();
+        let mut params: Vec<HashSet<usize>> = Vec::new();
+        for &(_, variants) in declared.iter() {
+            // Type variables in annotations are shared by all of a type's variants
+            let mut annotation_vars = HashMap::new();
+            let mut type_fields = Vec::new();
+            let mut vars = HashSet::new();
+            for variant in variants.iter() {
+                let variant_fields: Vec<Type> = variant
+                    .fields
+                    .iter()
+                    .map(|field| match *field {
+                        ASTNode::Annotated { ref annotation, .. } => {
+                            self.annotation_type(annotation, &mut annotation_vars)
+                        }
+                        _ => self.fresh(Class::Any),
+                    })
+                    .collect();
+                for field in variant_fields.iter() {
+                    self.free_vars(field, &mut vars);
+                }
+                type_fields.push(variant_fields);
+            }
+            self.release(&annotation_vars);
+
+            fields.push(type_fields);
+            params.push(vars.difference(&placeholder_vars).cloned().collect());
+        }
+
+        // A type's parameters include those of the types its fields refer to
+        loop {
+            let mut changed = false;
+            for i in 0..declared.len() {
+                for j in 0..declared.len() {
+                    let refers = fields[i]
+                        .iter()
+                        .flatten()
+                        .any(|field| self.refers_to(field, &placeholders[j]));
+                    if refers && !params[j].is_subset(&params[i]) {
+                        let inherited = params[j].clone();
+                        params[i].extend(inherited);
+                        changed = true;
+                    }
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+
+        for (i, &(name, variants)) in declared.iter().enumerate() {
+            let mut vars: Vec<usize> = params[i].iter().cloned().collect();
+            vars.sort();
+            let data = Type::Data(
+                String::from(name),
+                vars.iter().map(|var| Type::Var(*var)).collect(),
+            );
+            if let Type::Var(var) = placeholders[i] {
+                self.bindings[var] = Some(data.clone());
+            }
+
+            let data_type = self.types.last_mut().unwrap().get_mut(name).unwrap();
+            data_type.scheme = Scheme {
+                vars: vars.clone(),
+                ty: data.clone(),
+            };
+
+            for (variant, variant_fields) in variants.iter().zip(fields[i].iter()) {
+                let ty = if variant_fields.is_empty() {
+                    data.clone()
+                } else {
+                    Type::Function(variant_fields.clone(), Box::new(data.clone()))
+                };
+                let scheme = Scheme {
+                    vars: vars.clone(),
+                    ty: self.resolve(&ty),
+                };
+                self.scopes
+                    .last_mut()
+                    .unwrap()
+                    .insert(variant.name.clone(), scheme.clone());
+                self.constructors.last_mut().unwrap().insert(
+                    variant.name.clone(),
+                    Constructor {
+                        data: String::from(name),
+                        scheme,
+                    },
+                );
+            }
+        }
+    }
+
+    // Whether a field's type mentions the (still unbound) variable standing for a data type
+    fn refers_to(&self, ty: &Type, placeholder: &Type) -> bool {
+        match *placeholder {
+            Type::Var(var) => self.occurs(var, ty),
+            _ => false,
+        }
+    }
+
+    // Nothing is known about an extern beyond its annotations, so each of its unannotated
+    // parameters (and its return value, if unannotated) can have any type
+    fn define_externs(&mut self, functions: &[ExternFunction]) {
+        for function in functions.iter() {
+            let mut annotation_vars = HashMap::new();
+            let args = function
+                .args
+                .iter()
+                .map(|arg| match *arg {
+                    ASTNode::Annotated { ref annotation, .. } => {
+                        self.annotation_type(annotation, &mut annotation_vars)
+                    }
+                    _ => self.fresh(Class::Any),
+                })
+                .collect();
+            let ret = match function.ret {
+                Some(ref annotation) => self.annotation_type(annotation, &mut annotation_vars),
+                None => self.fresh(Class::Any),
+            };
+            self.release(&annotation_vars);
+
+            let scheme = self.generalize(&Type::Function(args, Box::new(ret)));
+            self.scopes
+                .last_mut()
+                .unwrap()
+                .insert(function.name.clone(), scheme);
+        }
+    }
+
+    // Every arm must match the subject's type and give the same type of result. Without a
+    // wildcard arm, every constructor of the subject's type must be covered.
+    fn infer_match(&mut self, subject: &ASTNode, arms: &[MatchArm], span: Span) -> Type {
+        let subject_ty = self.infer(subject);
+        let result = self.fresh(Class::Any);
+        let mut covered: Vec<&str> = Vec::new();
+        let mut data = None;
+        let mut wildcard = false;
+
+        for arm in arms.iter() {
+            self.scopes.push(HashMap::new());
+
+            let arm_span = match arm.pattern {
+                Pattern::Wildcard(span) => {
+                    wildcard = true;
+                    span
+                }
+                Pattern::Constructor {
+                    ref name,
+                    ref bindings,
+                    span,
+                } => {
+                    match self.lookup_constructor(name) {
+                        Some(constructor) => {
+                            let (fields, data_ty) = match self.instantiate(&constructor.scheme) {
+                                Type::Function(fields, data_ty) => (fields, *data_ty),
+                                data_ty => (Vec::new(), data_ty),
+                            };
+                            self.expect(&data_ty, &subject_ty, span, " in pattern");
+
+                            if bindings.len() == fields.len() {
+                                for (binding, field) in bindings.iter().zip(fields.iter()) {
+                                    if let Some((binding, _)) = binding.binding() {
+                                        self.bind_monomorphic(binding, field.clone());
+                                    }
+                                }
+                            } else {
+                                self.errors.push(span.get_error(format!(
+                                    "{} has {} fields, but the pattern binds {}",
+                                    name,
+                                    fields.len(),
+                                    bindings.len()
+                                )));
+                            }
+
+                            covered.push(name);
+                            data = Some(constructor.data);
+                        }
+                        None => self
+                            .errors
+                            .push(span.get_error(format!("{} is not a constructor", name))),
+                    }
+                    span
+                }
+            };
+
+            let body_ty = self.infer(&arm.body);
+            self.expect(&result, &body_ty, arm_span, " between match arms");
+            self.scopes.pop();
+        }
+
+        if !wildcard {
+            if let Some(data) = data {
+                let missing: Vec<String> = self
+                    .lookup_type(&data)
+                    .unwrap()
+                    .constructors
+                    .iter()
+                    .filter(|constructor| !covered.contains(&constructor.as_str()))
+                    .cloned()
+                    .collect();
+                if !missing.is_empty() {
+                    self.errors.push(span.get_error(format!(
+                        "Match is not exhaustive, missing {}",
+                        missing.join(", ")
+                    )));
+                }
+            } else if arms.is_empty() {
+                self.errors
+                    .push(span.get_error(String::from("Match has no arms")));
+            }
+        }
+
+        result
+    }
+
+    fn infer_invocation(&mut self, func: &ASTNode, args: &[ASTNode], span: Span) -> Type {
+        let func_ty = self.infer(func);
+        let arg_types: Vec<Type> = args.iter().map(|arg| self.infer(arg)).collect();
+
+        match self.prune(&func_ty) {
+            Type::Function(ref params, ref ret) => {
+                if params.len() != arg_types.len() {
+                    self.errors.push(span.get_error(format!(
+                        "Function of type {} expects {} arguments, given {}",
+                        self.describe(&func_ty),
+                        params.len(),
+                        arg_types.len()
+                    )));
+                    return self.fresh(Class::Any);
+                }
+
+                for (i, (param, arg)) in params.iter().zip(arg_types.iter()).enumerate() {
+                    self.expect(param, arg, span, &format!(" in argument {}", i + 1));
+                }
+                (**ret).clone()
+            }
+            Type::Var(_) => {
+                let ret = self.fresh(Class::Any);
+                let expected = Type::Function(arg_types, Box::new(ret.clone()));
+                self.expect(&expected, &func_ty, span, " in invocation");
+                ret
+            }
+            _ => {
+                self.errors.push(span.get_error(format!(
+                    "Cannot call a value of type {}",
+                    self.describe(&func_ty)
+                )));
+                self.fresh(Class::Any)
+            }
+        }
+    }
+
+    // Any value can be a condition, since only false is falsey
+    fn infer_conditional(
+        &mut self,
+        cond: &ASTNode,
+        if_body: &ASTNode,
+        else_body: &Option<ASTNode>,
+        span: Span,
+        discarded: bool,
+    ) -> Type {
+        self.infer(cond);
+
+        match *else_body {
+            Some(ref else_body) => {
+                let if_ty = if discarded {
+                    self.infer_discarded(if_body)
+                } else {
+                    self.infer(if_body)
+                };
+                let else_ty = if discarded {
+                    self.infer_discarded(else_body)
+                } else {
+                    self.infer(else_body)
+                };
+                self.expect(&if_ty, &else_ty, span, " between conditional branches");
+                if_ty
+            }
+            // A missing else branch evaluates to false
+            None => {
+                if discarded {
+                    self.infer_discarded(if_body);
+                } else {
+                    let if_ty = self.infer(if_body);
+                    self.expect(
+                        &Type::Bool,
+                        &if_ty,
+                        span,
+                        " in conditional without an else branch",
+                    );
+                }
+                Type::Bool
+            }
+        }
+    }
+
+    fn infer_binary(
+        &mut self,
+        op: &Token,
+        lhs: &ASTNode,
+        rhs: &ASTNode,
+        span: Span,
+        discarded: bool,
+    ) -> Type {
+        let op = match *op {
+            Token::Operator(ref op) => op.as_str(),
+            _ => {
+                self.errors
+                    .push(span.get_error(String::from("Malformed binary node")));
+                return self.fresh(Class::Any);
+            }
+        };
+
+        if op == "=" {
+            return self.infer_assignment(lhs, rhs, span);
+        }
+
+        let lhs_ty = self.infer(lhs);
+        let rhs_ty = if discarded && op == "&&" {
+            self.infer_discarded(rhs)
+        } else {
+            self.infer(rhs)
+        };
+        let context = format!(" in operand of {}", op);
+
+        match op {
+            "+" | "-" | "*" | "/" | "%" => {
+                let class = if op == "+" {
+                    Class::Ordered
+                } else {
+                    Class::Numeric
+                };
+                let operand = self.fresh(class);
+                self.expect(&operand, &lhs_ty, span, &context);
+                self.expect(&operand, &rhs_ty, span, &context);
+
+                // Division is never truncating, since it compiles to JS division
+                if op == "/" {
+                    Type::Float
+                } else {
+                    operand
+                }
+            }
+            "<" | "<=" | ">" | ">=" => {
+                let operand = self.fresh(Class::Ordered);
+                self.expect(&operand, &lhs_ty, span, &context);
+                self.expect(&operand, &rhs_ty, span, &context);
+                Type::Bool
+            }
+            "==" | "!=" => {
+                self.expect(&lhs_ty, &rhs_ty, span, &context);
+                Type::Bool
+            }
+            // `a || b` evaluates to a unless it's false, in which case it evaluates to b
+            "||" => {
+                self.expect(&lhs_ty, &rhs_ty, span, &context);
+                lhs_ty
+            }
+            // `a && b` evaluates to false if a is false, and b otherwise
+            "&&" => {
+                if !discarded {
+                    self.expect(&Type::Bool, &rhs_ty, span, &context);
+                }
+                Type::Bool
+            }
+            _ => {
+                self.errors
+                    .push(span.get_error(format!("Unknown operator {}", op)));
+                self.fresh(Class::Any)
+            }
+        }
+    }
+
+    // Assigning to a name that isn't in scope introduces it in the innermost scope
+    fn infer_assignment(&mut self, lhs: &ASTNode, rhs: &ASTNode, span: Span) -> Type {
+        let rhs_ty = self.infer(rhs);
+
+        match *lhs {
+            ASTNode::Name(ref name, _) => {
+                match self.lookup(name) {
+                    Some(scheme) => {
+                        let lhs_ty = self.instantiate(&scheme);
+                        self.expect(
+                            &lhs_ty,
+                            &rhs_ty,
+                            span,
+                            &format!(" in assignment to {}", name),
+                        );
+                    }
+                    None => self.bind_monomorphic(name, rhs_ty.clone()),
+                }
+                rhs_ty
+            }
+            _ => {
+                self.errors
+                    .push(span.get_error(String::from("Invalid assignment target")));
+                rhs_ty
+            }
+        }
+    }
+
+    // Let bindings of function values are generalized, while other values stay monomorphic
+    // since they could be reassigned
+    fn infer_let(&mut self, name: &ASTNode, value: &ASTNode, span: Span) -> Type {
+        let mut ty = self.infer(value);
+
+        if let ASTNode::Annotated {
+            ref annotation,
+            span,
+            ..
+        } = *name
+        {
+            let mut annotation_vars = HashMap::new();
+            ty = self.check_annotation(ty, annotation, &mut annotation_vars, span);
+            self.release(&annotation_vars);
+        }
+
+        let scheme = match *value {
+            ASTNode::Function { .. } => self.generalize(&ty),
+            _ => Scheme {
+                vars: Vec::new(),
+                ty: ty.clone(),
+            },
+        };
+
+        match name.binding() {
+            Some((name, _)) => {
+                self.scopes
+                    .last_mut()
+                    .unwrap()
+                    .insert(String::from(name), scheme);
+            }
+            None => self
+                .errors
+                .push(span.get_error(String::from("Invalid let binding"))),
+        }
+
+        ty
+    }
+
+    fn infer_sequence(&mut self, exprs: &[ASTNode]) -> Type {
+        self.scopes.push(HashMap::new());
+        self.types.push(HashMap::new());
+        self.constructors.push(HashMap::new());
+
+        // Named functions are visible throughout their sequence, so they can be mutually
+        // recursive. They're monomorphic until their own definition has been checked.
+        for expr in exprs.iter() {
+            if let Some((name, _)) = expr.function_name() {
+                let ty = self.fresh(Class::Any);
+                self.bind_monomorphic(name, ty);
+            }
+        }
+
+        // Data types are also visible throughout their sequence. All of their names are
+        // declared before any constructors, so they can refer to each other.
+        let mut declared = Vec::new();
+        for expr in exprs.iter() {
+            if let ASTNode::TypeDeclaration {
+                ref name,
+                ref variants,
+                span,
+            } = *expr
+            {
+                if self.declare_type(name, variants, span) {
+                    declared.push((name.as_str(), variants.as_slice()));
+                }
+            }
+        }
+        self.define_constructors(&declared);
+        for expr in exprs.iter() {
+            if let ASTNode::Extern { ref functions, .. } = *expr.declaration() {
+                self.define_externs(functions);
+            }
+        }
+
+        // Empty sequences are falsey
+        let mut ty = Type::Bool;
+        for (i, expr) in exprs.iter().enumerate() {
+            ty = if let ASTNode::TypeDeclaration { .. } | ASTNode::Extern { .. } =
+                *expr.declaration()
+            {
+                Type::Bool
+            } else if i + 1 == exprs.len() {
+                self.infer(expr)
+            } else {
+                self.infer_discarded(expr)
+            };
+
+            if let Some((name, _)) = expr.function_name() {
+                if let Some(placeholder) = self.scopes.last_mut().unwrap().remove(name) {
+                    let placeholder = self.instantiate(&placeholder);
+                    self.expect(&ty, &placeholder, expr.span(), " in use of function");
+                }
+                let scheme = self.generalize(&ty);
+                self.scopes
+                    .last_mut()
+                    .unwrap()
+                    .insert(String::from(name), scheme);
+            }
+
+            for (name, _) in expr.exported_names() {
+                if let Some(scheme) = self.lookup(name) {
+                    self.exports.insert(String::from(name), scheme);
+                }
+            }
+        }
+
+        self.scopes.pop();
+        self.types.pop();
+        self.constructors.pop();
+        ty
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use lexer;
+    use parser::Parser;
+
+    fn check_str(inp: &str) -> Result<Type, Vec<Error>> {
+        let lexer = lexer::Lexer::new(inp);
+        let mut parser = Parser { lexer };
+
+        check(&parser.parse_top_level().unwrap())
+    }
+
+    #[test]
+    fn test_infer_literals() {
+        assert_eq!(check_str("1"), Ok(Type::Int));
+        assert_eq!(check_str("1.5"), Ok(Type::Float));
+        assert_eq!(check_str("\"s\""), Ok(Type::String));
+        assert_eq!(check_str("true"), Ok(Type::Bool));
+        assert_eq!(check_str(""), Ok(Type::Bool));
+    }
+
+    #[test]
+    fn test_infer_arithmetic() {
+        assert_eq!(check_str("1 + 2 * 3"), Ok(Type::Int));
+        assert_eq!(check_str("\"a\" + \"b\""), Ok(Type::String));
+        assert_eq!(check_str("1 / 2"), Ok(Type::Float));
+        assert_eq!(check_str("1 < 2"), Ok(Type::Bool));
+        assert!(check_str("1 + 2.5").is_err());
+        assert!(check_str("\"a\" - \"b\"").is_err());
+    }
+
+    #[test]
+    fn test_let_polymorphism() {
+        let inp = "fn id(x) { x };
+                   id(1);
+                   id(\"s\")";
+
+        assert_eq!(check_str(inp), Ok(Type::String));
+    }
+
+    #[test]
+    fn test_parameters_are_monomorphic() {
+        let inp = "fn apply(f) { f(1); f(\"s\") }";
+
+        assert!(check_str(inp).is_err());
+    }
+
+    #[test]
+    fn test_infer_function_type() {
+        let inp = "fn add(a, b) { a + b }; add";
+
+        assert_eq!(
+            check_str(inp).map(|ty| match ty {
+                Type::Function(ref args, _) => args.len(),
+                _ => 0,
+            }),
+            Ok(2)
+        );
+    }
+
+    #[test]
+    fn test_recursion() {
+        let inp = "fn fact(n) { if n < 2 then 1 else n * fact(n - 1) }; fact(5)";
+
+        assert_eq!(check_str(inp), Ok(Type::Int));
+    }
+
+    #[test]
+    fn test_mutual_recursion() {
+        let inp = "fn even?(n) { if n == 0 then true else odd?(n - 1) };
+                   fn odd?(n) { if n == 0 then false else even?(n - 1) };
+                   even?(10)";
+
+        assert_eq!(check_str(inp), Ok(Type::Bool));
+    }
+
+    #[test]
+    fn test_forward_use_mismatch() {
+        // A use before the definition has been checked must agree with it
+        assert!(check_str("fn g() { f(\"s\") }; fn f(x) { x * 2 }; g()").is_err());
+        assert!(check_str("fn f(x) { f(1, 2) }").is_err());
+    }
+
+    #[test]
+    fn test_call_integer() {
+        let errors = check_str("x = 1;\ny = x(2)").unwrap_err();
+
+        assert_eq!(
+            errors,
+            vec![Error {
+                msg: String::from("Cannot call a value of type Int"),
+                line: 2,
+                col: 4,
+            },]
+        );
+    }
+
+    #[test]
+    fn test_add_function_to_string() {
+        let errors = check_str("fn f() { 1 }; f + \"s\"").unwrap_err();
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].msg,
+            "Type mismatch in operand of +: expected Ordered, found () -> Int"
+        );
+    }
+
+    #[test]
+    fn test_conditional_branches() {
+        assert_eq!(check_str("if true then 1 else 2"), Ok(Type::Int));
+        assert!(check_str("if true then 1 else \"s\"").is_err());
+        // Any value can be a condition
+        assert_eq!(check_str("if 0 then 1 else 2"), Ok(Type::Int));
+    }
+
+    #[test]
+    fn test_conditional_without_else() {
+        // The missing branch is false, which only matters when the value is used
+        assert!(check_str("if true then 1").is_err());
+        assert_eq!(check_str("if true then 1; 2"), Ok(Type::Int));
+        assert_eq!(check_str("if true then false"), Ok(Type::Bool));
+    }
+
+    #[test]
+    fn test_assignment() {
+        assert_eq!(check_str("x = 1; x + 2"), Ok(Type::Int));
+        assert!(check_str("x = 1; x = \"s\"").is_err());
+    }
+
+    #[test]
+    fn test_unknown_names() {
+        assert!(check_str("console(1); console(\"s\")").is_ok());
+    }
+
+    #[test]
+    fn test_reports_all_errors() {
+        let errors = check_str("1 + \"a\"; 2(3); true - 1").unwrap_err();
+
+        assert_eq!(errors.len(), 3);
+    }
+
+    #[test]
+    fn test_annotated_function() {
+        assert_eq!(
+            check_str("fn add(a: Int, b: Int): Int { a + b }; add(1, 2)"),
+            Ok(Type::Int)
+        );
+        assert!(check_str("fn add(a: Int, b: Int): Int { a + b }; add(1.5, 2.5)").is_err());
+        assert!(check_str("fn f(a: Int): String { a }").is_err());
+        assert!(check_str("fn f(a: Widget) { a }").is_err());
+    }
+
+    #[test]
+    fn test_annotation_type_variables() {
+        let inp = "fn apply(f: (a) -> b, x: a): b { f(x) }; apply(fn (n) { n + 1 }, 2)";
+
+        assert_eq!(check_str(inp), Ok(Type::Int));
+    }
+
+    #[test]
+    fn test_annotation_type_variables_are_rigid() {
+        // A type variable stands for any type, so the declaration can't pick one
+        let errors = check_str("fn f(x: a): a { 1 }").unwrap_err();
+        assert_eq!(
+            errors[0].msg,
+            "Type mismatch against annotation: expected a, found Int"
+        );
+        assert!(check_str("fn f(x: a) { x + 1 }").is_err());
+        assert!(check_str("fn f(x: a, y: b): a { y }").is_err());
+        assert!(check_str("let x: a = 1").is_err());
+        // Once checked, the declaration is polymorphic
+        assert_eq!(
+            check_str("fn id(x: a): a { x }; id(1); id(true)"),
+            Ok(Type::Bool)
+        );
+        assert_eq!(
+            check_str("let id: (a) -> a = fn (x) { x }; id(\"s\")"),
+            Ok(Type::String)
+        );
+    }
+
+    #[test]
+    fn test_let() {
+        assert_eq!(check_str("let x: Int = 1; x"), Ok(Type::Int));
+        assert!(check_str("let x: Bool = 1").is_err());
+        // Function values bound by let are polymorphic
+        assert_eq!(
+            check_str("let id = fn (x) { x }; id(1); id(true)"),
+            Ok(Type::Bool)
+        );
+        // Other values aren't
+        assert!(
+            check_str("let f = if true then fn (x) { x } else fn (x) { x }; f(1); f(true)")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_recursive_use_mismatch() {
+        assert!(check_str("fn f(x) { f(1, 2) }").is_err());
+        assert!(check_str("g(1, 2); fn g(x) { x }").is_err());
+    }
+
+    #[test]
+    fn test_data_types() {
+        let inp = "type Shape = Circle(r: Float) | Rect(w, h) | Empty;
+                   fn area(s) { match s { Circle(r) => r * r, Rect(w, h) => w * h, Empty => 0.0 } };
+                   area(Rect(1.0, 2.0))";
+        assert_eq!(check_str(inp), Ok(Type::Float));
+
+        let inp = "type List = Nil | Cons(head, tail: List);
+                   fn length(l: List): Int { match l { Nil => 0, Cons(_, tail) => 1 + length(tail) } };
+                   length(Cons(1, Nil))";
+        assert_eq!(check_str(inp), Ok(Type::Int));
+    }
+
+    #[test]
+    fn test_constructor_field_types() {
+        // Each use of a constructor can give its fields different types
+        assert_eq!(
+            check_str("type Box = B(v) | E; B(1); B(\"s\")"),
+            Ok(Type::Data(String::from("Box"), vec![Type::String]))
+        );
+
+        // Matching gives the fields the types they were constructed with
+        let errors =
+            check_str("type Box = B(v) | E; match B(\"s\") { B(v) => v * 2, E => 0 }").unwrap_err();
+        assert_eq!(
+            errors[0].msg,
+            "Type mismatch in operand of *: expected Numeric, found String"
+        );
+
+        // The fields of a recursive type share its parameters
+        let inp = "type List = Nil | Cons(head, tail: List); Cons(1, Cons(\"s\", Nil))";
+        assert!(check_str(inp).is_err());
+    }
+
+    #[test]
+    fn test_match_errors() {
+        let inp = "type Shape = Circle(r) | Rect(w, h); type Color = Red | Green;
+                   fn f(s) { match s { Circle(r) => r } };
+                   fn g(s) { match s { Circle(r) => r, Red => 0, _ => 1 } };
+                   fn h(s) { match s { Rect(w) => w, _ => 1 } }";
+        let errors = check_str(inp).unwrap_err();
+        let messages: Vec<&str> = errors.iter().map(|error| error.msg.as_str()).collect();
+
+        assert_eq!(
+            messages,
+            vec![
+                "Match is not exhaustive, missing Rect",
+                "Type mismatch in pattern: expected Color, found Shape<a, b, c>",
+                "Rect has 2 fields, but the pattern binds 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_module_interfaces() {
+        let lib = Parser {
+            lexer: lexer::Lexer::new(
+                "export fn id(x) { x }; export fn square(n) { n * n }; export let name = \"lib\"",
+            ),
+        }
+        .parse_top_level()
+        .unwrap();
+        let (_, interface) = check_module(&lib, HashMap::new(), Interface::default()).unwrap();
+        assert_eq!(
+            interface.get("id"),
+            Some(&Type::Function(vec![Type::Var(0)], Box::new(Type::Var(0))))
+        );
+        assert_eq!(interface.get("name"), Some(&Type::String));
+
+        let mut imports = HashMap::new();
+        imports.insert(String::from("lib"), interface);
+        let main = Parser {
+            lexer: lexer::Lexer::new(
+                "import \"lib.silver\" as lib; lib.id(1); lib.square(1.5); lib.id(lib.name)",
+            ),
+        }
+        .parse_top_level()
+        .unwrap();
+        assert_eq!(
+            check_module(&main, imports.clone(), Interface::default()).map(|(ty, _)| ty),
+            Ok(Type::String)
+        );
+
+        let main = Parser {
+            lexer: lexer::Lexer::new("import \"lib.silver\" as lib; lib.square(lib.name)"),
+        }
+        .parse_top_level()
+        .unwrap();
+        assert!(check_module(&main, imports, Interface::default()).is_err());
+    }
+
+    #[test]
+    fn test_data_type_scopes() {
+        // Types and constructors declared in a function aren't visible outside it
+        assert!(check_str("fn f() { type T = A; A }; fn g(x: T) { x }").is_err());
+        assert!(check_str("fn f() { type T = A(x); 1 }; match 1 { A(x) => x }").is_err());
+        // Nor can they shadow a type that is
+        assert!(check_str("type T = A; fn f() { type T = B; B }").is_err());
+
+        // Imported data types don't mix with this module's types of the same name
+        let lib = Parser {
+            lexer: lexer::Lexer::new("type Box = B(v); export fn wrap(x) { B(x) }"),
+        }
+        .parse_top_level()
+        .unwrap();
+        let (_, interface) = check_module(&lib, HashMap::new(), Interface::default()).unwrap();
+        let mut imports = HashMap::new();
+        imports.insert(String::from("lib"), interface);
+        let main = Parser {
+            lexer: lexer::Lexer::new(
+                "import \"lib.silver\" as lib; type Box = B(v); match lib.wrap(1) { B(v) => v }",
+            ),
+        }
+        .parse_top_level()
+        .unwrap();
+        let errors = check_module(&main, imports, Interface::default()).unwrap_err();
+        assert_eq!(
+            errors[0].msg,
+            "Type mismatch in pattern: expected Box<a>, found lib.Box<Int>"
+        );
+    }
+
+    #[test]
+    fn test_externs() {
+        let inp = "extern fn log(x) = \"console.log\"; log(1); log(\"s\")";
+        assert!(check_str(inp).is_ok());
+
+        let inp = "extern \"m\" { fn parse(s: String): Int }; parse(\"1\") + 1";
+        assert_eq!(check_str(inp), Ok(Type::Int));
+        assert!(check_str("extern \"m\" { fn parse(s: String): Int }; parse(1)").is_err());
+    }
+
+    #[test]
+    fn test_lists() {
+        assert_eq!(
+            check_str("let xs: [Int] = [1, 2]; xs"),
+            Ok(Type::List(Box::new(Type::Int)))
+        );
+        assert_eq!(
+            check_str("fn wrap(x: a): [a] { [x] }; wrap(\"s\")"),
+            Ok(Type::List(Box::new(Type::String)))
+        );
+        assert!(check_str("let xs: [Int] = [1.5]").is_err());
+
+        // A mismatched element is reported where it is
+        let errors = check_str("xs = [1,\n  \"s\"]").unwrap_err();
+        assert_eq!(
+            errors,
+            vec![Error {
+                msg: String::from(
+                    "Type mismatch between list elements: expected Int, found String"
+                ),
+                line: 2,
+                col: 2,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_prelude_names() {
+        let ast = Parser {
+            lexer: lexer::Lexer::new("export fn length(s) { s }; length(1)"),
+        }
+        .parse_top_level()
+        .unwrap();
+        let (lib, prelude) = check_module(&ast, HashMap::new(), Interface::default()).unwrap();
+        assert_eq!(lib, Type::Int);
+
+        // Prelude names are polymorphic, unless the module defines its own
+        let check = |inp: &str| {
+            let ast = Parser {
+                lexer: lexer::Lexer::new(inp),
+            }
+            .parse_top_level()
+            .unwrap();
+            check_module(&ast, HashMap::new(), prelude.clone()).map(|(ty, _)| ty)
+        };
+        assert_eq!(check("length(1); length(true)"), Ok(Type::Bool));
+        assert_eq!(check("fn length(s) { 1 }; length(true)"), Ok(Type::Int));
+    }
+}
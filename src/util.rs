@@ -16,3 +16,46 @@ impl fmt::Display for Error {
         )
     }
 }
+
+// A problem that doesn't stop compilation
+#[derive(Debug, PartialEq)]
+pub struct Warning {
+    pub msg: String,
+    pub line: u32,
+    pub col: u32,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Warning: {}\nLine: {}, Column: {}\n",
+            self.msg, self.line, self.col
+        )
+    }
+}
+
+// The position in the input where a token or node starts
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Span {
+    pub line: u32,
+    pub col: u32,
+}
+
+impl Span {
+    pub fn get_error(&self, msg: String) -> Error {
+        Error {
+            msg,
+            line: self.line,
+            col: self.col,
+        }
+    }
+
+    pub fn get_warning(&self, msg: String) -> Warning {
+        Warning {
+            msg,
+            line: self.line,
+            col: self.col,
+        }
+    }
+}
@@ -0,0 +1,1141 @@
+use super::bytecode::{Capture, Constant, Instr, Program};
+
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+// Calls nest no deeper than this, as they can't in JS
+const MAX_FRAMES: usize = 10000;
+
+#[derive(Debug, Clone)]
+pub enum Value {
+    Boolean(bool),
+    Number(f64),
+    String(Rc<str>),
+    List(Rc<Vec<Value>>),
+    Closure(Rc<Closure>),
+    // One of the program's natives, by index
+    Native(usize),
+    Data(Rc<Data>),
+    // Holds a local that a nested function captures. Only ever in a slot.
+    Cell(Rc<RefCell<Value>>),
+}
+
+#[derive(Debug)]
+pub struct Closure {
+    pub function: usize,
+    pub cells: Vec<Rc<RefCell<Value>>>,
+}
+
+#[derive(Debug)]
+pub struct Data {
+    pub constructor: usize,
+    pub fields: Vec<Value>,
+}
+
+// Long chains of data like lists built from constructors would be dropped recursively,
+// which could overflow the stack, so what only they hold is dropped in a loop
+impl Drop for Data {
+    fn drop(&mut self) {
+        let mut pending = std::mem::take(&mut self.fields);
+        while let Some(value) = pending.pop() {
+            match value {
+                Value::Data(data) => {
+                    if let Ok(mut data) = Rc::try_unwrap(data) {
+                        pending.append(&mut data.fields);
+                    }
+                }
+                Value::List(items) => {
+                    if let Ok(mut items) = Rc::try_unwrap(items) {
+                        pending.append(&mut items);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+// The functions externs can be bound to, by their JS names
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Builtin {
+    Length,
+    Get,
+    Append,
+    Concat,
+    Range,
+    Fold,
+    Map,
+    Filter,
+    Substring,
+    Split,
+    Join,
+    String,
+    Number,
+    Floor,
+    Ceil,
+    Round,
+    Sqrt,
+    Pow,
+    Log,
+    Error,
+}
+
+pub fn native(js_name: &str) -> Option<Builtin> {
+    match js_name {
+        "$length" => Some(Builtin::Length),
+        "$get" => Some(Builtin::Get),
+        "$append" => Some(Builtin::Append),
+        "$concat" => Some(Builtin::Concat),
+        "$range" => Some(Builtin::Range),
+        "$fold" => Some(Builtin::Fold),
+        "$map" => Some(Builtin::Map),
+        "$filter" => Some(Builtin::Filter),
+        "$substring" => Some(Builtin::Substring),
+        "$split" => Some(Builtin::Split),
+        "$join" => Some(Builtin::Join),
+        "String" => Some(Builtin::String),
+        "Number" => Some(Builtin::Number),
+        "Math.floor" => Some(Builtin::Floor),
+        "Math.ceil" => Some(Builtin::Ceil),
+        "Math.round" => Some(Builtin::Round),
+        "Math.sqrt" => Some(Builtin::Sqrt),
+        "Math.pow" => Some(Builtin::Pow),
+        "console.log" => Some(Builtin::Log),
+        "console.error" => Some(Builtin::Error),
+        _ => None,
+    }
+}
+
+// A call being run, whose slots start at its base on the stack. The callee is just below
+// them.
+struct Frame {
+    closure: Rc<Closure>,
+    ip: usize,
+    base: usize,
+}
+
+struct Machine<'a> {
+    program: &'a Program,
+    natives: Vec<Builtin>,
+    constants: Vec<Vec<Value>>,
+    globals: Vec<Value>,
+    stack: Vec<Value>,
+    frames: Vec<Frame>,
+    out: &'a mut dyn Write,
+}
+
+// Runs each of a program's modules, writing what it logs to `out`. An error ends the
+// program, as an uncaught one would in JS.
+pub fn run(program: &Program, out: &mut dyn Write) -> Result<(), String> {
+    let mut natives = Vec::new();
+    for declared in program.natives.iter() {
+        match native(&declared.js_name) {
+            Some(builtin) => natives.push(builtin),
+            None => {
+                return Err(format!(
+                    "{} isn't one of the VM's natives",
+                    declared.js_name
+                ))
+            }
+        }
+    }
+    let constants = program
+        .functions
+        .iter()
+        .map(|function| {
+            function
+                .constants
+                .iter()
+                .map(|constant| match *constant {
+                    Constant::Number(val) => Value::Number(val),
+                    Constant::String(ref val) => Value::String(Rc::from(val.as_str())),
+                })
+                .collect()
+        })
+        .collect();
+
+    let mut machine = Machine {
+        program,
+        natives,
+        constants,
+        globals: vec![Value::Boolean(false); program.globals.len()],
+        stack: Vec::new(),
+        frames: Vec::new(),
+        out,
+    };
+    for &init in program.inits.iter() {
+        let closure = Rc::new(Closure {
+            function: init,
+            cells: Vec::new(),
+        });
+        machine.call(Value::Closure(closure), Vec::new())?;
+    }
+    machine.out.flush().map_err(|err| err.to_string())
+}
+
+// Only false is falsy
+pub fn truthy(value: &Value) -> bool {
+    match *value {
+        Value::Boolean(val) => val,
+        _ => true,
+    }
+}
+
+// Arithmetic on anything but numbers gives NaN
+fn to_number(value: &Value) -> f64 {
+    match *value {
+        Value::Number(val) => val,
+        _ => f64::NAN,
+    }
+}
+
+// Numbers and strings are equal by value, as are booleans. Anything else is equal only to
+// itself, as JS compares objects.
+pub fn equals(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Boolean(a), Value::Boolean(b)) => a == b,
+        (Value::Number(a), Value::Number(b)) => a == b,
+        (Value::String(a), Value::String(b)) => a == b,
+        (Value::List(a), Value::List(b)) => Rc::ptr_eq(a, b),
+        (Value::Closure(a), Value::Closure(b)) => Rc::ptr_eq(a, b),
+        (Value::Native(a), Value::Native(b)) => a == b,
+        (Value::Data(a), Value::Data(b)) => Rc::ptr_eq(a, b),
+        _ => false,
+    }
+}
+
+// Only numbers and strings are ordered. Strings are compared by their bytes, which orders
+// them by code point.
+fn compare(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.partial_cmp(b),
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        _ => None,
+    }
+}
+
+impl<'a> Machine<'a> {
+    fn push(&mut self, value: Value) {
+        self.stack.push(value);
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack
+            .pop()
+            .expect("Instructions only pop what they've pushed")
+    }
+
+    fn top(&self) -> Value {
+        self.stack
+            .last()
+            .cloned()
+            .expect("Instructions only read what they've pushed")
+    }
+
+    fn frame(&self) -> &Frame {
+        self.frames
+            .last()
+            .expect("Instructions only run in a frame")
+    }
+
+    fn cell(&self, slot: usize) -> Rc<RefCell<Value>> {
+        match self.stack[slot] {
+            Value::Cell(ref cell) => cell.clone(),
+            _ => panic!("Slot {} doesn't hold a cell", slot),
+        }
+    }
+
+    // Calls a function from outside the instructions, as the natives that take functions do
+    fn call(&mut self, callee: Value, args: Vec<Value>) -> Result<Value, String> {
+        let count = args.len();
+        let depth = self.frames.len();
+        self.push(callee);
+        self.stack.extend(args);
+        if self.invoke(count)? {
+            self.execute(depth)?;
+        }
+        Ok(self.pop())
+    }
+
+    // Calls the callee below the arguments on top of the stack. A closure's frame is
+    // pushed, to be run by `execute`, and a native's result replaces the callee.
+    fn invoke(&mut self, count: usize) -> Result<bool, String> {
+        let base = self.stack.len() - count;
+        let arity_error = |arity: usize| {
+            format!(
+                "Called a function that takes {} arguments with {}",
+                arity, count
+            )
+        };
+        match self.stack[base - 1].clone() {
+            Value::Closure(closure) => {
+                let function = &self.program.functions[closure.function];
+                if function.arity != count {
+                    return Err(arity_error(function.arity));
+                }
+                if self.frames.len() >= MAX_FRAMES {
+                    return Err(String::from("Maximum call stack size exceeded"));
+                }
+                self.stack
+                    .resize(base + function.slots, Value::Boolean(false));
+                self.frames.push(Frame {
+                    closure,
+                    ip: 0,
+                    base,
+                });
+                Ok(true)
+            }
+            Value::Native(index) => {
+                let arity = self.program.natives[index].arity;
+                if arity != count {
+                    return Err(arity_error(arity));
+                }
+                let args = self.stack.split_off(base);
+                self.pop();
+                let result = self.builtin(self.natives[index], &args)?;
+                self.push(result);
+                Ok(false)
+            }
+            _ => Err(String::from("Called a value that isn't a function")),
+        }
+    }
+
+    // Runs instructions until the frame at `depth` returns
+    // The function, instruction pointer and base of the frame on top
+    fn resume(&self) -> (usize, usize, usize) {
+        let frame = self.frame();
+        (frame.closure.function, frame.ip, frame.base)
+    }
+
+    // Runs instructions until the frame at `depth` returns. The frame on top is kept in
+    // locals, and saved before a call.
+    fn execute(&mut self, depth: usize) -> Result<(), String> {
+        let program = self.program;
+        let (mut function, mut ip, mut base) = self.resume();
+        let mut code: &[u8] = &program.functions[function].code;
+        loop {
+            let (instr, next) = Instr::decode(code, ip);
+            ip = next;
+
+            match instr {
+                Instr::Constant(index) => {
+                    let constant = self.constants[function][usize::from(index)].clone();
+                    self.push(constant);
+                }
+                Instr::True => self.push(Value::Boolean(true)),
+                Instr::False => self.push(Value::Boolean(false)),
+                Instr::Pop => {
+                    self.pop();
+                }
+                Instr::Dup => {
+                    let value = self.top();
+                    self.push(value);
+                }
+                Instr::GetLocal(slot) => {
+                    let value = self.stack[base + usize::from(slot)].clone();
+                    self.push(value);
+                }
+                Instr::SetLocal(slot) => self.stack[base + usize::from(slot)] = self.top(),
+                Instr::NewCell(slot) => {
+                    let slot = base + usize::from(slot);
+                    let value = self.stack[slot].clone();
+                    self.stack[slot] = Value::Cell(Rc::new(RefCell::new(value)));
+                }
+                Instr::GetCell(slot) => {
+                    let value = self.cell(base + usize::from(slot)).borrow().clone();
+                    self.push(value);
+                }
+                Instr::SetCell(slot) => {
+                    *self.cell(base + usize::from(slot)).borrow_mut() = self.top();
+                }
+                Instr::GetCaptured(index) => {
+                    let value = self.frame().closure.cells[usize::from(index)]
+                        .borrow()
+                        .clone();
+                    self.push(value);
+                }
+                Instr::SetCaptured(index) => {
+                    let value = self.top();
+                    *self.frame().closure.cells[usize::from(index)].borrow_mut() = value;
+                }
+                Instr::GetGlobal(global) => {
+                    let value = self.globals[usize::from(global)].clone();
+                    self.push(value);
+                }
+                Instr::SetGlobal(global) => self.globals[usize::from(global)] = self.top(),
+                Instr::Itself => {
+                    let closure = self.frame().closure.clone();
+                    self.push(Value::Closure(closure));
+                }
+                Instr::Native(index) => self.push(Value::Native(usize::from(index))),
+                Instr::Closure(index) => {
+                    let function = &program.functions[usize::from(index)];
+                    let current = self.frame().closure.clone();
+                    let cells = function
+                        .captures
+                        .iter()
+                        .map(|capture| match *capture {
+                            Capture::Cell(slot) => self.cell(base + usize::from(slot)),
+                            Capture::Captured(index) => current.cells[usize::from(index)].clone(),
+                            Capture::Local(slot) => {
+                                let value = self.stack[base + usize::from(slot)].clone();
+                                Rc::new(RefCell::new(value))
+                            }
+                            Capture::Itself => {
+                                Rc::new(RefCell::new(Value::Closure(current.clone())))
+                            }
+                        })
+                        .collect();
+                    self.push(Value::Closure(Rc::new(Closure {
+                        function: usize::from(index),
+                        cells,
+                    })));
+                }
+                Instr::Call(count) => {
+                    self.frames
+                        .last_mut()
+                        .expect("Instructions only run in a frame")
+                        .ip = ip;
+                    if self.invoke(usize::from(count))? {
+                        (function, ip, base) = self.resume();
+                        code = &program.functions[function].code;
+                    }
+                }
+                // The callee and arguments are moved down over the frame, which is dropped
+                Instr::TailCall(count) => {
+                    let count = usize::from(count);
+                    let start = self.stack.len() - count - 1;
+                    self.stack.drain(base - 1..start);
+                    self.frames.pop();
+                    if !self.invoke(count)? && self.frames.len() == depth {
+                        return Ok(());
+                    }
+                    (function, ip, base) = self.resume();
+                    code = &program.functions[function].code;
+                }
+                Instr::Return => {
+                    let result = self.pop();
+                    self.stack.truncate(base - 1);
+                    self.push(result);
+                    self.frames.pop();
+                    if self.frames.len() == depth {
+                        return Ok(());
+                    }
+                    (function, ip, base) = self.resume();
+                    code = &program.functions[function].code;
+                }
+                Instr::Jump(target) => ip = target as usize,
+                Instr::JumpIfFalse(target) => {
+                    if !truthy(&self.pop()) {
+                        ip = target as usize;
+                    }
+                }
+                Instr::JumpUnlessFalse(target) => {
+                    if truthy(&self.pop()) {
+                        ip = target as usize;
+                    }
+                }
+                Instr::Add => {
+                    let b = self.pop();
+                    let a = self.pop();
+                    let sum = match (a, b) {
+                        (Value::Number(a), Value::Number(b)) => Value::Number(a + b),
+                        (Value::String(a), Value::String(b)) => {
+                            Value::String(Rc::from(format!("{}{}", a, b)))
+                        }
+                        _ => return Err(String::from("+ needs two numbers or two strings")),
+                    };
+                    self.push(sum);
+                }
+                Instr::Subtract | Instr::Multiply | Instr::Divide | Instr::Remainder => {
+                    let b = to_number(&self.pop());
+                    let a = to_number(&self.pop());
+                    self.push(Value::Number(match instr {
+                        Instr::Subtract => a - b,
+                        Instr::Multiply => a * b,
+                        Instr::Divide => a / b,
+                        _ => a % b,
+                    }));
+                }
+                Instr::Equal | Instr::NotEqual => {
+                    let b = self.pop();
+                    let a = self.pop();
+                    let equal = equals(&a, &b);
+                    self.push(Value::Boolean(equal == (instr == Instr::Equal)));
+                }
+                Instr::Less | Instr::LessEqual | Instr::Greater | Instr::GreaterEqual => {
+                    let b = self.pop();
+                    let a = self.pop();
+                    let order = compare(&a, &b);
+                    self.push(Value::Boolean(match instr {
+                        Instr::Less => order == Some(Ordering::Less),
+                        Instr::LessEqual => order.is_some_and(|order| order != Ordering::Greater),
+                        Instr::Greater => order == Some(Ordering::Greater),
+                        _ => order.is_some_and(|order| order != Ordering::Less),
+                    }));
+                }
+                Instr::List(count) => {
+                    let items = self.stack.split_off(self.stack.len() - usize::from(count));
+                    self.push(Value::List(Rc::new(items)));
+                }
+                Instr::Data(constructor, count) => {
+                    let fields = self.stack.split_off(self.stack.len() - usize::from(count));
+                    self.push(Value::Data(Rc::new(Data {
+                        constructor: usize::from(constructor),
+                        fields,
+                    })));
+                }
+                Instr::JumpUnlessTag(constructor, target) => match self.pop() {
+                    Value::Data(ref data) if data.constructor == usize::from(constructor) => {}
+                    _ => ip = target as usize,
+                },
+                Instr::Field(index) => match self.pop() {
+                    Value::Data(ref data) => self.push(data.fields[usize::from(index)].clone()),
+                    _ => return Err(String::from("Only data has fields")),
+                },
+                Instr::NoMatch => {
+                    return Err(match self.pop() {
+                        Value::Data(ref data) => {
+                            format!("No match for {}", program.constructors[data.constructor])
+                        }
+                        _ => String::from("No match for a value that isn't data"),
+                    });
+                }
+            }
+        }
+    }
+
+    fn builtin(&mut self, builtin: Builtin, args: &[Value]) -> Result<Value, String> {
+        let result = match builtin {
+            Builtin::Length => match args[0] {
+                Value::String(ref string) => string.encode_utf16().count() as f64,
+                _ => list(&args[0])?.len() as f64,
+            },
+            Builtin::Get => {
+                let items = list(&args[0])?;
+                let index = to_number(&args[1]);
+                if !(index >= 0.0 && index < items.len() as f64) {
+                    return Err(format!(
+                        "Index {} is out of bounds for a list of length {}",
+                        number_to_string(index),
+                        items.len()
+                    ));
+                }
+                return Ok(items[index as usize].clone());
+            }
+            Builtin::Append => {
+                let mut items = list(&args[0])?.to_vec();
+                items.push(args[1].clone());
+                return Ok(Value::List(Rc::new(items)));
+            }
+            Builtin::Concat => {
+                let mut items = list(&args[0])?.to_vec();
+                items.extend_from_slice(list(&args[1])?);
+                return Ok(Value::List(Rc::new(items)));
+            }
+            // The numbers from `from` up to but not including `to`
+            Builtin::Range => {
+                let (from, to) = (to_number(&args[0]), to_number(&args[1]));
+                let length = if to > from {
+                    (to - from).ceil() as usize
+                } else {
+                    0
+                };
+                let items = (0..length)
+                    .map(|i| Value::Number(from + i as f64))
+                    .collect();
+                return Ok(Value::List(Rc::new(items)));
+            }
+            Builtin::Fold => {
+                let mut acc = args[1].clone();
+                for item in list(&args[0])?.iter() {
+                    acc = self.call(args[2].clone(), vec![acc, item.clone()])?;
+                }
+                return Ok(acc);
+            }
+            Builtin::Map => {
+                let mut items = Vec::new();
+                for item in list(&args[0])?.iter() {
+                    items.push(self.call(args[1].clone(), vec![item.clone()])?);
+                }
+                return Ok(Value::List(Rc::new(items)));
+            }
+            Builtin::Filter => {
+                let mut items = Vec::new();
+                for item in list(&args[0])?.iter() {
+                    if truthy(&self.call(args[1].clone(), vec![item.clone()])?) {
+                        items.push(item.clone());
+                    }
+                }
+                return Ok(Value::List(Rc::new(items)));
+            }
+            // The indices are clamped and put in order, as JS's substring does
+            Builtin::Substring => {
+                let units: Vec<u16> = string(&args[0])?.encode_utf16().collect();
+                let clamp = |index: f64| {
+                    if index.is_nan() || index < 0.0 {
+                        0
+                    } else {
+                        index.min(units.len() as f64) as usize
+                    }
+                };
+                let from = clamp(to_number(&args[1]));
+                let to = clamp(to_number(&args[2]));
+                let (from, to) = if from > to { (to, from) } else { (from, to) };
+                return Ok(Value::String(Rc::from(String::from_utf16_lossy(
+                    &units[from..to],
+                ))));
+            }
+            // An empty separator splits a string into its characters
+            Builtin::Split => {
+                let (chars, separator) = (string(&args[0])?, string(&args[1])?);
+                let items: Vec<Value> = if separator.is_empty() {
+                    chars
+                        .chars()
+                        .map(|ch| Value::String(Rc::from(ch.to_string())))
+                        .collect()
+                } else {
+                    chars
+                        .split(separator)
+                        .map(|part| Value::String(Rc::from(part)))
+                        .collect()
+                };
+                return Ok(Value::List(Rc::new(items)));
+            }
+            Builtin::Join => {
+                let separator = to_string(self.program, &args[1]);
+                let parts: Vec<String> = list(&args[0])?
+                    .iter()
+                    .map(|item| to_string(self.program, item))
+                    .collect();
+                return Ok(Value::String(Rc::from(parts.join(&separator))));
+            }
+            Builtin::String => {
+                return Ok(Value::String(Rc::from(to_string(self.program, &args[0]))));
+            }
+            Builtin::Number => match args[0] {
+                Value::Number(val) => val,
+                Value::Boolean(val) => f64::from(u8::from(val)),
+                Value::String(ref val) => parse_number(val),
+                _ => f64::NAN,
+            },
+            Builtin::Floor => to_number(&args[0]).floor(),
+            Builtin::Ceil => to_number(&args[0]).ceil(),
+            // Halves round up, as they do in JS
+            Builtin::Round => (to_number(&args[0]) + 0.5).floor(),
+            Builtin::Sqrt => to_number(&args[0]).sqrt(),
+            // JS gives NaN where C's pow gives 1, for a NaN exponent or 1 to an infinite one
+            Builtin::Pow => {
+                let (base, exponent) = (to_number(&args[0]), to_number(&args[1]));
+                if exponent.is_nan() || (base.abs() == 1.0 && exponent.is_infinite()) {
+                    f64::NAN
+                } else {
+                    base.powf(exponent)
+                }
+            }
+            Builtin::Log | Builtin::Error => {
+                let mut line = String::new();
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        line.push(' ');
+                    }
+                    match *arg {
+                        Value::String(ref val) => line.push_str(val),
+                        _ => line.push_str(&inspect(self.program, arg)),
+                    }
+                }
+                let written = if builtin == Builtin::Log {
+                    writeln!(self.out, "{}", line)
+                } else {
+                    self.out
+                        .flush()
+                        .and_then(|_| writeln!(io::stderr(), "{}", line))
+                };
+                written.map_err(|err| err.to_string())?;
+                return Ok(Value::Boolean(false));
+            }
+        };
+        Ok(Value::Number(result))
+    }
+}
+
+fn list(value: &Value) -> Result<&[Value], String> {
+    match *value {
+        Value::List(ref items) => Ok(items),
+        _ => Err(String::from("Expected a list")),
+    }
+}
+
+fn string(value: &Value) -> Result<&str, String> {
+    match *value {
+        Value::String(ref val) => Ok(val),
+        _ => Err(String::from("Expected a string")),
+    }
+}
+
+// Blank strings are 0, and strings that aren't entirely a number are NaN
+fn parse_number(string: &str) -> f64 {
+    let trimmed = string.trim();
+    if trimmed.is_empty() {
+        return 0.0;
+    }
+    let radix = match trimmed.get(..2) {
+        Some("0x") | Some("0X") => 16,
+        Some("0o") | Some("0O") => 8,
+        Some("0b") | Some("0B") => 2,
+        _ => 10,
+    };
+    if radix != 10 {
+        return u64::from_str_radix(&trimmed[2..], radix).map_or(f64::NAN, |val| val as f64);
+    }
+    match trimmed.trim_start_matches(['+', '-']) {
+        "Infinity" => {
+            return if trimmed.starts_with('-') {
+                f64::NEG_INFINITY
+            } else {
+                f64::INFINITY
+            }
+        }
+        // Rust reads names like inf and NaN that JS doesn't
+        unsigned if unsigned.starts_with(|ch: char| ch.is_alphabetic()) => return f64::NAN,
+        _ => {}
+    }
+    trimmed.parse().unwrap_or(f64::NAN)
+}
+
+// Numbers are written as JS writes them: the shortest digits that read back as the same
+// number, with an exponent only if they're very large or small
+pub fn number_to_string(number: f64) -> String {
+    if number.is_nan() {
+        return String::from("NaN");
+    }
+    if number == 0.0 {
+        return String::from("0");
+    }
+    let sign = if number < 0.0 { "-" } else { "" };
+    if number.is_infinite() {
+        return format!("{}Infinity", sign);
+    }
+
+    let formatted = format!("{:e}", number.abs());
+    let (mantissa, exponent) = formatted.split_at(formatted.find('e').unwrap_or(0));
+    let digits: String = mantissa.chars().filter(|&ch| ch != '.').collect();
+    // The position of the decimal point relative to the digits
+    let exponent = exponent[1..].parse::<i32>().unwrap_or(0) + 1;
+    let count = digits.len() as i32;
+
+    let written = if count <= exponent && exponent <= 21 {
+        format!("{}{}", digits, "0".repeat((exponent - count) as usize))
+    } else if 0 < exponent && exponent <= 21 {
+        let (whole, fraction) = digits.split_at(exponent as usize);
+        format!("{}.{}", whole, fraction)
+    } else if -6 < exponent && exponent <= 0 {
+        format!("0.{}{}", "0".repeat(-exponent as usize), digits)
+    } else {
+        let (first, rest) = digits.split_at(1);
+        let point = if rest.is_empty() { "" } else { "." };
+        let exponent_sign = if exponent > 0 { '+' } else { '-' };
+        format!(
+            "{}{}{}e{}{}",
+            first,
+            point,
+            rest,
+            exponent_sign,
+            (exponent - 1).abs()
+        )
+    };
+    format!("{}{}", sign, written)
+}
+
+fn function_name(program: &Program, value: &Value) -> String {
+    match *value {
+        Value::Closure(ref closure) => program.functions[closure.function].name.clone(),
+        Value::Native(index) => {
+            let js_name = &program.natives[index].js_name;
+            String::from(js_name.rsplit('.').next().unwrap_or(js_name))
+        }
+        _ => String::new(),
+    }
+}
+
+// The string JS's String gives for a value
+pub fn to_string(program: &Program, value: &Value) -> String {
+    match *value {
+        Value::Boolean(val) => val.to_string(),
+        Value::Number(val) => number_to_string(val),
+        Value::String(ref val) => String::from(&**val),
+        Value::List(ref items) => {
+            let items: Vec<String> = items.iter().map(|item| to_string(program, item)).collect();
+            items.join(",")
+        }
+        Value::Closure(_) | Value::Native(_) => format!(
+            "function {}() {{ [native code] }}",
+            function_name(program, value)
+        ),
+        Value::Data(_) | Value::Cell(_) => String::from("[object Object]"),
+    }
+}
+
+// Strings are measured in UTF-16 code units, as they are in JS
+fn units(string: &str) -> usize {
+    string.encode_utf16().count()
+}
+
+// Shows a value the way Node's util.inspect does by default
+pub fn inspect(program: &Program, value: &Value) -> String {
+    let mut inspector = Inspector {
+        program,
+        indentation: 0,
+        inspected_depth: 0,
+    };
+    let mut out = String::new();
+    inspector.inspect(&mut out, value, 0);
+    out
+}
+
+// Lists and data are on one line while they fit in 80 columns, long lists of short items
+// are laid out in columns, and anything nested more than two levels deep is left out
+struct Inspector<'a> {
+    program: &'a Program,
+    indentation: usize,
+    // The depth of the list or data last inspected, which decides whether an enclosing one
+    // may be on one line
+    inspected_depth: usize,
+}
+
+// Quotes a string with whichever of ', " and ` it doesn't contain, escaping control
+// characters
+fn quote(out: &mut String, string: &str) {
+    let mut quote = '\'';
+    if string.contains('\'') {
+        if !string.contains('"') {
+            quote = '"';
+        } else if !string.contains('`') && !string.contains("${") {
+            quote = '`';
+        }
+    }
+
+    out.push(quote);
+    for ch in string.chars() {
+        match ch {
+            '\x08' => out.push_str("\\b"),
+            '\t' => out.push_str("\\t"),
+            '\n' => out.push_str("\\n"),
+            '\x0c' => out.push_str("\\f"),
+            '\r' => out.push_str("\\r"),
+            '\\' => out.push_str("\\\\"),
+            '\'' if quote == '\'' => out.push_str("\\'"),
+            '\x00'..='\x1f' | '\x7f'..='\u{9f}' => out.push_str(&format!("\\x{:02X}", ch as u32)),
+            _ => out.push(ch),
+        }
+    }
+    out.push(quote);
+}
+
+impl<'a> Inspector<'a> {
+    fn inspect(&mut self, out: &mut String, value: &Value, depth: usize) {
+        match *value {
+            Value::Number(val) if val == 0.0 && val.is_sign_negative() => out.push_str("-0"),
+            Value::String(ref val) => self.inspect_string(out, val),
+            Value::Closure(_) | Value::Native(_) => {
+                let name = function_name(self.program, value);
+                if name.is_empty() {
+                    out.push_str("[Function (anonymous)]");
+                } else {
+                    out.push_str(&format!("[Function: {}]", name));
+                }
+            }
+            Value::List(ref items) if items.is_empty() => out.push_str("[]"),
+            Value::List(_) if depth > 2 => out.push_str("[Array]"),
+            Value::Data(_) if depth > 2 => out.push_str("[Object]"),
+            Value::List(_) | Value::Data(_) => {
+                self.inspected_depth = depth;
+                self.entries(out, value, depth);
+            }
+            _ => out.push_str(&to_string(self.program, value)),
+        }
+    }
+
+    // Long strings are split after their line breaks
+    fn inspect_string(&self, out: &mut String, string: &str) {
+        let length = units(string);
+        if length <= 16 || length + self.indentation + 4 <= 80 {
+            return quote(out, string);
+        }
+        let mut lines = string.split_inclusive('\n').peekable();
+        while let Some(line) = lines.next() {
+            quote(out, line);
+            if lines.peek().is_some() {
+                out.push_str(" +\n");
+                out.push_str(&" ".repeat(self.indentation + 2));
+            }
+        }
+    }
+
+    // Inspects the items of a list or the fields of data, and joins them on one line or
+    // one to a line
+    fn entries(&mut self, out: &mut String, value: &Value, depth: usize) {
+        let (items, tag, open, close): (&[Value], _, _, _) = match *value {
+            Value::List(ref items) => (items, None, "[", "]"),
+            Value::Data(ref data) => (&data.fields, Some(data.constructor), "{", "}"),
+            _ => return,
+        };
+        let count = items.len() + usize::from(tag.is_some());
+        let shown = count.min(100);
+
+        let mut entries = Vec::new();
+        let mut numbers = true;
+        for i in 0..shown {
+            let mut entry = String::new();
+            let item = match tag {
+                None => &items[i],
+                Some(tag) if i == 0 => {
+                    entry.push_str("'$tag': ");
+                    quote(&mut entry, &self.program.constructors[tag]);
+                    entries.push(entry);
+                    continue;
+                }
+                Some(_) => {
+                    entry.push_str(&format!("'${}': ", i - 1));
+                    &items[i - 1]
+                }
+            };
+            if let Value::Number(_) = *item {
+            } else {
+                numbers = false;
+            }
+            self.indentation += 2;
+            self.inspect(&mut entry, item, depth + 1);
+            self.indentation -= 2;
+            entries.push(entry);
+        }
+        if shown < count {
+            let more = count - shown;
+            entries.push(format!(
+                "... {} more item{}",
+                more,
+                if more > 1 { "s" } else { "" }
+            ));
+            if let Value::Number(_) = items[shown] {
+            } else {
+                numbers = false;
+            }
+        }
+
+        let ungrouped = entries.len();
+        if tag.is_none() && entries.len() > 6 {
+            if let Some(grouped) = self.group(&entries, shown, numbers) {
+                entries = grouped;
+            }
+        }
+        let mut single = false;
+        if self.inspected_depth.wrapping_sub(depth) < 3 && entries.len() == ungrouped {
+            let mut width = entries.len() * 2 + self.indentation + 1 + 10;
+            single = width + entries.len() <= 80;
+            for entry in entries.iter() {
+                if !single {
+                    break;
+                }
+                width += units(entry);
+                single = width <= 80 && !entry.contains('\n');
+            }
+        }
+
+        out.push_str(open);
+        for (i, entry) in entries.iter().enumerate() {
+            if single {
+                out.push_str(if i > 0 { ", " } else { " " });
+            } else {
+                out.push_str(if i > 0 { ",\n" } else { "\n" });
+                out.push_str(&" ".repeat(self.indentation + 2));
+            }
+            out.push_str(entry);
+        }
+        if single {
+            out.push(' ');
+        } else {
+            out.push('\n');
+            out.push_str(&" ".repeat(self.indentation));
+        }
+        out.push_str(close);
+    }
+
+    // Lays out more than six items in as many columns as look square, if they're short
+    // enough to fit three to a line
+    fn group(&self, entries: &[String], shown: usize, numbers: bool) -> Option<Vec<String>> {
+        let widths: Vec<usize> = entries.iter().map(|entry| units(entry)).collect();
+        let total: usize = widths[..shown].iter().map(|width| width + 2).sum();
+        let longest = widths[..shown].iter().cloned().max().unwrap_or(0);
+        let widest = longest + 2;
+        if widest * 3 + self.indentation >= 80
+            || (total as f64 / widest as f64 <= 5.0 && longest > 6)
+        {
+            return None;
+        }
+
+        let bias = (widest as f64 - total as f64 / entries.len() as f64).sqrt();
+        let biased = (widest as f64 - 3.0 - bias).max(1.0);
+        let columns = ((2.5 * biased * shown as f64).sqrt() / biased + 0.5).floor() as usize;
+        let columns = columns
+            .min(80usize.saturating_sub(self.indentation) / widest)
+            .min(12);
+        if columns <= 1 {
+            return None;
+        }
+
+        let column_widths: Vec<usize> = (0..columns)
+            .map(|i| {
+                let widest = (i..shown).step_by(columns).map(|j| widths[j]).max();
+                widest.unwrap_or(0) + 2
+            })
+            .collect();
+        let mut lines = Vec::new();
+        for start in (0..shown).step_by(columns) {
+            let end = (start + columns).min(shown);
+            let mut line = String::new();
+            for j in start..end {
+                let padding = " ".repeat(column_widths[j - start] - widths[j] - 2);
+                if numbers {
+                    line.push_str(&padding);
+                }
+                line.push_str(&entries[j]);
+                if j + 1 < end {
+                    line.push_str(", ");
+                    if !numbers {
+                        line.push_str(&padding);
+                    }
+                }
+            }
+            lines.push(line);
+        }
+        if shown < entries.len() {
+            lines.push(entries[shown].clone());
+        }
+        Some(lines)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use bytecode;
+    use modules;
+    use prelude;
+    use std::path::Path;
+
+    // What a program logs, or the error that ends it
+    fn run_source(inp: &str) -> Result<String, String> {
+        let source = format!("extern fn print(x) = \"console.log\"; {}", inp);
+        let modules = modules::load_with(Path::new("main.silver"), |_| Ok(source.clone())).unwrap();
+        let program = bytecode::compile_program(&modules, &prelude::module()).unwrap();
+        let mut out = Vec::new();
+        run(&program, &mut out)?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn test_closures() {
+        let out = run_source(
+            "fn counter() { n = 0; fn () { n = n + 1; n } };
+             c = counter(); c(); c(); print(c());
+             fs = map([1, 2, 3], fn (i) { fn () { i * 10 } });
+             print(map(fs, fn (f) { f() }))",
+        );
+        assert_eq!(out.unwrap(), "3\n[ 10, 20, 30 ]\n");
+    }
+
+    #[test]
+    fn test_let_shadows_outer_names() {
+        let out = run_source("x = 1; fn f() { let x = 2; x }; print(f()); print(x)");
+        assert_eq!(out.unwrap(), "2\n1\n");
+    }
+
+    #[test]
+    fn test_only_false_is_falsy() {
+        let out = run_source(
+            "print(if 0 then 1 else 2); print(if \"\" then 1 else 2);
+             print(if [] then 1 else 2); print(if false then 1 else 2);
+             print(0 && 1); print(false || 3); print(false && 1)",
+        );
+        assert_eq!(out.unwrap(), "1\n1\n1\n2\n1\n3\nfalse\n");
+    }
+
+    #[test]
+    fn test_tail_calls_run_in_constant_space() {
+        let out = run_source(
+            "fn even?(n) { if n == 0 then true else odd?(n - 1) };
+             fn odd?(n) { if n == 0 then false else even?(n - 1) };
+             print(even?(100001))",
+        );
+        assert_eq!(out.unwrap(), "false\n");
+
+        let out = run_source("fn loop(n) { n == 0 || loop(n - 1) }; print(loop(200000))");
+        assert_eq!(out.unwrap(), "true\n");
+
+        let err = run_source("fn f(n) { 1 + f(n) }; f(1)").unwrap_err();
+        assert_eq!(err, "Maximum call stack size exceeded");
+    }
+
+    #[test]
+    fn test_data_and_matching() {
+        let out = run_source(
+            "type T = Leaf | Node(l, v, r);
+             fn sum(t) { match t { Leaf => 0, Node(l, v, r) => sum(l) + v + sum(r) } };
+             t = Node(Node(Leaf, 1, Leaf), 2, Leaf);
+             print(sum(t)); print(t)",
+        );
+        assert_eq!(
+            out.unwrap(),
+            "3\n{\n  '$tag': 'Node',\n  '$0': {\n    '$tag': 'Node',\n    \
+             '$0': { '$tag': 'Leaf' },\n    '$1': 1,\n    '$2': { '$tag': 'Leaf' }\n  },\n  \
+             '$1': 2,\n  '$2': { '$tag': 'Leaf' }\n}\n"
+        );
+
+        let err = run_source("type T = A | B(x); match B(1) { A => 1 }").unwrap_err();
+        assert_eq!(err, "No match for B");
+    }
+
+    #[test]
+    fn test_runtime_errors() {
+        let err = run_source("x = 1; x(2)").unwrap_err();
+        assert_eq!(err, "Called a value that isn't a function");
+        let err = run_source("f = fn (a) { a }; f(1, 2)").unwrap_err();
+        assert_eq!(err, "Called a function that takes 1 arguments with 2");
+        let err = run_source("\"a\" + 1").unwrap_err();
+        assert_eq!(err, "+ needs two numbers or two strings");
+        let err = run_source("get([1], 5)").unwrap_err();
+        assert_eq!(err, "Index 5 is out of bounds for a list of length 1");
+    }
+
+    #[test]
+    fn test_numbers_are_written_as_in_js() {
+        assert_eq!(number_to_string(1.0), "1");
+        assert_eq!(number_to_string(-2.5), "-2.5");
+        assert_eq!(number_to_string(0.1 + 0.2), "0.30000000000000004");
+        assert_eq!(number_to_string(1e21), "1e+21");
+        assert_eq!(number_to_string(123456789012.0), "123456789012");
+        assert_eq!(number_to_string(0.000001), "0.000001");
+        assert_eq!(number_to_string(1.5e-7), "1.5e-7");
+        assert_eq!(number_to_string(f64::NAN), "NaN");
+        assert_eq!(number_to_string(f64::NEG_INFINITY), "-Infinity");
+        assert_eq!(parse_number(" 12 "), 12.0);
+        assert_eq!(parse_number("0x1f"), 31.0);
+        assert!(parse_number("inf").is_nan());
+    }
+
+    #[test]
+    fn test_values_are_inspected_as_in_node() {
+        let out = run_source(
+            "print(range(0, 10)); print([\"it's\", 1.25, [[[1]]]]); print(print);
+             print(fn () { 1 }); print(0 - 0); print(\"a\")",
+        );
+        assert_eq!(
+            out.unwrap(),
+            "[\n  0, 1, 2, 3, 4,\n  5, 6, 7, 8, 9\n]\n[ \"it's\", 1.25, [ [ [Array] ] ] ]\n\
+             [Function: log]\n[Function (anonymous)]\n0\na\n"
+        );
+    }
+}
@@ -0,0 +1,2873 @@
+use super::backend::{self, Direct, Scope};
+use super::lexer::Token;
+use super::modules;
+use super::parser::{ASTNode, ExternFunction, MatchArm, Pattern, Variant};
+use super::prelude;
+
+use super::util::{Error, Span};
+
+use std::collections::HashMap;
+use std::fmt;
+
+// Every Silver value is 64 bits. Numbers are f64s, as they are in JS, and every other value
+// is a NaN that arithmetic never produces: the bits of BOXED are set, the value's tag is in
+// the three bits above the low 32, and the low 32 hold the value itself or its address.
+const BOXED: i64 = 0x7FFC_0000_0000_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Tag {
+    Bool = 1,
+    // The length in UTF-16 code units, then the code units
+    String = 2,
+    // The length and 4 unused bytes, then the elements
+    List = 3,
+    // The table slot of the function and its arity, then the address of each captured cell
+    Closure = 4,
+    // The constructor's tag and the number of fields, then the fields
+    Data = 5,
+}
+
+fn boxed(tag: Tag, payload: u32) -> i64 {
+    BOXED | (tag as i64) << 32 | i64::from(payload)
+}
+
+const FALSE: i64 = BOXED | (Tag::Bool as i64) << 32;
+
+// Address 0 is never used, so the data starts after it
+const DATA_START: u32 = 8;
+const PAGE_SIZE: u32 = 65536;
+
+// The reasons the runtime fails, passed to the loader along with a detail
+const NOT_A_FUNCTION: i32 = 0;
+const WRONG_ARITY: i32 = 1;
+const NO_MATCH: i32 = 2;
+const OUT_OF_BOUNDS: i32 = 3;
+const BAD_OPERANDS: i32 = 4;
+const OUT_OF_MEMORY: i32 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValType {
+    I32,
+    I64,
+    F64,
+}
+
+impl ValType {
+    fn name(self) -> &'static str {
+        match self {
+            ValType::I32 => "i32",
+            ValType::I64 => "i64",
+            ValType::F64 => "f64",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FuncType {
+    pub params: Vec<ValType>,
+    pub results: Vec<ValType>,
+}
+
+// The functions the program needs from its host, which are its only imports
+#[derive(Debug)]
+pub struct Import {
+    pub module: String,
+    pub field: String,
+    pub name: String,
+    pub ty: u32,
+}
+
+#[derive(Debug)]
+pub struct Function {
+    pub name: String,
+    pub ty: u32,
+    // The names of the parameters followed by the locals, and the types of the locals
+    pub names: Vec<String>,
+    pub locals: Vec<ValType>,
+    pub body: Vec<Instr>,
+}
+
+// Globals are all mutable and start out as a constant
+#[derive(Debug)]
+pub struct Global {
+    pub name: String,
+    pub ty: ValType,
+    pub init: Instr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportKind {
+    Function(u32),
+    Table,
+    Memory,
+}
+
+// A WebAssembly module with one memory, holding the data from DATA_START, and one table of
+// the functions that closures refer to
+#[derive(Debug)]
+pub struct Module {
+    pub types: Vec<FuncType>,
+    pub imports: Vec<Import>,
+    pub functions: Vec<Function>,
+    pub table: Vec<u32>,
+    pub globals: Vec<Global>,
+    pub pages: u32,
+    pub data: Vec<u8>,
+    pub exports: Vec<(String, ExportKind)>,
+    // The names of the program's constructors by tag, which the loader needs to convert
+    // data to JS
+    pub constructors: Vec<String>,
+}
+
+impl Module {
+    pub fn data_start(&self) -> u32 {
+        DATA_START
+    }
+
+    // Imported functions come before the module's own in the function index space
+    fn function_name(&self, index: u32) -> &str {
+        let index = index as usize;
+        match self.imports.get(index) {
+            Some(import) => &import.name,
+            None => &self.functions[index - self.imports.len()].name,
+        }
+    }
+}
+
+// How a load or store accesses memory
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    I32,
+    I64,
+    // The low 16 bits of an i32
+    U16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instr {
+    Block(Option<ValType>),
+    Loop(Option<ValType>),
+    If(Option<ValType>),
+    Else,
+    End,
+    Br(u32),
+    BrIf(u32),
+    Return,
+    Unreachable,
+    Drop,
+    Call(u32),
+    CallIndirect(u32),
+    LocalGet(u32),
+    LocalSet(u32),
+    LocalTee(u32),
+    GlobalGet(u32),
+    GlobalSet(u32),
+    // Each with its offset
+    Load(Access, u32),
+    Store(Access, u32),
+    MemorySize,
+    MemoryGrow,
+    MemoryCopy,
+    I32Const(i32),
+    I64Const(i64),
+    F64Const(f64),
+    Numeric(Op),
+}
+
+// The instructions without immediates
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    I32Eqz,
+    I32Eq,
+    I32Ne,
+    I32LtS,
+    I32LtU,
+    I32GtS,
+    I32LeS,
+    I32LeU,
+    I32GeU,
+    I32Add,
+    I32Sub,
+    I32Mul,
+    I32And,
+    I32Shl,
+    I32ShrU,
+    I64Eq,
+    I64Ne,
+    I64And,
+    I64Or,
+    I64ShrU,
+    F64Eq,
+    F64Lt,
+    F64Gt,
+    F64Ge,
+    F64Ceil,
+    F64Trunc,
+    F64Add,
+    F64Sub,
+    F64Mul,
+    F64Div,
+    I32WrapI64,
+    I32TruncF64U,
+    I64ExtendI32U,
+    F64ConvertI32U,
+    I64ReinterpretF64,
+    F64ReinterpretI64,
+}
+
+impl Op {
+    // The instruction's name in the text format and its opcode in the binary format
+    pub fn encoding(self) -> (&'static str, u8) {
+        match self {
+            Op::I32Eqz => ("i32.eqz", 0x45),
+            Op::I32Eq => ("i32.eq", 0x46),
+            Op::I32Ne => ("i32.ne", 0x47),
+            Op::I32LtS => ("i32.lt_s", 0x48),
+            Op::I32LtU => ("i32.lt_u", 0x49),
+            Op::I32GtS => ("i32.gt_s", 0x4A),
+            Op::I32LeS => ("i32.le_s", 0x4C),
+            Op::I32LeU => ("i32.le_u", 0x4D),
+            Op::I32GeU => ("i32.ge_u", 0x4F),
+            Op::I32Add => ("i32.add", 0x6A),
+            Op::I32Sub => ("i32.sub", 0x6B),
+            Op::I32Mul => ("i32.mul", 0x6C),
+            Op::I32And => ("i32.and", 0x71),
+            Op::I32Shl => ("i32.shl", 0x74),
+            Op::I32ShrU => ("i32.shr_u", 0x76),
+            Op::I64Eq => ("i64.eq", 0x51),
+            Op::I64Ne => ("i64.ne", 0x52),
+            Op::I64And => ("i64.and", 0x83),
+            Op::I64Or => ("i64.or", 0x84),
+            Op::I64ShrU => ("i64.shr_u", 0x88),
+            Op::F64Eq => ("f64.eq", 0x61),
+            Op::F64Lt => ("f64.lt", 0x63),
+            Op::F64Gt => ("f64.gt", 0x64),
+            Op::F64Ge => ("f64.ge", 0x66),
+            Op::F64Ceil => ("f64.ceil", 0x9B),
+            Op::F64Trunc => ("f64.trunc", 0x9D),
+            Op::F64Add => ("f64.add", 0xA0),
+            Op::F64Sub => ("f64.sub", 0xA1),
+            Op::F64Mul => ("f64.mul", 0xA2),
+            Op::F64Div => ("f64.div", 0xA3),
+            Op::I32WrapI64 => ("i32.wrap_i64", 0xA7),
+            Op::I32TruncF64U => ("i32.trunc_f64_u", 0xAB),
+            Op::I64ExtendI32U => ("i64.extend_i32_u", 0xAD),
+            Op::F64ConvertI32U => ("f64.convert_i32_u", 0xB8),
+            Op::I64ReinterpretF64 => ("i64.reinterpret_f64", 0xBD),
+            Op::F64ReinterpretI64 => ("f64.reinterpret_i64", 0xBF),
+        }
+    }
+}
+
+use self::Instr::*;
+use self::Op::*;
+
+// The functions the compiled code relies on, each included the first time it's used
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Runtime {
+    Alloc,
+    Closure,
+    TagOf,
+    Add,
+    JoinStrings,
+    CompareStrings,
+    Equals,
+    Compare,
+    Rem,
+    // The natives implementing the prelude's list externs
+    Length,
+    Get,
+    Append,
+    Concat,
+    Range,
+    Fold,
+    Map,
+    Filter,
+}
+
+impl Runtime {
+    // The prelude externs implemented in WebAssembly rather than by the host, since they
+    // call closures or are cheap enough that a call to the host would dominate
+    fn native(js_name: &str) -> Option<Runtime> {
+        match js_name {
+            "$length" => Some(Runtime::Length),
+            "$get" => Some(Runtime::Get),
+            "$append" => Some(Runtime::Append),
+            "$concat" => Some(Runtime::Concat),
+            "$range" => Some(Runtime::Range),
+            "$fold" => Some(Runtime::Fold),
+            "$map" => Some(Runtime::Map),
+            "$filter" => Some(Runtime::Filter),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Runtime::Alloc => "rt.alloc",
+            Runtime::Closure => "rt.closure",
+            Runtime::TagOf => "rt.tag-of",
+            Runtime::Add => "rt.add",
+            Runtime::JoinStrings => "rt.join-strings",
+            Runtime::CompareStrings => "rt.compare-strings",
+            Runtime::Equals => "rt.equals",
+            Runtime::Compare => "rt.compare",
+            Runtime::Rem => "rt.rem",
+            Runtime::Length => "rt.length",
+            Runtime::Get => "rt.get",
+            Runtime::Append => "rt.append",
+            Runtime::Concat => "rt.concat",
+            Runtime::Range => "rt.range",
+            Runtime::Fold => "rt.fold",
+            Runtime::Map => "rt.map",
+            Runtime::Filter => "rt.filter",
+        }
+    }
+}
+
+type GlobalName = backend::GlobalName<u32>;
+type Var = backend::Var<u32, GlobalName>;
+type Source = backend::Source<GlobalName>;
+
+// A function being compiled. The module's top-level code is compiled as a function too, at
+// the bottom of the stack of frames.
+struct Frame {
+    names: Vec<String>,
+    types: Vec<ValType>,
+    params: usize,
+    body: Vec<Instr>,
+    scope: Scope<u32, GlobalName>,
+    function: u32,
+    arity: usize,
+    // The block depth of the loop that the function's calls to itself in tail position
+    // jump to, if it has one, and the current block depth
+    tail: Option<u32>,
+    depth: u32,
+    temps: Vec<u32>,
+}
+
+impl Frame {
+    fn new(function: u32, params: Vec<(String, ValType)>, arity: usize) -> Frame {
+        Frame {
+            params: params.len(),
+            names: params.iter().map(|(name, _)| name.clone()).collect(),
+            types: params.iter().map(|&(_, ty)| ty).collect(),
+            body: Vec::new(),
+            scope: Scope::new(),
+            function,
+            arity,
+            tail: None,
+            depth: 0,
+            temps: Vec::new(),
+        }
+    }
+
+    // Local names are unique, so that the text format can refer to locals by name
+    fn add_local(&mut self, name: &str, ty: ValType) -> u32 {
+        let mut unique = String::from(name);
+        let mut count = 1;
+        while self.names.contains(&unique) {
+            count += 1;
+            unique = format!("{}.{}", name, count);
+        }
+        self.names.push(unique);
+        self.types.push(ty);
+        (self.names.len() - 1) as u32
+    }
+}
+
+impl backend::Frame<u32, GlobalName> for Frame {
+    fn scope(&self) -> &Scope<u32, GlobalName> {
+        &self.scope
+    }
+
+    fn scope_mut(&mut self) -> &mut Scope<u32, GlobalName> {
+        &mut self.scope
+    }
+}
+
+struct Compiler {
+    module: Module,
+    runtime: HashMap<Runtime, u32>,
+    // The host functions imported, by module, field and arity
+    externs: HashMap<(String, String, usize), u32>,
+    strings: HashMap<String, u32>,
+    sources: Vec<Source>,
+    current: usize,
+    // The functions of the declarations in the current module that calls go to directly,
+    // reserved before the module is compiled so that calls can come before the declaration
+    reserved: HashMap<String, u32>,
+    frames: Vec<Frame>,
+    errors: Vec<Error>,
+}
+
+// Compiles a program and the prelude to a WebAssembly module that exports `main`, which runs
+// the prelude and then each module in turn. Every function takes its closure as its first
+// parameter, then its arguments.
+pub fn compile_program(
+    modules: &[modules::Module],
+    prelude: &modules::Module,
+) -> Result<Module, Vec<Error>> {
+    let mut compiler = Compiler::new();
+
+    // Imports come first in the function index space, so they're all found beforehand
+    let sources: Vec<&modules::Module> = Some(prelude).into_iter().chain(modules).collect();
+    for source in sources.iter() {
+        compiler.import_externs(&source.ast);
+    }
+
+    let root = modules.len();
+    let mut inits = Vec::new();
+    for (index, source) in sources.iter().enumerate() {
+        let prefix = match index {
+            0 => String::from("prelude."),
+            _ if index == root => String::new(),
+            _ => format!("module{}.", index - 1),
+        };
+        inits.push(compiler.compile_source(index, source, prefix));
+    }
+
+    let main = compiler.add_function("main", Vec::new(), None);
+    let body = inits.into_iter().map(Call).collect();
+    compiler.define(main, Vec::new(), body);
+    let alloc = compiler.runtime(Runtime::Alloc);
+
+    let mut module = compiler.finish()?;
+    module.exports = vec![
+        (String::from("main"), ExportKind::Function(main)),
+        (String::from("alloc"), ExportKind::Function(alloc)),
+        (String::from("memory"), ExportKind::Memory),
+        (String::from("table"), ExportKind::Table),
+    ];
+    Ok(module)
+}
+
+impl Compiler {
+    fn new() -> Compiler {
+        let mut compiler = Compiler {
+            module: Module {
+                types: Vec::new(),
+                imports: Vec::new(),
+                functions: Vec::new(),
+                table: Vec::new(),
+                globals: vec![Global {
+                    name: String::from("rt.heap"),
+                    ty: ValType::I32,
+                    init: I32Const(0),
+                }],
+                pages: 1,
+                data: Vec::new(),
+                exports: Vec::new(),
+                constructors: Vec::new(),
+            },
+            runtime: HashMap::new(),
+            externs: HashMap::new(),
+            strings: HashMap::new(),
+            sources: Vec::new(),
+            current: 0,
+            reserved: HashMap::new(),
+            frames: Vec::new(),
+            errors: Vec::new(),
+        };
+
+        // The loader reports failures, given their reason and a detail
+        let ty = compiler.type_index(vec![ValType::I32, ValType::I32], None);
+        compiler.module.imports.push(Import {
+            module: String::from("runtime"),
+            field: String::from("fail"),
+            name: String::from("rt.fail"),
+            ty,
+        });
+        compiler
+    }
+
+    // The heap starts after the data, and the memory starts out big enough for both
+    fn finish(mut self) -> Result<Module, Vec<Error>> {
+        if !self.errors.is_empty() {
+            return Err(self.errors);
+        }
+
+        self.align_data();
+        let heap = DATA_START + self.module.data.len() as u32;
+        self.module.globals[0].init = I32Const(heap as i32);
+        self.module.pages = heap / PAGE_SIZE + 1;
+        Ok(self.module)
+    }
+
+    fn type_index(&mut self, params: Vec<ValType>, result: Option<ValType>) -> u32 {
+        let ty = FuncType {
+            params,
+            results: result.into_iter().collect(),
+        };
+        match self.module.types.iter().position(|known| *known == ty) {
+            Some(index) => index as u32,
+            None => {
+                self.module.types.push(ty);
+                (self.module.types.len() - 1) as u32
+            }
+        }
+    }
+
+    // Silver functions take their closure, then their arguments
+    fn silver_type(&mut self, arity: usize) -> u32 {
+        let mut params = vec![ValType::I32];
+        params.extend((0..arity).map(|_| ValType::I64));
+        self.type_index(params, Some(ValType::I64))
+    }
+
+    // Adds a function to be defined later, returning its index. Function names are unique,
+    // so that the text format can refer to functions by name.
+    fn add_function(&mut self, name: &str, params: Vec<ValType>, result: Option<ValType>) -> u32 {
+        let mut unique = String::from(name);
+        let mut count = 1;
+        while self.module.functions.iter().any(|f| f.name == unique)
+            || self
+                .module
+                .imports
+                .iter()
+                .any(|import| import.name == unique)
+        {
+            count += 1;
+            unique = format!("{}.{}", name, count);
+        }
+
+        let ty = self.type_index(params, result);
+        self.module.functions.push(Function {
+            name: unique,
+            ty,
+            names: Vec::new(),
+            locals: Vec::new(),
+            body: Vec::new(),
+        });
+        (self.module.imports.len() + self.module.functions.len() - 1) as u32
+    }
+
+    fn add_silver_function(&mut self, name: &str, arity: usize) -> u32 {
+        let mut params = vec![ValType::I32];
+        params.extend((0..arity).map(|_| ValType::I64));
+        self.add_function(name, params, Some(ValType::I64))
+    }
+
+    // The names are those of the parameters and then the locals
+    fn define(&mut self, index: u32, names: Vec<String>, body: Vec<Instr>) {
+        let mut unique: Vec<String> = Vec::new();
+        for name in names {
+            let mut name_unique = name.clone();
+            let mut count = 1;
+            while unique.contains(&name_unique) {
+                count += 1;
+                name_unique = format!("{}.{}", name, count);
+            }
+            unique.push(name_unique);
+        }
+        let function = self.function_mut(index);
+        function.names = unique;
+        function.body = body;
+    }
+
+    fn define_frame(&mut self, frame: Frame) {
+        let function = frame.function;
+        let locals = frame.types[frame.params..].to_vec();
+        self.define(function, frame.names, frame.body);
+        self.function_mut(function).locals = locals;
+    }
+
+    fn function_mut(&mut self, index: u32) -> &mut Function {
+        let imports = self.module.imports.len();
+        &mut self.module.functions[index as usize - imports]
+    }
+
+    fn align_data(&mut self) {
+        while !self.module.data.len().is_multiple_of(8) {
+            self.module.data.push(0);
+        }
+    }
+
+    // Places bytes in the data, returning their address
+    fn static_data(&mut self, bytes: &[u8]) -> u32 {
+        self.align_data();
+        let address = DATA_START + self.module.data.len() as u32;
+        self.module.data.extend_from_slice(bytes);
+        address
+    }
+
+    fn string(&mut self, string: &str) -> u32 {
+        if let Some(&address) = self.strings.get(string) {
+            return address;
+        }
+
+        let units: Vec<u16> = string.encode_utf16().collect();
+        let mut bytes = (units.len() as u32).to_le_bytes().to_vec();
+        for unit in units {
+            bytes.extend_from_slice(&unit.to_le_bytes());
+        }
+        let address = self.static_data(&bytes);
+        self.strings.insert(String::from(string), address);
+        address
+    }
+
+    // Constructors are told apart by name, as they are in JS
+    fn constructor_tag(&mut self, name: &str) -> u32 {
+        let constructors = &mut self.module.constructors;
+        match constructors
+            .iter()
+            .position(|constructor| constructor == name)
+        {
+            Some(tag) => tag as u32,
+            None => {
+                constructors.push(String::from(name));
+                (constructors.len() - 1) as u32
+            }
+        }
+    }
+
+    // A closure of a function that captures nothing never changes, so it's in the data
+    fn static_closure(&mut self, function: u32, arity: usize) -> i64 {
+        self.module.table.push(function);
+        let slot = (self.module.table.len() - 1) as u32;
+        let mut bytes = slot.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&(arity as u32).to_le_bytes());
+        boxed(Tag::Closure, self.static_data(&bytes))
+    }
+
+    fn error(&mut self, span: Span, msg: String) {
+        self.errors.push(span.get_error(msg));
+    }
+
+    fn frame(&mut self) -> &mut Frame {
+        self.frames
+            .last_mut()
+            .expect("Code is only emitted into a frame")
+    }
+
+    fn emit(&mut self, instr: Instr) {
+        let frame = self.frame();
+        // A number's bits that are used as a float right away needn't be converted
+        if instr == Numeric(F64ReinterpretI64)
+            && frame.body.last() == Some(&Numeric(I64ReinterpretF64))
+        {
+            frame.body.pop();
+            return;
+        }
+        match instr {
+            Block(_) | Loop(_) | If(_) => frame.depth += 1,
+            End => frame.depth -= 1,
+            _ => {}
+        }
+        frame.body.push(instr);
+    }
+
+    fn emit_all(&mut self, instrs: Vec<Instr>) {
+        for instr in instrs {
+            self.emit(instr);
+        }
+    }
+
+    // A local for intermediate values, which is reused once it's released
+    fn temp(&mut self, ty: ValType) -> u32 {
+        let frame = self.frame();
+        match frame
+            .temps
+            .iter()
+            .position(|&temp| frame.types[temp as usize] == ty)
+        {
+            Some(index) => frame.temps.remove(index),
+            None => frame.add_local("tmp", ty),
+        }
+    }
+
+    fn release(&mut self, temp: u32) {
+        self.frame().temps.push(temp);
+    }
+
+    // Imports the host functions that the externs of a module are bound to
+    fn import_externs(&mut self, ast: &ASTNode) {
+        let exprs = match *ast {
+            ASTNode::Sequence(ref exprs) => exprs.as_slice(),
+            _ => return,
+        };
+        for expr in exprs.iter() {
+            if let ASTNode::Extern {
+                ref module,
+                ref functions,
+                ..
+            } = *expr.declaration()
+            {
+                for function in functions.iter() {
+                    let (module, field) = backend::extern_target(module, function);
+                    let arity = function.args.len();
+                    if module == "host" && Runtime::native(&field).is_some() {
+                        continue;
+                    }
+
+                    let key = (module, field, arity);
+                    if !self.externs.contains_key(&key) {
+                        let ty = self.type_index(vec![ValType::I64; arity], Some(ValType::I64));
+                        self.module.imports.push(Import {
+                            module: key.0.clone(),
+                            field: key.1.clone(),
+                            name: format!("{}.{}", key.0, key.1),
+                            ty,
+                        });
+                        let index = (self.module.imports.len() - 1) as u32;
+                        self.externs.insert(key, index);
+                    }
+                }
+            }
+        }
+    }
+
+    // The function an extern calls: a native or an import, which take only the arguments
+    fn extern_function(&mut self, module: &Option<String>, function: &ExternFunction) -> u32 {
+        let (module, field) = backend::extern_target(module, function);
+        let arity = function.args.len();
+        match self.externs.get(&(module, field.clone(), arity)) {
+            Some(&index) => index,
+            None => {
+                let native = Runtime::native(&field).expect("Externs are imported beforehand");
+                self.runtime(native)
+            }
+        }
+    }
+
+    fn compile_source(&mut self, index: usize, module: &modules::Module, prefix: String) -> u32 {
+        let mut source = Source::new(module, prefix);
+        let exprs: &[ASTNode] = match module.ast {
+            ASTNode::Sequence(ref exprs) => exprs,
+            _ => &[],
+        };
+        for name in backend::global_names(module) {
+            self.module.globals.push(Global {
+                name: format!("{}{}", source.prefix, name),
+                ty: ValType::I64,
+                init: I64Const(FALSE),
+            });
+            let global = GlobalName {
+                index: (self.module.globals.len() - 1) as u32,
+                direct: None,
+            };
+            source.globals.insert(name, global);
+        }
+
+        // Calls to a declaration whose name is bound nowhere else go straight to it
+        self.reserved.clear();
+        let counts = backend::bindings(&module.ast);
+        let once = |name: &str| counts.get(name) == Some(&1);
+        for expr in exprs.iter() {
+            let mut direct = Vec::new();
+            match *expr.declaration() {
+                ASTNode::Function { ref args, .. } => {
+                    if let Some((name, _)) = expr.function_name() {
+                        if once(name) {
+                            let function = self.add_silver_function(
+                                &format!("{}{}", source.prefix, name),
+                                args.len(),
+                            );
+                            self.reserved.insert(String::from(name), function);
+                            direct.push((name, Direct::Function(function, args.len())));
+                        }
+                    }
+                }
+                ASTNode::TypeDeclaration { ref variants, .. } => {
+                    for variant in variants.iter().filter(|variant| !variant.fields.is_empty()) {
+                        if once(&variant.name) {
+                            let name = format!("{}{}", source.prefix, variant.name);
+                            let function = self.add_silver_function(&name, variant.fields.len());
+                            self.reserved.insert(variant.name.clone(), function);
+                            direct.push((
+                                &variant.name,
+                                Direct::Function(function, variant.fields.len()),
+                            ));
+                        }
+                    }
+                }
+                ASTNode::Extern {
+                    ref module,
+                    ref functions,
+                    ..
+                } => {
+                    for function in functions.iter().filter(|function| once(&function.name)) {
+                        let target = self.extern_function(module, function);
+                        direct.push((&function.name, Direct::Extern(target, function.args.len())));
+                    }
+                }
+                _ => {}
+            }
+            for (name, call) in direct {
+                if let Some(global) = source.globals.get_mut(name) {
+                    global.direct = Some(call);
+                }
+            }
+        }
+
+        let init = match index {
+            0 => String::from("init.prelude"),
+            _ => format!("init.{}", source.prefix.trim_end_matches('.')),
+        };
+        let init = match init.as_str() {
+            "init." => String::from("init"),
+            _ => init,
+        };
+        self.sources.push(source);
+        self.current = index;
+
+        let function = self.add_function(&init, Vec::new(), None);
+        self.frames.push(Frame::new(function, Vec::new(), 0));
+        backend::collect_captured(&module.ast, &mut self.frame().scope.captured);
+        match module.ast {
+            ASTNode::Sequence(ref exprs) => {
+                for expr in exprs.iter() {
+                    self.element(expr, false);
+                    self.emit(Drop);
+                }
+            }
+            ref ast => {
+                self.expression(ast, false);
+                self.emit(Drop);
+            }
+        }
+        let frame = self.frames.pop().expect("The frame was pushed above");
+        self.define_frame(frame);
+        function
+    }
+
+    fn lookup(&mut self, name: &str) -> Option<Var> {
+        backend::lookup(&mut self.frames, &self.sources, self.current, name)
+    }
+
+    fn lookup_qualified(&self, module: &str, name: &str) -> Option<Var> {
+        backend::lookup_qualified(&self.sources, self.current, module, name)
+    }
+
+    fn load(&mut self, var: Var) {
+        match var {
+            Var::Local(local) => self.emit(LocalGet(local)),
+            Var::Cell(local) => self.emit_all(vec![LocalGet(local), Load(Access::I64, 0)]),
+            Var::Captured(slot) => self.emit_all(vec![
+                LocalGet(0),
+                Load(Access::I32, 8 + 4 * slot),
+                Load(Access::I64, 0),
+            ]),
+            Var::Global(global) => self.emit(GlobalGet(global.index)),
+            Var::Itself => self.emit_all(vec![
+                LocalGet(0),
+                Numeric(I64ExtendI32U),
+                I64Const(boxed(Tag::Closure, 0)),
+                Numeric(I64Or),
+            ]),
+        }
+    }
+
+    // Stores the value on the stack, leaving it there as the value of the assignment
+    fn store(&mut self, var: Var) {
+        match var {
+            Var::Local(local) => self.emit(LocalTee(local)),
+            Var::Global(global) => {
+                self.emit_all(vec![GlobalSet(global.index), GlobalGet(global.index)])
+            }
+            Var::Cell(_) | Var::Captured(_) => {
+                let value = self.temp(ValType::I64);
+                self.emit(LocalSet(value));
+                self.cell(var);
+                self.emit_all(vec![
+                    LocalGet(value),
+                    Store(Access::I64, 0),
+                    LocalGet(value),
+                ]);
+                self.release(value);
+            }
+            // A function's name within its body is bound to the function, as in JS
+            Var::Itself => {}
+        }
+    }
+
+    // Pushes the address of the cell a name is kept in. The function itself is only in a
+    // cell once a closure captures it.
+    fn cell(&mut self, var: Var) {
+        match var {
+            Var::Cell(local) => self.emit(LocalGet(local)),
+            Var::Captured(slot) => {
+                self.emit_all(vec![LocalGet(0), Load(Access::I32, 8 + 4 * slot)])
+            }
+            Var::Local(_) | Var::Global(_) | Var::Itself => {
+                let alloc = self.runtime(Runtime::Alloc);
+                let cell = self.temp(ValType::I32);
+                self.emit_all(vec![I32Const(8), Call(alloc), LocalTee(cell)]);
+                self.load(var);
+                self.emit_all(vec![Store(Access::I64, 0), LocalGet(cell)]);
+                self.release(cell);
+            }
+        }
+    }
+
+    fn assign(&mut self, name: &str, span: Span) {
+        match self.lookup(name) {
+            Some(var) => self.store(var),
+            None => self.error(span, format!("{} isn't defined", name)),
+        }
+    }
+
+    // Binds a name in the innermost scope, in a cell if a nested function refers to it
+    fn declare(&mut self, name: &str) -> Var {
+        let var = if self.frame().scope.is_captured(name) {
+            let alloc = self.runtime(Runtime::Alloc);
+            let local = self.frame().add_local(name, ValType::I32);
+            self.emit_all(vec![I32Const(8), Call(alloc), LocalSet(local)]);
+            Var::Cell(local)
+        } else {
+            Var::Local(self.frame().add_local(name, ValType::I64))
+        };
+        self.frame().scope.bind(name, var);
+        var
+    }
+
+    // Compiles an element of a sequence, in which a function declaration binds its name
+    fn element(&mut self, expr: &ASTNode, tail: bool) {
+        match (expr.function_name(), expr.declaration()) {
+            (Some((name, span)), ASTNode::Function { args, body, .. }) => {
+                // The only top-level declaration of a name may have had its function reserved
+                let reserved = if self.frames.len() == 1 {
+                    self.reserved.remove(name)
+                } else {
+                    None
+                };
+                self.closure(Some(name), args, body, reserved);
+                self.assign(name, span);
+            }
+            _ => self.expression(expr, tail),
+        }
+    }
+
+    // Leaves the value of an expression on the stack. An expression in tail position may
+    // jump back to the start of the function instead, if it's a call to the function.
+    fn expression(&mut self, node: &ASTNode, tail: bool) {
+        match *node {
+            ASTNode::Integer(val, _) => self.number(f64::from(val)),
+            ASTNode::Float(val, _) => self.number(val.to_string().parse().unwrap_or(f64::NAN)),
+            ASTNode::StringLiteral(ref val, _) => {
+                let address = self.string(val);
+                self.emit(I64Const(boxed(Tag::String, address)));
+            }
+            ASTNode::Boolean(val, _) => self.emit(I64Const(boxed(Tag::Bool, val as u32))),
+            ASTNode::Name(ref name, span) => match self.lookup(name) {
+                Some(var) => self.load(var),
+                None => {
+                    let msg = if self.sources[self.current].imports.contains_key(name) {
+                        format!("The module {0} isn't a value, so only its exports like {0}.name can be used", name)
+                    } else {
+                        format!("{} isn't defined", name)
+                    };
+                    self.error(span, msg);
+                    self.emit(I64Const(FALSE));
+                }
+            },
+            ASTNode::Qualified {
+                ref module,
+                ref name,
+                span,
+            } => match self.lookup_qualified(module, name) {
+                Some(var) => self.load(var),
+                None => {
+                    self.error(span, format!("{}.{} isn't defined", module, name));
+                    self.emit(I64Const(FALSE));
+                }
+            },
+            ASTNode::Function {
+                ref args, ref body, ..
+            } => self.closure(node.function_name().map(|(name, _)| name), args, body, None),
+            ASTNode::Invocation {
+                ref func, ref args, ..
+            } => self.invocation(func, args, tail),
+            ASTNode::Conditional {
+                ref cond,
+                ref if_body,
+                ref else_body,
+                ..
+            } => {
+                self.condition(cond);
+                self.emit(If(Some(ValType::I64)));
+                self.expression(if_body, tail);
+                self.emit(Else);
+                match **else_body {
+                    Some(ref else_body) => self.expression(else_body, tail),
+                    None => self.emit(I64Const(FALSE)),
+                }
+                self.emit(End);
+            }
+            ASTNode::Binary {
+                ref op,
+                ref lhs,
+                ref rhs,
+                span,
+            } => self.binary(op, lhs, rhs, span),
+            ASTNode::Sequence(ref exprs) => {
+                if exprs.is_empty() {
+                    self.emit(I64Const(FALSE));
+                }
+                for (i, expr) in exprs.iter().enumerate() {
+                    if i > 0 {
+                        self.emit(Drop);
+                    }
+                    self.element(expr, tail && i + 1 == exprs.len());
+                }
+            }
+            ASTNode::List(ref elements, _) => self.list(elements),
+            ASTNode::Let {
+                ref name,
+                ref value,
+                span,
+            } => {
+                self.expression(value, false);
+                match name.binding() {
+                    Some((name, _)) => self.assign(name, span),
+                    None => self.error(span, String::from("Only a name can be bound by let")),
+                }
+            }
+            ASTNode::Annotated { ref expr, .. } => self.expression(expr, tail),
+            ASTNode::TypeDeclaration { ref variants, .. } => {
+                for variant in variants.iter() {
+                    self.variant(variant);
+                    self.emit(Drop);
+                }
+                self.emit(I64Const(FALSE));
+            }
+            ASTNode::Match {
+                ref subject,
+                ref arms,
+                ..
+            } => self.match_arms(subject, arms, tail),
+            ASTNode::Export { ref decl, .. } => self.element(decl, tail),
+            ASTNode::Extern {
+                ref module,
+                ref functions,
+                ..
+            } => {
+                for function in functions.iter() {
+                    self.extern_binding(module, function);
+                    self.emit(Drop);
+                }
+                self.emit(I64Const(FALSE));
+            }
+            // The modules a module imports are compiled before it
+            ASTNode::Import { .. } => self.emit(I64Const(FALSE)),
+        }
+    }
+
+    fn number(&mut self, val: f64) {
+        self.emit_all(vec![F64Const(val), Numeric(I64ReinterpretF64)]);
+    }
+
+    // Only false is falsey
+    fn condition(&mut self, cond: &ASTNode) {
+        self.expression(cond, false);
+        self.emit_all(vec![I64Const(FALSE), Numeric(I64Ne)]);
+    }
+
+    fn boolean(&mut self) {
+        self.emit_all(vec![
+            Numeric(I64ExtendI32U),
+            I64Const(FALSE),
+            Numeric(I64Or),
+        ]);
+    }
+
+    fn binary(&mut self, op: &Token, lhs: &ASTNode, rhs: &ASTNode, span: Span) {
+        let op = match *op {
+            Token::Operator(ref op) => op.as_str(),
+            _ => {
+                self.error(span, String::from("Malformed binary node"));
+                self.emit(I64Const(FALSE));
+                return;
+            }
+        };
+
+        match op {
+            "=" => {
+                self.expression(rhs, false);
+                match *lhs {
+                    ASTNode::Name(ref name, span) => self.assign(name, span),
+                    _ => self.error(span, String::from("Only a name can be assigned to")),
+                }
+            }
+            "&&" => {
+                self.condition(lhs);
+                self.emit(If(Some(ValType::I64)));
+                self.expression(rhs, false);
+                self.emit_all(vec![Else, I64Const(FALSE), End]);
+            }
+            "||" => {
+                let value = self.temp(ValType::I64);
+                self.expression(lhs, false);
+                self.emit_all(vec![
+                    LocalTee(value),
+                    I64Const(FALSE),
+                    Numeric(I64Ne),
+                    If(Some(ValType::I64)),
+                    LocalGet(value),
+                    Else,
+                ]);
+                self.expression(rhs, false);
+                self.emit(End);
+                self.release(value);
+            }
+            // The other arithmetic operators only apply to numbers, so they're done inline
+            "-" | "*" | "/" | "%" => {
+                self.expression(lhs, false);
+                self.emit(Numeric(F64ReinterpretI64));
+                self.expression(rhs, false);
+                self.emit(Numeric(F64ReinterpretI64));
+                let instr = match op {
+                    "-" => Numeric(F64Sub),
+                    "*" => Numeric(F64Mul),
+                    "/" => Numeric(F64Div),
+                    _ => Call(self.runtime(Runtime::Rem)),
+                };
+                self.emit_all(vec![instr, Numeric(I64ReinterpretF64)]);
+            }
+            _ => {
+                let runtime = match op {
+                    "+" => Runtime::Add,
+                    "==" | "!=" => Runtime::Equals,
+                    "<" | "<=" | ">" | ">=" => Runtime::Compare,
+                    _ => {
+                        self.error(span, format!("{} isn't an operator on values", op));
+                        self.emit(I64Const(FALSE));
+                        return;
+                    }
+                };
+                self.expression(lhs, false);
+                self.expression(rhs, false);
+                let function = self.runtime(runtime);
+                self.emit(Call(function));
+
+                // Comparisons give -1, 0 or 1, or 2 if the operands are unordered
+                let test = match op {
+                    "+" => return,
+                    "==" => vec![],
+                    "!=" => vec![Numeric(I32Eqz)],
+                    "<" => vec![I32Const(-1), Numeric(I32Eq)],
+                    "<=" => vec![I32Const(0), Numeric(I32LeS)],
+                    ">" => vec![I32Const(1), Numeric(I32Eq)],
+                    _ => vec![I32Const(2), Numeric(I32LtU)],
+                };
+                self.emit_all(test);
+                self.boolean();
+            }
+        }
+    }
+
+    fn list(&mut self, elements: &[ASTNode]) {
+        let alloc = self.runtime(Runtime::Alloc);
+        let list = self.temp(ValType::I32);
+        self.emit_all(vec![
+            I32Const(8 + 8 * elements.len() as i32),
+            Call(alloc),
+            LocalTee(list),
+            I32Const(elements.len() as i32),
+            Store(Access::I32, 0),
+        ]);
+        for (i, element) in elements.iter().enumerate() {
+            self.emit(LocalGet(list));
+            self.expression(element, false);
+            self.emit(Store(Access::I64, 8 + 8 * i as u32));
+        }
+        self.emit(LocalGet(list));
+        self.release(list);
+        self.pointer(Tag::List);
+    }
+
+    // Boxes the address on the stack
+    fn pointer(&mut self, tag: Tag) {
+        self.emit_all(vec![
+            Numeric(I64ExtendI32U),
+            I64Const(boxed(tag, 0)),
+            Numeric(I64Or),
+        ]);
+    }
+
+    fn invocation(&mut self, func: &ASTNode, args: &[ASTNode], tail: bool) {
+        let callee = match *func {
+            ASTNode::Name(ref name, _) => self.lookup(name),
+            ASTNode::Qualified {
+                ref module,
+                ref name,
+                ..
+            } => self.lookup_qualified(module, name),
+            _ => None,
+        };
+
+        match callee {
+            Some(Var::Itself) if args.len() == self.frame().arity => {
+                if let (true, Some(depth)) = (tail, self.frame().tail) {
+                    return self.jump(args, depth);
+                }
+                let function = self.frame().function;
+                self.emit(LocalGet(0));
+                self.arguments(args);
+                self.emit(Call(function));
+            }
+            Some(Var::Global(GlobalName {
+                index,
+                direct: Some(Direct::Function(function, arity)),
+            })) if arity == args.len() => {
+                self.emit_all(vec![GlobalGet(index), Numeric(I32WrapI64)]);
+                self.arguments(args);
+                self.emit(Call(function));
+            }
+            Some(Var::Global(GlobalName {
+                direct: Some(Direct::Extern(function, arity)),
+                ..
+            })) if arity == args.len() => {
+                self.arguments(args);
+                self.emit(Call(function));
+            }
+            _ => {
+                let closure = self.runtime(Runtime::Closure);
+                let ty = self.silver_type(args.len());
+                let pointer = self.temp(ValType::I32);
+                self.expression(func, false);
+                self.emit_all(vec![
+                    I32Const(args.len() as i32),
+                    Call(closure),
+                    LocalTee(pointer),
+                ]);
+                self.arguments(args);
+                self.emit_all(vec![
+                    LocalGet(pointer),
+                    Load(Access::I32, 0),
+                    CallIndirect(ty),
+                ]);
+                self.release(pointer);
+            }
+        }
+    }
+
+    fn arguments(&mut self, args: &[ASTNode]) {
+        for arg in args.iter() {
+            self.expression(arg, false);
+        }
+    }
+
+    // A call to the function itself in tail position rebinds its parameters and starts its
+    // body over. The arguments are all evaluated first, since they may refer to each other.
+    fn jump(&mut self, args: &[ASTNode], depth: u32) {
+        let temps: Vec<u32> = args.iter().map(|_| self.temp(ValType::I64)).collect();
+        for (arg, &temp) in args.iter().zip(temps.iter()) {
+            self.expression(arg, false);
+            self.emit(LocalSet(temp));
+        }
+        for (i, &temp) in temps.iter().enumerate() {
+            self.emit_all(vec![LocalGet(temp), LocalSet(i as u32 + 1)]);
+            self.release(temp);
+        }
+        let br = self.frame().depth - depth;
+        self.emit(Br(br));
+    }
+
+    // Compiles a function and leaves its closure on the stack
+    fn closure(
+        &mut self,
+        name: Option<&str>,
+        args: &[ASTNode],
+        body: &ASTNode,
+        reserved: Option<u32>,
+    ) {
+        let arity = args.len();
+        let function = match reserved {
+            Some(function) => function,
+            None => {
+                let prefix = self.sources[self.current].prefix.clone();
+                let name = format!("{}{}", prefix, name.unwrap_or("anonymous"));
+                self.add_silver_function(&name, arity)
+            }
+        };
+
+        let params: Vec<String> = args
+            .iter()
+            .map(|arg| String::from(arg.binding().map_or("_", |(arg, _)| arg)))
+            .collect();
+        let source = &self.sources[self.current];
+        let locals = backend::function_locals(&self.frames, source, name, &params, body);
+
+        let mut frame_params = vec![(String::from("env"), ValType::I32)];
+        frame_params.extend(params.iter().map(|param| (param.clone(), ValType::I64)));
+        let mut frame = Frame::new(function, frame_params, arity);
+        backend::collect_captured(body, &mut frame.scope.captured);
+        if let Some(name) = name {
+            frame.scope.bind(name, Var::Itself);
+        }
+        frame.scope.enter();
+        self.frames.push(frame);
+
+        let loops = name.is_some_and(|name| {
+            !params.iter().any(|param| param == name)
+                && !locals.contains(&String::from(name))
+                && backend::calls_itself(name, arity, body)
+        });
+        if loops {
+            self.emit(Loop(Some(ValType::I64)));
+            let depth = self.frame().depth;
+            self.frame().tail = Some(depth);
+        }
+
+        // Parameters that nested functions capture are copied to cells as the body starts
+        for (i, param) in params.iter().enumerate() {
+            let var = self.declare(param);
+            if let Var::Cell(local) = var {
+                self.emit_all(vec![
+                    LocalGet(local),
+                    LocalGet(i as u32 + 1),
+                    Store(Access::I64, 0),
+                ]);
+            } else {
+                let frame = self.frame();
+                frame.types.pop();
+                frame.names.pop();
+                let scopes = &mut frame.scope.scopes;
+                let scope = scopes.last_mut().expect("A frame always has a scope");
+                scope.pop();
+                scope.push((param.clone(), Var::Local(i as u32 + 1)));
+            }
+        }
+        for local in locals.iter() {
+            self.declare(local);
+        }
+
+        self.expression(body, true);
+        if loops {
+            self.emit(End);
+        }
+
+        let frame = self.frames.pop().expect("The frame was pushed above");
+        let captures = frame.scope.captures.clone();
+        self.define_frame(frame);
+
+        if captures.is_empty() {
+            let closure = self.static_closure(function, arity);
+            self.emit(I64Const(closure));
+            return;
+        }
+
+        self.module.table.push(function);
+        let slot = (self.module.table.len() - 1) as i32;
+        let alloc = self.runtime(Runtime::Alloc);
+        let closure = self.temp(ValType::I32);
+        self.emit_all(vec![
+            I32Const(8 + 4 * captures.len() as i32),
+            Call(alloc),
+            LocalTee(closure),
+            I32Const(slot),
+            Store(Access::I32, 0),
+            LocalGet(closure),
+            I32Const(arity as i32),
+            Store(Access::I32, 4),
+        ]);
+        for (slot, name) in captures.iter().enumerate() {
+            self.emit(LocalGet(closure));
+            let var = self
+                .lookup(name)
+                .expect("A captured name is bound in an enclosing function");
+            self.cell(var);
+            self.emit(Store(Access::I32, 8 + 4 * slot as u32));
+        }
+        self.emit(LocalGet(closure));
+        self.release(closure);
+        self.pointer(Tag::Closure);
+    }
+
+    // Binds a constructor, leaving it on the stack. A constructor without fields is a
+    // single value, and one with fields a function.
+    fn variant(&mut self, variant: &Variant) {
+        let tag = self.constructor_tag(&variant.name);
+        let arity = variant.fields.len();
+        if arity == 0 {
+            let mut bytes = tag.to_le_bytes().to_vec();
+            bytes.extend_from_slice(&0u32.to_le_bytes());
+            let address = self.static_data(&bytes);
+            self.emit(I64Const(boxed(Tag::Data, address)));
+            return self.assign(&variant.name, variant.span);
+        }
+
+        let function = if self.frames.len() == 1 {
+            self.reserved.remove(&variant.name)
+        } else {
+            None
+        };
+        let function = match function {
+            Some(function) => function,
+            None => {
+                let prefix = self.sources[self.current].prefix.clone();
+                self.add_silver_function(&format!("{}{}", prefix, variant.name), arity)
+            }
+        };
+
+        let alloc = self.runtime(Runtime::Alloc);
+        let mut names = vec![String::from("env")];
+        names.extend(variant.fields.iter().enumerate().map(|(i, field)| {
+            field
+                .binding()
+                .map_or_else(|| format!("field{}", i), |(field, _)| String::from(field))
+        }));
+        let data = (arity + 1) as u32;
+        names.push(String::from("data"));
+        let mut body = vec![
+            I32Const(8 + 8 * arity as i32),
+            Call(alloc),
+            LocalTee(data),
+            I32Const(tag as i32),
+            Store(Access::I32, 0),
+            LocalGet(data),
+            I32Const(arity as i32),
+            Store(Access::I32, 4),
+        ];
+        for i in 0..arity as u32 {
+            body.extend(vec![
+                LocalGet(data),
+                LocalGet(i + 1),
+                Store(Access::I64, 8 + 8 * i),
+            ]);
+        }
+        body.extend(vec![
+            LocalGet(data),
+            Numeric(I64ExtendI32U),
+            I64Const(boxed(Tag::Data, 0)),
+            Numeric(I64Or),
+        ]);
+        self.define(function, names, body);
+        self.function_mut(function).locals = vec![ValType::I32];
+
+        let closure = self.static_closure(function, arity);
+        self.emit(I64Const(closure));
+        self.assign(&variant.name, variant.span);
+    }
+
+    // Binds an extern's name to a function that calls the host or native with its arguments
+    fn extern_binding(&mut self, module: &Option<String>, function: &ExternFunction) {
+        let target = self.extern_function(module, function);
+        let arity = function.args.len();
+        let prefix = self.sources[self.current].prefix.clone();
+        let wrapper = self.add_silver_function(&format!("{}{}", prefix, function.name), arity);
+
+        let mut names = vec![String::from("env")];
+        names.extend((0..arity).map(|i| format!("arg{}", i)));
+        let mut body: Vec<Instr> = (1..=arity as u32).map(LocalGet).collect();
+        body.push(Call(target));
+        self.define(wrapper, names, body);
+
+        let closure = self.static_closure(wrapper, arity);
+        self.emit(I64Const(closure));
+        self.assign(&function.name, function.span);
+    }
+
+    // Tests the subject's tag against each arm in turn, in nested ifs
+    fn match_arms(&mut self, subject: &ASTNode, arms: &[MatchArm], tail: bool) {
+        let tag_of = self.runtime(Runtime::TagOf);
+        let value = self.temp(ValType::I64);
+        let tag = self.temp(ValType::I32);
+        self.expression(subject, false);
+        self.emit_all(vec![LocalTee(value), Call(tag_of), LocalSet(tag)]);
+
+        let mut open = 0;
+        let mut exhaustive = false;
+        for arm in arms.iter() {
+            match arm.pattern {
+                Pattern::Wildcard(_) => {
+                    self.expression(&arm.body, tail);
+                    exhaustive = true;
+                    break;
+                }
+                Pattern::Constructor {
+                    ref name,
+                    ref bindings,
+                    ..
+                } => {
+                    let constructor = self.constructor_tag(name);
+                    self.emit_all(vec![
+                        LocalGet(tag),
+                        I32Const(constructor as i32),
+                        Numeric(I32Eq),
+                        If(Some(ValType::I64)),
+                    ]);
+                    self.frame().scope.enter();
+                    for (i, binding) in bindings.iter().enumerate() {
+                        if let Some((binding, _)) = binding.binding() {
+                            if binding != "_" {
+                                let var = self.declare(binding);
+                                self.emit_all(vec![
+                                    LocalGet(value),
+                                    Numeric(I32WrapI64),
+                                    Load(Access::I64, 8 + 8 * i as u32),
+                                ]);
+                                match var {
+                                    Var::Local(local) => self.emit(LocalSet(local)),
+                                    _ => {
+                                        self.store(var);
+                                        self.emit(Drop);
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    self.expression(&arm.body, tail);
+                    self.frame().scope.leave();
+                    self.emit(Else);
+                    open += 1;
+                }
+            }
+        }
+
+        if !exhaustive {
+            self.emit_all(vec![
+                I32Const(NO_MATCH),
+                LocalGet(tag),
+                Call(0),
+                Unreachable,
+            ]);
+        }
+        for _ in 0..open {
+            self.emit(End);
+        }
+        self.release(value);
+        self.release(tag);
+    }
+
+    // The index of a runtime function, which is compiled the first time it's needed
+    fn runtime(&mut self, which: Runtime) -> u32 {
+        if let Some(&index) = self.runtime.get(&which) {
+            return index;
+        }
+
+        let (params, result) = match which {
+            Runtime::Alloc => (vec!["size"], Some(ValType::I32)),
+            Runtime::Closure => (vec!["value", "arity"], Some(ValType::I32)),
+            Runtime::TagOf => (vec!["value"], Some(ValType::I32)),
+            Runtime::JoinStrings | Runtime::CompareStrings => (vec!["a", "b"], None),
+            Runtime::Add | Runtime::Equals | Runtime::Compare | Runtime::Rem => {
+                (vec!["a", "b"], None)
+            }
+            Runtime::Length => (vec!["xs"], None),
+            Runtime::Get => (vec!["xs", "i"], None),
+            Runtime::Append => (vec!["xs", "x"], None),
+            Runtime::Concat => (vec!["xs", "ys"], None),
+            Runtime::Range => (vec!["from", "to"], None),
+            Runtime::Fold => (vec!["xs", "init", "f"], None),
+            Runtime::Map => (vec!["xs", "f"], None),
+            Runtime::Filter => (vec!["xs", "keep"], None),
+        };
+        let param_types = match which {
+            Runtime::Alloc => vec![ValType::I32],
+            Runtime::Closure => vec![ValType::I64, ValType::I32],
+            Runtime::JoinStrings | Runtime::CompareStrings => vec![ValType::I32; 2],
+            Runtime::Rem => vec![ValType::F64; 2],
+            _ => vec![ValType::I64; params.len()],
+        };
+        let result = match which {
+            Runtime::JoinStrings => Some(ValType::I64),
+            Runtime::CompareStrings | Runtime::Equals | Runtime::Compare => Some(ValType::I32),
+            Runtime::Rem => Some(ValType::F64),
+            _ => result.or(Some(ValType::I64)),
+        };
+
+        let index = self.add_function(which.name(), param_types.clone(), result);
+        self.runtime.insert(which, index);
+
+        let mut frame = Frame::new(
+            index,
+            params
+                .iter()
+                .zip(param_types)
+                .map(|(name, ty)| (String::from(*name), ty))
+                .collect(),
+            params.len(),
+        );
+        self.runtime_body(which, &mut frame);
+        self.define_frame(frame);
+        index
+    }
+
+    fn runtime_body(&mut self, which: Runtime, frame: &mut Frame) {
+        let body = match which {
+            // Memory is allocated from a heap that only grows, 8 bytes at a time
+            Runtime::Alloc => {
+                let address = frame.add_local("address", ValType::I32);
+                vec![
+                    GlobalGet(0),
+                    LocalTee(address),
+                    LocalGet(0),
+                    I32Const(7),
+                    Numeric(I32Add),
+                    I32Const(-8),
+                    Numeric(I32And),
+                    Numeric(I32Add),
+                    GlobalSet(0),
+                    Block(None),
+                    GlobalGet(0),
+                    MemorySize,
+                    I32Const(16),
+                    Numeric(I32Shl),
+                    Numeric(I32LeU),
+                    BrIf(0),
+                    GlobalGet(0),
+                    MemorySize,
+                    I32Const(16),
+                    Numeric(I32Shl),
+                    Numeric(I32Sub),
+                    I32Const(PAGE_SIZE as i32 - 1),
+                    Numeric(I32Add),
+                    I32Const(16),
+                    Numeric(I32ShrU),
+                    MemoryGrow,
+                    I32Const(-1),
+                    Numeric(I32Ne),
+                    BrIf(0),
+                    I32Const(OUT_OF_MEMORY),
+                    I32Const(0),
+                    Call(0),
+                    Unreachable,
+                    End,
+                    LocalGet(address),
+                ]
+            }
+            // The address of a closure, which must take the given number of arguments
+            Runtime::Closure => {
+                let closure = frame.add_local("closure", ValType::I32);
+                let mut body = has_tag(0, Tag::Closure);
+                body.extend(vec![
+                    Numeric(I32Eqz),
+                    If(None),
+                    I32Const(NOT_A_FUNCTION),
+                    I32Const(0),
+                    Call(0),
+                    Unreachable,
+                    End,
+                    LocalGet(0),
+                    Numeric(I32WrapI64),
+                    LocalTee(closure),
+                    Load(Access::I32, 4),
+                    LocalGet(1),
+                    Numeric(I32Ne),
+                    If(None),
+                    I32Const(WRONG_ARITY),
+                    LocalGet(closure),
+                    Load(Access::I32, 4),
+                    Call(0),
+                    Unreachable,
+                    End,
+                    LocalGet(closure),
+                ]);
+                body
+            }
+            // The tag of a data value's constructor, or -1 for any other value
+            Runtime::TagOf => {
+                let mut body = has_tag(0, Tag::Data);
+                body.extend(vec![
+                    If(Some(ValType::I32)),
+                    LocalGet(0),
+                    Numeric(I32WrapI64),
+                    Load(Access::I32, 0),
+                    Else,
+                    I32Const(-1),
+                    End,
+                ]);
+                body
+            }
+            Runtime::Add => {
+                let join = self.runtime(Runtime::JoinStrings);
+                let mut body = is_number(0);
+                body.extend(is_number(1));
+                body.extend(vec![Numeric(I32And), If(None)]);
+                body.extend(vec![
+                    LocalGet(0),
+                    Numeric(F64ReinterpretI64),
+                    LocalGet(1),
+                    Numeric(F64ReinterpretI64),
+                    Numeric(F64Add),
+                    Numeric(I64ReinterpretF64),
+                    Return,
+                    End,
+                ]);
+                body.extend(has_tag(0, Tag::String));
+                body.extend(has_tag(1, Tag::String));
+                body.extend(vec![
+                    Numeric(I32And),
+                    If(None),
+                    LocalGet(0),
+                    Numeric(I32WrapI64),
+                    LocalGet(1),
+                    Numeric(I32WrapI64),
+                    Call(join),
+                    Return,
+                    End,
+                    I32Const(BAD_OPERANDS),
+                    I32Const(0),
+                    Call(0),
+                    Unreachable,
+                ]);
+                body
+            }
+            Runtime::JoinStrings => {
+                let alloc = self.runtime(Runtime::Alloc);
+                let joined = frame.add_local("joined", ValType::I32);
+                vec![
+                    LocalGet(0),
+                    Load(Access::I32, 0),
+                    LocalGet(1),
+                    Load(Access::I32, 0),
+                    Numeric(I32Add),
+                    I32Const(1),
+                    Numeric(I32Shl),
+                    I32Const(4),
+                    Numeric(I32Add),
+                    Call(alloc),
+                    LocalTee(joined),
+                    LocalGet(0),
+                    Load(Access::I32, 0),
+                    LocalGet(1),
+                    Load(Access::I32, 0),
+                    Numeric(I32Add),
+                    Store(Access::I32, 0),
+                    // The code units of a, then those of b
+                    LocalGet(joined),
+                    I32Const(4),
+                    Numeric(I32Add),
+                    LocalGet(0),
+                    I32Const(4),
+                    Numeric(I32Add),
+                    LocalGet(0),
+                    Load(Access::I32, 0),
+                    I32Const(1),
+                    Numeric(I32Shl),
+                    MemoryCopy,
+                    LocalGet(joined),
+                    I32Const(4),
+                    Numeric(I32Add),
+                    LocalGet(0),
+                    Load(Access::I32, 0),
+                    I32Const(1),
+                    Numeric(I32Shl),
+                    Numeric(I32Add),
+                    LocalGet(1),
+                    I32Const(4),
+                    Numeric(I32Add),
+                    LocalGet(1),
+                    Load(Access::I32, 0),
+                    I32Const(1),
+                    Numeric(I32Shl),
+                    MemoryCopy,
+                    LocalGet(joined),
+                    Numeric(I64ExtendI32U),
+                    I64Const(boxed(Tag::String, 0)),
+                    Numeric(I64Or),
+                ]
+            }
+            // Strings are compared by their UTF-16 code units, as in JS, giving -1, 0 or 1
+            Runtime::CompareStrings => {
+                let i = frame.add_local("i", ValType::I32);
+                let length = frame.add_local("length", ValType::I32);
+                let a = frame.add_local("unit-a", ValType::I32);
+                let b = frame.add_local("unit-b", ValType::I32);
+                // The length of the shorter string
+                let mut body = vec![
+                    LocalGet(0),
+                    Load(Access::I32, 0),
+                    LocalSet(length),
+                    LocalGet(1),
+                    Load(Access::I32, 0),
+                    LocalGet(length),
+                    Numeric(I32LtU),
+                    If(None),
+                    LocalGet(1),
+                    Load(Access::I32, 0),
+                    LocalSet(length),
+                    End,
+                    I32Const(0),
+                    LocalSet(i),
+                ];
+                let compare = vec![
+                    LocalGet(0),
+                    LocalGet(i),
+                    I32Const(1),
+                    Numeric(I32Shl),
+                    Numeric(I32Add),
+                    Load(Access::U16, 4),
+                    LocalSet(a),
+                    LocalGet(1),
+                    LocalGet(i),
+                    I32Const(1),
+                    Numeric(I32Shl),
+                    Numeric(I32Add),
+                    Load(Access::U16, 4),
+                    LocalSet(b),
+                    LocalGet(a),
+                    LocalGet(b),
+                    Numeric(I32Ne),
+                    If(None),
+                    LocalGet(a),
+                    LocalGet(b),
+                    Numeric(I32LtU),
+                    If(Some(ValType::I32)),
+                    I32Const(-1),
+                    Else,
+                    I32Const(1),
+                    End,
+                    Return,
+                    End,
+                ];
+                body.extend(counted_loop(i, length, compare));
+                // A string that the other starts with comes first
+                body.extend(vec![
+                    LocalGet(0),
+                    Load(Access::I32, 0),
+                    LocalGet(1),
+                    Load(Access::I32, 0),
+                    Numeric(I32Sub),
+                    LocalTee(a),
+                    I32Const(0),
+                    Numeric(I32LtS),
+                    If(Some(ValType::I32)),
+                    I32Const(-1),
+                    Else,
+                    LocalGet(a),
+                    I32Const(0),
+                    Numeric(I32GtS),
+                    End,
+                ]);
+                body
+            }
+            // Numbers are equal as floats and strings by their contents. Anything else is
+            // equal only to itself, as JS compares objects.
+            Runtime::Equals => {
+                let compare = self.runtime(Runtime::CompareStrings);
+                let mut body = is_number(0);
+                body.extend(is_number(1));
+                body.extend(vec![
+                    Numeric(I32And),
+                    If(None),
+                    LocalGet(0),
+                    Numeric(F64ReinterpretI64),
+                    LocalGet(1),
+                    Numeric(F64ReinterpretI64),
+                    Numeric(F64Eq),
+                    Return,
+                    End,
+                ]);
+                body.extend(has_tag(0, Tag::String));
+                body.extend(has_tag(1, Tag::String));
+                body.extend(vec![
+                    Numeric(I32And),
+                    If(None),
+                    LocalGet(0),
+                    Numeric(I32WrapI64),
+                    LocalGet(1),
+                    Numeric(I32WrapI64),
+                    Call(compare),
+                    Numeric(I32Eqz),
+                    Return,
+                    End,
+                    LocalGet(0),
+                    LocalGet(1),
+                    Numeric(I64Eq),
+                ]);
+                body
+            }
+            // -1, 0 or 1 for numbers or strings in order, and 2 for anything else
+            Runtime::Compare => {
+                let compare = self.runtime(Runtime::CompareStrings);
+                let a = frame.add_local("float-a", ValType::F64);
+                let b = frame.add_local("float-b", ValType::F64);
+                let mut body = is_number(0);
+                body.extend(is_number(1));
+                body.extend(vec![
+                    Numeric(I32And),
+                    If(None),
+                    LocalGet(0),
+                    Numeric(F64ReinterpretI64),
+                    LocalSet(a),
+                    LocalGet(1),
+                    Numeric(F64ReinterpretI64),
+                    LocalSet(b),
+                    I32Const(-1),
+                    LocalGet(a),
+                    LocalGet(b),
+                    Numeric(F64Lt),
+                    BrIf(1),
+                    Drop,
+                    I32Const(1),
+                    LocalGet(a),
+                    LocalGet(b),
+                    Numeric(F64Gt),
+                    BrIf(1),
+                    Drop,
+                    I32Const(0),
+                    LocalGet(a),
+                    LocalGet(b),
+                    Numeric(F64Eq),
+                    BrIf(1),
+                    Drop,
+                    I32Const(2),
+                    Return,
+                    End,
+                ]);
+                body.extend(has_tag(0, Tag::String));
+                body.extend(has_tag(1, Tag::String));
+                body.extend(vec![
+                    Numeric(I32And),
+                    If(None),
+                    LocalGet(0),
+                    Numeric(I32WrapI64),
+                    LocalGet(1),
+                    Numeric(I32WrapI64),
+                    Call(compare),
+                    Return,
+                    End,
+                    I32Const(2),
+                ]);
+                body
+            }
+            // The remainder has the sign of the dividend, as JS's % does. It's exact for
+            // 32-bit integers.
+            Runtime::Rem => vec![
+                LocalGet(0),
+                LocalGet(1),
+                LocalGet(0),
+                LocalGet(1),
+                Numeric(F64Div),
+                Numeric(F64Trunc),
+                Numeric(F64Mul),
+                Numeric(F64Sub),
+            ],
+            Runtime::Length => vec![
+                LocalGet(0),
+                Numeric(I32WrapI64),
+                Load(Access::I32, 0),
+                Numeric(F64ConvertI32U),
+                Numeric(I64ReinterpretF64),
+            ],
+            Runtime::Get => {
+                let index = frame.add_local("index", ValType::F64);
+                let list = frame.add_local("list", ValType::I32);
+                vec![
+                    LocalGet(0),
+                    Numeric(I32WrapI64),
+                    LocalSet(list),
+                    LocalGet(1),
+                    Numeric(F64ReinterpretI64),
+                    LocalTee(index),
+                    F64Const(0.0),
+                    Numeric(F64Ge),
+                    LocalGet(index),
+                    LocalGet(list),
+                    Load(Access::I32, 0),
+                    Numeric(F64ConvertI32U),
+                    Numeric(F64Lt),
+                    Numeric(I32And),
+                    Numeric(I32Eqz),
+                    If(None),
+                    I32Const(OUT_OF_BOUNDS),
+                    LocalGet(list),
+                    Load(Access::I32, 0),
+                    Call(0),
+                    Unreachable,
+                    End,
+                    LocalGet(list),
+                    LocalGet(index),
+                    Numeric(I32TruncF64U),
+                    I32Const(3),
+                    Numeric(I32Shl),
+                    Numeric(I32Add),
+                    Load(Access::I64, 8),
+                ]
+            }
+            Runtime::Append | Runtime::Concat => {
+                let alloc = self.runtime(Runtime::Alloc);
+                let list = frame.add_local("list", ValType::I32);
+                let a = frame.add_local("length-a", ValType::I32);
+                let b = frame.add_local("length-b", ValType::I32);
+                let mut body = vec![
+                    LocalGet(0),
+                    Numeric(I32WrapI64),
+                    Load(Access::I32, 0),
+                    LocalSet(a),
+                ];
+                if which == Runtime::Append {
+                    body.extend(vec![I32Const(1), LocalSet(b)]);
+                } else {
+                    body.extend(vec![
+                        LocalGet(1),
+                        Numeric(I32WrapI64),
+                        Load(Access::I32, 0),
+                        LocalSet(b),
+                    ]);
+                }
+                body.extend(vec![
+                    LocalGet(a),
+                    LocalGet(b),
+                    Numeric(I32Add),
+                    I32Const(3),
+                    Numeric(I32Shl),
+                    I32Const(8),
+                    Numeric(I32Add),
+                    Call(alloc),
+                    LocalTee(list),
+                    LocalGet(a),
+                    LocalGet(b),
+                    Numeric(I32Add),
+                    Store(Access::I32, 0),
+                    LocalGet(list),
+                    I32Const(8),
+                    Numeric(I32Add),
+                    LocalGet(0),
+                    Numeric(I32WrapI64),
+                    I32Const(8),
+                    Numeric(I32Add),
+                    LocalGet(a),
+                    I32Const(3),
+                    Numeric(I32Shl),
+                    MemoryCopy,
+                    // The end of the copy of xs
+                    LocalGet(list),
+                    LocalGet(a),
+                    I32Const(3),
+                    Numeric(I32Shl),
+                    Numeric(I32Add),
+                ]);
+                if which == Runtime::Append {
+                    body.extend(vec![LocalGet(1), Store(Access::I64, 8)]);
+                } else {
+                    body.extend(vec![
+                        I32Const(8),
+                        Numeric(I32Add),
+                        LocalGet(1),
+                        Numeric(I32WrapI64),
+                        I32Const(8),
+                        Numeric(I32Add),
+                        LocalGet(b),
+                        I32Const(3),
+                        Numeric(I32Shl),
+                        MemoryCopy,
+                    ]);
+                }
+                body.extend(vec![
+                    LocalGet(list),
+                    Numeric(I64ExtendI32U),
+                    I64Const(boxed(Tag::List, 0)),
+                    Numeric(I64Or),
+                ]);
+                body
+            }
+            // The numbers from `from` up to but not including `to`
+            Runtime::Range => {
+                let alloc = self.runtime(Runtime::Alloc);
+                let list = frame.add_local("list", ValType::I32);
+                let length = frame.add_local("length", ValType::I32);
+                let i = frame.add_local("i", ValType::I32);
+                // No elements unless `to` is above `from`
+                let mut body = vec![
+                    I32Const(0),
+                    LocalSet(length),
+                    LocalGet(1),
+                    Numeric(F64ReinterpretI64),
+                    LocalGet(0),
+                    Numeric(F64ReinterpretI64),
+                    Numeric(F64Sub),
+                    Numeric(F64Ceil),
+                    F64Const(0.0),
+                    Numeric(F64Gt),
+                    If(None),
+                    LocalGet(1),
+                    Numeric(F64ReinterpretI64),
+                    LocalGet(0),
+                    Numeric(F64ReinterpretI64),
+                    Numeric(F64Sub),
+                    Numeric(F64Ceil),
+                    Numeric(I32TruncF64U),
+                    LocalSet(length),
+                    End,
+                    LocalGet(length),
+                    I32Const(3),
+                    Numeric(I32Shl),
+                    I32Const(8),
+                    Numeric(I32Add),
+                    Call(alloc),
+                    LocalTee(list),
+                    LocalGet(length),
+                    Store(Access::I32, 0),
+                    I32Const(0),
+                    LocalSet(i),
+                ];
+                body.extend(counted_loop(
+                    i,
+                    length,
+                    vec![
+                        LocalGet(list),
+                        LocalGet(i),
+                        I32Const(3),
+                        Numeric(I32Shl),
+                        Numeric(I32Add),
+                        LocalGet(0),
+                        Numeric(F64ReinterpretI64),
+                        LocalGet(i),
+                        Numeric(F64ConvertI32U),
+                        Numeric(F64Add),
+                        Numeric(I64ReinterpretF64),
+                        Store(Access::I64, 8),
+                    ],
+                ));
+                body.extend(vec![
+                    LocalGet(list),
+                    Numeric(I64ExtendI32U),
+                    I64Const(boxed(Tag::List, 0)),
+                    Numeric(I64Or),
+                ]);
+                body
+            }
+            Runtime::Fold | Runtime::Map | Runtime::Filter => self.higher_order(which, frame),
+        };
+        frame.body = body;
+    }
+
+    // The natives that call a closure for each element of a list
+    fn higher_order(&mut self, which: Runtime, frame: &mut Frame) -> Vec<Instr> {
+        let closure = self.runtime(Runtime::Closure);
+        let alloc = self.runtime(Runtime::Alloc);
+        let arity = if which == Runtime::Fold { 2 } else { 1 };
+        let ty = self.silver_type(arity as usize);
+        let function = if which == Runtime::Fold { 2 } else { 1 };
+
+        let list = frame.add_local("list", ValType::I32);
+        let length = frame.add_local("length", ValType::I32);
+        let i = frame.add_local("i", ValType::I32);
+        let pointer = frame.add_local("closure", ValType::I32);
+        let element = frame.add_local("element", ValType::I64);
+        let mut body = vec![
+            LocalGet(function),
+            I32Const(arity),
+            Call(closure),
+            LocalSet(pointer),
+            LocalGet(0),
+            Numeric(I32WrapI64),
+            LocalTee(list),
+            Load(Access::I32, 0),
+            LocalSet(length),
+            I32Const(0),
+            LocalSet(i),
+        ];
+        let load = vec![
+            LocalGet(list),
+            LocalGet(i),
+            I32Const(3),
+            Numeric(I32Shl),
+            Numeric(I32Add),
+            Load(Access::I64, 8),
+            LocalSet(element),
+        ];
+        let mut call = vec![LocalGet(pointer)];
+        if which == Runtime::Fold {
+            call.push(LocalGet(1));
+        }
+        call.extend(vec![
+            LocalGet(element),
+            LocalGet(pointer),
+            Load(Access::I32, 0),
+            CallIndirect(ty),
+        ]);
+
+        if which == Runtime::Fold {
+            let mut step = load;
+            step.extend(call);
+            step.push(LocalSet(1));
+            body.extend(counted_loop(i, length, step));
+            body.push(LocalGet(1));
+            return body;
+        }
+
+        // The result has room for every element, and a filter's is shortened afterwards
+        let result = frame.add_local("result", ValType::I32);
+        let kept = frame.add_local("kept", ValType::I32);
+        body.extend(vec![
+            LocalGet(length),
+            I32Const(3),
+            Numeric(I32Shl),
+            I32Const(8),
+            Numeric(I32Add),
+            Call(alloc),
+            LocalSet(result),
+            I32Const(0),
+            LocalSet(kept),
+        ]);
+        let mut step = load;
+        let store = vec![
+            LocalGet(result),
+            LocalGet(kept),
+            I32Const(3),
+            Numeric(I32Shl),
+            Numeric(I32Add),
+        ];
+        if which == Runtime::Map {
+            step.extend(store);
+            step.extend(call);
+            step.push(Store(Access::I64, 8));
+            step.extend(vec![
+                LocalGet(kept),
+                I32Const(1),
+                Numeric(I32Add),
+                LocalSet(kept),
+            ]);
+        } else {
+            step.extend(call);
+            step.extend(vec![I64Const(FALSE), Numeric(I64Ne), If(None)]);
+            step.extend(store);
+            step.extend(vec![
+                LocalGet(element),
+                Store(Access::I64, 8),
+                LocalGet(kept),
+                I32Const(1),
+                Numeric(I32Add),
+                LocalSet(kept),
+                End,
+            ]);
+        }
+        body.extend(counted_loop(i, length, step));
+        body.extend(vec![
+            LocalGet(result),
+            LocalGet(kept),
+            Store(Access::I32, 0),
+            LocalGet(result),
+            Numeric(I64ExtendI32U),
+            I64Const(boxed(Tag::List, 0)),
+            Numeric(I64Or),
+        ]);
+        body
+    }
+}
+
+// Whether the i64 in a local is a number, leaving an i32 on the stack
+fn is_number(local: u32) -> Vec<Instr> {
+    vec![
+        LocalGet(local),
+        I64Const(32),
+        Numeric(I64ShrU),
+        I64Const(BOXED >> 32),
+        Numeric(I64And),
+        I64Const(BOXED >> 32),
+        Numeric(I64Ne),
+    ]
+}
+
+// Whether the i64 in a local is boxed with the given tag, leaving an i32 on the stack
+fn has_tag(local: u32, tag: Tag) -> Vec<Instr> {
+    vec![
+        LocalGet(local),
+        I64Const(32),
+        Numeric(I64ShrU),
+        I64Const(BOXED >> 32 | 7),
+        Numeric(I64And),
+        I64Const(boxed(tag, 0) >> 32),
+        Numeric(I64Eq),
+    ]
+}
+
+// Runs the body for each value of the counter below the limit, which are both i32 locals.
+// The counter starts out however it's been set.
+fn counted_loop(counter: u32, limit: u32, body: Vec<Instr>) -> Vec<Instr> {
+    let mut instrs = vec![
+        Block(None),
+        Loop(None),
+        LocalGet(counter),
+        LocalGet(limit),
+        Numeric(I32GeU),
+        BrIf(1),
+    ];
+    instrs.extend(body);
+    instrs.extend(vec![
+        LocalGet(counter),
+        I32Const(1),
+        Numeric(I32Add),
+        LocalSet(counter),
+        Br(0),
+        End,
+        End,
+    ]);
+    instrs
+}
+
+// Names in the text format are made of idchars, and anything else is written as the hex of
+// its UTF-8 bytes after a %
+fn write_name(f: &mut fmt::Formatter, name: &str) -> fmt::Result {
+    f.write_str("$")?;
+    for ch in name.chars() {
+        if ch.is_ascii_alphanumeric() || "!#$&'*+-./:<=>?@\\^_`|~".contains(ch) {
+            write!(f, "{}", ch)?;
+        } else {
+            let mut bytes = [0; 4];
+            for byte in ch.encode_utf8(&mut bytes).bytes() {
+                write!(f, "%{:02X}", byte)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_type(f: &mut fmt::Formatter, ty: &FuncType) -> fmt::Result {
+    for param in ty.params.iter() {
+        write!(f, " (param {})", param.name())?;
+    }
+    for result in ty.results.iter() {
+        write!(f, " (result {})", result.name())?;
+    }
+    Ok(())
+}
+
+fn write_block_type(f: &mut fmt::Formatter, ty: Option<ValType>) -> fmt::Result {
+    match ty {
+        Some(ty) => write!(f, " (result {})", ty.name()),
+        None => Ok(()),
+    }
+}
+
+fn write_memory_access(f: &mut fmt::Formatter, instr: &str, offset: u32) -> fmt::Result {
+    f.write_str(instr)?;
+    if offset != 0 {
+        write!(f, " offset={}", offset)?;
+    }
+    Ok(())
+}
+
+// Boxed values are written in hex, which shows their tag
+fn write_i64(f: &mut fmt::Formatter, val: i64) -> fmt::Result {
+    if val & BOXED == BOXED {
+        write!(f, "{:#x}", val)
+    } else {
+        write!(f, "{}", val)
+    }
+}
+
+// The text format writes infinities and NaN by name
+fn write_float(f: &mut fmt::Formatter, val: f64) -> fmt::Result {
+    if val.is_nan() {
+        f.write_str("nan")
+    } else if val.is_infinite() {
+        f.write_str(if val > 0.0 { "inf" } else { "-inf" })
+    } else {
+        write!(f, "{:?}", val)
+    }
+}
+
+impl Module {
+    fn write_instr(
+        &self,
+        f: &mut fmt::Formatter,
+        function: &Function,
+        instr: &Instr,
+    ) -> fmt::Result {
+        let local =
+            |f: &mut fmt::Formatter, index: u32| write_name(f, &function.names[index as usize]);
+        match *instr {
+            Block(ty) => {
+                f.write_str("block")?;
+                write_block_type(f, ty)
+            }
+            Loop(ty) => {
+                f.write_str("loop")?;
+                write_block_type(f, ty)
+            }
+            If(ty) => {
+                f.write_str("if")?;
+                write_block_type(f, ty)
+            }
+            Else => f.write_str("else"),
+            End => f.write_str("end"),
+            Br(depth) => write!(f, "br {}", depth),
+            BrIf(depth) => write!(f, "br_if {}", depth),
+            Return => f.write_str("return"),
+            Unreachable => f.write_str("unreachable"),
+            Drop => f.write_str("drop"),
+            Call(index) => {
+                f.write_str("call ")?;
+                write_name(f, self.function_name(index))
+            }
+            CallIndirect(ty) => write!(f, "call_indirect (type {})", ty),
+            LocalGet(index) => {
+                f.write_str("local.get ")?;
+                local(f, index)
+            }
+            LocalSet(index) => {
+                f.write_str("local.set ")?;
+                local(f, index)
+            }
+            LocalTee(index) => {
+                f.write_str("local.tee ")?;
+                local(f, index)
+            }
+            GlobalGet(index) => {
+                f.write_str("global.get ")?;
+                write_name(f, &self.globals[index as usize].name)
+            }
+            GlobalSet(index) => {
+                f.write_str("global.set ")?;
+                write_name(f, &self.globals[index as usize].name)
+            }
+            Load(access, offset) => {
+                let instr = match access {
+                    Access::I32 => "i32.load",
+                    Access::I64 => "i64.load",
+                    Access::U16 => "i32.load16_u",
+                };
+                write_memory_access(f, instr, offset)
+            }
+            Store(access, offset) => {
+                let instr = match access {
+                    Access::I32 => "i32.store",
+                    Access::I64 => "i64.store",
+                    Access::U16 => "i32.store16",
+                };
+                write_memory_access(f, instr, offset)
+            }
+            MemorySize => f.write_str("memory.size"),
+            MemoryGrow => f.write_str("memory.grow"),
+            MemoryCopy => f.write_str("memory.copy"),
+            I32Const(val) => write!(f, "i32.const {}", val),
+            I64Const(val) => {
+                f.write_str("i64.const ")?;
+                write_i64(f, val)
+            }
+            F64Const(val) => {
+                f.write_str("f64.const ")?;
+                write_float(f, val)
+            }
+            Numeric(op) => f.write_str(op.encoding().0),
+        }
+    }
+
+    fn write_function(&self, f: &mut fmt::Formatter, function: &Function) -> fmt::Result {
+        let ty = &self.types[function.ty as usize];
+        f.write_str("  (func ")?;
+        write_name(f, &function.name)?;
+        write!(f, " (type {})", function.ty)?;
+        for (name, param) in function.names.iter().zip(ty.params.iter()) {
+            f.write_str(" (param ")?;
+            write_name(f, name)?;
+            write!(f, " {})", param.name())?;
+        }
+        for result in ty.results.iter() {
+            write!(f, " (result {})", result.name())?;
+        }
+        for (name, local) in function.names[ty.params.len()..]
+            .iter()
+            .zip(function.locals.iter())
+        {
+            f.write_str("\n    (local ")?;
+            write_name(f, name)?;
+            write!(f, " {})", local.name())?;
+        }
+
+        // Instructions are indented by the blocks they're in
+        let mut depth = 2;
+        for instr in function.body.iter() {
+            if let Else | End = *instr {
+                depth -= 1;
+            }
+            write!(f, "\n{:1$}", "", depth * 2)?;
+            self.write_instr(f, function, instr)?;
+            if let Block(_) | Loop(_) | If(_) | Else = *instr {
+                depth += 1;
+            }
+        }
+        f.write_str(")\n")
+    }
+}
+
+// Writes the module in the text format
+impl fmt::Display for Module {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("(module\n")?;
+        for (index, ty) in self.types.iter().enumerate() {
+            write!(f, "  (type (;{};) (func", index)?;
+            write_type(f, ty)?;
+            f.write_str("))\n")?;
+        }
+        for import in self.imports.iter() {
+            write!(f, "  (import {:?} {:?} (func ", import.module, import.field)?;
+            write_name(f, &import.name)?;
+            writeln!(f, " (type {})))", import.ty)?;
+        }
+        writeln!(f, "  (table {} funcref)", self.table.len())?;
+        writeln!(f, "  (memory {})", self.pages)?;
+        for global in self.globals.iter() {
+            f.write_str("  (global ")?;
+            write_name(f, &global.name)?;
+            write!(f, " (mut {}) (", global.ty.name())?;
+            match global.init {
+                I32Const(val) => write!(f, "i32.const {}", val)?,
+                I64Const(val) => {
+                    f.write_str("i64.const ")?;
+                    write_i64(f, val)?;
+                }
+                F64Const(val) => {
+                    f.write_str("f64.const ")?;
+                    write_float(f, val)?;
+                }
+                _ => unreachable!("Globals start out as constants"),
+            }
+            f.write_str("))\n")?;
+        }
+        for &(ref name, kind) in self.exports.iter() {
+            write!(f, "  (export {:?} ", name)?;
+            match kind {
+                ExportKind::Function(index) => {
+                    f.write_str("(func ")?;
+                    write_name(f, self.function_name(index))?;
+                    f.write_str("))\n")?;
+                }
+                ExportKind::Table => f.write_str("(table 0))\n")?,
+                ExportKind::Memory => f.write_str("(memory 0))\n")?,
+            }
+        }
+        if !self.table.is_empty() {
+            f.write_str("  (elem (i32.const 0) func")?;
+            for &index in self.table.iter() {
+                f.write_str(" ")?;
+                write_name(f, self.function_name(index))?;
+            }
+            f.write_str(")\n")?;
+        }
+        for function in self.functions.iter() {
+            self.write_function(f, function)?;
+        }
+        if !self.data.is_empty() {
+            write!(f, "  (data (i32.const {}) \"", DATA_START)?;
+            for &byte in self.data.iter() {
+                match byte {
+                    b'"' | b'\\' => write!(f, "\\{}", byte as char)?,
+                    0x20..=0x7E => write!(f, "{}", byte as char)?,
+                    _ => write!(f, "\\{:02x}", byte)?,
+                }
+            }
+            f.write_str("\")\n")?;
+        }
+        f.write_str(")\n")
+    }
+}
+
+// The JS that runs a compiled module, in Node or a browser. It converts values between JS
+// and the module's representation for the host's functions, which are the prelude's shims,
+// the JS globals and the exports of JS modules that externs name.
+pub fn loader(module: &Module, wasm_path: &str) -> String {
+    let mut constructors = String::new();
+    for (i, constructor) in module.constructors.iter().enumerate() {
+        if i > 0 {
+            constructors.push_str(", ");
+        }
+        constructors.push_str(&js_string(constructor));
+    }
+
+    let mut specs: Vec<&str> = Vec::new();
+    let mut requires = String::new();
+    let mut hosts = String::new();
+    for import in module.imports.iter().skip(1) {
+        let target = match import.module.as_str() {
+            "host" => match prelude::shim(&import.field) {
+                Some(shim) => String::from(shim),
+                None => format!("function (...args) {{ return {}(...args) }}", import.field),
+            },
+            spec => {
+                let index = match specs.iter().position(|&known| known == spec) {
+                    Some(index) => index,
+                    None => {
+                        requires.push_str(&format!(
+                            "var $extern{} = require({});\n",
+                            specs.len(),
+                            js_string(spec)
+                        ));
+                        specs.push(spec);
+                        specs.len() - 1
+                    }
+                };
+                format!(
+                    "function (...args) {{ return $extern{}[{}](...args) }}",
+                    index,
+                    js_string(&import.field)
+                )
+            }
+        };
+        hosts.push_str(&format!(
+            "imports[{}] = imports[{}] || {{}};\nimports[{}][{}] = host({});\n",
+            js_string(&import.module),
+            js_string(&import.module),
+            js_string(&import.module),
+            js_string(&import.field),
+            target
+        ));
+    }
+
+    LOADER
+        .replace("$CONSTRUCTORS", &constructors)
+        .replace("$REQUIRES", &requires)
+        .replace("$IMPORTS", &hosts)
+        .replace("$PATH", &js_string(wasm_path))
+}
+
+fn js_string(string: &str) -> String {
+    let mut quoted = String::from("\"");
+    for ch in string.chars() {
+        match ch {
+            '\\' => quoted.push_str("\\\\"),
+            '"' => quoted.push_str("\\\""),
+            _ => quoted.push(ch),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+const LOADER: &str = r#""use strict";
+(function () {
+var constructors = [$CONSTRUCTORS];
+var exports;
+var scratch = new DataView(new ArrayBuffer(8));
+$REQUIRES
+function memory() { return new DataView(exports.memory.buffer) }
+
+function boxed(tag, payload) { return (0x7FFCn << 48n) | (BigInt(tag) << 32n) | BigInt(payload >>> 0) }
+
+// Converts a value of the module to JS
+function decode(value) {
+  var bits = BigInt.asUintN(64, value);
+  var high = Number(bits >> 32n), low = Number(bits & 0xFFFFFFFFn);
+  if ((high & 0x7FFC0000) !== 0x7FFC0000) { scratch.setBigUint64(0, bits); return scratch.getFloat64(0) }
+  var view = memory(), i;
+  switch (high & 7) {
+    case 1: return low !== 0;
+    case 2:
+      var units = [];
+      for (i = 0; i < view.getUint32(low, true); i++) { units.push(view.getUint16(low + 4 + 2 * i, true)) }
+      return String.fromCharCode.apply(null, units);
+    case 3:
+      var xs = [];
+      for (i = 0; i < view.getUint32(low, true); i++) { xs.push(decode(view.getBigInt64(low + 8 + 8 * i, true))) }
+      return xs;
+    case 4:
+      var f = exports.table.get(view.getUint32(low, true));
+      return function (...args) { return decode(f(low, ...args.map(encode))) };
+    case 5:
+      var data = {$tag: constructors[view.getUint32(low, true)]};
+      for (i = 0; i < view.getUint32(low + 4, true); i++) { data["$" + i] = decode(view.getBigInt64(low + 8 + 8 * i, true)) }
+      return data;
+  }
+  throw new TypeError("Can't convert " + bits + " to JS");
+}
+
+// Converts a JS value to one of the module's. Strings, lists and data are copied into its
+// memory, and undefined and null are false.
+function encode(x) {
+  var address, i;
+  if (typeof x === "number") { scratch.setFloat64(0, x); return scratch.getBigInt64(0) }
+  if (typeof x === "boolean") { return boxed(1, x ? 1 : 0) }
+  if (x === undefined || x === null) { return boxed(1, 0) }
+  if (typeof x === "string") {
+    address = exports.alloc(4 + 2 * x.length);
+    var view = memory();
+    view.setUint32(address, x.length, true);
+    for (i = 0; i < x.length; i++) { view.setUint16(address + 4 + 2 * i, x.charCodeAt(i), true) }
+    return boxed(2, address);
+  }
+  if (Array.isArray(x)) {
+    var xs = x.map(encode);
+    address = exports.alloc(8 + 8 * xs.length);
+    memory().setUint32(address, xs.length, true);
+    for (i = 0; i < xs.length; i++) { memory().setBigInt64(address + 8 + 8 * i, xs[i], true) }
+    return boxed(3, address);
+  }
+  if (typeof x === "object" && constructors.indexOf(x.$tag) >= 0) {
+    var fields = [];
+    for (i = 0; ("$" + i) in x; i++) { fields.push(encode(x["$" + i])) }
+    address = exports.alloc(8 + 8 * fields.length);
+    memory().setUint32(address, constructors.indexOf(x.$tag), true);
+    memory().setUint32(address + 4, fields.length, true);
+    for (i = 0; i < fields.length; i++) { memory().setBigInt64(address + 8 + 8 * i, fields[i], true) }
+    return boxed(5, address);
+  }
+  throw new TypeError("Can't pass " + x + " to WebAssembly");
+}
+
+// A host function that takes and returns the module's values
+function host(f) { return function (...args) { return encode(f(...args.map(decode))) } }
+
+function fail(code, detail) {
+  switch (code) {
+    case 0: throw new TypeError("Called a value that isn't a function");
+    case 1: throw new TypeError("Called a function that takes " + detail + " arguments with a different number");
+    case 2: throw new Error("No match for " + (detail < 0 ? "a value that isn't data" : constructors[detail]));
+    case 3: throw new RangeError("Index is out of bounds for a list of length " + detail);
+    case 4: throw new TypeError("+ needs two numbers or two strings");
+    case 5: throw new RangeError("Out of memory");
+  }
+  throw new Error("Failed with code " + code);
+}
+
+var imports = {runtime: {fail: fail}};
+$IMPORTS
+function run(bytes) {
+  return WebAssembly.instantiate(bytes, imports).then(function (result) {
+    exports = result.instance.exports;
+    exports.main();
+  });
+}
+
+if (typeof process !== "undefined" && typeof require === "function") {
+  run(require("fs").readFileSync(require("path").join(__dirname, $PATH)));
+} else {
+  fetch($PATH).then(function (response) { return response.arrayBuffer() }).then(run);
+}
+})();
+"#;
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::path::Path;
+
+    struct Escaped(&'static str);
+
+    impl fmt::Display for Escaped {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write_name(f, self.0)
+        }
+    }
+
+    fn compile(inp: &str) -> Result<Module, Vec<Error>> {
+        let modules =
+            modules::load_with(Path::new("main.silver"), |_| Ok(String::from(inp))).unwrap();
+        compile_program(&modules, &prelude::module())
+    }
+
+    fn wat(inp: &str) -> String {
+        compile(inp).unwrap().to_string()
+    }
+
+    // The text of one function, up to the next
+    fn func(wat: &str, name: &str) -> String {
+        let start = wat.find(&format!("(func ${} ", name)).unwrap();
+        let end = wat[start + 1..]
+            .find("(func ")
+            .map_or(wat.len(), |end| start + 1 + end);
+        String::from(&wat[start..end])
+    }
+
+    #[test]
+    fn test_arithmetic_is_inline() {
+        let f = func(&wat("fn f(a, b) { a * b - 1 }"), "f");
+        assert!(f.contains("f64.mul"));
+        assert!(f.contains("f64.sub"));
+        assert!(!f.contains("call"));
+        assert!(!f.contains("f64.reinterpret_i64\n      i64.reinterpret_f64"));
+    }
+
+    #[test]
+    fn test_runtime_is_included_when_used() {
+        assert!(!wat("1 - 2").contains("$rt.rem"));
+        let rem = func(&wat("fn f(a, b) { a % b }"), "f");
+        assert!(rem.contains("call $rt.rem"));
+    }
+
+    #[test]
+    fn test_tail_self_calls_loop() {
+        let f = func(
+            &wat("fn count(n, acc) { if n == 0 then acc else count(n - 1, acc + 1) }"),
+            "count",
+        );
+        assert!(f.contains("loop (result i64)"));
+        assert!(f.contains(
+            "local.set $n\n        local.get $tmp.2\n        local.set $acc\n        br 1"
+        ));
+        assert!(!f.contains("call $count"));
+    }
+
+    #[test]
+    fn test_calls_to_stable_functions_are_direct() {
+        let init = func(&wat("fn f(x) { x }; f(1)"), "init");
+        assert!(
+            init.contains("i32.wrap_i64\n    f64.const 1.0\n    i64.reinterpret_f64\n    call $f")
+        );
+        assert!(!init.contains("call_indirect"));
+
+        let init = func(&wat("fn f(x) { x }; f = fn (x) { 2 }; f(1)"), "init");
+        assert!(init.contains("call $rt.closure"));
+        assert!(init.contains("call_indirect (type"));
+    }
+
+    #[test]
+    fn test_captured_names_are_in_cells() {
+        let wat = wat("fn counter() { n = 0; fn () { n = n + 1 } }");
+        let counter = func(&wat, "counter");
+        assert!(counter.contains("(local $n i32)"));
+        assert!(counter.contains("i32.const 8\n    call $rt.alloc\n    local.set $n"));
+        let anonymous = func(&wat, "anonymous");
+        assert!(anonymous.contains("local.get $env\n    i32.load offset=8\n    i64.load"));
+        assert!(anonymous.contains("i64.store"));
+    }
+
+    #[test]
+    fn test_let_shadows_outer_names() {
+        let f = func(&wat("x = 1; fn f() { let x = 2; x }; f()"), "f");
+        assert!(f.contains("(local $x i64)"));
+        assert!(f.contains("local.tee $x"));
+        assert!(!f.contains("global.set $x"));
+    }
+
+    #[test]
+    fn test_strings_are_utf16_data() {
+        let module = compile("\"hi\"").unwrap();
+        let data = &module.data;
+        assert!(data.windows(8).any(|bytes| bytes == b"\x02\0\0\0h\0i\0"));
+    }
+
+    #[test]
+    fn test_match_tests_tags() {
+        let wat = wat("type Shape = Circle(r) | Empty;
+             fn area(s) { match s { Circle(r) => r * r, Empty => 0 } }");
+        let area = func(&wat, "area");
+        assert!(area.contains("call $rt.tag-of"));
+        assert!(area.contains("i32.wrap_i64\n      i64.load offset=8\n      local.set $r"));
+        assert!(area.contains("call $rt.fail\n        unreachable"));
+        assert!(wat.contains("(func $Circle "));
+    }
+
+    #[test]
+    fn test_names_are_escaped() {
+        assert_eq!(format!("{}", Escaped("a b")), "$a%20b");
+        assert_eq!(format!("{}", Escaped("é")), "$%C3%A9");
+        assert!(wat("fn empty?() { 1 }").contains("(func $empty? "));
+    }
+
+    #[test]
+    fn test_undefined_names_are_errors() {
+        let errors = compile("x = 1; y(x)").unwrap_err();
+        assert_eq!(
+            errors,
+            vec![Error {
+                msg: String::from("y isn't defined"),
+                line: 1,
+                col: 7,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_loader_binds_externs() {
+        let module = compile(
+            "extern fn print(x) = \"console.log\";
+             extern \"node:path\" { fn basename(p: String): String };
+             print(basename(substring(\"abc\", 0, 1)))",
+        )
+        .unwrap();
+        let loader = loader(&module, "out.wasm");
+        assert!(loader.contains("var $extern0 = require(\"node:path\");"));
+        assert!(loader.contains(
+            "imports[\"host\"][\"console.log\"] = host(function (...args) { return console.log(...args) });"
+        ));
+        assert!(loader.contains("imports[\"host\"][\"$substring\"] = host(function (s, from, to)"));
+        assert!(loader.contains("join(__dirname, \"out.wasm\")"));
+    }
+}
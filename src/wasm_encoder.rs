@@ -0,0 +1,356 @@
+use super::wasm::{Access, ExportKind, FuncType, Instr, Module, ValType};
+
+// Encodes a module in the binary format, which is what a host instantiates
+pub fn encode(module: &Module) -> Vec<u8> {
+    let mut out = b"\0asm".to_vec();
+    out.extend_from_slice(&1u32.to_le_bytes());
+
+    section(&mut out, 1, &module.types, func_type);
+    section(&mut out, 2, &module.imports, |out, import| {
+        name(out, &import.module);
+        name(out, &import.field);
+        out.push(0x00);
+        unsigned(out, u64::from(import.ty));
+    });
+    section(&mut out, 3, &module.functions, |out, function| {
+        unsigned(out, u64::from(function.ty))
+    });
+
+    // A table of funcrefs and a memory, both with only a minimum size
+    let table = [module.table.len() as u32];
+    section(&mut out, 4, &table, |out, &size| {
+        out.extend_from_slice(&[0x70, 0x00]);
+        unsigned(out, u64::from(size));
+    });
+    section(&mut out, 5, &[module.pages], |out, &pages| {
+        out.push(0x00);
+        unsigned(out, u64::from(pages));
+    });
+    section(&mut out, 6, &module.globals, |out, global| {
+        out.extend_from_slice(&[val_type(global.ty), 0x01]);
+        instr(out, &global.init);
+        out.push(0x0B);
+    });
+    section(&mut out, 7, &module.exports, |out, &(ref export, kind)| {
+        name(out, export);
+        match kind {
+            ExportKind::Function(index) => {
+                out.push(0x00);
+                unsigned(out, u64::from(index));
+            }
+            ExportKind::Table => out.extend_from_slice(&[0x01, 0x00]),
+            ExportKind::Memory => out.extend_from_slice(&[0x02, 0x00]),
+        }
+    });
+    if !module.table.is_empty() {
+        section(&mut out, 9, &[&module.table], |out, table| {
+            out.push(0x00);
+            instr(out, &Instr::I32Const(0));
+            out.push(0x0B);
+            vector(out, table, |out, &index| unsigned(out, u64::from(index)));
+        });
+    }
+    section(&mut out, 10, &module.functions, |out, function| {
+        let mut code = Vec::new();
+        // Runs of locals of the same type are declared together
+        let mut runs: Vec<(u32, ValType)> = Vec::new();
+        for &local in function.locals.iter() {
+            match runs.last_mut() {
+                Some(&mut (ref mut count, ty)) if ty == local => *count += 1,
+                _ => runs.push((1, local)),
+            }
+        }
+        vector(&mut code, &runs, |out, &(count, ty)| {
+            unsigned(out, u64::from(count));
+            out.push(val_type(ty));
+        });
+        for body_instr in function.body.iter() {
+            instr(&mut code, body_instr);
+        }
+        code.push(0x0B);
+        unsigned(out, code.len() as u64);
+        out.extend(code);
+    });
+    if !module.data.is_empty() {
+        section(&mut out, 11, &[&module.data], |out, data| {
+            out.push(0x00);
+            instr(out, &Instr::I32Const(module.data_start() as i32));
+            out.push(0x0B);
+            unsigned(out, data.len() as u64);
+            out.extend_from_slice(data);
+        });
+    }
+
+    out
+}
+
+// A section holds a vector of items, preceded by its id and size. Empty sections are left
+// out.
+fn section<T, F>(out: &mut Vec<u8>, id: u8, items: &[T], item: F)
+where
+    F: Fn(&mut Vec<u8>, &T),
+{
+    if items.is_empty() {
+        return;
+    }
+    let mut contents = Vec::new();
+    vector(&mut contents, items, item);
+    out.push(id);
+    unsigned(out, contents.len() as u64);
+    out.extend(contents);
+}
+
+fn vector<T, F>(out: &mut Vec<u8>, items: &[T], item: F)
+where
+    F: Fn(&mut Vec<u8>, &T),
+{
+    unsigned(out, items.len() as u64);
+    for each in items.iter() {
+        item(out, each);
+    }
+}
+
+fn name(out: &mut Vec<u8>, name: &str) {
+    unsigned(out, name.len() as u64);
+    out.extend_from_slice(name.as_bytes());
+}
+
+fn val_type(ty: ValType) -> u8 {
+    match ty {
+        ValType::I32 => 0x7F,
+        ValType::I64 => 0x7E,
+        ValType::F64 => 0x7C,
+    }
+}
+
+fn func_type(out: &mut Vec<u8>, ty: &FuncType) {
+    out.push(0x60);
+    vector(out, &ty.params, |out, &param| out.push(val_type(param)));
+    vector(out, &ty.results, |out, &result| out.push(val_type(result)));
+}
+
+fn block_type(out: &mut Vec<u8>, ty: Option<ValType>) {
+    match ty {
+        Some(ty) => out.push(val_type(ty)),
+        None => out.push(0x40),
+    }
+}
+
+// The alignment of a memory access, as a power of 2, and then its offset
+fn memory_access(out: &mut Vec<u8>, opcode: u8, align: u64, offset: u32) {
+    out.push(opcode);
+    unsigned(out, align);
+    unsigned(out, u64::from(offset));
+}
+
+fn instr(out: &mut Vec<u8>, instr: &Instr) {
+    match *instr {
+        Instr::Block(ty) => {
+            out.push(0x02);
+            block_type(out, ty);
+        }
+        Instr::Loop(ty) => {
+            out.push(0x03);
+            block_type(out, ty);
+        }
+        Instr::If(ty) => {
+            out.push(0x04);
+            block_type(out, ty);
+        }
+        Instr::Else => out.push(0x05),
+        Instr::End => out.push(0x0B),
+        Instr::Br(depth) => {
+            out.push(0x0C);
+            unsigned(out, u64::from(depth));
+        }
+        Instr::BrIf(depth) => {
+            out.push(0x0D);
+            unsigned(out, u64::from(depth));
+        }
+        Instr::Return => out.push(0x0F),
+        Instr::Unreachable => out.push(0x00),
+        Instr::Drop => out.push(0x1A),
+        Instr::Call(index) => {
+            out.push(0x10);
+            unsigned(out, u64::from(index));
+        }
+        Instr::CallIndirect(ty) => {
+            out.push(0x11);
+            unsigned(out, u64::from(ty));
+            out.push(0x00);
+        }
+        Instr::LocalGet(index) => {
+            out.push(0x20);
+            unsigned(out, u64::from(index));
+        }
+        Instr::LocalSet(index) => {
+            out.push(0x21);
+            unsigned(out, u64::from(index));
+        }
+        Instr::LocalTee(index) => {
+            out.push(0x22);
+            unsigned(out, u64::from(index));
+        }
+        Instr::GlobalGet(index) => {
+            out.push(0x23);
+            unsigned(out, u64::from(index));
+        }
+        Instr::GlobalSet(index) => {
+            out.push(0x24);
+            unsigned(out, u64::from(index));
+        }
+        Instr::Load(access, offset) => match access {
+            Access::I32 => memory_access(out, 0x28, 2, offset),
+            Access::I64 => memory_access(out, 0x29, 3, offset),
+            Access::U16 => memory_access(out, 0x2F, 1, offset),
+        },
+        Instr::Store(access, offset) => match access {
+            Access::I32 => memory_access(out, 0x36, 2, offset),
+            Access::I64 => memory_access(out, 0x37, 3, offset),
+            Access::U16 => memory_access(out, 0x3B, 1, offset),
+        },
+        Instr::MemorySize => out.extend_from_slice(&[0x3F, 0x00]),
+        Instr::MemoryGrow => out.extend_from_slice(&[0x40, 0x00]),
+        Instr::MemoryCopy => out.extend_from_slice(&[0xFC, 0x0A, 0x00, 0x00]),
+        Instr::I32Const(val) => {
+            out.push(0x41);
+            signed(out, i64::from(val));
+        }
+        Instr::I64Const(val) => {
+            out.push(0x42);
+            signed(out, val);
+        }
+        Instr::F64Const(val) => {
+            out.push(0x44);
+            out.extend_from_slice(&val.to_le_bytes());
+        }
+        Instr::Numeric(op) => out.push(op.encoding().1),
+    }
+}
+
+// LEB128, 7 bits at a time with the high bit set on all but the last byte
+fn unsigned(out: &mut Vec<u8>, mut val: u64) {
+    loop {
+        let byte = (val & 0x7F) as u8;
+        val >>= 7;
+        if val == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+// Signed LEB128 ends once the rest of the value is just the sign bit of the last byte
+fn signed(out: &mut Vec<u8>, mut val: i64) {
+    loop {
+        let byte = (val & 0x7F) as u8;
+        val >>= 7;
+        if (val == 0 && byte & 0x40 == 0) || (val == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use modules;
+    use prelude;
+    use std::path::Path;
+    use wasm;
+
+    fn leb(val: u64) -> Vec<u8> {
+        let mut out = Vec::new();
+        unsigned(&mut out, val);
+        out
+    }
+
+    fn sleb(val: i64) -> Vec<u8> {
+        let mut out = Vec::new();
+        signed(&mut out, val);
+        out
+    }
+
+    #[test]
+    fn test_leb128() {
+        assert_eq!(leb(0), vec![0x00]);
+        assert_eq!(leb(127), vec![0x7F]);
+        assert_eq!(leb(128), vec![0x80, 0x01]);
+        assert_eq!(leb(624485), vec![0xE5, 0x8E, 0x26]);
+        assert_eq!(sleb(-1), vec![0x7F]);
+        assert_eq!(sleb(63), vec![0x3F]);
+        assert_eq!(sleb(64), vec![0xC0, 0x00]);
+        assert_eq!(sleb(-123456), vec![0xC0, 0xBB, 0x78]);
+        assert_eq!(sleb(i64::MIN).len(), 10);
+    }
+
+    #[test]
+    fn test_sections_are_in_order() {
+        let modules = modules::load_with(Path::new("main.silver"), |_| {
+            Ok(String::from("fn f(x) { [x, \"s\"] }; f(1)"))
+        })
+        .unwrap();
+        let module = wasm::compile_program(&modules, &prelude::module()).unwrap();
+        let bytes = encode(&module);
+        assert_eq!(&bytes[..8], b"\0asm\x01\0\0\0");
+
+        // Each section's id and size, which must account for every byte
+        let mut ids = Vec::new();
+        let mut i = 8;
+        while i < bytes.len() {
+            ids.push(bytes[i]);
+            let (mut size, mut shift) = (0, 0);
+            loop {
+                i += 1;
+                size |= usize::from(bytes[i] & 0x7F) << shift;
+                shift += 7;
+                if bytes[i] & 0x80 == 0 {
+                    break;
+                }
+            }
+            i += 1 + size;
+        }
+        assert_eq!(i, bytes.len());
+        assert_eq!(ids, vec![1, 2, 3, 4, 5, 6, 7, 9, 10, 11]);
+    }
+
+    // The encoded module is run by its loader, with node when it's on the PATH
+    #[test]
+    fn test_round_trip() {
+        use std::fs;
+        use std::process::Command;
+
+        if Command::new("node").arg("--version").output().is_err() {
+            return;
+        }
+        let inp = "extern fn print(x) = \"console.log\";
+                   fn count(n, total) { if n == 0 then total else count(n - 1, total + n) };
+                   print(count(100000, 0));
+                   fn adder(x) { fn (y) { x + y } }; let add = adder(1); print(add(2) * 3);
+                   type Shape = Circle(r) | Square(side);
+                   fn area(s) { match s { Circle(r) => 3 * r * r, Square(side) => side * side } };
+                   print(area(Circle(2)) - area(Square(3)));
+                   print(\"a b\" + to-string(sum([1, 2, 3])))";
+        let modules =
+            modules::load_with(Path::new("main.silver"), |_| Ok(String::from(inp))).unwrap();
+        let module = wasm::compile_program(&modules, &prelude::module()).unwrap();
+
+        let dir = std::env::temp_dir().join(format!("silver-wasm-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("out.wasm"), encode(&module)).unwrap();
+        fs::write(dir.join("out.js"), wasm::loader(&module, "out.wasm")).unwrap();
+        let output = Command::new("node").arg(dir.join("out.js")).output();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let output = output.unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stderr), "");
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            "5000050000\n9\n3\na b6\n"
+        );
+    }
+}